@@ -1,13 +1,11 @@
-use std::{collections::HashMap, sync::Arc, u64};
+use std::{collections::HashMap, sync::Arc};
 
-use call_agent::chat::{client::{ModelConfig, OpenAIClient, OpenAIClientState, ToolMode}, prompt::{Message, MessageContext, MessageImage}};
+use call_agent::chat::{client::{APIResult, ModelConfig, OpenAIClient, OpenAIClientState, ToolMode}, prompt::{Message, MessageContext, MessageImage}};
 use log::{debug, info};
-use observer::prefix::{ASK_DEVELOPER_PROMPT, ASSISTANT_NAME, MAX_USE_TOOL_COUNT, MODEL_GENERATE_MAX_TOKENS, MODEL_NAME};
+use observer::{attachments, prefix::MODEL_NAME, history, image_cache, images, openai_api::TokenUsage, sources, turn::{self, TurnFile}};
 use regex::Regex;
-use serenity::all::{Context, CreateMessage, MessageFlags};
 use tokio::sync::Mutex;
 
-use crate::fetch_and_encode_images;
 
 pub const PROMPT_ENTRY_LIMIT: u64 = 48; // プロンプトのエントリ数の上限
 
@@ -20,8 +18,72 @@ pub struct InputMessage {
     pub user_id: String,
     pub attached_files: Vec<String>,
 }
+/// 推論結果
+#[derive(Clone, Debug)]
+pub struct Answer {
+    /// モデルの応答本文 (エラー時はエラーメッセージ)
+    pub content: String,
+    /// `-# model: ...` などの付加情報
+    pub footer: String,
+    /// ツールで参照した情報源の URL (重複なし・参照順)
+    pub sources: Vec<String>,
+    /// 推論に失敗したかどうか
+    pub is_error: bool,
+    /// ツールループ全体で使ったトークン数
+    pub usage: TokenUsage,
+    /// ツールが作った添付ファイル (生成した画像など)
+    pub files: Vec<TurnFile>,
+}
+
+impl Answer {
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            content: message.into(),
+            footer: String::new(),
+            sources: Vec::new(),
+            is_error: true,
+            usage: TokenUsage::default(),
+            files: Vec::new(),
+        }
+    }
+}
+
+/// 推論に使う設定
+#[derive(Clone, Debug)]
+pub struct ReasoningConfig {
+    pub model_config: ModelConfig,
+    pub developer_prompt: String,
+    /// ツールを使える最大回数 (これを超えるとツールを無効にして応答させる)
+    pub max_use_tool_count: usize,
+}
+
+/// API の応答に含まれるトークン数
+fn api_usage(result: &APIResult) -> TokenUsage {
+    result.response.usage.as_ref().map(|u| TokenUsage {
+        prompt_tokens: u.prompt_tokens.unwrap_or(0),
+        completion_tokens: u.completion_tokens.unwrap_or(0),
+    }).unwrap_or_default()
+}
+
+/// 推論中の状況 (`-# using browser...` など) の通知先
+pub trait StatusSink {
+    async fn post_status(&self, text: String);
+
+    /// 1 ターンで履歴に追加されるメッセージ (ツールコールとその結果を含む) を受け取る
+    fn show_turn(&self, _turn: &[Message]) {}
+}
+
+/// 進捗を流さない (API やダイジェストなど)
+pub struct Silent;
+
+impl StatusSink for Silent {
+    async fn post_status(&self, _text: String) {}
+}
+
 // 各チャンネルの会話履歴（state）を保持する構造体
 pub struct ChannelState {
+    /// `Bot::channels` のキー (添付ファイルの置き場もこれで分ける)
+    pub channel: String,
     // 並列処理のため、prompt_stream を Mutex で保護する
     pub prompt_stream: Mutex<OpenAIClientState>,
 }
@@ -67,6 +129,11 @@ impl AIModel {
         }
     }
 
+    /// 選択できるすべてのモデル
+    pub fn all() -> [AIModel; 5] {
+        [AIModel::MO4Mini, AIModel::MO3, AIModel::M5Nano, AIModel::M5Mini, AIModel::M5]
+    }
+
     pub fn from_model_name(model_name: &str) -> Result<Self, String> {
         match model_name {
             // "o3" => Ok(AIModel::MO3),
@@ -79,7 +146,8 @@ impl AIModel {
         }
     }
 
-    pub fn to_model_config(&self) -> ModelConfig {
+    /// 推論に使うモデル設定 (`assistant_name` は発言者名、`max_tokens` は生成トークン数の上限)
+    pub fn model_config(&self, assistant_name: &str, max_tokens: u64) -> ModelConfig {
         match self {
             // AIModel::MO3 => ModelConfig {
             //     model: "o3".to_string(),
@@ -94,10 +162,10 @@ impl AIModel {
             // },
             AIModel::MO4Mini => ModelConfig {
                 model: "o4-mini".to_string(),
-                model_name: Some(ASSISTANT_NAME.to_string()),
+                model_name: Some(assistant_name.to_string()),
                 parallel_tool_calls: None,
                 temperature: None,
-                max_completion_tokens: Some(*MODEL_GENERATE_MAX_TOKENS as u64),
+                max_completion_tokens: Some(max_tokens),
                 reasoning_effort: Some("low".to_string()),
                 presence_penalty: None,
                 strict: Some(false),
@@ -106,10 +174,10 @@ impl AIModel {
             },
             AIModel::MO3 => ModelConfig {
                 model: "o3".to_string(),
-                model_name: Some(ASSISTANT_NAME.to_string()),
+                model_name: Some(assistant_name.to_string()),
                 parallel_tool_calls: None,
                 temperature: None,
-                max_completion_tokens: Some(*MODEL_GENERATE_MAX_TOKENS as u64),
+                max_completion_tokens: Some(max_tokens),
                 reasoning_effort: Some("low".to_string()),
                 presence_penalty: None,
                 strict: Some(false),
@@ -118,10 +186,10 @@ impl AIModel {
             },
             AIModel::M5Nano => ModelConfig {
                 model: "gpt-5-nano".to_string(),
-                model_name: Some(ASSISTANT_NAME.to_string()),
+                model_name: Some(assistant_name.to_string()),
                 parallel_tool_calls: Some(true),
                 temperature: None,
-                max_completion_tokens: Some(*MODEL_GENERATE_MAX_TOKENS as u64),
+                max_completion_tokens: Some(max_tokens),
                 reasoning_effort: Some("low".to_string()),
                 presence_penalty: None,
                 strict: Some(false),
@@ -130,10 +198,10 @@ impl AIModel {
             },
             AIModel::M5Mini => ModelConfig {
                 model: "gpt-5-mini".to_string(),
-                model_name: Some(ASSISTANT_NAME.to_string()),
+                model_name: Some(assistant_name.to_string()),
                 parallel_tool_calls: Some(true),
                 temperature: None,
-                max_completion_tokens: Some(*MODEL_GENERATE_MAX_TOKENS as u64),
+                max_completion_tokens: Some(max_tokens),
                 reasoning_effort: Some("low".to_string()),
                 presence_penalty: None,
                 strict: Some(false),
@@ -142,10 +210,10 @@ impl AIModel {
             },
             AIModel::M5 => ModelConfig {
                 model: "gpt-5".to_string(),
-                model_name: Some(ASSISTANT_NAME.to_string()),
+                model_name: Some(assistant_name.to_string()),
                 parallel_tool_calls: Some(true),
                 temperature: None,
-                max_completion_tokens: Some(*MODEL_GENERATE_MAX_TOKENS as u64),
+                max_completion_tokens: Some(max_tokens),
                 reasoning_effort: Some("low".to_string()),
                 presence_penalty: None,
                 strict: Some(false),
@@ -159,25 +227,31 @@ impl AIModel {
 impl Default for AIModel {
     fn default() -> Self {
         AIModel::from_model_name(*MODEL_NAME)
-            .unwrap_or_else(|_| AIModel::MO4Mini) // デフォルトは o4-mini
+            .unwrap_or(AIModel::MO4Mini) // デフォルトは o4-mini
     }
 }
 
 impl ChannelState {
-    pub async fn new(client: &Arc<OpenAIClient>) -> Self {
+    pub async fn new(client: &Arc<OpenAIClient>, channel: &str) -> Self {
         // 新しい PromptStream を生成する
         let mut prompt_stream = client.create_prompt();
         prompt_stream.set_entry_limit(PROMPT_ENTRY_LIMIT).await;
         // Extend lifetime to 'static; safe because client lives for the entire duration of the program
         Self {
+            channel: channel.to_string(),
             prompt_stream: Mutex::new(prompt_stream),
         }
     }
 
-    async fn prepare_user_prompt(message: &mut InputMessage, viw_image_detail: u8) -> Vec<Message> {
-        // スポイラーを含むメッセージの処理
+    /// スポイラーを伏せる
+    fn mask_spoilers(content: &str) -> String {
         let re = Regex::new(r"(\|\|.*?\|\|)").unwrap();
-        message.content = re.replace_all(&message.content, "||<spoiler_msg>||").to_string();
+        re.replace_all(content, "||<spoiler_msg>||").to_string()
+    }
+
+    async fn prepare_user_prompt(&self, message: &mut InputMessage, viw_image_detail: u8) -> Vec<Message> {
+        // スポイラーを含むメッセージの処理
+        message.content = Self::mask_spoilers(&message.content);
 
         // !hidetail が含まれていれば強制的に high detail
         let mut detail_flag = viw_image_detail;
@@ -199,19 +273,40 @@ impl ChannelState {
         let mut content_vec = Vec::new();
         content_vec.push(MessageContext::Text(meta));
 
+        // テキスト・コード・PDF の添付ファイルは見出し付きのブロックにする
+        if !message.attached_files.is_empty() {
+            for block in attachments::load(&self.channel, &message.user_id, &message.message_id, &message.attached_files).await {
+                content_vec.push(MessageContext::Text(block));
+            }
+        }
+
+        // 画像の URL はツール (image_captioner や image_generator の編集) に渡せるように書いておく
+        let image_urls: Vec<&str> = message.attached_files
+            .iter()
+            .filter(|url| images::is_visual(url))
+            .map(String::as_str)
+            .collect();
+        if !image_urls.is_empty() {
+            content_vec.push(MessageContext::Text(format!("[attached images: {}]", image_urls.join(" "))));
+        }
+
         // detail_flag に応じて画像を追加
         if detail_flag != 0 {
-            // 画像を取得して data URL にした Vec<String>
-            let img_urls = fetch_and_encode_images(&message.attached_files).await;
+            // 画像を取得して data URL (キャッシュがあれば参照) にする
+            let loaded = image_cache::load_all(&message.attached_files).await;
 
-            for url in img_urls {
+            for image in loaded {
+                // コンタクトシートにはフレーム数と長さを添える
+                if let Some(note) = image.note() {
+                    content_vec.push(MessageContext::Text(note));
+                }
                 let detail_str = match detail_flag {
                     1 => Some("low".to_string()),
                     255 => Some("high".to_string()),
                     _ => None,
                 };
                 content_vec.push(MessageContext::Image(MessageImage {
-                    url,
+                    url: image.url,
                     detail: detail_str,
                 }));
             }
@@ -223,28 +318,118 @@ impl ChannelState {
         }]
     }
 
-    pub async fn reasoning(
-        &self, 
-        ctx: &Context,
-        msg: &serenity::all::Message,
+    /// 推論の本体
+    pub async fn run_reasoning(
+        &self,
+        mut message: InputMessage,
+        config: &ReasoningConfig,
+        status: &impl StatusSink,
+    ) -> Answer {
+        let user_prompt = self.prepare_user_prompt(&mut message, 1).await;
+        self.reason(user_prompt, config, status).await
+    }
+
+    /// 履歴の写しで推論する (発言も応答も履歴には残さない)
+    pub async fn run_detached(
+        &self,
         mut message: InputMessage,
-        model: AIModel,
-    ) -> String {
+        config: &ReasoningConfig,
+        status: &impl StatusSink,
+    ) -> Answer {
+        let user_prompt = self.prepare_user_prompt(&mut message, 1).await;
+        let mut prompt_stream = self.prompt_stream.lock().await.clone();
+        prompt_stream.add(user_prompt).await;
+        Self::reason_on(prompt_stream, config, status).await.0
+    }
+
+    /// 用意済みのユーザーメッセージを履歴に加えて推論する
+    pub async fn reason(
+        &self,
+        user_prompt: Vec<Message>,
+        config: &ReasoningConfig,
+        status: &impl StatusSink,
+    ) -> Answer {
+        // 応答は推論の始まった発言の直後に入れる
+        let trigger = user_prompt.iter().find_map(history::UserMeta::from_message).map(|meta| meta.message_id);
         // プロンプトストリームの取得
-        let user_prompt = ChannelState::prepare_user_prompt(&mut message, 1).await;
         let mut r_prompt_stream = self.prompt_stream.lock().await;
         r_prompt_stream.add(user_prompt).await;
-        let mut prompt_stream = r_prompt_stream.clone();
-        drop(r_prompt_stream); // 先にロックを解除t_stream.clone();
-        prompt_stream.client.set_model_config(&model.to_model_config());
+        let prompt_stream = r_prompt_stream.clone();
+        drop(r_prompt_stream); // 先にロックを解除
+        let (answer, turn) = Self::reason_on(prompt_stream, config, status).await;
+        if let Some(turn) = turn {
+            Self::merge_turn(&mut *self.prompt_stream.lock().await, trigger.as_deref(), turn).await;
+        }
+        answer
+    }
+
+    /// 発言 `message_id` への応答を作り直す (応答がなければ None)
+    /// 前の応答を除いた履歴で推論し、成功したときだけ前の応答を新しい応答に置き換える
+    pub async fn regenerate(
+        &self,
+        message_id: &str,
+        config: &ReasoningConfig,
+        status: &impl StatusSink,
+    ) -> Option<Answer> {
+        let mut prompt_stream = self.prompt_stream.lock().await.clone();
+        let span = history::find_turn(&prompt_stream.prompt, message_id)?;
+        // 発言は最後に置き直す (応答の後に積まれた発言はそのまま見せる)
+        let user_message = prompt_stream.prompt.drain(span.user..=*span.answer.end()).next()?;
+        prompt_stream.add(vec![user_message]).await;
+        let (answer, turn) = Self::reason_on(prompt_stream, config, status).await;
+        if let Some(turn) = turn {
+            Self::merge_turn(&mut *self.prompt_stream.lock().await, Some(message_id), turn).await;
+        }
+        Some(answer)
+    }
+
+    /// 発言 `message_id` に応答があるか
+    pub async fn has_answer(&self, message_id: &str) -> bool {
+        history::find_turn(&self.prompt_stream.lock().await.prompt, message_id).is_some()
+    }
+
+    /// 推論したターンを発言の直後に入れる (前の応答があれば置き換える)
+    /// 推論中に積まれた発言より前に置くので、ターンは発言から応答までひと続きになる
+    /// 発言が履歴から消えていれば末尾に足す
+    async fn merge_turn(prompt_stream: &mut OpenAIClientState, trigger: Option<&str>, turn: Vec<Message>) {
+        let Some(trigger) = trigger else {
+            prompt_stream.add(turn).await;
+            return;
+        };
+        if let Some(span) = history::find_turn(&prompt_stream.prompt, trigger) {
+            prompt_stream.prompt.drain(span.answer);
+        }
+        let Some(index) = history::find_user_message(&prompt_stream.prompt, trigger) else {
+            prompt_stream.add(turn).await;
+            return;
+        };
+        for (i, message) in turn.into_iter().enumerate() {
+            prompt_stream.prompt.insert(index + 1 + i, message);
+        }
+        if let Some(limit) = prompt_stream.entry_limit {
+            while prompt_stream.prompt.len() as u64 > limit {
+                prompt_stream.prompt.pop_front();
+            }
+        }
+    }
+
+    /// 切り離したプロンプトストリームで推論し、応答と履歴に加えるターン (失敗したら None) を返す
+    async fn reason_on(
+        mut prompt_stream: OpenAIClientState,
+        config: &ReasoningConfig,
+        status: &impl StatusSink,
+    ) -> (Answer, Option<Vec<Message>>) {
+        prompt_stream.client.set_model_config(&config.model_config);
         prompt_stream.set_entry_limit(u64::MAX).await;
+        // 送る分だけ画像の参照を戻し、古い画像を軽くする (履歴は参照のまま)
+        image_cache::prepare(image_cache::installed(), &mut prompt_stream.prompt);
         let last_pos = prompt_stream.prompt.len();
 
         // システムプロンプトの追加
         debug!("prompt_stream - {:#?}", prompt_stream.prompt);
         let system_prompt = vec![Message::Developer {
-            content: ASK_DEVELOPER_PROMPT.to_string(),
-            name: Some(ASSISTANT_NAME.to_string()),
+            content: config.developer_prompt.clone(),
+            name: config.model_config.model_name.clone(),
         }];
         prompt_stream.add_last(system_prompt).await;
 
@@ -254,11 +439,12 @@ impl ChannelState {
         // 推論ストリームの生成
         let mut reasoning_stream = match prompt_stream.reasoning(None, &ToolMode::Auto).await {
             Ok(stream) => stream,
-            Err(e) => return format!("Err: failed reasoning - {:?}", e),
+            Err(e) => return (Answer::error(format!("Err: failed reasoning - {:?}", e)), None),
         };
+        let mut usage = api_usage(&reasoning_stream.api_result);
 
         // 推論ループ
-        for i in 0..*MAX_USE_TOOL_COUNT + 1 {
+        for i in 0..config.max_use_tool_count + 1 {
             // 終了できるなら終了
             if reasoning_stream.can_finish() {
                 break;
@@ -274,37 +460,23 @@ impl ChannelState {
             for (tool_name, argument) in show_tool_call {
                 used_tools.push(tool_name.clone());
                 if let Some(explain) = argument.get("$explain") {
-                    let status_res = CreateMessage::new()
-                        .content(format!("-# {}...", explain.to_string()))
-                        .flags(MessageFlags::SUPPRESS_EMBEDS);
-    
-                    if let Err(e) = msg.channel_id.send_message(&ctx.http, status_res).await {
-                        debug!("Error sending message: {:?}", e);
-                    }
+                    status.post_status(format!("-# {}...", explain)).await;
                 } else {
-                    let status_res = CreateMessage::new()
-                        .content(format!("-# using {}...", tool_name))
-                        .flags(MessageFlags::SUPPRESS_EMBEDS);
-    
-                    if let Err(e) = msg.channel_id.send_message(&ctx.http, status_res).await {
-                        debug!("Error sending message: {:?}", e);
-                    }
+                    status.post_status(format!("-# using {}...", tool_name)).await;
                 }
             }
             
             // 推論の上限回数を超えた場合はツールモードを無効化
-            let mode = if i == *MAX_USE_TOOL_COUNT {
+            let mode = if i == config.max_use_tool_count {
                 ToolMode::Disable
             } else {
                 ToolMode::Auto
             };
             // 推論の実行
-            match reasoning_stream.proceed(&mode).await {
-                Err(e) => {
-                                return format!("Err: failed reasoning - {:?}", e);
-                            }
-                Ok(_) => {},
+            if let Err(e) = reasoning_stream.proceed(&mode).await {
+                return (Answer::error(format!("Err: failed reasoning - {:?}", e)), None);
             }
+            usage.add(api_usage(&reasoning_stream.api_result));
         }
 
         // 推論結果の取得
@@ -328,17 +500,23 @@ impl ChannelState {
         } else {
             "".to_string()
         };
-        // プロンプトストリームに分岐した分部をマージ
-        let differential_stream = prompt_stream.prompt.split_off(last_pos + 1 /* 先頭のシステムプロンプト消す */);
-        {
-            let mut r_prompt_stream = self.prompt_stream.lock().await;
-            r_prompt_stream.add(differential_stream.into()).await;
-        }
-        return content.replace("\\n", "\n") + &model_info + &used_tools_info;
+        // プロンプトストリームに分岐した分部を返す
+        let mut differential_stream = prompt_stream.prompt.split_off(last_pos + 1 /* 先頭のシステムプロンプト消す */);
+        status.show_turn(differential_stream.make_contiguous());
+        let sources = sources::collect_sources(&differential_stream);
+        let answer = Answer {
+            content: content.replace("\\n", "\n"),
+            footer: model_info + &used_tools_info,
+            sources,
+            is_error: false,
+            usage,
+            files: turn::take_files(),
+        };
+        (answer, Some(differential_stream.into()))
     }
 
     pub async fn add_message(&self, mut message: InputMessage) {
-        let user_prompt = ChannelState::prepare_user_prompt(&mut message, 1).await;
+        let user_prompt = self.prepare_user_prompt(&mut message, 1).await;
         let mut prompt_stream = self.prompt_stream.lock().await;
 
 
@@ -346,8 +524,218 @@ impl ChannelState {
         prompt_stream.add(user_prompt).await;
     }
 
+    /// 編集されたメッセージの本文を書き換える (履歴になければ false)
+    pub async fn edit_message(&self, message_id: &str, content: &str) -> bool {
+        let mut prompt_stream = self.prompt_stream.lock().await;
+        let Some(index) = history::find_user_message(&prompt_stream.prompt, message_id) else {
+            return false;
+        };
+        history::rewrite_body(&mut prompt_stream.prompt[index], &Self::mask_spoilers(content))
+    }
+
+    /// 削除されたメッセージを応答とツールのやり取りごと履歴から取り除き、その応答でツールが作った記事とメモリを返す
+    /// 履歴になければ None
+    pub async fn remove_message(&self, message_id: &str) -> Option<Vec<history::Derived>> {
+        let mut prompt_stream = self.prompt_stream.lock().await;
+        let index = history::find_user_message(&prompt_stream.prompt, message_id)?;
+        let range = history::turn_range(&prompt_stream.prompt, index);
+        let turn: Vec<Message> = prompt_stream.prompt.drain(range).collect();
+        Some(history::derived_from(&turn[1..]))
+    }
+
     pub async fn clear_prompt(&self) {
         let mut prompt_stream = self.prompt_stream.lock().await;
         prompt_stream.clear().await;
     }
-}
\ No newline at end of file
+
+    /// 現在の会話履歴を指定の形式で書き出す
+    pub async fn export(&self, format: history::ExportFormat) -> String {
+        let prompt_stream = self.prompt_stream.lock().await;
+        history::export(&prompt_stream.prompt, format)
+    }
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use call_agent::chat::function::Tool;
+    use observer::cassette::{CassetteMode, CassetteServer};
+    use serde_json::{json, Value};
+
+    pub(crate) struct EchoTool;
+
+    impl Tool for EchoTool {
+        fn def_name(&self) -> &str {
+            "echo"
+        }
+
+        fn def_description(&self) -> &str {
+            "Echo back the given text"
+        }
+
+        fn def_parameters(&self) -> Value {
+            json!({
+                "type": "object",
+                "properties": { "text": { "type": "string" } },
+                "required": ["text"]
+            })
+        }
+
+        fn run(&self, args: Value) -> Result<String, String> {
+            args["text"].as_str().map(|s| s.to_string()).ok_or("text is required".to_string())
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingStatus(std::sync::Mutex<Vec<String>>);
+
+    impl StatusSink for RecordingStatus {
+        async fn post_status(&self, text: String) {
+            self.0.lock().unwrap().push(text);
+        }
+    }
+
+    async fn setup(cassette: &str) -> (CassetteServer, ChannelState) {
+        let path = format!("{}/tests/cassettes/{}.json", env!("CARGO_MANIFEST_DIR"), cassette);
+        let server = CassetteServer::start(path, CassetteMode::from_env()).await.unwrap();
+        let api_key = std::env::var("OPENAI_API_KEY").unwrap_or_else(|_| "test".to_string());
+        let mut client = OpenAIClient::new(&server.endpoint(), Some(&api_key));
+        client.def_tool(Arc::new(EchoTool));
+        let state = ChannelState::new(&Arc::new(client), "c1").await;
+        (server, state)
+    }
+
+    fn config(max_use_tool_count: usize) -> ReasoningConfig {
+        ReasoningConfig {
+            model_config: ModelConfig {
+                model: "gpt-5-mini".to_string(),
+                model_name: Some("observer".to_string()),
+                top_p: None,
+                parallel_tool_calls: None,
+                temperature: None,
+                max_completion_tokens: None,
+                reasoning_effort: None,
+                presence_penalty: None,
+                strict: Some(false),
+                web_search_options: None,
+            },
+            developer_prompt: "You are observer. Use tools when asked.".to_string(),
+            max_use_tool_count,
+        }
+    }
+
+    fn input(message_id: &str, content: &str) -> InputMessage {
+        InputMessage {
+            content: content.to_string(),
+            name: "alice".to_string(),
+            message_id: message_id.to_string(),
+            reply_msg: None,
+            user_id: "42".to_string(),
+            attached_files: Vec::new(),
+        }
+    }
+
+    fn roles(state: &OpenAIClientState) -> Vec<&'static str> {
+        state.prompt.iter().map(|m| match m {
+            Message::User { .. } => "user",
+            Message::Assistant { tool_calls: Some(_), .. } => "assistant(tool_calls)",
+            Message::Assistant { .. } => "assistant",
+            Message::Tool { .. } => "tool",
+            Message::System { .. } => "system",
+            Message::Developer { .. } => "developer",
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn tool_call_loop_runs_tool_and_merges_history() {
+        let (server, state) = setup("tool_loop").await;
+        let status = RecordingStatus::default();
+        let answer = state.run_reasoning(input("1", "echo hello please"), &config(3), &status).await;
+
+        assert!(server.misses().is_empty(), "unexpected requests: {:#?}", server.misses());
+        assert!(!answer.is_error, "{}", answer.content);
+        assert_eq!(answer.content, "echo said: hello");
+        assert_eq!(answer.footer, "\n-# model: gpt-5-mini\n-# tools: echo");
+        assert_eq!(*status.0.lock().unwrap(), vec!["-# using echo...".to_string()]);
+        // 開発者プロンプトは履歴に残らない
+        let prompt = state.prompt_stream.lock().await;
+        assert_eq!(roles(&prompt), vec!["user", "assistant(tool_calls)", "tool", "assistant"]);
+    }
+
+    #[tokio::test]
+    async fn tools_are_disabled_after_max_use_tool_count() {
+        let (server, state) = setup("tool_cutoff").await;
+        let answer = state.run_reasoning(input("1", "keep echoing forever"), &config(1), &RecordingStatus::default()).await;
+
+        assert!(server.misses().is_empty(), "unexpected requests: {:#?}", server.misses());
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1]["tool_choice"], "auto");
+        // ToolMode::Disable では tool_choice が送られない
+        assert!(requests[2].get("tool_choice").is_none());
+        assert_eq!(answer.content, "I stopped echoing.");
+        assert_eq!(answer.footer, "\n-# model: gpt-5-mini\n-# tools: echo x2");
+    }
+
+    #[tokio::test]
+    async fn consecutive_turns_share_history() {
+        let (server, state) = setup("history_merge").await;
+        let first = state.run_reasoning(input("1", "my name is alice"), &config(3), &RecordingStatus::default()).await;
+        let second = state.run_reasoning(input("2", "what is my name?"), &config(3), &RecordingStatus::default()).await;
+
+        assert!(server.misses().is_empty(), "unexpected requests: {:#?}", server.misses());
+        assert_eq!(first.content, "nice to meet you, alice");
+        assert_eq!(second.content, "alice");
+        // 2 回目のリクエストに 1 回目のやり取りが含まれている
+        assert_eq!(server.requests()[1]["messages"].as_array().unwrap().len(), 4);
+        let prompt = state.prompt_stream.lock().await;
+        assert_eq!(roles(&prompt), vec!["user", "assistant", "user", "assistant"]);
+    }
+
+    #[tokio::test]
+    async fn turns_are_merged_right_after_their_message() {
+        use call_agent::chat::function::{FunctionCall, FunctionCallInner};
+
+        let (_server, state) = setup("tool_loop").await;
+        let text = |id: &str, body: &str| vec![MessageContext::Text(format!("[META]msg_id:{},user_name:alice,replay_msg:none;\n{}", id, body))];
+        let answer = |body: &str| Message::Assistant { name: None, content: vec![MessageContext::Text(body.into())], tool_calls: None };
+        let mut prompt = state.prompt_stream.lock().await;
+        prompt.prompt.extend([
+            Message::User { name: Some("42".into()), content: text("1", "question") },
+            // 推論中に積まれた発言
+            Message::User { name: Some("7".into()), content: text("2", "chatter") },
+        ]);
+        let turn = vec![
+            Message::Assistant {
+                name: None,
+                content: vec![],
+                tool_calls: Some(vec![FunctionCall {
+                    id: "c1".into(),
+                    tool_type: "function".into(),
+                    function: FunctionCallInner { name: "echo".into(), arguments: json!({ "text": "out" }) },
+                }]),
+            },
+            Message::Tool { tool_call_id: "c1".into(), content: vec![MessageContext::Text("out".into())] },
+            answer("one"),
+        ];
+        ChannelState::merge_turn(&mut prompt, Some("1"), turn).await;
+        assert_eq!(roles(&prompt), vec!["user", "assistant(tool_calls)", "tool", "assistant", "user"]);
+        assert_eq!(history::find_turn(&prompt.prompt, "1").unwrap().answer, 1..=3);
+        assert!(history::find_turn(&prompt.prompt, "2").is_none());
+
+        // 作り直した応答は前の応答と置き換える
+        ChannelState::merge_turn(&mut prompt, Some("1"), vec![answer("two")]).await;
+        assert_eq!(roles(&prompt), vec!["user", "assistant", "user"]);
+        assert!(matches!(&prompt.prompt[1], Message::Assistant { content, .. } if matches!(&content[0], MessageContext::Text(t) if t == "two")));
+    }
+
+    #[tokio::test]
+    async fn api_error_keeps_only_the_user_message() {
+        let (_server, state) = setup("rate_limited").await;
+        let answer = state.run_reasoning(input("1", "hello"), &config(3), &RecordingStatus::default()).await;
+
+        assert!(answer.is_error);
+        assert!(answer.content.starts_with("Err: failed reasoning"));
+        let prompt = state.prompt_stream.lock().await;
+        assert_eq!(roles(&prompt), vec!["user"]);
+    }
+}
@@ -0,0 +1,577 @@
+//! テキスト・コード・PDF の添付ファイル
+//!
+//! 画像以外の添付ファイルのうち本文として読めるもの (`web_scraper` の WHITELIST にある Content-Type か、
+//! 対応する拡張子) と PDF を取得してテキストにし、ユーザーの発言に見出し付きのブロックとして加える。
+//! 長いファイルは先頭だけを入れ、残りは `attachment_reader` ツールでページごとに読ませる。
+//! 読んだファイルはチャンネルごとに分けて保存するので、ほかのチャンネルからは読めず、再起動しても続きを読める。
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{tools::web_scraper::is_text_content_type, untrusted};
+
+/// 取得する添付ファイルの上限
+pub const MAX_ATTACHMENT_BYTES: usize = 2 * 1024 * 1024;
+/// 発言にそのまま入れる最大文字数 (超えたぶんはツールで読む)
+pub const INLINE_CHARS: usize = 6000;
+/// ツールが 1 回で返す最大文字数
+pub const PAGE_CHARS: usize = 8000;
+/// チャンネルごとにツールで読めるように覚えておくファイル数 (古いものから忘れる)
+const STORE_CAPACITY: usize = 64;
+/// PDF から取り出す最大ページ数
+const MAX_PDF_PAGES: usize = 300;
+
+/// 拡張子と、対応する WHITELIST の Content-Type
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("tsv", "text/csv"),
+    ("log", "text/x-log"),
+    ("json", "application/json"),
+    ("jsonl", "application/json"),
+    ("yaml", "application/x-yaml"),
+    ("yml", "application/x-yaml"),
+    ("toml", "text/x-toml"),
+    ("ini", "text/x-ini"),
+    ("cfg", "text/x-ini"),
+    ("conf", "text/x-config"),
+    ("env", "text/x-env"),
+    ("properties", "text/x-properties"),
+    ("xml", "application/xml"),
+    ("rs", "text/x-rust"),
+    ("py", "text/x-python"),
+    ("java", "text/x-java-source"),
+    ("c", "text/x-c"),
+    ("h", "text/x-c"),
+    ("cpp", "text/x-c++src"),
+    ("cc", "text/x-c++src"),
+    ("hpp", "text/x-c++src"),
+    ("go", "text/x-go"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("ts", "application/typescript"),
+    ("tsx", "application/typescript"),
+    ("css", "text/css"),
+    ("php", "text/x-php"),
+    ("rb", "text/x-ruby"),
+    ("pl", "text/x-perl"),
+    ("sh", "text/x-shellscript"),
+    ("bash", "text/x-shellscript"),
+    ("ps1", "text/x-powershell"),
+    ("bat", "text/x-msdos-batch"),
+    ("sql", "text/x-sql"),
+    ("kt", "text/x-kotlin"),
+    ("swift", "text/x-swift"),
+    ("scala", "text/x-scala"),
+    ("hs", "text/x-haskell"),
+    ("lua", "text/x-lua"),
+    ("dart", "text/x-dart"),
+    ("r", "text/x-r"),
+    ("zig", "text/x-zig"),
+    ("nim", "text/x-nim"),
+    ("vue", "text/x-vue"),
+    ("svelte", "text/x-svelte"),
+    ("tex", "text/x-tex"),
+    ("diff", "text/x-diff"),
+    ("patch", "text/x-diff"),
+    ("cmake", "text/x-cmake"),
+    ("dockerfile", "text/x-dockerfile"),
+];
+
+/// 添付ファイルの種類
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Kind {
+    /// 本文として読めるファイル (Content-Type)
+    Text(String),
+    Pdf,
+}
+
+impl Kind {
+    pub fn label(&self) -> &str {
+        match self {
+            Self::Text(mime) => mime,
+            Self::Pdf => "application/pdf",
+        }
+    }
+}
+
+/// URL の最後のパス要素 (クエリを除く)
+pub fn file_name(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let name = path.rsplit('/').next().unwrap_or(path);
+    urlencoding::decode(name).map(|n| n.into_owned()).unwrap_or_else(|_| name.to_string())
+}
+
+/// ファイル名と Content-Type から読めるファイルかを判断する (画像などは None)
+/// CDN が `application/octet-stream` を返すことがあるので、拡張子も見る
+pub fn classify(name: &str, content_type: Option<&str>) -> Option<Kind> {
+    let content_type = content_type.unwrap_or("").to_lowercase();
+    let lower = name.to_lowercase();
+    let extension = if lower == "dockerfile" { "dockerfile" } else { lower.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("") };
+    if content_type.contains("application/pdf") || extension == "pdf" {
+        return Some(Kind::Pdf);
+    }
+    if is_text_content_type(&content_type) {
+        let mime = content_type.split(';').next().unwrap_or("").trim().to_string();
+        return Some(Kind::Text(mime));
+    }
+    EXTENSIONS
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, mime)| Kind::Text(mime.to_string()))
+}
+
+/// 取得した中身をテキストにする
+pub fn decode(kind: &Kind, bytes: &[u8]) -> Result<String, String> {
+    match kind {
+        Kind::Pdf => extract_pdf_text(bytes),
+        Kind::Text(_) => {
+            // NUL を含むものはテキストではない
+            if bytes.contains(&0) {
+                return Err("binary content".to_string());
+            }
+            let text = String::from_utf8_lossy(bytes);
+            Ok(text.strip_prefix('\u{feff}').unwrap_or(&text).replace("\r\n", "\n"))
+        }
+    }
+}
+
+/// PDF の各ページのテキストを取り出す
+pub fn extract_pdf_text(bytes: &[u8]) -> Result<String, String> {
+    let document = lopdf::Document::load_mem(bytes).map_err(|e| format!("invalid pdf: {}", e))?;
+    let pages: Vec<u32> = document.get_pages().into_keys().collect();
+    if pages.len() > MAX_PDF_PAGES {
+        warn!("PDF has {} pages; reading the first {}", pages.len(), MAX_PDF_PAGES);
+    }
+    let mut out = String::new();
+    for page in pages.into_iter().take(MAX_PDF_PAGES) {
+        match document.extract_text(&[page]) {
+            Ok(text) => out.push_str(&format!("--- page {} ---\n{}\n", page, text.trim_end())),
+            Err(e) => out.push_str(&format!("--- page {} ---\n[could not extract text: {}]\n", page, e)),
+        }
+    }
+    if out.lines().all(|line| line.starts_with("--- page ") || line.trim().is_empty()) {
+        return Err("no extractable text (scanned pdf?)".to_string());
+    }
+    Ok(out)
+}
+
+/// `max_bytes` を超えたら途中でやめて取得する
+pub async fn fetch(client: &reqwest::Client, url: &str, max_bytes: usize) -> Result<(Option<String>, Vec<u8>), String> {
+    let mut response = client.get(url).send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("status {}", response.status()));
+    }
+    if response.content_length().is_some_and(|len| len as usize > max_bytes) {
+        return Err(format!("larger than {} bytes", max_bytes));
+    }
+    let content_type = response.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(format!("larger than {} bytes", max_bytes));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((content_type, body))
+}
+
+/// 読み込んだ添付ファイル
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    /// `attachment_reader` で指定する ID (`<メッセージ ID>-<番号>`)
+    pub id: String,
+    /// 添付したユーザー (`/forget_me` で消す)
+    pub user_id: String,
+    pub name: String,
+    pub kind: Kind,
+    pub text: String,
+}
+
+impl Attachment {
+    /// 添付されていたメッセージの ID
+    pub fn message_id(&self) -> &str {
+        self.id.rsplit_once('-').map_or(self.id.as_str(), |(message_id, _)| message_id)
+    }
+
+    pub fn char_count(&self) -> usize {
+        self.text.chars().count()
+    }
+
+    /// 発言に加えるブロック (長ければ先頭だけ)
+    pub fn render_block(&self) -> String {
+        let total = self.char_count();
+        let lines = self.text.lines().count();
+        let mut out = format!(
+            "[attachment {} | {} | {} lines, {} chars | id: {}]\n----- begin {} -----\n",
+            self.name, self.kind.label(), lines, total, self.id, self.name,
+        );
+        if total <= INLINE_CHARS {
+            out.push_str(self.text.trim_end());
+        } else {
+            let head: String = self.text.chars().take(INLINE_CHARS).collect();
+            out.push_str(&head);
+            out.push_str(&format!(
+                "\n[... {} more chars not shown. Call attachment_reader with id \"{}\" and offset {} to read the rest.]",
+                total - INLINE_CHARS, self.id, INLINE_CHARS,
+            ));
+        }
+        out.push_str(&format!("\n----- end {} -----", self.name));
+        out
+    }
+}
+
+/// 1 ページぶんの本文
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Page {
+    pub name: String,
+    pub offset: usize,
+    pub text: String,
+    pub total_chars: usize,
+    /// 続きがあれば次の offset
+    pub next_offset: Option<usize>,
+}
+
+/// ツールで読めるように添付ファイルをチャンネルごとに覚えておく
+/// 保存先があれば 1 ファイル 1 JSON で `<保存先>/<チャンネルのハッシュ>/` に書き、初めて使うチャンネルで読み込む
+#[derive(Default)]
+pub struct AttachmentStore {
+    /// 保存先 (None ならメモリだけ)
+    dir: Option<PathBuf>,
+    /// チャンネル → 添付ファイル (古い順)
+    channels: Mutex<HashMap<String, VecDeque<Attachment>>>,
+}
+
+impl AttachmentStore {
+    /// `dir` に保存する置き場を開く
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, String> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        Ok(Self { dir: Some(dir), channels: Mutex::new(HashMap::new()) })
+    }
+
+    /// チャンネルのファイルを置くディレクトリ (チャンネル名にはパスに使えない文字があるのでハッシュにする)
+    fn channel_dir(&self, channel: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("{:x}", Sha256::digest(channel.as_bytes()))))
+    }
+
+    fn file_path(dir: &std::path::Path, id: &str) -> PathBuf {
+        let id: String = id.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+        dir.join(format!("{}.json", id))
+    }
+
+    /// ディレクトリに保存された添付ファイル (古い順)
+    fn read_dir(dir: &std::path::Path) -> Vec<(PathBuf, Attachment)> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut files: Vec<_> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let modified = entry.metadata().ok()?.modified().ok()?;
+                let attachment: Attachment = serde_json::from_str(&fs::read_to_string(entry.path()).ok()?).ok()?;
+                Some((modified, entry.path(), attachment))
+            })
+            .collect();
+        files.sort_by_key(|(modified, _, _)| *modified);
+        files.into_iter().map(|(_, path, attachment)| (path, attachment)).collect()
+    }
+
+    /// チャンネルの添付ファイルを扱う (初めてなら保存先から読み込む)
+    fn with_channel<R>(&self, channel: &str, f: impl FnOnce(&mut VecDeque<Attachment>) -> R) -> R {
+        let mut channels = self.channels.lock().unwrap();
+        let files = channels.entry(channel.to_string()).or_insert_with(|| {
+            self.channel_dir(channel)
+                .map(|dir| Self::read_dir(&dir).into_iter().map(|(_, attachment)| attachment).collect())
+                .unwrap_or_default()
+        });
+        f(files)
+    }
+
+    pub fn insert(&self, channel: &str, attachment: Attachment) {
+        let dir = self.channel_dir(channel);
+        if let Some(dir) = &dir {
+            let saved = fs::create_dir_all(dir)
+                .and_then(|_| fs::write(Self::file_path(dir, &attachment.id), serde_json::to_string(&attachment).unwrap_or_default()));
+            if let Err(e) = saved {
+                warn!("Failed to save attachment {} - {:?}", attachment.id, e);
+            }
+        }
+        let evicted = self.with_channel(channel, |files| {
+            files.retain(|f| f.id != attachment.id);
+            files.push_back(attachment);
+            let mut evicted = Vec::new();
+            while files.len() > STORE_CAPACITY {
+                evicted.extend(files.pop_front());
+            }
+            evicted
+        });
+        if let Some(dir) = &dir {
+            for file in evicted {
+                let _ = fs::remove_file(Self::file_path(dir, &file.id));
+            }
+        }
+    }
+
+    pub fn ids(&self, channel: &str) -> Vec<String> {
+        self.with_channel(channel, |files| files.iter().map(|f| f.id.clone()).collect())
+    }
+
+    /// `offset` 文字目から `length` 文字 (最大 `PAGE_CHARS`) を返す
+    pub fn page(&self, channel: &str, id: &str, offset: usize, length: usize) -> Result<Page, String> {
+        self.with_channel(channel, |files| {
+            let file = files.iter().find(|f| f.id == id).ok_or_else(|| format!("unknown attachment id: {}", id))?;
+            let total_chars = file.char_count();
+            let length = length.clamp(1, PAGE_CHARS);
+            let text: String = file.text.chars().skip(offset).take(length).collect();
+            let end = offset + text.chars().count();
+            Ok(Page {
+                name: file.name.clone(),
+                offset,
+                text,
+                total_chars,
+                next_offset: (end < total_chars).then_some(end),
+            })
+        })
+    }
+
+    /// 削除されたメッセージの添付ファイルを消す
+    pub fn remove_message(&self, channel: &str, message_id: &str) {
+        let removed = self.with_channel(channel, |files| {
+            let (removed, kept) = std::mem::take(files).into_iter().partition(|f| f.message_id() == message_id);
+            *files = kept;
+            removed
+        });
+        if let Some(dir) = self.channel_dir(channel) {
+            for file in removed {
+                let _ = fs::remove_file(Self::file_path(&dir, &file.id));
+            }
+        }
+    }
+
+    /// ユーザーが添付したファイルをすべてのチャンネルから消し、消した数を返す (`/forget_me`)
+    pub fn forget_user(&self, user_id: &str) -> usize {
+        let mut channels = self.channels.lock().unwrap();
+        let mut count = 0;
+        for files in channels.values_mut() {
+            let before = files.len();
+            files.retain(|f| f.user_id != user_id);
+            count += before - files.len();
+        }
+        // まだ読み込んでいないチャンネルの分は保存先から消す
+        let Some(dir) = &self.dir else {
+            return count;
+        };
+        let mut on_disk = 0;
+        for channel_dir in fs::read_dir(dir).into_iter().flatten().flatten() {
+            for (path, attachment) in Self::read_dir(&channel_dir.path()) {
+                if attachment.user_id == user_id && fs::remove_file(path).is_ok() {
+                    on_disk += 1;
+                }
+            }
+        }
+        on_disk
+    }
+}
+
+static INSTALLED: OnceLock<AttachmentStore> = OnceLock::new();
+
+/// プロセス全体で使う置き場を設定する (起動時に一度だけ)
+pub fn install(store: AttachmentStore) {
+    if INSTALLED.set(store).is_err() {
+        warn!("attachment store is already installed");
+    }
+}
+
+/// 発言の処理とツールで共有する置き場 (設定されていなければメモリだけ)
+pub fn store() -> &'static AttachmentStore {
+    INSTALLED.get_or_init(AttachmentStore::default)
+}
+
+/// メッセージの添付ファイルのうち読めるものを取得して置き場に加える
+/// 画像や読めないファイルは飛ばし、失敗したものは理由を添えたブロックにする
+/// ファイルの中身は発言者が書いたものとは限らないので、外部の内容として封筒に入れる
+pub async fn load(channel: &str, user_id: &str, message_id: &str, urls: &[String]) -> Vec<String> {
+    let client = reqwest::Client::new();
+    let mut blocks = Vec::new();
+    for (i, url) in urls.iter().enumerate() {
+        let name = file_name(url);
+        // 拡張子で判断できないものは Content-Type を見るまでわからないので、画像と音声だけ先に除く
+        if is_media(&name) {
+            continue;
+        }
+        let (content_type, bytes) = match fetch(&client, url, MAX_ATTACHMENT_BYTES).await {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                if classify(&name, None).is_some() {
+                    blocks.push(format!("[attachment {} could not be read: {}]", name, e));
+                }
+                continue;
+            }
+        };
+        let Some(kind) = classify(&name, content_type.as_deref()) else {
+            continue;
+        };
+        match decode(&kind, &bytes) {
+            Ok(text) => {
+                let attachment = Attachment { id: format!("{}-{}", message_id, i), user_id: user_id.to_string(), name, kind, text };
+                blocks.push(untrusted::wrap(&format!("attachment {}", attachment.name), &attachment.render_block()));
+                store().insert(channel, attachment);
+            }
+            Err(e) => blocks.push(format!("[attachment {} could not be read: {}]", name, e)),
+        }
+    }
+    blocks
+}
+
+/// 画像ファイルか (拡張子で判断する)
+pub fn is_image(name: &str) -> bool {
+    let lower = name.to_lowercase();
+    [".png", ".jpg", ".jpeg", ".gif", ".webp"].iter().any(|ext| lower.ends_with(ext))
+}
+
+/// 動画か (拡張子で判断する、短いものはコンタクトシートにする)
+pub fn is_video(name: &str) -> bool {
+    let lower = name.to_lowercase();
+    [".mp4", ".m4v", ".mov"].iter().any(|ext| lower.ends_with(ext))
+}
+
+fn is_media(name: &str) -> bool {
+    is_image(name) || is_video(name) || crate::transcription::is_audio(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_http::{self, Response};
+
+    /// 1 ページに `text` を書いた PDF
+    fn sample_pdf(text: &str) -> Vec<u8> {
+        use lopdf::{content::{Content, Operation}, dictionary, Document, Object, Stream};
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Courier" });
+        let resources_id = doc.add_object(dictionary! { "Font" => dictionary! { "F1" => font_id } });
+        let content = Content {
+            operations: vec![
+                Operation::new("BT", vec![]),
+                Operation::new("Tf", vec!["F1".into(), 24.into()]),
+                Operation::new("Td", vec![100.into(), 600.into()]),
+                Operation::new("Tj", vec![Object::string_literal(text)]),
+                Operation::new("ET", vec![]),
+            ],
+        };
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+        let page_id = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "Contents" => content_id });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => vec![page_id.into()],
+            "Count" => 1,
+            "Resources" => resources_id,
+            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+        }));
+        let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog_id);
+        let mut bytes = Vec::new();
+        doc.save_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn classifies_by_content_type_and_extension() {
+        assert_eq!(classify("main.rs", Some("application/octet-stream")), Some(Kind::Text("text/x-rust".into())));
+        assert_eq!(classify("data", Some("text/csv; charset=utf-8")), Some(Kind::Text("text/csv".into())));
+        assert_eq!(classify("paper.PDF", None), Some(Kind::Pdf));
+        assert_eq!(classify("photo.png", Some("image/png")), None);
+        assert_eq!(classify("archive.zip", Some("application/zip")), None);
+        assert_eq!(file_name("https://cdn.example.com/a/b/my%20notes.log?ex=1&is=2"), "my notes.log");
+    }
+
+    #[test]
+    fn long_files_are_paged() {
+        let text: String = (0..2000).map(|i| format!("line {}\n", i)).collect();
+        let attachment = Attachment { id: "m1-0".into(), user_id: "42".into(), name: "big.log".into(), kind: Kind::Text("text/x-log".into()), text };
+        let block = attachment.render_block();
+        assert!(block.starts_with("[attachment big.log | text/x-log | 2000 lines, "));
+        assert!(block.contains("Call attachment_reader with id \"m1-0\" and offset 6000"));
+        assert!(block.ends_with("\n----- end big.log -----"));
+
+        let store = AttachmentStore::default();
+        store.insert("c1", attachment.clone());
+        let page = store.page("c1", "m1-0", INLINE_CHARS, 100_000).unwrap();
+        assert_eq!(page.text.chars().count(), PAGE_CHARS);
+        assert_eq!(page.next_offset, Some(INLINE_CHARS + PAGE_CHARS));
+        let last = store.page("c1", "m1-0", attachment.char_count() - 5, 100).unwrap();
+        assert_eq!((last.text.as_str(), last.next_offset), ("1999\n", None));
+        assert!(store.page("c1", "m9-0", 0, 10).is_err());
+    }
+
+    #[test]
+    fn attachments_are_kept_per_channel_across_restarts() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = |id: &str, user_id: &str| Attachment { id: id.into(), user_id: user_id.into(), name: "a.txt".into(), kind: Kind::Text("text/plain".into()), text: format!("from {}", id) };
+        let store = AttachmentStore::open(dir.path()).unwrap();
+        store.insert("c1", file("1-0", "42"));
+        store.insert("c1", file("2-0", "7"));
+        store.insert("irc:#rust", file("3-0", "42"));
+        // ほかのチャンネルの添付ファイルは読めない
+        assert!(store.page("c2", "1-0", 0, 10).is_err());
+
+        let reopened = AttachmentStore::open(dir.path()).unwrap();
+        assert_eq!(reopened.ids("c1"), vec!["1-0", "2-0"]);
+        assert_eq!(reopened.page("irc:#rust", "3-0", 0, 100).unwrap().text, "from 3-0");
+
+        reopened.remove_message("c1", "2");
+        assert_eq!(reopened.ids("c1"), vec!["1-0"]);
+        // 読み込んでいないチャンネルの分も消える
+        let reopened = AttachmentStore::open(dir.path()).unwrap();
+        assert_eq!(reopened.forget_user("42"), 2);
+        let reopened = AttachmentStore::open(dir.path()).unwrap();
+        assert!(reopened.ids("c1").is_empty() && reopened.ids("irc:#rust").is_empty());
+    }
+
+    #[test]
+    fn extracts_pdf_text() {
+        let text = extract_pdf_text(&sample_pdf("Hello PDF")).unwrap();
+        assert!(text.starts_with("--- page 1 ---\n"));
+        assert!(text.contains("Hello PDF"));
+        assert!(extract_pdf_text(b"not a pdf").is_err());
+        assert!(decode(&Kind::Text("text/plain".into()), b"a\0b").is_err());
+    }
+
+    #[tokio::test]
+    async fn loads_text_and_pdf_attachments() {
+        let (addr, _handle) = local_http::serve("127.0.0.1:0", |request| async move {
+            match request.path.split('?').next().unwrap() {
+                "/main.rs" => Response { status: 200, headers: vec![("Content-Type".into(), "application/octet-stream".into())], body: "fn main() {}\n".into() },
+                "/huge.txt" => Response { status: 200, headers: vec![], body: "x".repeat(MAX_ATTACHMENT_BYTES + 1).into() },
+                _ => Response { status: 404, headers: vec![], body: Vec::new() },
+            }
+        }).await.unwrap();
+        let urls: Vec<String> = ["main.rs", "photo.png", "huge.txt", "missing.zip"]
+            .iter()
+            .map(|name| format!("http://{}/{}?ex=1", addr, name))
+            .collect();
+        let blocks = load("c1", "42", "m2", &urls).await;
+        assert_eq!(blocks.len(), 2);
+        // 中身は外部の内容として封筒に入る
+        assert!(blocks[0].starts_with("<untrusted_content source=\"attachment main.rs\">"));
+        assert!(untrusted::unwrap(&blocks[0]).starts_with("[attachment main.rs | text/x-rust | 1 lines, 13 chars | id: m2-0]"));
+        assert!(blocks[0].contains("----- begin main.rs -----\nfn main() {}\n----- end main.rs -----"));
+        assert!(blocks[1].starts_with("[attachment huge.txt could not be read: larger than"));
+        assert_eq!(store().page("c1", "m2-0", 0, 100).unwrap().text, "fn main() {}\n");
+        assert!(store().page("c2", "m2-0", 0, 100).is_err());
+    }
+}
@@ -0,0 +1,39 @@
+//! OpenAI 互換のモック LLM サーバー
+//!
+//! 使い方: `cargo run --bin mock-llm -- <scenario.json> [addr]`
+//! config.json の `main_model_endpoint` を表示されたエンドポイントに向けて使う。
+
+use log::{error, info};
+use observer::mock_llm::{MockLlmServer, Scenario};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:8089";
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut args = std::env::args().skip(1);
+    let Some(path) = args.next() else {
+        eprintln!("usage: mock-llm <scenario.json> [addr]");
+        std::process::exit(2);
+    };
+    let addr = args.next().unwrap_or_else(|| DEFAULT_ADDR.to_string());
+
+    let scenario = match Scenario::load(&path) {
+        Ok(scenario) => scenario,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let steps = scenario.steps.len();
+    let server = match MockLlmServer::start(scenario, &addr).await {
+        Ok(server) => server,
+        Err(e) => {
+            error!("failed to bind {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    info!("mock-llm: {} ({} steps) listening on {}", path, steps, server.endpoint());
+    server.wait().await;
+}
@@ -0,0 +1,2181 @@
+//! プラットフォームに依存しない Bot の本体
+//!
+//! チャンネルごとの会話履歴・設定、ユーザーごとのレートリミットを持ち、
+//! メッセージへの応答やコマンドの処理を `ChatPlatform` 越しに行う。
+
+use std::{collections::{BTreeMap, HashMap}, future::Future, io::Write, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use call_agent::chat::client::OpenAIClient;
+use dashmap::DashMap;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+use chrono::{Local, Utc};
+use observer::{digest::{self, DigestEntry, DigestFrequency}, history, image_cache, markdown, attachments, moderation::{ModerationPolicy, Moderator, Outcome}, privacy::{self, ForgetReport}, prefix::{ADMIN_USERS, ASK_DEVELOPER_PROMPT, ASSISTANT_NAME, AUTO_DEPLOY_MIN_CHARS, AUTO_DEPLOY_STRUCTURED, MAX_USE_TOOL_COUNT, MODEL_GENERATE_MAX_TOKENS, MODERATION, RATE_CP, SEC_PER_RATE, SUMMARY_MAX_CHARS, TRANSCRIPTION}, reactions::{self, ReactionAction}, sources, splitter::split_message, tools::{memory::MemoryTool, scheduler::{Job, Scheduler}, web_deploy::WebDeploy}, transcription::{self, Transcriber, Transcript}, turn::{self, TurnContext}};
+
+use crate::{agent::{AIModel, Answer, ChannelState, InputMessage, ReasoningConfig, Silent}, platform::{ChannelStatus, ChatPlatform, OutgoingFile, Reply}};
+
+pub const TIMEOUT: Duration = Duration::from_secs(180);
+/// 入力中の表示を更新する間隔
+const TYPING_INTERVAL: Duration = Duration::from_secs(4);
+/// 1 メッセージあたりの添付ファイル数の上限
+const MAX_ATTACHMENTS: usize = 10;
+/// 予約がないときでもこの間隔でジョブとダイジェストを確認する
+const SCHEDULER_POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// ダイジェストのために 1 回で取得するメッセージ数と、1 回のダイジェストに入れる最大数
+const DIGEST_FETCH_PAGE: usize = 100;
+const DIGEST_MAX_MESSAGES: usize = 1000;
+/// モデレーションの通知に載せる本文の長さ
+const MODERATION_EXCERPT_CHARS: usize = 200;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ChConf {
+    pub enable: bool,
+    /// 応答がこのメッセージ数を超える場合はプレビューと .md 添付で送る (0 で無効)
+    #[serde(default)]
+    pub attach_threshold: usize,
+    /// 添付するときにコードブロックも個別のファイルとして添付する
+    #[serde(default)]
+    pub attach_code_blocks: bool,
+    /// ダイジェスト記事の頻度 (Off なら作らない)
+    #[serde(default)]
+    pub digest: DigestFrequency,
+    /// 前回のダイジェストの時刻 (UNIX 秒)
+    #[serde(default)]
+    pub last_digest: u64,
+    /// 前回のダイジェストに含めた最後のメッセージ ID
+    #[serde(default)]
+    pub last_digest_message: Option<String>,
+    /// リアクションと操作の対応表 (None なら `reactions::default_map`)
+    #[serde(default)]
+    pub reactions: Option<BTreeMap<String, ReactionAction>>,
+    /// モデレーションの扱い (None なら `BotSettings::moderation_policy`)
+    #[serde(default)]
+    pub moderation: Option<ModerationPolicy>,
+}
+
+/// メッセージに付けられたリアクション
+#[derive(Clone, Debug)]
+pub struct ReactionEvent {
+    pub emoji: String,
+    /// リアクションを付けたユーザー
+    pub user_id: String,
+    pub user_name: String,
+    /// リアクションを付けられたメッセージ
+    pub message: InputMessage,
+    /// 対象が Bot 自身の発言かどうか
+    pub from_bot: bool,
+    /// 対象を含むスレッド (返信元や Discord のスレッド、古い順)
+    pub thread: Vec<InputMessage>,
+}
+
+/// チャンネルで最後に応答したターン (作り直しの対象)
+#[derive(Clone, Debug)]
+pub struct LastTurn {
+    /// 推論の始まった発言のメッセージ ID
+    pub message_id: String,
+    /// 発言したユーザー
+    pub user_id: String,
+}
+
+pub struct PerUserConfig {
+    pub rate_limit: u64, // レートリミットの秒数
+    pub model: AIModel,
+    /// 音声を書き起こした日 (UNIX 日) とその日に書き起こした秒数
+    pub voice_day: u64,
+    pub voice_secs: f64,
+}
+
+/// Bot の動作設定 (通常は config.json から作る)
+pub struct BotSettings {
+    pub assistant_name: String,
+    pub max_tokens: u64,
+    pub developer_prompt: String,
+    pub max_use_tool_count: usize,
+    pub default_model: AIModel,
+    /// レートの回復時間
+    pub sec_per_rate: u64,
+    /// レートの許容量
+    pub rate_cp: u64,
+    pub admin_users: Vec<String>,
+    pub auto_deploy_min_chars: usize,
+    pub auto_deploy_structured: bool,
+    pub summary_max_chars: usize,
+    /// チャンネル設定の保存先
+    pub ch_conf_path: String,
+    /// 見直しが必要な記事・メモリなどの記録 (JSON Lines)
+    pub audit_path: String,
+    /// REPL のセッションの保存先 (/forget_me で保存された履歴からも消す)
+    pub session_dir: String,
+    /// チャンネルに設定がないときのモデレーションの扱い
+    pub moderation_policy: ModerationPolicy,
+    /// モデレーションに引っかかったことを知らせるチャンネル
+    pub moderation_log_channel: Option<String>,
+    /// 1 ユーザーが 1 日に書き起こせる音声の分数 (0 で無制限)
+    pub voice_minutes_per_day: u64,
+}
+
+impl BotSettings {
+    pub fn from_config() -> Self {
+        Self {
+            assistant_name: ASSISTANT_NAME.to_string(),
+            max_tokens: *MODEL_GENERATE_MAX_TOKENS as u64,
+            developer_prompt: ASK_DEVELOPER_PROMPT.to_string(),
+            max_use_tool_count: *MAX_USE_TOOL_COUNT,
+            default_model: AIModel::default(),
+            sec_per_rate: *SEC_PER_RATE as u64,
+            rate_cp: *RATE_CP as u64,
+            admin_users: ADMIN_USERS.clone(),
+            auto_deploy_min_chars: *AUTO_DEPLOY_MIN_CHARS,
+            auto_deploy_structured: *AUTO_DEPLOY_STRUCTURED,
+            summary_max_chars: *SUMMARY_MAX_CHARS,
+            ch_conf_path: "./data/ch_conf.json".to_string(),
+            audit_path: "./data/audit.jsonl".to_string(),
+            session_dir: "./data/repl".to_string(),
+            moderation_policy: ModerationPolicy::from_name(&MODERATION.default_policy).unwrap_or_else(|e| {
+                warn!("{} - using flag", e);
+                ModerationPolicy::Flag
+            }),
+            moderation_log_channel: Some(MODERATION.log_channel.clone()).filter(|c| !c.is_empty()),
+            voice_minutes_per_day: TRANSCRIPTION.minutes_per_day,
+        }
+    }
+}
+
+pub struct Bot {
+    /// Botに1つのOpenAIClientを保持
+    pub base_client: Arc<OpenAIClient>,
+    /// 有効なチャンネルのset
+    pub channels_conf: DashMap<String, ChConf>,
+    /// 各チャンネルごとの状態（会話履歴）を保持（DashMapは並列処理可能）
+    pub channels: DashMap<String, Arc<ChannelState>>,
+    /// ユーザーごとにレートリミット
+    pub user_configs: DashMap<String, PerUserConfig>,
+    /// 長い応答の記事化に使う (web_deploy_tool が無効なら None)
+    pub web_deploy: Option<Arc<WebDeploy>>,
+    /// 予約実行 (scheduler が無効なら None)
+    pub scheduler: Option<Arc<Scheduler>>,
+    /// チャンネルごとの直前の応答のメッセージ ID (作り直したときに置き換える)
+    pub answer_messages: DashMap<String, Vec<String>>,
+    /// チャンネルごとの最後に応答したターン
+    pub last_turns: DashMap<String, LastTurn>,
+    /// memory_tool (無効なら None)
+    pub memory: Option<Arc<MemoryTool>>,
+    /// /forget_me の確認の期限 (ユーザー ID ごと、UNIX 秒)
+    pub forget_requests: DashMap<String, u64>,
+    /// 入力と出力のモデレーション (無効なら None)
+    pub moderator: Option<Arc<Moderator>>,
+    /// 音声メッセージの書き起こし (無効なら None)
+    pub transcriber: Option<Arc<Transcriber>>,
+    pub settings: BotSettings,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+impl Bot {
+    pub fn new(base_client: Arc<OpenAIClient>, web_deploy: Option<Arc<WebDeploy>>, settings: BotSettings) -> Self {
+        Self {
+            base_client,
+            channels_conf: DashMap::new(),
+            channels: DashMap::new(),
+            user_configs: DashMap::new(),
+            web_deploy,
+            scheduler: None,
+            answer_messages: DashMap::new(),
+            last_turns: DashMap::new(),
+            memory: None,
+            forget_requests: DashMap::new(),
+            moderator: None,
+            transcriber: None,
+            settings,
+        }
+    }
+
+    pub fn with_scheduler(mut self, scheduler: Option<Arc<Scheduler>>) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    pub fn with_memory(mut self, memory: Option<Arc<MemoryTool>>) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    pub fn with_moderator(mut self, moderator: Option<Arc<Moderator>>) -> Self {
+        self.moderator = moderator;
+        self
+    }
+
+    pub fn with_transcriber(mut self, transcriber: Option<Arc<Transcriber>>) -> Self {
+        self.transcriber = transcriber;
+        self
+    }
+
+    pub fn is_admin(&self, user_id: &str) -> bool {
+        self.settings.admin_users.iter().any(|id| id == user_id)
+    }
+
+    /// チャンネルの状態を取得または作成する
+    pub async fn channel_state(&self, channel: &str) -> Arc<ChannelState> {
+        if let Some(existing) = self.channels.get(channel) {
+            Arc::clone(&existing)
+        } else {
+            let new_state = Arc::new(ChannelState::new(&self.base_client, channel).await);
+            self.channels.insert(channel.to_string(), new_state.clone());
+            new_state
+        }
+    }
+
+    pub fn reasoning_config(&self, model: &AIModel) -> ReasoningConfig {
+        ReasoningConfig {
+            model_config: model.model_config(&self.settings.assistant_name, self.settings.max_tokens),
+            developer_prompt: self.settings.developer_prompt.clone(),
+            max_use_tool_count: self.settings.max_use_tool_count,
+        }
+    }
+
+    /// ユーザー設定を取得する (なければ `rate_limit` で作る)
+    fn user_config(&self, user_id: &str, rate_limit: u64) -> dashmap::mapref::one::RefMut<'_, String, PerUserConfig> {
+        self.user_configs.entry(user_id.to_string()).or_insert(
+            PerUserConfig {
+                rate_limit,
+                model: self.settings.default_model.clone(), // デフォルトモデルを使用
+                voice_day: 0,
+                voice_secs: 0.0,
+            }
+        )
+    }
+
+    /// レートを消費して使用するモデルを返す
+    /// `model` を指定すればユーザーの設定の代わりにそのモデルを使い、そのコストを消費する
+    pub fn consume_rate(&self, user_id: &str, time_stamp: u64, model: Option<AIModel>) -> Result<AIModel, String> {
+        let mut user_conf = self.user_config(user_id, 1); // デフォルトは1
+        let model = model.unwrap_or_else(|| user_conf.model.clone());
+        let model_cost = model.to_sec_per_rate() as u64; // モデルのレート使用量
+        let sec_per_rate = self.settings.sec_per_rate; // レートの回復時間
+        let cp = self.settings.rate_cp; // レートの許容量
+
+        // レートリミットの計算
+        let limit_line = sec_per_rate * cp;
+        let add_line = model_cost * sec_per_rate;
+        let mut user_line = user_conf.rate_limit;
+        if user_line > time_stamp + limit_line {
+            return Err(format!("Err: rate limit - try again after <t:{}:R>", (user_line - limit_line)));
+        }
+        if user_line == 0 {
+            // リミットレスアカウント
+        } else if user_line < time_stamp {
+            user_line = time_stamp + add_line;
+            user_conf.rate_limit = user_line;
+        } else {
+            user_line += add_line;
+            user_conf.rate_limit = user_line;
+        }
+        Ok(model)
+    }
+
+    /// ツールが使った分のレートを消費する (画像生成など、応答のあとで数えるので上限は超えうる)
+    pub fn charge_rate(&self, user_id: &str, rate_cost: u64, time_stamp: u64) {
+        if rate_cost == 0 {
+            return;
+        }
+        let mut user_conf = self.user_config(user_id, 1);
+        let add_line = rate_cost * self.settings.sec_per_rate;
+        if user_conf.rate_limit == 0 {
+            // リミットレスアカウント
+        } else if user_conf.rate_limit < time_stamp {
+            user_conf.rate_limit = time_stamp + add_line;
+        } else {
+            user_conf.rate_limit += add_line;
+        }
+    }
+
+    /// 今日あと何秒の音声を書き起こせるか (None なら無制限)
+    fn voice_remaining(&self, user_conf: &mut PerUserConfig, time_stamp: u64) -> Option<f64> {
+        let limit = self.settings.voice_minutes_per_day;
+        // レートリミットのないアカウントは音声も無制限
+        if limit == 0 || user_conf.rate_limit == 0 {
+            return None;
+        }
+        let day = time_stamp / 86_400;
+        if user_conf.voice_day != day {
+            user_conf.voice_day = day;
+            user_conf.voice_secs = 0.0;
+        }
+        Some((limit * 60) as f64 - user_conf.voice_secs)
+    }
+
+    /// 書き起こす前に `secs` 秒を今日の利用量から確保する (足りなければ残りの秒数を返す)
+    /// 確かめてから加えるまでを同じロックの中で行うので、同時に届いた音声が揃って上限をすり抜けることはない
+    pub fn reserve_voice(&self, user_id: &str, secs: f64, time_stamp: u64) -> Result<(), f64> {
+        let mut user_conf = self.user_config(user_id, 1);
+        let Some(remaining) = self.voice_remaining(&mut user_conf, time_stamp) else {
+            return Ok(());
+        };
+        if remaining <= 0.0 || secs > remaining {
+            return Err(remaining);
+        }
+        user_conf.voice_secs += secs;
+        Ok(())
+    }
+
+    /// 今日の音声の利用量に `secs` 秒を加える (負なら確保した分を戻す)
+    pub fn charge_voice(&self, user_id: &str, secs: f64, time_stamp: u64) {
+        let mut user_conf = self.user_config(user_id, 1);
+        if self.voice_remaining(&mut user_conf, time_stamp).is_some() {
+            user_conf.voice_secs = (user_conf.voice_secs + secs).max(0.0);
+        }
+    }
+
+    /// 音声の添付ファイルを書き起こして本文の後ろに加える
+    /// 書き起こした音声は添付ファイルから外す (書き起こしが無効なら何もしない)
+    async fn transcribe_voice(&self, message: &mut InputMessage) {
+        let Some(transcriber) = &self.transcriber else {
+            return;
+        };
+        let (voices, others): (Vec<String>, Vec<String>) = message.attached_files
+            .drain(..)
+            .partition(|url| transcription::is_audio(&attachments::file_name(url)));
+        message.attached_files = others;
+
+        let client = reqwest::Client::new();
+        for url in voices {
+            let name = attachments::file_name(&url);
+            let note = match self.transcribe_one(transcriber, &client, &message.user_id, &name, &url).await {
+                Ok(Transcript { text, duration: Some(secs) }) => {
+                    format!("[voice message {} ({})]\n{}", transcription::format_duration(secs), name, text)
+                }
+                Ok(Transcript { text, duration: None }) => format!("[voice message ({})]\n{}", name, text),
+                Err(e) => {
+                    warn!("Failed to transcribe {}: {}", url, e);
+                    format!("[voice message ({}) could not be transcribed: {}]", name, e)
+                }
+            };
+            if !message.content.is_empty() {
+                message.content.push('\n');
+            }
+            message.content.push_str(&note);
+        }
+    }
+
+    async fn transcribe_one(&self, transcriber: &Transcriber, client: &reqwest::Client, user_id: &str, name: &str, url: &str) -> Result<Transcript, String> {
+        let (_, bytes) = attachments::fetch(client, url, transcription::MAX_AUDIO_BYTES).await?;
+        // 書き起こしを頼む前に長さの分を確保する (長さが読めない形式は書き起こしたあとに返ってきた長さで数える)
+        let estimated = transcription::audio_duration(&bytes).unwrap_or(0.0);
+        self.reserve_voice(user_id, estimated, now()).map_err(|remaining| format!(
+            "daily voice limit of {} minutes reached ({} left)",
+            self.settings.voice_minutes_per_day,
+            transcription::format_duration(remaining.max(0.0))
+        ))?;
+        match transcriber.transcribe(name, bytes).await {
+            Ok(transcript) => {
+                self.charge_voice(user_id, transcript.duration.unwrap_or(estimated) - estimated, now());
+                Ok(transcript)
+            }
+            Err(e) => {
+                self.charge_voice(user_id, -estimated, now());
+                Err(e)
+            }
+        }
+    }
+
+    /// 受け取ったメッセージを処理する
+    /// メンションされていれば応答し、そうでなければ履歴に追加する
+    pub async fn handle_message<P: ChatPlatform>(&self, platform: &P, channel: &str, mut message: InputMessage, mentioned: bool) {
+        let state = self.channel_state(channel).await;
+        info!("Message: {:?}", message);
+
+        // 応答するときは先にレートを消費する (制限中のユーザーの音声は書き起こさない)
+        let model = if mentioned {
+            match self.admit(channel, &message.user_id, None) {
+                Ok(model) => Some(model),
+                Err(answer) => {
+                    self.send_answer(platform, channel, &answer, answer.content.clone()).await;
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+
+        // 応答するときだけ音声を書き起こす (書き起こした内容もモデレーションにかける)
+        if model.is_some() {
+            self.transcribe_voice(&mut message).await;
+        }
+
+        // 止められた発言は履歴にも残さない (無効なチャンネルの発言はモデルに渡らないので照合しない)
+        // 履歴に積むだけの発言はルールだけで照合し、エンドポイントには応答するときだけ問い合わせる
+        if self.is_enabled(channel)
+            && let Err(answer) = self.moderate_input(platform, channel, &mut message, mentioned).await {
+            if mentioned {
+                self.send_answer(platform, channel, &answer, answer.content.clone()).await;
+            }
+            return;
+        }
+
+        if let Some(model) = model {
+            let message_id = message.message_id.clone();
+            let answer = self.answer(platform, channel, state, message, model).await;
+            let answer = self.moderate_answer(platform, channel, "answer", answer).await;
+            let answer_text = self.format_answer(&answer, &message_id).await;
+            self.send_answer(platform, channel, &answer, answer_text).await;
+        } else {
+            state.add_message(message).await;
+        }
+    }
+
+    /// 有効なチャンネルかどうかを確認し、レートを消費して使用するモデルを返す
+    fn admit(&self, channel: &str, user_id: &str, model: Option<AIModel>) -> Result<AIModel, Answer> {
+        if !self.is_enabled(channel) {
+            return Err(Answer::error("Err: AI is disabled in this channel"));
+        }
+        self.consume_rate(user_id, now(), model).map_err(Answer::error)
+    }
+
+    pub fn is_enabled(&self, channel: &str) -> bool {
+        self.channels_conf.get(channel).is_some_and(|conf| conf.enable)
+    }
+
+    /// タイムアウトを設定して推論を待つ (終わるまで入力中の表示を続ける)
+    async fn wait_reasoning<P: ChatPlatform>(&self, platform: &P, channel: &str, reasoning: impl Future<Output = Answer>) -> Answer {
+        let reasoning = time::timeout(TIMEOUT, reasoning);
+        tokio::pin!(reasoning);
+        let mut typing = time::interval(TYPING_INTERVAL);
+        loop {
+            tokio::select! {
+                result = &mut reasoning => break result.unwrap_or_else(|_| Answer::error("Err: timeout")),
+                _ = typing.tick() => platform.broadcast_typing(channel).await,
+            }
+        }
+    }
+
+    /// メッセージを推論する (レートは `admit` で消費済み)
+    async fn answer<P: ChatPlatform>(
+        &self,
+        platform: &P,
+        channel: &str,
+        state: Arc<ChannelState>,
+        message: InputMessage,
+        model: AIModel,
+    ) -> Answer {
+        // AIに質問
+        let config = self.reasoning_config(&model);
+        let status = ChannelStatus { platform, channel };
+        // ツールが発言者とチャンネルを参照できるようにする
+        let context = TurnContext {
+            channel: channel.to_string(),
+            user_id: message.user_id.clone(),
+            user_name: message.name.clone(),
+            ..Default::default()
+        };
+        // 作り直せるように推論の始まった発言を覚えておく
+        let last_turn = LastTurn { message_id: message.message_id.clone(), user_id: message.user_id.clone() };
+        let answer = self.wait_reasoning(platform, channel, turn::scope(context, state.run_reasoning(message, &config, &status))).await;
+        self.charge_rate(&last_turn.user_id, answer.files.iter().map(|f| f.rate_cost).sum(), now());
+        if !answer.is_error {
+            self.last_turns.insert(channel.to_string(), last_turn);
+        }
+        answer
+    }
+
+    /// 会話に残さずに推論する (リアクションの翻訳・解説・記事化)
+    /// チャンネルの履歴は見せるが、指示も応答も履歴に入れず、作り直しの対象も変えない
+    async fn answer_detached<P: ChatPlatform>(&self, platform: &P, channel: &str, message: InputMessage) -> Answer {
+        let model = match self.admit(channel, &message.user_id, None) {
+            Ok(model) => model,
+            Err(answer) => return answer,
+        };
+        let state = self.channel_state(channel).await;
+        let config = self.reasoning_config(&model);
+        let status = ChannelStatus { platform, channel };
+        let context = TurnContext {
+            channel: channel.to_string(),
+            user_id: message.user_id.clone(),
+            user_name: message.name.clone(),
+            ..Default::default()
+        };
+        let user_id = message.user_id.clone();
+        let answer = self.wait_reasoning(platform, channel, turn::scope(context, state.run_detached(message, &config, &status))).await;
+        self.charge_rate(&user_id, answer.files.iter().map(|f| f.rate_cost).sum(), now());
+        answer
+    }
+
+    /// 最後の応答を同じ発言でもう一度推論する (`model` で別のモデルを使える)
+    /// 作り直せるのは発言したユーザーと、`can_manage` (管理者やチャンネル管理者) のときだけ
+    /// 戻り値の 2 つ目はもとの発言のメッセージ ID
+    pub async fn regenerate<P: ChatPlatform>(&self, platform: &P, channel: &str, user_id: &str, user_name: &str, model: Option<AIModel>, can_manage: bool) -> (Answer, String) {
+        // 作り直すものがないのにレートを消費しない
+        let state = self.channel_state(channel).await;
+        let Some(last_turn) = self.last_turns.get(channel).map(|t| t.clone()) else {
+            return (Answer::error("Err: no answer to regenerate"), String::new());
+        };
+        if !state.has_answer(&last_turn.message_id).await {
+            return (Answer::error("Err: no answer to regenerate"), String::new());
+        }
+        if last_turn.user_id != user_id && !can_manage && !self.is_admin(user_id) {
+            return (Answer::error("Err: only the asker or a channel manager can regenerate this answer"), String::new());
+        }
+        let model = match self.admit(channel, user_id, model) {
+            Ok(model) => model,
+            Err(answer) => return (answer, String::new()),
+        };
+
+        let config = self.reasoning_config(&model);
+        let status = ChannelStatus { platform, channel };
+        let context = TurnContext {
+            channel: channel.to_string(),
+            user_id: user_id.to_string(),
+            user_name: user_name.to_string(),
+            ..Default::default()
+        };
+        let reasoning = async {
+            state.regenerate(&last_turn.message_id, &config, &status).await
+                .unwrap_or_else(|| Answer::error("Err: no answer to regenerate"))
+        };
+        let answer = self.wait_reasoning(platform, channel, turn::scope(context, reasoning)).await;
+        self.charge_rate(user_id, answer.files.iter().map(|f| f.rate_cost).sum(), now());
+        (answer, last_turn.message_id)
+    }
+
+    /// 直前の応答を作り直し、前の応答のメッセージを消して新しい応答を送る
+    /// 作り直しに失敗したときは前の応答を残す
+    pub async fn retry<P: ChatPlatform>(&self, platform: &P, channel: &str, user_id: &str, user_name: &str, model_name: Option<&str>, can_manage: bool) {
+        let model = match model_name.map(AIModel::from_model_name).transpose() {
+            Ok(model) => model,
+            Err(e) => {
+                self.send_split_message(platform, channel, format!("Err: {}", e)).await;
+                return;
+            }
+        };
+        let (answer, message_id) = self.regenerate(platform, channel, user_id, user_name, model, can_manage).await;
+        let answer = self.moderate_answer(platform, channel, "answer", answer).await;
+        if !answer.is_error
+            && let Some((_, old)) = self.answer_messages.remove(channel) {
+            for id in old {
+                if let Err(e) = platform.delete_message(channel, &id).await {
+                    info!("Keeping the previous answer {} - {}", id, e);
+                }
+            }
+        }
+        let body = self.format_answer(&answer, &message_id).await;
+        self.send_answer(platform, channel, &answer, body).await;
+    }
+
+    /// チャンネルでリアクションに対応する操作
+    pub fn reaction_action(&self, channel: &str, emoji: &str) -> Option<ReactionAction> {
+        match self.channels_conf.get(channel).and_then(|conf| conf.reactions.clone()) {
+            Some(map) => reactions::lookup(&map, emoji),
+            None => reactions::lookup(&reactions::default_map(), emoji),
+        }
+    }
+
+    /// リアクションに応じて記事化・作り直し・翻訳・解説を行う
+    /// 無効なチャンネルや対応のない絵文字は無視し、レートは付けたユーザーから消費する
+    pub async fn handle_reaction<P: ChatPlatform>(&self, platform: &P, channel: &str, event: ReactionEvent) {
+        let Some(action) = self.reaction_action(channel, &event.emoji) else {
+            return;
+        };
+        if !self.is_enabled(channel) {
+            return;
+        }
+        info!("Reaction {} ({}) by {} on {}", event.emoji, action.name(), event.user_name, event.message.message_id);
+
+        let (answer, message_id) = match action {
+            ReactionAction::Regenerate => {
+                // 作り直せるのは Bot の最後の応答だけ (それより前の応答は履歴の途中にあるので作り直さない)
+                let is_last_answer = self.answer_messages
+                    .get(channel)
+                    .is_some_and(|ids| ids.contains(&event.message.message_id));
+                if event.from_bot && is_last_answer {
+                    self.retry(platform, channel, &event.user_id, &event.user_name, None, false).await;
+                }
+                return;
+            }
+            ReactionAction::Article if self.web_deploy.is_none() => {
+                (Answer::error("Err: web_deploy_tool is disabled"), String::new())
+            }
+            action => {
+                let target = if action == ReactionAction::Article {
+                    let thread = if event.thread.is_empty() { std::slice::from_ref(&event.message) } else { &event.thread[..] };
+                    let entries: Vec<DigestEntry> = thread.iter().map(|m| DigestEntry {
+                        message_id: m.message_id.clone(),
+                        user_name: m.name.clone(),
+                        text: m.content.clone(),
+                        images: m.attached_files.clone(),
+                    }).collect();
+                    digest::transcript(&entries, digest::MAX_TRANSCRIPT_CHARS)
+                } else {
+                    event.message.content.clone()
+                };
+                let content = action.instruction(&event.emoji, &event.message.name, &target).unwrap_or_default();
+                let message_id = format!("{}-{}", event.message.message_id, action.name());
+                let mut message = InputMessage {
+                    content,
+                    name: event.user_name.clone(),
+                    message_id: message_id.clone(),
+                    reply_msg: None,
+                    user_id: event.user_id.clone(),
+                    attached_files: Vec::new(),
+                };
+                let answer = match self.moderate_input(platform, channel, &mut message, true).await {
+                    Ok(()) => self.answer_detached(platform, channel, message).await,
+                    Err(answer) => answer,
+                };
+                (answer, message_id)
+            }
+        };
+        let answer = self.moderate_answer(platform, channel, action.name(), answer).await;
+
+        let body = match (&self.web_deploy, action) {
+            (Some(web_deploy), ReactionAction::Article) if !answer.is_error => {
+                let key = format!("reaction-{}", event.message.message_id);
+                let article = answer.content.clone() + &sources::render_full(&answer.sources);
+                match web_deploy.create_article(&key, &article).await {
+                    Ok(url) => format!("{}\n\n📄 {}", markdown::summarize(&answer.content, self.settings.summary_max_chars), url),
+                    Err(e) => format!("Err: failed to publish the article - {}", e),
+                }
+            }
+            _ => self.format_answer(&answer, &message_id).await,
+        };
+        // 作り直しの対象は会話の最後の応答のままにする
+        self.deliver_answer(platform, channel, &answer, body, false).await;
+    }
+
+    /// リアクションの対応表を表示・変更する
+    /// `action` が "none" なら絵文字の割り当てを外し (絵文字がなければすべて無効)、"reset" なら既定に戻す
+    pub fn reaction_conf(&self, channel: &str, emoji: Option<&str>, action: Option<&str>) -> String {
+        let message = {
+            let mut ch_conf = self.channels_conf.entry(channel.to_string()).or_default();
+            let mut map = ch_conf.reactions.clone().unwrap_or_else(reactions::default_map);
+            match (emoji.map(reactions::normalize_emoji).filter(|e| !e.is_empty()), action) {
+                (_, Some("reset")) => {
+                    ch_conf.reactions = None;
+                    "Info: reactions reset to the defaults".to_string()
+                }
+                (None, Some("none")) => {
+                    ch_conf.reactions = Some(BTreeMap::new());
+                    "Info: reactions are disabled in this channel".to_string()
+                }
+                (Some(emoji), Some("none")) => {
+                    map.retain(|key, _| reactions::normalize_emoji(key) != emoji);
+                    ch_conf.reactions = Some(map);
+                    format!("Info: {} no longer triggers anything", emoji)
+                }
+                (Some(emoji), Some(name)) => {
+                    let action = match ReactionAction::from_name(name) {
+                        Ok(action) => action,
+                        Err(e) => return format!("Err: {}", e),
+                    };
+                    map.retain(|key, _| reactions::normalize_emoji(key) != emoji);
+                    map.insert(emoji.clone(), action);
+                    ch_conf.reactions = Some(map);
+                    format!("Info: {} now triggers {}", emoji, action.name())
+                }
+                (None, Some(_)) => return "Err: specify the emoji to assign".to_string(),
+                (_, None) => {
+                    if map.is_empty() {
+                        return "Info: reactions are disabled in this channel".to_string();
+                    }
+                    let lines: Vec<String> = map.iter().map(|(emoji, action)| format!("{} → {}", emoji, action.name())).collect();
+                    return format!("Info: reactions in this channel\n{}", lines.join("\n"));
+                }
+            }
+        };
+        self.save_ch_conf();
+        message
+    }
+
+    /// チャンネルのモデレーションの扱い
+    pub fn moderation_policy(&self, channel: &str) -> ModerationPolicy {
+        self.channels_conf
+            .get(channel)
+            .and_then(|conf| conf.moderation)
+            .unwrap_or(self.settings.moderation_policy)
+    }
+
+    /// モデレーションの扱いを表示・変更する
+    pub fn moderation_conf(&self, channel: &str, policy: Option<&str>) -> String {
+        let Some(name) = policy else {
+            let state = if self.moderator.is_some() { "" } else { " (moderation is disabled in the config)" };
+            return format!("Info: moderation policy in this channel is {}{}", self.moderation_policy(channel).name(), state);
+        };
+        let policy = match name {
+            "reset" => None,
+            name => match ModerationPolicy::from_name(name) {
+                Ok(policy) => Some(policy),
+                Err(e) => return format!("Err: {}", e),
+            },
+        };
+        self.channels_conf.entry(channel.to_string()).or_default().moderation = policy;
+        self.save_ch_conf();
+        format!("Info: moderation policy in this channel is now {}", self.moderation_policy(channel).name())
+    }
+
+    /// 照合してチャンネルのポリシーを当てはめる
+    /// 引っかかれば監査ログに残し、ログチャンネルがこのプラットフォームのものなら通知する
+    /// `history` のときはルールだけで照合し、監査ログに残すだけで通知はしない
+    async fn moderate<P: ChatPlatform>(&self, platform: &P, channel: &str, stage: &str, author: &str, text: &str) -> Outcome {
+        let Some(moderator) = &self.moderator else {
+            return Outcome::Clean;
+        };
+        let history = stage == "history";
+        let outcome = if history {
+            moderator.moderate_rules(text, self.moderation_policy(channel))
+        } else {
+            moderator.moderate(text, self.moderation_policy(channel)).await
+        };
+        let Some(reasons) = outcome.reasons() else {
+            return outcome;
+        };
+        warn!("Moderation: {} {} in {} by {} - {}", stage, outcome.action(), channel, author, reasons.join(", "));
+        self.audit("moderation", serde_json::json!({
+            "channel": channel,
+            "stage": stage,
+            "author": author,
+            "action": outcome.action(),
+            "reasons": reasons,
+        }));
+        if !history
+            && let Some(log_channel) = &self.settings.moderation_log_channel
+            && log_channel != channel
+            && platform.owns_channel(log_channel) {
+            let excerpt: String = text.chars().take(MODERATION_EXCERPT_CHARS).collect();
+            let notice = format!(
+                "⚠️ moderation: {} {} in {} by {} ({})\n> {}",
+                stage,
+                outcome.action(),
+                channel,
+                author,
+                reasons.join(", "),
+                excerpt.replace('\n', "\n> "),
+            );
+            self.send_split_message(platform, log_channel, notice).await;
+        }
+        outcome
+    }
+
+    /// 推論の前に発言を照合する (伏せるときは本文を書き換え、止めるときはエラーの応答を返す)
+    /// 応答しない発言 (`answered` が false) は履歴に積む前にルールだけで照合する
+    async fn moderate_input<P: ChatPlatform>(&self, platform: &P, channel: &str, message: &mut InputMessage, answered: bool) -> Result<(), Answer> {
+        let stage = if answered { "input" } else { "history" };
+        match self.moderate(platform, channel, stage, &message.name, &message.content).await {
+            Outcome::Blocked(_) => Err(Answer::error("Err: your message was blocked by moderation")),
+            Outcome::Redacted(text, _) => {
+                message.content = text;
+                Ok(())
+            }
+            Outcome::Clean | Outcome::Flagged(_) => Ok(()),
+        }
+    }
+
+    /// 送信や記事化の前に応答を照合する
+    async fn moderate_answer<P: ChatPlatform>(&self, platform: &P, channel: &str, stage: &str, mut answer: Answer) -> Answer {
+        if answer.is_error {
+            return answer;
+        }
+        match self.moderate(platform, channel, stage, &self.settings.assistant_name, &answer.content).await {
+            Outcome::Blocked(_) => Answer::error("Err: the answer was withheld by moderation"),
+            Outcome::Redacted(text, _) => {
+                answer.content = text;
+                answer
+            }
+            Outcome::Clean | Outcome::Flagged(_) => answer,
+        }
+    }
+
+    /// 応答本文を表示できる形に整える (付加情報は含まない)
+    /// 長い応答や構造化された応答は設定に応じて記事化し、要約とリンクだけを返す
+    pub async fn format_answer(&self, answer: &Answer, message_id: &str) -> String {
+        if answer.is_error {
+            return answer.content.clone();
+        }
+        let formatted = markdown::to_discord(&answer.content);
+
+        let too_long = self.settings.auto_deploy_min_chars != 0 && answer.content.chars().count() > self.settings.auto_deploy_min_chars;
+        let structured = self.settings.auto_deploy_structured && formatted.is_structured();
+        if let Some(web_deploy) = &self.web_deploy
+            && (too_long || structured) {
+            let key = format!("answer-{}", message_id);
+            let article = answer.content.clone() + &sources::render_full(&answer.sources);
+            match web_deploy.create_article(&key, &article).await {
+                Ok(url) => {
+                    let summary = markdown::summarize(&answer.content, self.settings.summary_max_chars);
+                    return format!("{}\n\n📄 {}", summary, url);
+                }
+                Err(e) => error!("Failed to deploy answer as article - {}", e),
+            }
+        }
+
+        formatted.text + &sources::render_compact(&answer.sources)
+    }
+
+    /// 応答を送信し、作り直しのために送ったメッセージを覚えておく
+    pub async fn send_answer<P: ChatPlatform>(&self, platform: &P, channel: &str, answer: &Answer, body: String) {
+        if let Some(ids) = self.deliver_answer(platform, channel, answer, body, true).await {
+            self.answer_messages.insert(channel.to_string(), ids);
+        }
+    }
+
+    /// 応答を送信し、送ったメッセージの ID を返す (エラーの通知なら None)
+    /// メッセージ数がチャンネルの閾値を超える場合はプレビューだけを流し、全文を .md で添付する
+    async fn deliver_answer<P: ChatPlatform>(&self, platform: &P, channel: &str, answer: &Answer, body: String, retry_button: bool) -> Option<Vec<String>> {
+        let text = body + &answer.footer;
+        let chunk_count = split_message(&text, platform.message_limit()).len();
+        let (threshold, attach_code_blocks) = self.channels_conf
+            .get(channel)
+            .map(|conf| (conf.attach_threshold, conf.attach_code_blocks))
+            .unwrap_or_default();
+        if answer.is_error {
+            self.send_split_message(platform, channel, text).await;
+            return None;
+        }
+        // ツールが作った画像などは応答に添付する
+        let generated: Vec<OutgoingFile> = answer.files
+            .iter()
+            .take(MAX_ATTACHMENTS - 1)
+            .map(|file| OutgoingFile { name: file.name.clone(), data: file.data.clone() })
+            .collect();
+        if threshold == 0 || chunk_count <= threshold {
+            return Some(self.send_chunks(platform, channel, text, retry_button, generated).await);
+        }
+
+        let full_answer = answer.content.clone() + &sources::render_full(&answer.sources);
+        let mut files = vec![OutgoingFile { name: "answer.md".to_string(), data: full_answer.into_bytes() }];
+        files.extend(generated.iter().cloned());
+        if attach_code_blocks {
+            for (i, block) in markdown::extract_code_blocks(&answer.content)
+                .into_iter()
+                .take(MAX_ATTACHMENTS - files.len())
+                .enumerate()
+            {
+                let name = format!("code-{}.{}", i + 1, block.extension());
+                files.push(OutgoingFile { name, data: block.code.into_bytes() });
+            }
+        }
+        let preview = format!(
+            "{}\n-# full answer attached ({} messages){}",
+            markdown::summarize(&answer.content, self.settings.summary_max_chars),
+            chunk_count,
+            answer.footer,
+        );
+        let ids = match platform.send_reply(channel, Reply { content: preview, files, retry_button }).await {
+            Ok(id) => id.into_iter().collect(),
+            Err(why) => {
+                error!("Failed to send answer as attachment - {:?}", why);
+                self.send_chunks(platform, channel, text, retry_button, generated).await
+            }
+        };
+        Some(ids)
+    }
+
+    /// メッセージを分割して送信する
+    pub async fn send_split_message<P: ChatPlatform>(&self, platform: &P, channel: &str, text: String) {
+        self.send_chunks(platform, channel, text, false, Vec::new()).await;
+    }
+
+    /// メッセージを分割して送信し、送れたメッセージの ID を返す
+    /// `retry_button` なら最後のメッセージに作り直しのボタンを付け、`files` も最後のメッセージに添付する
+    async fn send_chunks<P: ChatPlatform>(&self, platform: &P, channel: &str, text: String, retry_button: bool, files: Vec<OutgoingFile>) -> Vec<String> {
+        let chunks = split_message(&text, platform.message_limit());
+        let last = chunks.len().saturating_sub(1);
+        let mut files = Some(files);
+        let mut ids = Vec::new();
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let files = if i == last { files.take().unwrap_or_default() } else { Vec::new() };
+            let reply = Reply { retry_button: retry_button && i == last, files, ..Reply::text(chunk) };
+            match platform.send_reply(channel, reply).await {
+                Ok(id) => ids.extend(id),
+                Err(why) => error!("{:?}", why),
+            }
+        }
+        ids
+    }
+
+    /// 会話履歴を消す
+    pub async fn reset(&self, channel: &str) -> String {
+        self.channel_state(channel).await.clear_prompt().await;
+        self.last_turns.remove(channel);
+        "reset brain".to_string()
+    }
+
+    /// チャンネルで AI を有効・無効にする
+    pub fn set_enabled(&self, channel: &str, enable: bool) -> String {
+        let state = if enable { "enabled" } else { "disabled" };
+        if let Some(mut ch_conf) = self.channels_conf.get_mut(channel) {
+            if ch_conf.enable == enable {
+                return format!("Info: AI is already {}", state);
+            }
+            ch_conf.enable = enable;
+        } else {
+            self.channels_conf.insert(channel.to_string(), ChConf { enable, ..Default::default() });
+        }
+        self.save_ch_conf();
+        format!("Info: AI is {}", state)
+    }
+
+    /// 長い応答を添付にする閾値を設定する
+    pub fn attach_conf(&self, channel: &str, threshold: usize, code_blocks: Option<bool>) -> String {
+        let message = {
+            let mut ch_conf = self.channels_conf.entry(channel.to_string()).or_default();
+            ch_conf.attach_threshold = threshold;
+            if let Some(code_blocks) = code_blocks {
+                ch_conf.attach_code_blocks = code_blocks;
+            }
+            if threshold == 0 {
+                "Info: attachment mode is disabled".to_string()
+            } else {
+                format!(
+                    "Info: answers longer than {} messages are attached as a file (code blocks: {})",
+                    threshold,
+                    if ch_conf.attach_code_blocks { "separate files" } else { "inline" },
+                )
+            }
+        };
+        self.save_ch_conf();
+        message
+    }
+
+    /// プラットフォームから直近のメッセージを取り込む
+    pub async fn collect_history<P: ChatPlatform>(&self, platform: &P, channel: &str, entry_num: usize) -> String {
+        let state = self.channel_state(channel).await;
+        let messages = match platform.fetch_history(channel, entry_num).await {
+            Ok(messages) => messages,
+            Err(e) => return format!("Error: failed to fetch history - {}", e),
+        };
+        for message in messages {
+            state.add_message(message).await;
+        }
+        format!("Info: Complete collecting history ({} entries)", entry_num)
+    }
+
+    /// ユーザーのレートを強制的に消費・リセットする (管理者用)
+    /// `user_line` が 0 なら無制限、負ならリセット
+    pub async fn rate_conf<P: ChatPlatform>(&self, platform: &P, target_user_id: &str, user_line: i64) -> String {
+        // ユーザーidから名前を取得
+        let target_user_name = platform.resolve_user(target_user_id).await.unwrap_or_default();
+
+        let mut user_conf = self.user_config(target_user_id, 0); // デフォルトは無制限
+
+        // レートリミットを設定
+        let timestamp = now();
+        let sec_per_rate = self.settings.sec_per_rate; // レートの回復時間
+        if user_line == 0 {
+            user_conf.rate_limit = 0; // 無制限
+        } else if user_line < 0 {
+            user_conf.rate_limit = timestamp; // リセット
+        } else {
+            if user_conf.rate_limit < timestamp {
+                user_conf.rate_limit = timestamp;
+            }
+            user_conf.rate_limit += user_line as u64 * sec_per_rate;
+        }
+        if user_conf.rate_limit == 0 {
+            format!("Info: {} rate limit line set to unlimited", target_user_name)
+        } else {
+            let cp = self.settings.rate_cp; // レートの許容量
+
+            // レートリミットの計算
+            let limit_line = sec_per_rate * cp;
+            let now_rate = ((timestamp + limit_line) as i64 - user_conf.rate_limit as i64) / sec_per_rate as i64;
+            let next_time = user_conf.rate_limit.saturating_sub(limit_line);
+            format!("Info: rate limit forcibly consumed. Now {}'s rate is {} (relative: <t:{}:R>)", target_user_name, now_rate, next_time)
+        }
+    }
+
+    /// ユーザーが使うモデルを変える
+    pub fn set_model(&self, user_id: &str, model_name: Option<&str>) -> Result<String, String> {
+        let default_model_name = self.settings.default_model.to_model_name();
+        let model = AIModel::from_model_name(model_name.unwrap_or(&default_model_name))?;
+        // 初めてのユーザーは通常のレートリミットにする (モデルを変えただけで無制限にしない)
+        let mut user_conf = self.user_config(user_id, 1);
+        user_conf.model = model.clone();
+        Ok(format!("Info: Model set to {}", model.to_model_name()))
+    }
+
+    /// 予約の一覧・取り消し
+    /// 取り消せるのは登録者と、`can_manage` (管理者やチャンネル管理者) のときだけ
+    pub fn schedule(&self, channel: &str, user_id: &str, can_manage: bool, cancel: Option<u64>) -> String {
+        let Some(scheduler) = &self.scheduler else {
+            return "Err: scheduler is disabled".to_string();
+        };
+        if let Some(id) = cancel {
+            return match scheduler.cancel(id, user_id, can_manage || self.is_admin(user_id)) {
+                Ok(job) => format!("Info: cancelled job #{}", job.id),
+                Err(e) => format!("Err: {}", e),
+            };
+        }
+        let jobs = scheduler.list(Some(channel));
+        if jobs.is_empty() {
+            return "Info: no scheduled jobs in this channel".to_string();
+        }
+        let lines: Vec<String> = jobs.iter().map(|job| job.summary(scheduler.default_timezone)).collect();
+        format!("Scheduled jobs:\n{}", lines.join("\n"))
+    }
+
+    /// 予約されたジョブとダイジェストを時刻どおりに実行し続ける
+    /// プラットフォームごとに起動し、`owns` が true のチャンネルのものだけを扱う
+    pub async fn run_scheduler<P: ChatPlatform + Clone + 'static>(self: Arc<Self>, platform: P, owns: fn(&str) -> bool) {
+        loop {
+            if let Some(scheduler) = &self.scheduler {
+                for job in scheduler.take_due(Utc::now(), owns) {
+                    info!("Running scheduled job #{} in {}", job.id, job.channel);
+                    let bot = self.clone();
+                    let platform = platform.clone();
+                    tokio::spawn(async move { bot.run_job(&platform, job).await });
+                }
+            }
+            self.run_due_digests(&platform, owns);
+
+            let wait = self.scheduler
+                .as_ref()
+                .and_then(|scheduler| scheduler.next_wakeup(owns))
+                .map(|next| (next - Utc::now()).to_std().unwrap_or_default())
+                .unwrap_or(SCHEDULER_POLL_INTERVAL)
+                .min(SCHEDULER_POLL_INTERVAL);
+            let changed = async {
+                match &self.scheduler {
+                    Some(scheduler) => scheduler.changed().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = time::sleep(wait) => {}
+                _ = changed => {}
+            }
+        }
+    }
+
+    /// 頻度に達したチャンネルのダイジェストを作って流す
+    fn run_due_digests<P: ChatPlatform + Clone + 'static>(self: &Arc<Self>, platform: &P, owns: fn(&str) -> bool) {
+        let timestamp = now();
+        let due: Vec<String> = self.channels_conf
+            .iter()
+            .filter(|entry| owns(entry.key()))
+            .filter(|entry| entry.digest.period_secs().is_some_and(|period| entry.last_digest + period <= timestamp))
+            .map(|entry| entry.key().clone())
+            .collect();
+        for channel in due {
+            // 作成中に再び実行されないように先に時刻を進める
+            if let Some(mut conf) = self.channels_conf.get_mut(&channel) {
+                conf.last_digest = timestamp;
+            }
+            info!("Creating digest for {}", channel);
+            let bot = self.clone();
+            let platform = platform.clone();
+            tokio::spawn(async move {
+                match bot.digest(&platform, &channel).await {
+                    Ok(Some(text)) => bot.send_split_message(&platform, &channel, text).await,
+                    Ok(None) => info!("No new messages for the digest of {}", channel),
+                    Err(e) => error!("Failed to create digest for {} - {}", channel, e),
+                }
+            });
+        }
+    }
+
+    /// ダイジェストの頻度を設定する (次のダイジェストは今から 1 周期後)
+    pub fn set_digest(&self, channel: &str, frequency: DigestFrequency) -> String {
+        {
+            let mut ch_conf = self.channels_conf.entry(channel.to_string()).or_default();
+            ch_conf.digest = frequency;
+            ch_conf.last_digest = now();
+        }
+        self.save_ch_conf();
+        match frequency {
+            DigestFrequency::Off => "Info: digest is disabled".to_string(),
+            frequency => format!("Info: {} digest is enabled", frequency.name()),
+        }
+    }
+
+    /// すぐにダイジェストを作り、チャンネルに流す文面を返す
+    pub async fn digest_now<P: ChatPlatform>(&self, platform: &P, channel: &str) -> String {
+        match self.digest(platform, channel).await {
+            Ok(Some(text)) => text,
+            Ok(None) => "Info: no new messages since the last digest".to_string(),
+            Err(e) => e,
+        }
+    }
+
+    /// 前回のダイジェスト以降の発言を集める
+    /// プラットフォームから取得できなければ会話履歴から取り出す
+    async fn digest_entries<P: ChatPlatform>(&self, platform: &P, channel: &str, last_message: Option<String>) -> Vec<DigestEntry> {
+        let mut entries: Vec<DigestEntry> = Vec::new();
+        let mut after = last_message.clone();
+        while entries.len() < DIGEST_MAX_MESSAGES {
+            let page = match platform.fetch_since(channel, after.as_deref(), DIGEST_FETCH_PAGE).await {
+                Ok(page) => page,
+                Err(e) if entries.is_empty() => {
+                    info!("Using conversation history for the digest of {} - {}", channel, e);
+                    let state = self.channel_state(channel).await;
+                    let prompt_stream = state.prompt_stream.lock().await;
+                    return digest::after_message(digest::entries_from_history(&prompt_stream.prompt), last_message.as_deref());
+                }
+                Err(e) => {
+                    error!("Failed to fetch messages for the digest of {} - {}", channel, e);
+                    break;
+                }
+            };
+            let full_page = page.len() >= DIGEST_FETCH_PAGE;
+            entries.extend(page.into_iter().map(|m| DigestEntry {
+                message_id: m.message_id,
+                user_name: m.name,
+                text: m.content,
+                images: m.attached_files,
+            }));
+            // ID がなければ続きを取得できない (最初の取得は直近のメッセージなので続けない)
+            if !full_page || after.is_none() {
+                break;
+            }
+            after = digest::last_message_id(&entries);
+        }
+        entries
+    }
+
+    /// 前回以降の発言をまとめて記事にし、チャンネルに流す文面を返す (新しい発言がなければ None)
+    pub async fn digest<P: ChatPlatform>(&self, platform: &P, channel: &str) -> Result<Option<String>, String> {
+        let (frequency, last_message) = self.channels_conf
+            .get(channel)
+            .map(|conf| (conf.digest, conf.last_digest_message.clone()))
+            .unwrap_or_default();
+        let entries = self.digest_entries(platform, channel, last_message).await;
+        {
+            let mut ch_conf = self.channels_conf.entry(channel.to_string()).or_default();
+            ch_conf.last_digest = now();
+            if let Some(id) = digest::last_message_id(&entries) {
+                ch_conf.last_digest_message = Some(id);
+            }
+        }
+        self.save_ch_conf();
+        if entries.is_empty() {
+            return Ok(None);
+        }
+
+        // チャンネルの会話とは別の履歴で、ツールを使わずにまとめさせる
+        let state = ChannelState::new(&self.base_client, &format!("digest:{}", channel)).await;
+        let config = ReasoningConfig {
+            developer_prompt: digest::DIGEST_PROMPT.to_string(),
+            max_use_tool_count: 0,
+            ..self.reasoning_config(&self.settings.default_model)
+        };
+        let message = InputMessage {
+            content: digest::transcript(&entries, digest::MAX_TRANSCRIPT_CHARS),
+            name: "digest".to_string(),
+            message_id: format!("digest-{}", now()),
+            reply_msg: None,
+            user_id: "digest".to_string(),
+            attached_files: Vec::new(),
+        };
+        let answer = time::timeout(TIMEOUT, state.run_reasoning(message, &config, &Silent))
+            .await
+            .map_err(|_| "Err: timeout".to_string())?;
+        if answer.is_error {
+            return Err(answer.content);
+        }
+        let answer = self.moderate_answer(platform, channel, "digest", answer).await;
+        if answer.is_error {
+            return Err(answer.content);
+        }
+
+        let date = Local::now().format("%Y-%m-%d").to_string();
+        let Some(web_deploy) = &self.web_deploy else {
+            return Ok(Some(markdown::to_discord(&answer.content).text));
+        };
+        let article = digest::render_article(frequency, &date, &answer.content, entries.len());
+        let url = web_deploy.create_article(&digest::article_key(channel, &date), &article)
+            .await
+            .map_err(|e| format!("Err: failed to publish the digest - {}", e))?;
+        Ok(Some(format!(
+            "📰 digest {} ({} messages)\n{}\n\n📄 {}",
+            date,
+            entries.len(),
+            markdown::summarize(&answer.content, self.settings.summary_max_chars),
+            url,
+        )))
+    }
+
+    /// ジョブのプロンプトを登録者の発言として推論させる
+    /// チャンネルの有効・無効とレートリミットは通常の発言と同じく適用される
+    pub async fn run_job<P: ChatPlatform>(&self, platform: &P, job: Job) {
+        let message = InputMessage {
+            content: format!("[scheduled job #{} by {}] {}", job.id, job.owner_name, job.prompt),
+            name: job.owner_name.clone(),
+            message_id: format!("job-{}-{}", job.id, now()),
+            reply_msg: None,
+            user_id: job.owner.clone(),
+            attached_files: Vec::new(),
+        };
+        self.handle_message(platform, &job.channel, message, true).await;
+    }
+
+    /// 編集されたメッセージを履歴に反映する
+    pub async fn message_edited(&self, channel: &str, message_id: &str, content: &str) {
+        let Some(state) = self.channels.get(channel).map(|s| s.clone()) else {
+            return;
+        };
+        if state.edit_message(message_id, content).await {
+            info!("Edited message {} in the history of {}", message_id, channel);
+        }
+    }
+
+    /// 削除されたメッセージを履歴から取り除く
+    /// そのメッセージから作られた記事やメモリは監査ログに記録して見直せるようにする
+    pub async fn messages_deleted(&self, channel: &str, message_ids: &[String]) {
+        let state = self.channels.get(channel).map(|s| s.clone());
+        for message_id in message_ids {
+            attachments::store().remove_message(channel, message_id);
+            let mut derived = match &state {
+                Some(state) => state.remove_message(message_id).await.unwrap_or_default(),
+                None => Vec::new(),
+            };
+            // 自動で記事にした応答と、リアクションで作った記事
+            if let Some(web_deploy) = &self.web_deploy {
+                for key in [format!("answer-{}", message_id), format!("reaction-{}", message_id)] {
+                    let article = history::Derived::Article(key);
+                    if !derived.contains(&article) && web_deploy.found_article(article.key()).await {
+                        derived.push(article);
+                    }
+                }
+            }
+            if derived.is_empty() {
+                continue;
+            }
+            warn!("Message {} in {} was deleted; review {:?}", message_id, channel, derived);
+            let derived: Vec<serde_json::Value> = derived
+                .iter()
+                .map(|d| serde_json::json!({ "kind": d.kind(), "key": d.key() }))
+                .collect();
+            self.audit("source_deleted", serde_json::json!({
+                "channel": channel,
+                "message_id": message_id,
+                "derived": derived,
+            }));
+        }
+    }
+
+    /// ユーザーの発言を会話履歴・保存された履歴・メモリから消し、引用している記事を管理者の見直しに回す
+    /// `confirm` なしで呼ぶと消える量を示して確認を求め、期限内に `confirm` 付きで呼ぶと実行する
+    pub async fn forget_me(&self, user_id: &str, user_name: &str, confirm: bool) -> String {
+        if !confirm {
+            let mut count = 0;
+            for state in self.channels.iter().map(|s| s.clone()).collect::<Vec<_>>() {
+                count += privacy::count_user_messages(&state.prompt_stream.lock().await.prompt, user_id);
+            }
+            self.forget_requests.insert(user_id.to_string(), now() + privacy::CONFIRM_WINDOW_SECS);
+            return format!(
+                "⚠️ This permanently removes your {} messages from the conversation history (and saved sessions), \
+                deletes memory entries made from them, anonymizes memory entries that mention you \
+                and flags articles quoting you for admin review.\n\
+                Run forget_me again with confirm within {} minutes to proceed.",
+                count,
+                privacy::CONFIRM_WINDOW_SECS / 60,
+            );
+        }
+        match self.forget_requests.remove(user_id) {
+            Some((_, deadline)) if deadline >= now() => {}
+            _ => return "Err: run forget_me without confirm first".to_string(),
+        }
+
+        let mut removed = privacy::Removed::default();
+        for state in self.channels.iter().map(|s| s.clone()).collect::<Vec<_>>() {
+            removed.extend(privacy::remove_user_messages(&mut state.prompt_stream.lock().await.prompt, user_id));
+        }
+        removed.extend(privacy::purge_snapshot_dir(std::path::Path::new(&self.settings.session_dir), user_id));
+        let mut report = ForgetReport {
+            removed_messages: removed.count(),
+            deleted_attachments: attachments::store().forget_user(user_id),
+            ..Default::default()
+        };
+        if let Some(cache) = image_cache::installed() {
+            let mut remaining = Vec::new();
+            for state in self.channels.iter().map(|s| s.clone()).collect::<Vec<_>>() {
+                let prompt_stream = state.prompt_stream.lock().await;
+                remaining.extend(prompt_stream.prompt.iter().filter(|m| !image_cache::references(m).is_empty()).cloned());
+            }
+            let unused = privacy::unreferenced_images(&removed.images, &remaining, std::path::Path::new(&self.settings.session_dir));
+            for hash in &unused {
+                cache.remove(hash);
+            }
+            report.deleted_images = unused.len();
+        }
+
+        if let Some(memory) = &self.memory {
+            for item in &removed.derived {
+                if let history::Derived::Memory(key) = item
+                    && !memory.get_memory(Some(key)).is_empty() {
+                    memory.clear_memory(Some(key));
+                    report.deleted_memories.push(key.clone());
+                }
+            }
+            let mention = format!("<@{}>", user_id);
+            report.anonymized_memories = memory.anonymize(&[user_name, &mention], privacy::FORGOTTEN_USER);
+        }
+
+        if let Some(web_deploy) = &self.web_deploy {
+            let mut derived: Vec<String> = removed.derived.iter()
+                .filter(|d| matches!(d, history::Derived::Article(_)))
+                .map(|d| d.key().to_string())
+                .collect();
+            for id in &removed.message_ids {
+                derived.push(format!("answer-{}", id));
+                derived.push(format!("reaction-{}", id));
+            }
+            for key in web_deploy.article_keys().await {
+                let quoted = derived.contains(&key) || web_deploy.get_article(&key).await
+                    .is_ok_and(|article| privacy::quotes(&article, user_name, user_id, &removed.bodies));
+                if quoted {
+                    report.articles.push(key);
+                }
+            }
+        }
+
+        warn!("Forgot user {}: {:?}", user_id, report);
+        self.audit("forget_me", report.to_audit(user_id));
+        report.summary()
+    }
+
+    /// 監査ログに 1 行追記する
+    pub fn audit(&self, event: &str, mut record: serde_json::Value) {
+        record["event"] = event.into();
+        record["time"] = Utc::now().to_rfc3339().into();
+        let path = &self.settings.audit_path;
+        let result = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", record));
+        if let Err(e) = result {
+            error!("Failed to write audit record to {}: {:?}", path, e);
+        }
+    }
+
+    /// 会話履歴を書き出す (ファイル名と中身)
+    pub async fn export(&self, channel: &str, format_name: &str) -> Result<(String, String), String> {
+        let format = history::ExportFormat::from_name(format_name)?;
+        let state = self.channels.get(channel)
+            .map(|s| s.clone())
+            .ok_or("No history in this channel".to_string())?;
+        let exported = state.export(format).await;
+        Ok((format!("export-{}.{}", channel, format.extension()), exported))
+    }
+
+    /// チャンネル設定の保存
+    pub fn save_ch_conf(&self) {
+        let json_path = &self.settings.ch_conf_path;
+        let mut conf_map = HashMap::new();
+        for entry in self.channels_conf.iter() {
+            conf_map.insert(entry.key().clone(), entry.value().clone());
+        }
+        match serde_json::to_string_pretty(&conf_map) {
+            Ok(json_str) => {
+                if let Err(e) = std::fs::write(json_path, json_str) {
+                    error!("Failed to write channel configuration to {}: {:?}", json_path, e);
+                } else {
+                    info!("Channel configuration saved to {}", json_path);
+                }
+            }
+            Err(e) => {
+                error!("Failed to serialize channel configuration: {:?}", e);
+            }
+        }
+    }
+
+    /// チャンネル設定の読み込み
+    pub fn load(&self) {
+        let json_path = &self.settings.ch_conf_path;
+        if let Ok(json_str) = std::fs::read_to_string(json_path) {
+            match serde_json::from_str::<HashMap<String, ChConf>>(&json_str) {
+                Ok(conf_map) => {
+                    for (key, value) in conf_map {
+                        self.channels_conf.insert(key, value);
+                    }
+                    info!("Channel configuration loaded from {}", json_path);
+                }
+                Err(e) => {
+                    error!("Failed to deserialize channel configuration: {:?}", e);
+                }
+            }
+        } else {
+            info!("No channel configuration found at {}", json_path);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{agent::tests::EchoTool, platform::fake::FakePlatform};
+    use observer::{cassette::{CassetteMode, CassetteServer}, history::UserMeta, mock_llm::{MockLlmServer, Scenario}, moderation::ModeratorConfig, tools::scheduler::Schedule};
+    use tempfile::TempDir;
+
+    pub(crate) fn settings(dir: &TempDir) -> BotSettings {
+        BotSettings {
+            assistant_name: "observer".to_string(),
+            max_tokens: 1000,
+            developer_prompt: "You are observer. Use tools when asked.".to_string(),
+            max_use_tool_count: 3,
+            default_model: AIModel::M5Mini,
+            sec_per_rate: 100,
+            rate_cp: 10,
+            admin_users: vec!["1".to_string()],
+            auto_deploy_min_chars: 0,
+            auto_deploy_structured: false,
+            summary_max_chars: 200,
+            ch_conf_path: dir.path().join("ch_conf.json").to_string_lossy().to_string(),
+            audit_path: dir.path().join("audit.jsonl").to_string_lossy().to_string(),
+            session_dir: dir.path().join("repl").to_string_lossy().to_string(),
+            moderation_policy: ModerationPolicy::Flag,
+            moderation_log_channel: None,
+            voice_minutes_per_day: 1,
+        }
+    }
+
+    /// API に繋がらないクライアントで作る
+    pub(crate) fn offline_bot(dir: &TempDir) -> Bot {
+        let client = OpenAIClient::new("http://127.0.0.1:9", None);
+        Bot::new(Arc::new(client), None, settings(dir))
+    }
+
+    /// シナリオを流すモックの LLM サーバーと、それに繋がるクライアント (ツールは呼び出し側で足す)
+    pub(crate) async fn mock_client(scenario: &str) -> (MockLlmServer, OpenAIClient) {
+        let server = MockLlmServer::start(Scenario::from_json(scenario).unwrap(), "127.0.0.1:0").await.unwrap();
+        let client = OpenAIClient::new(&server.endpoint(), Some("test"));
+        (server, client)
+    }
+
+    /// ツールを持たないモックのクライアントで作る
+    pub(crate) async fn mock_bot(scenario: &str, dir: &TempDir) -> (MockLlmServer, Bot) {
+        let (server, client) = mock_client(scenario).await;
+        (server, Bot::new(Arc::new(client), None, settings(dir)))
+    }
+
+    fn input(message_id: &str, user_id: &str, content: &str) -> InputMessage {
+        InputMessage {
+            content: content.to_string(),
+            name: "alice".to_string(),
+            message_id: message_id.to_string(),
+            reply_msg: None,
+            user_id: user_id.to_string(),
+            attached_files: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn disabled_channel_refuses_mentions() {
+        let dir = TempDir::new().unwrap();
+        let bot = offline_bot(&dir);
+        let platform = FakePlatform::new();
+
+        bot.handle_message(&platform, "c1", input("1", "42", "hi"), true).await;
+        assert_eq!(platform.sent(), vec!["Err: AI is disabled in this channel"]);
+
+        // メンションでなければ履歴に積むだけ
+        bot.handle_message(&platform, "c1", input("2", "42", "hello"), false).await;
+        assert_eq!(platform.sent().len(), 1);
+        assert_eq!(bot.channel_state("c1").await.prompt_stream.lock().await.prompt.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_user_gets_an_error() {
+        let dir = TempDir::new().unwrap();
+        let bot = offline_bot(&dir);
+        let platform = FakePlatform::new();
+        bot.set_enabled("c1", true);
+        bot.user_configs.insert("42".to_string(), PerUserConfig { rate_limit: now() + 10_000, model: AIModel::M5Mini, voice_day: 0, voice_secs: 0.0 });
+
+        bot.handle_message(&platform, "c1", input("1", "42", "hi"), true).await;
+        let sent = platform.sent();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].starts_with("Err: rate limit - try again after <t:"), "{}", sent[0]);
+        assert_eq!(*platform.typing.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_voice_messages_are_not_transcribed() {
+        let dir = TempDir::new().unwrap();
+        // 音声の取得も書き起こしもこのサーバーに届く
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let transcriber = Transcriber::new(transcription::TranscriberConfig {
+            endpoint: format!("http://{}/v1/", address),
+            model: "whisper-1".to_string(),
+            ..Default::default()
+        });
+        let bot = offline_bot(&dir).with_transcriber(Some(Arc::new(transcriber)));
+        let platform = FakePlatform::new();
+        bot.set_enabled("c1", true);
+        bot.user_configs.insert("42".to_string(), PerUserConfig { rate_limit: now() + 10_000, model: AIModel::M5Mini, voice_day: 0, voice_secs: 0.0 });
+
+        let mut message = input("1", "42", "聞いて");
+        message.attached_files = vec![format!("http://{}/voice-message.ogg", address)];
+        bot.handle_message(&platform, "c1", message, true).await;
+        assert!(platform.sent()[0].starts_with("Err: rate limit"));
+        assert!(time::timeout(Duration::from_millis(100), listener.accept()).await.is_err());
+    }
+
+    #[test]
+    fn consume_rate_charges_the_model_cost() {
+        let dir = TempDir::new().unwrap();
+        let bot = offline_bot(&dir);
+        let cost = AIModel::M5Mini.to_sec_per_rate() as u64 * 100;
+
+        // 新規ユーザーは現在時刻からコスト分だけ進む
+        assert!(bot.consume_rate("42", 1_000, None).is_ok());
+        assert_eq!(bot.user_configs.get("42").unwrap().rate_limit, 1_000 + cost);
+        assert!(bot.consume_rate("42", 1_000, None).is_ok());
+        assert_eq!(bot.user_configs.get("42").unwrap().rate_limit, 1_000 + cost * 2);
+
+        // 許容量を超えると拒否され、消費もしない
+        bot.user_configs.get_mut("42").unwrap().rate_limit = 1_000 + 1_000 + 1;
+        assert!(bot.consume_rate("42", 1_000, None).is_err());
+        assert_eq!(bot.user_configs.get("42").unwrap().rate_limit, 2_001);
+
+        // 無制限のユーザーは変化しない
+        bot.user_configs.get_mut("42").unwrap().rate_limit = 0;
+        assert!(bot.consume_rate("42", 1_000, None).is_ok());
+        assert_eq!(bot.user_configs.get("42").unwrap().rate_limit, 0);
+
+        // モデルを指定するとそのモデルのコストを消費する
+        let model = bot.consume_rate("7", 1_000, Some(AIModel::M5)).unwrap();
+        assert_eq!(model.to_model_name(), "gpt-5");
+        assert_eq!(bot.user_configs.get("7").unwrap().rate_limit, 1_000 + AIModel::M5.to_sec_per_rate() as u64 * 100);
+    }
+
+    #[test]
+    fn voice_budget_resets_daily() {
+        let dir = TempDir::new().unwrap();
+        let bot = offline_bot(&dir);
+        let day = 86_400 * 20_000;
+
+        assert_eq!(bot.reserve_voice("42", 45.0, day + 10), Ok(()));
+        // 書き起こし中の分も数えるので、同時に届いた音声は上限を超えられない
+        assert_eq!(bot.reserve_voice("42", 30.0, day + 20), Err(15.0));
+        // 書き起こしに失敗すれば戻し、長さがわかれば実際の長さに合わせる
+        bot.charge_voice("42", -45.0, day + 30);
+        assert_eq!(bot.reserve_voice("42", 50.0, day + 40), Ok(()));
+        bot.charge_voice("42", 20.0, day + 50);
+        assert_eq!(bot.reserve_voice("42", 0.0, day + 60), Err(-10.0));
+        // 日が変われば戻る
+        assert_eq!(bot.reserve_voice("42", 60.0, day + 86_400), Ok(()));
+
+        // レートリミットのないアカウントは無制限
+        bot.user_configs.get_mut("42").unwrap().rate_limit = 0;
+        assert_eq!(bot.reserve_voice("42", 1_000.0, day), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn voice_messages_are_replaced_by_a_note() {
+        let dir = TempDir::new().unwrap();
+        let transcriber = Transcriber::new(transcription::TranscriberConfig {
+            endpoint: "http://127.0.0.1:9/v1/".to_string(),
+            model: "whisper-1".to_string(),
+            ..Default::default()
+        });
+        let bot = offline_bot(&dir).with_transcriber(Some(Arc::new(transcriber)));
+        let mut message = input("1", "42", "聞いて");
+        message.attached_files = vec![
+            "http://127.0.0.1:9/voice-message.ogg".to_string(),
+            "http://127.0.0.1:9/photo.png".to_string(),
+        ];
+
+        bot.transcribe_voice(&mut message).await;
+        assert!(message.content.starts_with("聞いて\n[voice message (voice-message.ogg) could not be transcribed: "), "{}", message.content);
+        assert_eq!(message.attached_files, vec!["http://127.0.0.1:9/photo.png".to_string()]);
+    }
+
+    #[test]
+    fn enable_disable_is_persisted() {
+        let dir = TempDir::new().unwrap();
+        let bot = offline_bot(&dir);
+        assert_eq!(bot.set_enabled("c1", true), "Info: AI is enabled");
+        assert_eq!(bot.set_enabled("c1", true), "Info: AI is already enabled");
+        assert_eq!(bot.set_enabled("c2", false), "Info: AI is disabled");
+        bot.attach_conf("c1", 3, Some(true));
+
+        let reloaded = offline_bot(&dir);
+        reloaded.load();
+        let c1 = reloaded.channels_conf.get("c1").unwrap().clone();
+        assert!(c1.enable);
+        assert_eq!(c1.attach_threshold, 3);
+        assert!(c1.attach_code_blocks);
+        assert!(!reloaded.channels_conf.get("c2").unwrap().enable);
+        assert_eq!(reloaded.set_enabled("c1", false), "Info: AI is disabled");
+    }
+
+    #[tokio::test]
+    async fn collect_history_reads_from_the_platform() {
+        let dir = TempDir::new().unwrap();
+        let bot = offline_bot(&dir);
+        let mut platform = FakePlatform::new();
+        platform.history.insert(
+            "c1".to_string(),
+            (1..=5).map(|i| input(&i.to_string(), "42", &format!("message {}", i))).collect(),
+        );
+
+        let message = bot.collect_history(&platform, "c1", 3).await;
+        assert_eq!(message, "Info: Complete collecting history (3 entries)");
+        let state = bot.channel_state("c1").await;
+        let prompt = state.prompt_stream.lock().await.prompt.clone();
+        assert_eq!(prompt.len(), 3);
+        let first = UserMeta::from_message(&prompt[0]).unwrap();
+        assert_eq!(first.body, "message 3");
+    }
+
+    #[tokio::test]
+    async fn commands_use_settings_and_platform_users() {
+        let dir = TempDir::new().unwrap();
+        let bot = offline_bot(&dir);
+        let mut platform = FakePlatform::new();
+        platform.users.insert("42".to_string(), "alice".to_string());
+
+        assert!(bot.is_admin("1"));
+        assert!(!bot.is_admin("42"));
+        assert_eq!(bot.rate_conf(&platform, "42", 0).await, "Info: alice rate limit line set to unlimited");
+        assert!(bot.rate_conf(&platform, "42", 2).await.starts_with("Info: rate limit forcibly consumed. Now alice's rate is 8 "));
+
+        assert_eq!(bot.set_model("42", Some("gpt-5")).unwrap(), "Info: Model set to gpt-5");
+        assert!(bot.set_model("42", Some("nope")).is_err());
+        assert_eq!(bot.set_model("42", None).unwrap(), "Info: Model set to gpt-5-mini");
+        // モデルを変えてもレートリミットは外れない
+        bot.set_model("99", Some("gpt-5")).unwrap();
+        assert_ne!(bot.user_configs.get("99").unwrap().rate_limit, 0);
+
+        assert!(bot.export("c1", "markdown").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn mention_runs_the_agent_and_replies() {
+        let dir = TempDir::new().unwrap();
+        let path = format!("{}/tests/cassettes/tool_loop.json", env!("CARGO_MANIFEST_DIR"));
+        let server = CassetteServer::start(path, CassetteMode::from_env()).await.unwrap();
+        let api_key = std::env::var("OPENAI_API_KEY").unwrap_or_else(|_| "test".to_string());
+        let mut client = OpenAIClient::new(&server.endpoint(), Some(&api_key));
+        client.def_tool(Arc::new(EchoTool));
+        let bot = Bot::new(Arc::new(client), None, settings(&dir));
+        bot.set_enabled("c1", true);
+        let platform = FakePlatform::new();
+
+        bot.handle_message(&platform, "c1", input("1", "42", "echo hello please"), true).await;
+
+        assert!(server.misses().is_empty(), "unexpected requests: {:#?}", server.misses());
+        let statuses = platform.statuses.lock().unwrap().clone();
+        assert_eq!(statuses, vec![("c1".to_string(), "-# using echo...".to_string())]);
+        let sent = platform.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0], "echo said: hello\n-# model: gpt-5-mini\n-# tools: echo");
+        assert!(*platform.typing.lock().unwrap() >= 1);
+        assert!(bot.user_configs.get("42").unwrap().rate_limit > 0);
+    }
+
+    #[tokio::test]
+    async fn scheduled_jobs_are_listed_cancelled_and_run_as_the_owner() {
+        let dir = TempDir::new().unwrap();
+        let scheduler = Arc::new(Scheduler::load(dir.path().join("jobs.json"), 0, chrono_tz::UTC));
+        let bot = offline_bot(&dir).with_scheduler(Some(scheduler.clone()));
+        let platform = FakePlatform::new();
+        let once = Schedule::Once { at: Utc::now() + chrono::Duration::hours(1) };
+        let job = scheduler.add("42", "alice", "c1", "say hi", once.clone(), Utc::now()).unwrap();
+        let other = scheduler.add("7", "bob", "c1", "say bye", once, Utc::now()).unwrap();
+
+        let listed = bot.schedule("c1", "42", false, None);
+        assert!(listed.contains("say hi") && listed.contains("say bye"), "{}", listed);
+        assert_eq!(bot.schedule("c2", "42", false, None), "Info: no scheduled jobs in this channel");
+        assert!(bot.schedule("c1", "42", false, Some(other.id)).starts_with("Err:"));
+        // 管理者は他人のジョブも取り消せる
+        assert_eq!(bot.schedule("c1", "1", false, Some(other.id)), format!("Info: cancelled job #{}", other.id));
+
+        // 無効なチャンネルでは通常の発言と同じく断る
+        bot.run_job(&platform, job).await;
+        assert_eq!(platform.sent(), vec!["Err: AI is disabled in this channel"]);
+    }
+
+    const DIGEST_SCENARIO: &str = r###"
+    {
+      "name": "digest",
+      "steps": [
+        {
+          "expect": { "user_contains": "[bob] we decided to ship on friday https://example.com/release" },
+          "respond": { "content": "## Topics\n\nRelease planning.\n\n## Decisions\n\nShip on friday." }
+        }
+      ]
+    }
+    "###;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn digest_summarizes_new_messages_once() {
+        let dir = TempDir::new().unwrap();
+        let (server, bot) = mock_bot(DIGEST_SCENARIO, &dir).await;
+        let mut platform = FakePlatform::new();
+        let mut old = input("1", "42", "old message");
+        old.name = "alice".into();
+        let mut new = input("2", "7", "we decided to ship on friday https://example.com/release");
+        new.name = "bob".into();
+        platform.history.insert("c1".into(), vec![old, new]);
+        bot.channels_conf.insert("c1".into(), ChConf { last_digest_message: Some("1".into()), ..Default::default() });
+
+        assert_eq!(bot.set_digest("c1", DigestFrequency::Weekly), "Info: weekly digest is enabled");
+        let text = bot.digest_now(&platform, "c1").await;
+        assert!(server.failures().is_empty(), "{:?}", server.failures());
+        assert!(text.contains("Ship on friday."), "{}", text);
+        // 人格プロンプトの代わりにダイジェスト用のプロンプトを使う
+        assert!(!server.requests()[0]["messages"].to_string().contains("You are observer."));
+
+        // 前回以降の発言がなければ作らない
+        assert_eq!(bot.channels_conf.get("c1").unwrap().last_digest_message.as_deref(), Some("2"));
+        assert_eq!(bot.digest_now(&platform, "c1").await, "Info: no new messages since the last digest");
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    const REACTION_SCENARIO: &str = r#"
+    {
+      "name": "reactions",
+      "steps": [
+        { "expect": { "user_contains": "what is rust?" }, "respond": { "content": "a language" } },
+        {
+          "expect": { "user_contains": "what is rust?" },
+          "respond": { "content": "a systems programming language" }
+        },
+        {
+          "expect": { "user_contains": "Translate the following message" },
+          "respond": { "content": "Hello" }
+        }
+      ]
+    }
+    "#;
+
+    const MODERATION_SCENARIO: &str = r#"
+    {
+      "name": "moderation",
+      "steps": [
+        {
+          "expect": { "user_contains": "my [redacted] plan" },
+          "respond": { "content": "the secret is out" }
+        }
+      ]
+    }
+    "#;
+
+    /// 応答に画像を添付するだけのツール
+    struct PaintTool;
+
+    impl call_agent::chat::function::Tool for PaintTool {
+        fn def_name(&self) -> &str {
+            "paint"
+        }
+
+        fn def_description(&self) -> &str {
+            "Paints a picture."
+        }
+
+        fn def_parameters(&self) -> serde_json::Value {
+            serde_json::json!({ "type": "object", "properties": {} })
+        }
+
+        fn run(&self, _args: serde_json::Value) -> Result<String, String> {
+            turn::attach_file("cat.png", vec![1, 2, 3], 3);
+            Ok("{\"attached\":\"cat.png\"}".to_string())
+        }
+    }
+
+    const PAINT_SCENARIO: &str = r#"
+    {
+      "name": "paint",
+      "steps": [
+        {
+          "expect": { "user_contains": "draw a cat", "tools_include": ["paint"] },
+          "respond": { "tool_calls": [{ "name": "paint", "arguments": {} }] }
+        },
+        {
+          "expect": { "tool_result_contains": "cat.png" },
+          "respond": { "content": "here is your cat" }
+        }
+      ]
+    }
+    "#;
+
+    #[tokio::test]
+    async fn generated_files_are_attached_and_charged() {
+        let dir = TempDir::new().unwrap();
+        let (server, mut client) = mock_client(PAINT_SCENARIO).await;
+        client.def_tool(Arc::new(PaintTool));
+        let bot = Bot::new(Arc::new(client), None, settings(&dir));
+        let platform = FakePlatform::new();
+        bot.set_enabled("c1", true);
+
+        let before = now();
+        bot.handle_message(&platform, "c1", input("1", "42", "draw a cat"), true).await;
+        assert!(server.failures().is_empty(), "{:?}", server.failures());
+        let replies = platform.replies.lock().unwrap().clone();
+        let (_, reply) = replies.last().unwrap();
+        assert!(reply.content.starts_with("here is your cat"), "{}", reply.content);
+        assert_eq!(reply.files, vec![OutgoingFile { name: "cat.png".into(), data: vec![1, 2, 3] }]);
+        // モデルのコスト (5) に画像のコスト (3) を足して消費する
+        let charged = bot.user_configs.get("42").unwrap().rate_limit - before;
+        assert!((800..=802).contains(&charged), "{}", charged);
+    }
+
+    #[tokio::test]
+    async fn moderation_redacts_blocks_and_notifies() {
+        let dir = TempDir::new().unwrap();
+        let (server, client) = mock_client(MODERATION_SCENARIO).await;
+        let moderator = Moderator::new(ModeratorConfig { words: vec!["secret".into()], ..Default::default() }).unwrap();
+        let settings = BotSettings { moderation_log_channel: Some("mod-log".to_string()), ..settings(&dir) };
+        let bot = Bot::new(Arc::new(client), None, settings).with_moderator(Some(Arc::new(moderator)));
+        let platform = FakePlatform::new();
+        bot.set_enabled("c1", true);
+        assert_eq!(bot.moderation_conf("c1", Some("redact")), "Info: moderation policy in this channel is now redact");
+
+        bot.handle_message(&platform, "c1", input("1", "42", "my secret plan"), true).await;
+        let replies = platform.replies.lock().unwrap().clone();
+        let in_log: Vec<&str> = replies.iter().filter(|(c, _)| c == "mod-log").map(|(_, r)| r.content.as_str()).collect();
+        assert_eq!(in_log.len(), 2);
+        assert!(in_log[0].starts_with("⚠️ moderation: input redacted in c1 by alice (word:secret)"));
+        assert!(in_log[1].starts_with("⚠️ moderation: answer redacted in c1 by observer"));
+        assert_eq!(replies.last().unwrap().1.content, "the [redacted] is out\n-# model: gpt-5-mini");
+
+        // 止めた発言は推論せず履歴にも残さない
+        bot.moderation_conf("c1", Some("block"));
+        bot.handle_message(&platform, "c1", input("2", "42", "another secret"), false).await;
+        bot.handle_message(&platform, "c1", input("3", "42", "secret again"), true).await;
+        assert_eq!(platform.sent().last().unwrap(), "Err: your message was blocked by moderation");
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(bot.channel_state("c1").await.prompt_stream.lock().await.prompt.len(), 2);
+        assert!(server.failures().is_empty(), "{:?}", server.failures());
+
+        // 無効なチャンネルの発言は照合しない (監査ログに増えない)
+        bot.handle_message(&platform, "c2", input("4", "42", "secret in a disabled channel"), false).await;
+
+        let audit = std::fs::read_to_string(&bot.settings.audit_path).unwrap();
+        let actions: Vec<String> = audit.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|record| record["event"] == "moderation")
+            .map(|record| record["action"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(actions, vec!["redacted", "redacted", "blocked", "blocked"]);
+        assert_eq!(bot.moderation_conf("c1", Some("reset")), "Info: moderation policy in this channel is now flag");
+        // 履歴に積むだけの発言で止めたものはログチャンネルには流さない
+        let notices = platform.replies.lock().unwrap().iter().filter(|(c, _)| c == "mod-log").count();
+        assert_eq!(notices, 3);
+    }
+
+    #[tokio::test]
+    async fn history_messages_are_not_sent_to_the_moderation_endpoint() {
+        let dir = TempDir::new().unwrap();
+        // 問い合わせればここに届く
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let moderator = Moderator::new(ModeratorConfig {
+            words: vec!["secret".into()],
+            endpoint: Some(format!("http://{}/v1/", listener.local_addr().unwrap())),
+            ..Default::default()
+        }).unwrap();
+        let bot = offline_bot(&dir).with_moderator(Some(Arc::new(moderator)));
+        let platform = FakePlatform::new();
+        bot.set_enabled("c1", true);
+        bot.moderation_conf("c1", Some("redact"));
+
+        bot.handle_message(&platform, "c1", input("1", "42", "just chatting"), false).await;
+        bot.handle_message(&platform, "c1", input("2", "42", "my secret plan"), false).await;
+        assert!(time::timeout(Duration::from_millis(100), listener.accept()).await.is_err());
+
+        // ルールは履歴にも当てはめる
+        let state = bot.channel_state("c1").await;
+        let prompt = state.prompt_stream.lock().await.prompt.clone();
+        let bodies: Vec<String> = prompt.iter().filter_map(|m| UserMeta::from_message(m).map(|meta| meta.body)).collect();
+        assert_eq!(bodies, vec!["just chatting", "my [redacted] plan"]);
+    }
+
+    fn reaction(emoji: &str, message: InputMessage, from_bot: bool) -> ReactionEvent {
+        ReactionEvent {
+            emoji: emoji.to_string(),
+            user_id: "7".to_string(),
+            user_name: "bob".to_string(),
+            message,
+            from_bot,
+            thread: Vec::new(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reactions_regenerate_and_translate() {
+        let dir = TempDir::new().unwrap();
+        let (server, bot) = mock_bot(REACTION_SCENARIO, &dir).await;
+        let platform = FakePlatform::new();
+        // FakePlatform のメッセージ ID は送信順の番号
+        let answer = input("1", "0", "a language");
+
+        // 無効なチャンネルでは何もしない
+        bot.handle_reaction(&platform, "c1", reaction("🔁", answer.clone(), true)).await;
+        assert!(platform.sent().is_empty());
+
+        bot.set_enabled("c1", true);
+        bot.handle_message(&platform, "c1", input("1", "7", "what is rust?"), true).await;
+        // Bot 以外の発言は作り直さない
+        bot.handle_reaction(&platform, "c1", reaction("🔁", input("1", "7", "what is rust?"), false)).await;
+        assert_eq!(platform.sent().len(), 1);
+
+        bot.handle_reaction(&platform, "c1", reaction("🔁\u{fe0f}", answer, true)).await;
+        assert_eq!(platform.sent()[1], "a systems programming language\n-# model: gpt-5-mini");
+        // 作り直しのリクエストに前の応答は含まれない
+        assert!(!server.requests()[1]["messages"].to_string().contains("a language\""));
+        assert_eq!(bot.channel_state("c1").await.prompt_stream.lock().await.prompt.len(), 2);
+        // 前の応答は消して置き換える
+        assert_eq!(*platform.deleted.lock().unwrap(), vec!["1"]);
+
+        bot.handle_reaction(&platform, "c1", reaction("🌐", input("2", "42", "こんにちは"), false)).await;
+        assert_eq!(platform.sent()[2], "Hello\n-# model: gpt-5-mini");
+        // 翻訳は会話の履歴にも作り直しの対象にも入らない
+        assert_eq!(bot.channel_state("c1").await.prompt_stream.lock().await.prompt.len(), 2);
+        assert_eq!(bot.last_turns.get("c1").unwrap().message_id, "1");
+        assert_eq!(*bot.answer_messages.get("c1").unwrap(), vec!["2".to_string()]);
+        assert!(server.failures().is_empty(), "{:?}", server.failures());
+        // リアクションを付けたユーザーのレートを消費する
+        assert!(bot.user_configs.get("7").unwrap().rate_limit > 0);
+
+        // 対応のない絵文字は無視する
+        bot.handle_reaction(&platform, "c1", reaction("👍", input("2", "42", "x"), false)).await;
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    const REGENERATE_SCENARIO: &str = r#"
+    {
+      "name": "regenerate-last",
+      "steps": [
+        {
+          "expect": { "user_contains": "first question" },
+          "respond": { "content": "first answer" }
+        },
+        {
+          "expect": { "user_contains": "second question" },
+          "respond": { "content": "second answer" }
+        },
+        {
+          "expect": { "user_contains": "second question" },
+          "respond": { "content": "better second answer" }
+        }
+      ]
+    }
+    "#;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn regenerate_reaction_only_applies_to_the_last_answer() {
+        let dir = TempDir::new().unwrap();
+        let (server, bot) = mock_bot(REGENERATE_SCENARIO, &dir).await;
+        let platform = FakePlatform::new();
+        bot.set_enabled("c1", true);
+        bot.handle_message(&platform, "c1", input("10", "7", "first question"), true).await;
+        bot.handle_message(&platform, "c1", input("11", "7", "second question"), true).await;
+        assert_eq!(platform.sent().len(), 2);
+
+        // 前の応答に付けても作り直さない
+        bot.handle_reaction(&platform, "c1", reaction("🔁", input("1", "0", "first answer"), true)).await;
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(platform.sent().len(), 2);
+        assert!(platform.deleted.lock().unwrap().is_empty());
+
+        bot.handle_reaction(&platform, "c1", reaction("🔁", input("2", "0", "second answer"), true)).await;
+        assert!(server.failures().is_empty(), "{:?}", server.failures());
+        assert!(platform.sent()[2].starts_with("better second answer"));
+        assert_eq!(*platform.deleted.lock().unwrap(), vec!["2"]);
+        // 最初のターンはそのまま
+        let history = format!("{:?}", bot.channel_state("c1").await.prompt_stream.lock().await.prompt);
+        assert!(history.contains("first answer") && !history.contains("\"second answer"));
+    }
+
+    #[test]
+    fn reaction_map_is_configurable_per_channel() {
+        let dir = TempDir::new().unwrap();
+        let bot = offline_bot(&dir);
+        assert_eq!(bot.reaction_action("c1", "📝"), Some(ReactionAction::Article));
+
+        assert_eq!(bot.reaction_conf("c1", Some("📖"), Some("explain")), "Info: 📖 now triggers explain");
+        assert_eq!(bot.reaction_conf("c1", Some("❓"), Some("none")), "Info: ❓ no longer triggers anything");
+        assert!(bot.reaction_conf("c1", Some("📖"), Some("delete")).starts_with("Err:"));
+        let listed = bot.reaction_conf("c1", None, None);
+        assert!(listed.contains("📖 → explain") && !listed.contains("❓"), "{}", listed);
+        assert_eq!(bot.reaction_action("c2", "❓"), Some(ReactionAction::Explain));
+
+        let reloaded = offline_bot(&dir);
+        reloaded.load();
+        assert_eq!(reloaded.reaction_action("c1", "📖"), Some(ReactionAction::Explain));
+        assert_eq!(reloaded.reaction_action("c1", "❓"), None);
+
+        assert_eq!(bot.reaction_conf("c1", None, Some("none")), "Info: reactions are disabled in this channel");
+        assert_eq!(bot.reaction_action("c1", "📝"), None);
+        bot.reaction_conf("c1", None, Some("reset"));
+        assert_eq!(bot.reaction_action("c1", "📝"), Some(ReactionAction::Article));
+    }
+
+    const RETRY_SCENARIO: &str = r#"
+    {
+      "name": "retry",
+      "steps": [
+        {
+          "expect": { "user_contains": "tell me a joke", "model": "gpt-5-mini" },
+          "respond": { "content": "a bad joke" }
+        },
+        {
+          "expect": { "user_contains": "tell me a joke" },
+          "respond": { "status": 400, "error": "overloaded" }
+        },
+        {
+          "expect": { "user_contains": "tell me a joke", "model": "gpt-5" },
+          "respond": { "content": "a good joke" }
+        }
+      ]
+    }
+    "#;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn retry_uses_another_model_and_replaces_the_answer() {
+        let dir = TempDir::new().unwrap();
+        let (server, bot) = mock_bot(RETRY_SCENARIO, &dir).await;
+        let mut platform = FakePlatform::new();
+        bot.set_enabled("c1", true);
+
+        bot.retry(&platform, "c1", "42", "alice", None, false).await;
+        assert_eq!(platform.sent(), vec!["Err: no answer to regenerate"]);
+        // 作り直すものがなければレートを消費しない
+        assert!(bot.user_configs.get("42").is_none());
+        bot.retry(&platform, "c1", "42", "alice", Some("gpt-9"), false).await;
+        assert_eq!(platform.sent()[1], "Err: Unknown model name: gpt-9");
+        platform.replies.lock().unwrap().clear();
+        platform.limit = 10;
+
+        bot.handle_message(&platform, "c1", input("1", "42", "tell me a joke"), true).await;
+        let replies = platform.replies.lock().unwrap().clone();
+        // 分割したときは最後のメッセージにだけボタンを付ける
+        assert!(replies.len() > 1);
+        assert!(replies.last().unwrap().1.retry_button);
+        assert!(!replies[0].1.retry_button);
+
+        // 発言したユーザーとチャンネル管理者以外は作り直せない
+        platform.replies.lock().unwrap().clear();
+        bot.retry(&platform, "c1", "7", "bob", None, false).await;
+        assert!(platform.sent().concat().starts_with("Err: only the asker"), "{:?}", platform.sent());
+        assert!(bot.user_configs.get("7").is_none());
+        platform.replies.lock().unwrap().clear();
+
+        // 作り直しに失敗したら前の応答を残す
+        bot.retry(&platform, "c1", "7", "bob", None, true).await;
+        assert!(platform.sent().concat().starts_with("Err: failed reasoning"), "{:?}", platform.sent());
+        assert!(platform.deleted.lock().unwrap().is_empty());
+        let history = format!("{:?}", bot.channel_state("c1").await.prompt_stream.lock().await.prompt);
+        assert!(history.contains("a bad joke"));
+        platform.replies.lock().unwrap().clear();
+
+        bot.retry(&platform, "c1", "42", "alice", Some("gpt-5"), false).await;
+        assert!(server.failures().is_empty(), "{:?}", server.failures());
+        let deleted = platform.deleted.lock().unwrap().clone();
+        assert_eq!(deleted, (1..=replies.len()).map(|i| i.to_string()).collect::<Vec<_>>());
+        let new_answer = platform.sent().concat();
+        assert!(new_answer.contains("good") && new_answer.contains("gpt-5"), "{}", new_answer);
+        // 指定したモデルは今回だけ
+        assert_eq!(bot.user_configs.get("42").unwrap().model.to_model_name(), "gpt-5-mini");
+    }
+
+    #[tokio::test]
+    async fn edits_and_deletions_update_the_history() {
+        use call_agent::chat::{function::{FunctionCall, FunctionCallInner}, prompt::Message};
+
+        let dir = TempDir::new().unwrap();
+        let bot = offline_bot(&dir);
+        let platform = FakePlatform::new();
+        bot.handle_message(&platform, "c1", input("1", "42", "teh typo ||secret||"), false).await;
+        bot.handle_message(&platform, "c1", input("2", "42", "remember my birthday"), false).await;
+        bot.handle_message(&platform, "c1", input("3", "42", "oops"), false).await;
+        let state = bot.channel_state("c1").await;
+        state.prompt_stream.lock().await.prompt.insert(2, Message::Assistant {
+            name: None,
+            content: vec![],
+            tool_calls: Some(vec![FunctionCall {
+                id: "c".into(),
+                tool_type: "function".into(),
+                function: FunctionCallInner { name: "memory_tool".into(), arguments: serde_json::json!({ "action": "add", "key": "birthday", "value": "..." }) },
+            }]),
+        });
+        state.prompt_stream.lock().await.prompt.insert(3, Message::Assistant {
+            name: None,
+            content: vec![call_agent::chat::prompt::MessageContext::Text("I'll remember your birthday".into())],
+            tool_calls: None,
+        });
+
+        bot.message_edited("c1", "1", "the fix ||still secret||").await;
+        bot.message_edited("c2", "1", "unknown channel").await;
+        let first = UserMeta::from_message(&state.prompt_stream.lock().await.prompt[0]).unwrap();
+        assert_eq!(first.body, "the fix ||<spoiler_msg>||");
+
+        bot.messages_deleted("c1", &["2".to_string(), "3".to_string(), "9".to_string()]).await;
+        let ids: Vec<String> = state.prompt_stream.lock().await.prompt.iter()
+            .filter_map(|m| UserMeta::from_message(m).map(|meta| meta.message_id))
+            .collect();
+        assert_eq!(ids, vec!["1"]);
+        // 応答とツールのやり取りも一緒に消える
+        assert_eq!(state.prompt_stream.lock().await.prompt.len(), 1);
+
+        // メモリを作ったメッセージだけ記録される
+        let audit = std::fs::read_to_string(dir.path().join("audit.jsonl")).unwrap();
+        let records: Vec<serde_json::Value> = audit.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["event"], "source_deleted");
+        assert_eq!(records[0]["message_id"], "2");
+        assert_eq!(records[0]["derived"], serde_json::json!([{ "kind": "memory", "key": "birthday" }]));
+    }
+
+    #[tokio::test]
+    async fn forget_me_needs_confirmation_and_is_audited() {
+        use call_agent::chat::{function::{FunctionCall, FunctionCallInner}, prompt::Message};
+
+        let dir = TempDir::new().unwrap();
+        let memory = Arc::new(MemoryTool::open(dir.path().join("memory")));
+        memory.add_memory("address", "alice lives at 1-2-3").unwrap();
+        memory.add_memory("friends", "alice and bob like rust").unwrap();
+        memory.add_memory("weather", "sunny").unwrap();
+        memory.add_memory("style", "bob writes malice-free rust").unwrap();
+        let bot = offline_bot(&dir).with_memory(Some(memory.clone()));
+        let platform = FakePlatform::new();
+        bot.handle_message(&platform, "c1", input("1", "42", "my address is 1-2-3"), false).await;
+        bot.handle_message(&platform, "c2", input("2", "7", "hello"), false).await;
+        bot.handle_message(&platform, "c2", input("3", "42", "bye"), false).await;
+        bot.channel_state("c1").await.prompt_stream.lock().await.prompt.push_back(Message::Assistant {
+            name: None,
+            content: vec![],
+            tool_calls: Some(vec![FunctionCall {
+                id: "c".into(),
+                tool_type: "function".into(),
+                function: FunctionCallInner { name: "memory_tool".into(), arguments: serde_json::json!({ "action": "add", "key": "address", "value": "..." }) },
+            }]),
+        });
+
+        // 確認なしでは実行しない
+        assert_eq!(bot.forget_me("42", "alice", true).await, "Err: run forget_me without confirm first");
+        let warning = bot.forget_me("42", "alice", false).await;
+        assert!(warning.contains("your 2 messages"), "{}", warning);
+        assert_eq!(bot.channel_state("c2").await.prompt_stream.lock().await.prompt.len(), 2);
+
+        let summary = bot.forget_me("42", "alice", true).await;
+        assert!(summary.starts_with("Info: removed 2 of your messages"), "{}", summary);
+        for channel in ["c1", "c2"] {
+            let state = bot.channel_state(channel).await;
+            assert_eq!(privacy::count_user_messages(&state.prompt_stream.lock().await.prompt, "42"), 0);
+        }
+        assert_eq!(privacy::count_user_messages(&bot.channel_state("c2").await.prompt_stream.lock().await.prompt, "7"), 1);
+        // 発言から作られたメモリは消し、名前を含むメモリは匿名化する
+        assert!(memory.get_memory(Some("address")).is_empty());
+        assert_eq!(memory.get_memory(Some("friends"))["friends"], "[forgotten user] and bob like rust");
+        assert_eq!(memory.get_memory(Some("weather"))["weather"], "sunny");
+        // 名前が別の単語の一部になっているだけなら匿名化しない
+        assert_eq!(memory.get_memory(Some("style"))["style"], "bob writes malice-free rust");
+
+        let audit = std::fs::read_to_string(dir.path().join("audit.jsonl")).unwrap();
+        let record: serde_json::Value = serde_json::from_str(audit.lines().last().unwrap()).unwrap();
+        assert_eq!(record["event"], "forget_me");
+        assert_eq!(record["deleted_memories"], serde_json::json!(["address"]));
+        assert_eq!(record["anonymized_memories"], serde_json::json!(["friends"]));
+        // 確認は 1 回きり
+        assert!(bot.forget_me("42", "alice", true).await.starts_with("Err:"));
+    }
+}
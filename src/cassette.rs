@@ -0,0 +1,381 @@
+//! OpenAI API の記録・再生 (カセット)
+//!
+//! `OpenAIClient` のエンドポイントをローカルに立てたこのサーバーに向けることで、
+//! `/chat/completions` へのリクエストとレスポンスの組をファイルに記録したり、
+//! 記録済みのレスポンスをネットワークなしで決定的に返したりできる。
+//!
+//! 再生時のマッチングは正規化したリクエストボディで行う。
+//! サンプリング用のパラメータやツールのスキーマ、画像のデータなどは比較に使わない。
+//!
+//! - `CASSETTE_MODE=record` で記録 (`CASSETTE_UPSTREAM` に転送、既定は OpenAI)
+//! - それ以外は再生
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::local_http::{self, LocalServer, Request, Response};
+
+const DEFAULT_UPSTREAM: &str = "https://api.openai.com/v1";
+
+/// カセットの動作モード
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// `upstream` に転送し、やり取りを記録する
+    Record { upstream: String },
+    /// 記録済みのレスポンスを返す
+    Replay,
+}
+
+impl CassetteMode {
+    /// 環境変数 `CASSETTE_MODE` / `CASSETTE_UPSTREAM` からモードを決める
+    pub fn from_env() -> Self {
+        match std::env::var("CASSETTE_MODE").as_deref() {
+            Ok("record") => Self::Record {
+                upstream: std::env::var("CASSETTE_UPSTREAM").unwrap_or_else(|_| DEFAULT_UPSTREAM.to_string()),
+            },
+            _ => Self::Replay,
+        }
+    }
+}
+
+/// 記録されたレスポンス
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+/// リクエストとレスポンスの組
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    /// 正規化済みのリクエストボディ
+    pub request: Value,
+    pub response: RecordedResponse,
+}
+
+/// カセットファイルの中身
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = std::fs::read_to_string(path)?;
+        serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        std::fs::write(path, data + "\n")
+    }
+}
+
+struct ServerState {
+    mode: CassetteMode,
+    path: PathBuf,
+    cassette: Cassette,
+    /// 再生済みの interaction (同じリクエストが複数回来たときは記録順に返す)
+    used: Vec<bool>,
+    /// 受け取ったリクエスト (正規化済み)
+    requests: Vec<Value>,
+    /// 再生時に見つからなかったリクエスト
+    misses: Vec<Value>,
+}
+
+/// カセットを提供するローカル HTTP サーバー
+pub struct CassetteServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<ServerState>>,
+    /// drop されたときにサーバーを止めるために持っておく
+    _server: LocalServer,
+}
+
+impl CassetteServer {
+    /// `127.0.0.1` の空いているポートでサーバーを起動する
+    ///
+    /// 再生モードでカセットファイルが存在しない場合はエラーになる。
+    pub async fn start(path: impl AsRef<Path>, mode: CassetteMode) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let cassette = match &mode {
+            CassetteMode::Replay => Cassette::load(&path)?,
+            CassetteMode::Record { .. } => Cassette::default(),
+        };
+        let state = Arc::new(Mutex::new(ServerState {
+            mode,
+            path,
+            used: vec![false; cassette.interactions.len()],
+            cassette,
+            requests: Vec::new(),
+            misses: Vec::new(),
+        }));
+
+        let handler_state = state.clone();
+        let http = reqwest::Client::new();
+        let (addr, _server) = local_http::serve("127.0.0.1:0", move |request| {
+            let state = handler_state.clone();
+            let http = http.clone();
+            async move { handle_request(request, &state, &http).await }
+        })
+        .await?;
+
+        Ok(Self { addr, state, _server })
+    }
+
+    /// `OpenAIClient::new` に渡すエンドポイント
+    pub fn endpoint(&self) -> String {
+        format!("http://{}/v1", self.addr)
+    }
+
+    /// これまでに受け取ったリクエスト (正規化済み)
+    pub fn requests(&self) -> Vec<Value> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// 再生時にカセットに見つからなかったリクエスト
+    pub fn misses(&self) -> Vec<Value> {
+        self.state.lock().unwrap().misses.clone()
+    }
+}
+
+async fn handle_request(request: Request, state: &Arc<Mutex<ServerState>>, http: &reqwest::Client) -> Response {
+    let authorization = request.header("authorization").map(|v| v.to_string());
+    let (status, body) = match serde_json::from_slice::<Value>(&request.body) {
+        Ok(body) => respond(state, http, &request.path, authorization, body).await,
+        Err(e) => (400, error_body(&format!("invalid request body: {}", e), "invalid_request_error")),
+    };
+    Response::json(status, &body)
+}
+
+async fn respond(
+    state: &Arc<Mutex<ServerState>>,
+    http: &reqwest::Client,
+    path: &str,
+    authorization: Option<String>,
+    request: Value,
+) -> (u16, Value) {
+    let normalized = normalize_request(&request);
+    let mode = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(normalized.clone());
+        state.mode.clone()
+    };
+
+    match mode {
+        CassetteMode::Replay => {
+            let mut state = state.lock().unwrap();
+            let found = state
+                .cassette
+                .interactions
+                .iter()
+                .enumerate()
+                .position(|(i, it)| !state.used[i] && it.request == normalized);
+            match found {
+                Some(i) => {
+                    state.used[i] = true;
+                    let response = &state.cassette.interactions[i].response;
+                    (response.status, response.body.clone())
+                }
+                None => {
+                    warn!("cassette miss - {}", normalized);
+                    state.misses.push(normalized);
+                    (500, error_body("no matching interaction in cassette", "cassette_miss"))
+                }
+            }
+        }
+        CassetteMode::Record { upstream } => {
+            let url = format!("{}{}", upstream.trim_end_matches('/'), path.strip_prefix("/v1").unwrap_or(path));
+            let mut builder = http.post(url).json(&request);
+            if let Some(authorization) = authorization {
+                builder = builder.header("authorization", authorization);
+            }
+            let (status, body) = match builder.send().await {
+                Ok(res) => {
+                    let status = res.status().as_u16();
+                    let body = res.json::<Value>().await.unwrap_or(Value::Null);
+                    (status, body)
+                }
+                Err(e) => return (502, error_body(&format!("upstream error: {}", e), "cassette_upstream")),
+            };
+            let mut state = state.lock().unwrap();
+            state.cassette.interactions.push(Interaction {
+                request: normalized,
+                response: RecordedResponse { status, body: body.clone() },
+            });
+            if let Err(e) = state.cassette.save(&state.path) {
+                warn!("failed to save cassette - {:?}", e);
+            }
+            (status, body)
+        }
+    }
+}
+
+fn error_body(message: &str, err_type: &str) -> Value {
+    json!({ "error": { "message": message, "type": err_type, "code": 500 } })
+}
+
+/// マッチング用にリクエストボディを正規化する
+///
+/// - `model` / `tool_choice` / `messages` だけを残す
+/// - `tools` は名前の一覧 (ソート済み) にする
+/// - メッセージは role・テキスト・ツールコール (名前と引数)・tool_call_id だけにする
+/// - 画像は `[image]` に置き換える
+pub fn normalize_request(request: &Value) -> Value {
+    let mut out = Map::new();
+    for key in ["model", "tool_choice"] {
+        if let Some(value) = request.get(key) {
+            out.insert(key.to_string(), value.clone());
+        }
+    }
+    if let Some(Value::Array(tools)) = request.get("tools") {
+        let mut names: Vec<&str> = tools
+            .iter()
+            .filter_map(|t| t.get("function")?.get("name")?.as_str())
+            .collect();
+        names.sort_unstable();
+        out.insert("tools".to_string(), json!(names));
+    }
+    let messages: Vec<Value> = request
+        .get("messages")
+        .and_then(|m| m.as_array())
+        .map(|messages| messages.iter().map(normalize_message).collect())
+        .unwrap_or_default();
+    out.insert("messages".to_string(), Value::Array(messages));
+    Value::Object(out)
+}
+
+fn normalize_message(message: &Value) -> Value {
+    let mut out = Map::new();
+    if let Some(role) = message.get("role") {
+        out.insert("role".to_string(), role.clone());
+    }
+    let content = match message.get("content") {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Array(parts)) => parts
+            .iter()
+            .map(|part| match part.get("type").and_then(|t| t.as_str()) {
+                Some("image_url") => "[image]".to_string(),
+                _ => part.get("text").and_then(|t| t.as_str()).unwrap_or_default().to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    };
+    out.insert("content".to_string(), Value::String(content));
+    if let Some(Value::Array(calls)) = message.get("tool_calls") {
+        let calls: Vec<Value> = calls
+            .iter()
+            .map(|call| {
+                let function = call.get("function").cloned().unwrap_or(Value::Null);
+                // 引数は JSON 文字列で送られることもオブジェクトで送られることもある
+                let arguments = match function.get("arguments") {
+                    Some(Value::String(s)) => serde_json::from_str(s).unwrap_or_else(|_| Value::String(s.clone())),
+                    Some(v) => v.clone(),
+                    None => Value::Null,
+                };
+                json!({
+                    "id": call.get("id"),
+                    "name": function.get("name"),
+                    "arguments": arguments,
+                })
+            })
+            .collect();
+        out.insert("tool_calls".to_string(), Value::Array(calls));
+    }
+    if let Some(id) = message.get("tool_call_id") {
+        out.insert("tool_call_id".to_string(), id.clone());
+    }
+    Value::Object(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalization_ignores_sampling_params_and_images() {
+        let a = json!({
+            "model": "gpt-5-mini",
+            "messages": [
+                { "role": "user", "name": "1", "content": [
+                    { "type": "text", "text": "hi" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA", "detail": "low" } }
+                ] },
+                { "role": "assistant", "content": null, "tool_calls": [
+                    { "id": "c1", "type": "function", "function": { "name": "browser", "arguments": "{\"url\":\"https://a\"}" } }
+                ] }
+            ],
+            "tools": [
+                { "type": "function", "function": { "name": "memory", "parameters": {} } },
+                { "type": "function", "function": { "name": "browser", "parameters": {} } }
+            ],
+            "tool_choice": "auto",
+            "temperature": 0.3
+        });
+        let b = json!({
+            "tool_choice": "auto",
+            "model": "gpt-5-mini",
+            "top_p": 1.0,
+            "tools": [
+                { "type": "function", "function": { "name": "browser", "parameters": { "changed": true } } },
+                { "type": "function", "function": { "name": "memory" } }
+            ],
+            "messages": [
+                { "role": "user", "content": [
+                    { "type": "text", "text": "hi" },
+                    { "type": "image_url", "image_url": { "url": "https://example.com/x.png" } }
+                ] },
+                { "role": "assistant", "content": "", "tool_calls": [
+                    { "id": "c1", "type": "function", "function": { "name": "browser", "arguments": { "url": "https://a" } } }
+                ] }
+            ]
+        });
+        assert_eq!(normalize_request(&a), normalize_request(&b));
+        assert_eq!(normalize_request(&a)["tools"], json!(["browser", "memory"]));
+        assert_eq!(normalize_request(&a)["messages"][0]["content"], "hi\n[image]");
+    }
+
+    #[tokio::test]
+    async fn replays_in_order_and_reports_misses() {
+        let request = json!({ "model": "m", "messages": [{ "role": "user", "content": "ping" }] });
+        let cassette = Cassette {
+            interactions: vec![
+                Interaction {
+                    request: normalize_request(&request),
+                    response: RecordedResponse { status: 200, body: json!({ "n": 1 }) },
+                },
+                Interaction {
+                    request: normalize_request(&request),
+                    response: RecordedResponse { status: 429, body: json!({ "n": 2 }) },
+                },
+            ],
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ping.json");
+        cassette.save(&path).unwrap();
+
+        let server = CassetteServer::start(&path, CassetteMode::Replay).await.unwrap();
+        let url = format!("{}/chat/completions", server.endpoint());
+        let http = reqwest::Client::new();
+        let first = http.post(&url).json(&request).send().await.unwrap();
+        assert_eq!(first.status().as_u16(), 200);
+        assert_eq!(first.json::<Value>().await.unwrap()["n"], 1);
+        let second = http.post(&url).json(&request).send().await.unwrap();
+        assert_eq!(second.status().as_u16(), 429);
+        let third = http.post(&url).json(&request).send().await.unwrap();
+        assert_eq!(third.status().as_u16(), 500);
+        assert_eq!(server.misses().len(), 1);
+        assert_eq!(server.requests().len(), 3);
+    }
+}
@@ -0,0 +1,490 @@
+//! 動く画像のコンタクトシート
+//!
+//! アニメーション GIF・アニメーション WebP・短い MP4 は最初のフレームだけでは内容がわからないので、
+//! 全体から等間隔にフレームを抜き出し、番号と時刻のラベルを付けて 1 枚の格子状の画像にまとめる。
+//! モデルにはこの 1 枚を画像として渡し、フレーム数と長さは `ClipInfo` としてメッセージに書き添える。
+//! MP4 のデコードは `ffmpeg` に任せる (なければその動画は読めない)。
+//! ffmpeg は止まることがあるので、呼び出しごとに時間を区切り、過ぎたらプロセスを止める。
+
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{atomic::{AtomicUsize, Ordering}, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use image::{
+    codecs::{gif::GifDecoder, webp::WebPDecoder},
+    imageops::FilterType,
+    AnimationDecoder, DynamicImage, Frames, Rgb, RgbImage,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::images::{self, SourceFormat};
+
+/// 抜き出すフレームの数
+pub const FRAMES: usize = 9;
+/// これより長い動画は読まない (秒)
+pub const MAX_VIDEO_SECS: f64 = 60.0;
+/// 数えるフレームの上限 (これより先は見ない)
+const MAX_FRAMES: usize = 1000;
+/// 1 コマの長い辺 (大きければ縮小、小さければこの下限まで拡大する)
+const CELL_LONG_SIDE: u32 = 600;
+const MIN_CELL_LONG_SIDE: u32 = 256;
+/// コマの間の余白
+const GAP: u32 = 8;
+/// ラベルの文字の拡大率と帯の高さ
+const LABEL_SCALE: u32 = 3;
+const LABEL_HEIGHT: u32 = 5 * LABEL_SCALE + 8;
+/// ブラウザと同じく、これより短い GIF の遅延は 100ms とみなす
+const MIN_DELAY_MS: u64 = 20;
+const DEFAULT_DELAY_MS: u64 = 100;
+const FFMPEG: &str = "ffmpeg";
+/// ffmpeg の 1 回の呼び出しにかける時間の上限
+const FFMPEG_TIMEOUT: Duration = Duration::from_secs(10);
+/// ffmpeg が終わったかを確かめる間隔
+const FFMPEG_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// コンタクトシートにした元の動く画像の情報
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClipInfo {
+    /// シートに並べたフレームの数
+    pub sampled: usize,
+    /// 元のフレーム数 (動画ではわからないので None)
+    pub total_frames: Option<usize>,
+    pub duration_ms: u64,
+}
+
+impl ClipInfo {
+    /// メッセージに書き添える説明
+    pub fn describe(&self) -> String {
+        let secs = self.duration_ms as f64 / 1000.0;
+        let source = match self.total_frames {
+            Some(total) => format!("animation, {} frames over {:.1}s", total, secs),
+            None => format!("video, {:.1}s", secs),
+        };
+        format!("{}; shown as a contact sheet of {} evenly sampled frames, left to right, top to bottom", source, self.sampled)
+    }
+}
+
+/// 抜き出した 1 コマ
+struct Sample {
+    image: RgbImage,
+    /// 先頭からの時刻 (秒)
+    at: f64,
+}
+
+/// 動画のデコードに使う ffmpeg
+pub struct Ffmpeg {
+    program: PathBuf,
+    timeout: Duration,
+}
+
+impl Ffmpeg {
+    pub fn new(program: impl Into<PathBuf>, timeout: Duration) -> Self {
+        Self { program: program.into(), timeout }
+    }
+
+    /// PATH にある ffmpeg (最初に一度だけ動くか確かめ、なければ None)
+    pub fn system() -> Option<&'static Ffmpeg> {
+        static SYSTEM: OnceLock<Option<Ffmpeg>> = OnceLock::new();
+        SYSTEM.get_or_init(|| {
+            let ffmpeg = Ffmpeg::new(FFMPEG, FFMPEG_TIMEOUT);
+            ffmpeg.run(["-version"]).is_ok().then_some(ffmpeg)
+        }).as_ref()
+    }
+
+    /// 時間を区切って実行し、標準出力を返す (時間を過ぎたらプロセスを止める)
+    fn run<I, S>(&self, args: I) -> Result<Vec<u8>, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let name = self.program.display();
+        let mut child = Command::new(&self.program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("could not run {}: {}", name, e))?;
+        // パイプが詰まらないように別スレッドで読み切る
+        let read_all = |mut pipe: Box<dyn Read + Send>| thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = pipe.read_to_end(&mut buf);
+            buf
+        });
+        let stdout = read_all(Box::new(child.stdout.take().expect("stdout is piped")));
+        let stderr = read_all(Box::new(child.stderr.take().expect("stderr is piped")));
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(|e| format!("{} failed: {}", name, e))? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("{} timed out after {:?}", name, self.timeout));
+            }
+            thread::sleep(FFMPEG_POLL_INTERVAL);
+        };
+        let stdout = stdout.join().unwrap_or_default();
+        if !status.success() || stdout.is_empty() {
+            let stderr = stderr.join().unwrap_or_default();
+            return Err(format!("{} could not read the video: {}", name, String::from_utf8_lossy(&stderr).trim()));
+        }
+        Ok(stdout)
+    }
+}
+
+/// 動く画像ならコンタクトシートを作る (静止画なら None)
+pub fn build(bytes: &[u8]) -> Result<Option<(RgbImage, ClipInfo)>, String> {
+    build_with(bytes, Ffmpeg::system())
+}
+
+/// `ffmpeg` で動画を読んでコンタクトシートを作る (None なら動画は読めない)
+pub fn build_with(bytes: &[u8], ffmpeg: Option<&Ffmpeg>) -> Result<Option<(RgbImage, ClipInfo)>, String> {
+    let (samples, info) = if is_mp4(bytes) {
+        video(bytes, ffmpeg)?
+    } else {
+        match SourceFormat::sniff(bytes) {
+            Some(format @ (SourceFormat::Gif | SourceFormat::WebP)) => match animation(bytes, format)? {
+                Some(sampled) => sampled,
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        }
+    };
+    Ok(Some((compose(&samples), info)))
+}
+
+/// MP4 (や MOV) のコンテナか
+pub fn is_mp4(bytes: &[u8]) -> bool {
+    bytes.get(4..8) == Some(b"ftyp")
+}
+
+/// 総数 `total` から `n` 個を等間隔に選ぶ (各区間の中央)
+fn sample_indices(total: usize, n: usize) -> Vec<usize> {
+    let n = n.min(total);
+    (0..n).map(|i| (2 * i + 1) * total / (2 * n)).collect()
+}
+
+fn open_frames(bytes: &[u8], format: SourceFormat) -> Result<Frames<'_>, String> {
+    let broken = |e: image::ImageError| format!("broken animation: {}", e);
+    match format {
+        SourceFormat::Gif => Ok(GifDecoder::new(Cursor::new(bytes)).map_err(broken)?.into_frames()),
+        _ => Ok(WebPDecoder::new(Cursor::new(bytes)).map_err(broken)?.into_frames()),
+    }
+}
+
+/// アニメーションのフレームを抜き出す (1 フレームしかなければ None)
+/// 全フレームを持つとメモリを食うので、1 回目で数と遅延を数え、2 回目で選んだものだけ残す
+fn animation(bytes: &[u8], format: SourceFormat) -> Result<Option<(Vec<Sample>, ClipInfo)>, String> {
+    if format == SourceFormat::WebP
+        && !WebPDecoder::new(Cursor::new(bytes)).is_ok_and(|decoder| decoder.has_animation())
+    {
+        return Ok(None);
+    }
+
+    // 開けないものは静止画としてのデコードでエラーにする
+    let Ok(frames) = open_frames(bytes, format) else {
+        return Ok(None);
+    };
+    // 途中で壊れていても読めたところまでは使う
+    let delays: Vec<u64> = frames
+        .take(MAX_FRAMES)
+        .map_while(Result::ok)
+        .map(|frame| {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let ms = numer as u64 / (denom as u64).max(1);
+            if ms < MIN_DELAY_MS { DEFAULT_DELAY_MS } else { ms }
+        })
+        .collect();
+    if delays.len() < 2 {
+        return Ok(None);
+    }
+
+    let indices = sample_indices(delays.len(), FRAMES);
+    let mut samples = Vec::with_capacity(indices.len());
+    for (index, frame) in open_frames(bytes, format)?.enumerate().take(delays.len()) {
+        if !indices.contains(&index) {
+            continue;
+        }
+        let frame = frame.map_err(|e| format!("broken animation: {}", e))?;
+        let at = delays[..index].iter().sum::<u64>() as f64 / 1000.0;
+        samples.push(Sample { image: images::flatten(&DynamicImage::ImageRgba8(frame.into_buffer())), at });
+    }
+    let info = ClipInfo { sampled: samples.len(), total_frames: Some(delays.len()), duration_ms: delays.iter().sum() };
+    Ok(Some((samples, info)))
+}
+
+/// `moov/mvhd` から長さ (秒) を読む
+pub fn mp4_duration(bytes: &[u8]) -> Option<f64> {
+    let mvhd = find_box(find_box(bytes, b"moov")?, b"mvhd")?;
+    let be32 = |range: std::ops::Range<usize>| Some(u32::from_be_bytes(mvhd.get(range)?.try_into().ok()?));
+    let (timescale, duration) = match *mvhd.first()? {
+        1 => (be32(20..24)?, u64::from_be_bytes(mvhd.get(24..32)?.try_into().ok()?)),
+        _ => (be32(12..16)?, be32(16..20)? as u64),
+    };
+    (timescale > 0).then(|| duration as f64 / timescale as f64)
+}
+
+/// 同じ階層から `kind` のボックスの中身を探す
+fn find_box<'a>(bytes: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut pos = 0;
+    while let Some(header) = bytes.get(pos..pos + 8) {
+        let size = u32::from_be_bytes(header[0..4].try_into().ok()?) as usize;
+        let (start, end) = match size {
+            // 64 ビットの大きさ
+            1 => (pos + 16, pos.checked_add(u64::from_be_bytes(bytes.get(pos + 8..pos + 16)?.try_into().ok()?) as usize)?),
+            // ファイルの終わりまで
+            0 => (pos + 8, bytes.len()),
+            _ => (pos + 8, pos + size),
+        };
+        if end < start || end > bytes.len() {
+            return None;
+        }
+        if &header[4..8] == kind {
+            return Some(&bytes[start..end]);
+        }
+        pos = end;
+    }
+    None
+}
+
+/// 動画のフレームを `ffmpeg` で抜き出す
+fn video(bytes: &[u8], ffmpeg: Option<&Ffmpeg>) -> Result<(Vec<Sample>, ClipInfo), String> {
+    let duration = mp4_duration(bytes).ok_or("could not read the length of the video")?;
+    if duration > MAX_VIDEO_SECS {
+        return Err(format!("the video is longer than {}s", MAX_VIDEO_SECS));
+    }
+    let ffmpeg = ffmpeg.ok_or_else(|| format!("{} is not installed", FFMPEG))?;
+    // ffmpeg はシークするのでファイルに書き出して渡す
+    let path = std::env::temp_dir().join(format!(
+        "observer-clip-{}-{}.mp4",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&path, bytes).map_err(|e| e.to_string())?;
+    let samples: Result<Vec<Sample>, String> = (0..FRAMES)
+        .map(|i| {
+            let at = duration * (2 * i + 1) as f64 / (2 * FRAMES) as f64;
+            video_frame(ffmpeg, &path, at).map(|image| Sample { image, at })
+        })
+        .collect();
+    let _ = fs::remove_file(&path);
+    let samples = samples?;
+    let info = ClipInfo { sampled: samples.len(), total_frames: None, duration_ms: (duration * 1000.0).round() as u64 };
+    Ok((samples, info))
+}
+
+fn video_frame(ffmpeg: &Ffmpeg, path: &Path, at: f64) -> Result<RgbImage, String> {
+    let at = format!("{:.3}", at);
+    let png = ffmpeg.run([
+        OsStr::new("-v"), OsStr::new("error"), OsStr::new("-ss"), OsStr::new(&at), OsStr::new("-i"), path.as_os_str(),
+        OsStr::new("-frames:v"), OsStr::new("1"), OsStr::new("-f"), OsStr::new("image2pipe"), OsStr::new("-c:v"), OsStr::new("png"), OsStr::new("-"),
+    ])?;
+    let frame = image::load_from_memory(&png).map_err(|e| format!("broken video frame: {}", e))?;
+    Ok(frame.to_rgb8())
+}
+
+/// コマを格子状に並べ、それぞれの下に「番号/総数 時刻」のラベルを付ける
+fn compose(samples: &[Sample]) -> RgbImage {
+    let cells: Vec<RgbImage> = samples.iter().map(|sample| cell(&sample.image)).collect();
+    let cell_width = cells.iter().map(RgbImage::width).max().unwrap_or(1);
+    let cell_height = cells.iter().map(RgbImage::height).max().unwrap_or(1) + LABEL_HEIGHT;
+    let columns = if cells.len() <= 4 { 2 } else { 3 }.min(cells.len().max(1)) as u32;
+    let rows = cells.len().div_ceil(columns as usize).max(1) as u32;
+
+    let mut sheet = RgbImage::from_pixel(
+        columns * cell_width + (columns + 1) * GAP,
+        rows * cell_height + (rows + 1) * GAP,
+        Rgb([32, 32, 32]),
+    );
+    for (i, (cell, sample)) in cells.iter().zip(samples).enumerate() {
+        let x = GAP + (i as u32 % columns) * (cell_width + GAP);
+        let y = GAP + (i as u32 / columns) * (cell_height + GAP);
+        // コマは中央に寄せる
+        let offset_x = x + (cell_width - cell.width()) / 2;
+        image::imageops::replace(&mut sheet, cell, offset_x as i64, y as i64);
+        let label = format!("{}/{} {:.1}s", i + 1, samples.len(), sample.at);
+        draw_text(&mut sheet, &label, x + 4, y + cell_height - LABEL_HEIGHT + 4, x + cell_width);
+    }
+    sheet
+}
+
+/// 1 コマの大きさにそろえる
+fn cell(image: &RgbImage) -> RgbImage {
+    let (w, h) = image.dimensions();
+    let long = w.max(h).max(1);
+    let target = long.clamp(MIN_CELL_LONG_SIDE, CELL_LONG_SIDE);
+    if target == long {
+        return image.clone();
+    }
+    let scale = target as f32 / long as f32;
+    let (nw, nh) = (((w as f32 * scale).round() as u32).max(1), ((h as f32 * scale).round() as u32).max(1));
+    // 小さいドット絵はぼかさずに拡大する
+    let filter = if target > long { FilterType::Nearest } else { FilterType::Triangle };
+    image::imageops::resize(image, nw, nh, filter)
+}
+
+/// 3x5 のビットマップ字形 (ラベルに使う文字だけ)
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        's' => [0b000, 0b011, 0b110, 0b011, 0b110],
+        _ => [0; 5],
+    }
+}
+
+/// 白い文字を書く (`max_x` を超える分は書かない)
+fn draw_text(image: &mut RgbImage, text: &str, x: u32, y: u32, max_x: u32) {
+    for (i, c) in text.chars().enumerate() {
+        let left = x + i as u32 * 4 * LABEL_SCALE;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..LABEL_SCALE {
+                    for dx in 0..LABEL_SCALE {
+                        let (px, py) = (left + col * LABEL_SCALE + dx, y + row as u32 * LABEL_SCALE + dy);
+                        if px < max_x.min(image.width()) && py < image.height() {
+                            image.put_pixel(px, py, Rgb([255, 255, 255]));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{gif, png};
+
+    /// 色の違うフレームが `n` 枚、1 枚 100ms の GIF
+    fn clip(n: usize) -> Vec<u8> {
+        let frames: Vec<[u8; 4]> = (0..n).map(|i| [(i * 10) as u8, 0, 255 - (i * 10) as u8, 255]).collect();
+        gif(40, 30, 100, &frames)
+    }
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(body);
+        out
+    }
+
+    #[test]
+    fn samples_frames_evenly() {
+        assert_eq!(sample_indices(90, 9), vec![5, 15, 25, 35, 45, 55, 65, 75, 85]);
+        assert_eq!(sample_indices(3, 9), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn animated_gifs_become_a_labeled_grid() {
+        let (sheet, info) = build(&clip(20)).unwrap().unwrap();
+        assert_eq!(info, ClipInfo { sampled: 9, total_frames: Some(20), duration_ms: 2000 });
+        assert!(info.describe().starts_with("animation, 20 frames over 2.0s"));
+        // 40x30 のコマは長辺 256 まで拡大して 3 列 3 行に並ぶ
+        assert_eq!(sheet.width(), 3 * 256 + 4 * GAP);
+        assert_eq!(sheet.height(), 3 * (192 + LABEL_HEIGHT) + 4 * GAP);
+        // 1 コマ目はフレーム 1、最後のコマはフレーム 18 (GIF の減色で少しずれる)
+        let red = |x: u32, y: u32| sheet.get_pixel(x, y)[0] as i32;
+        assert!((red(GAP + 10, GAP + 10) - 10).abs() <= 4);
+        assert!((red(GAP + 2 * (256 + GAP) + 10, GAP + 2 * (192 + LABEL_HEIGHT + GAP) + 10) - 180).abs() <= 4);
+        // ラベルの帯に白い文字がある
+        let label_y = GAP + 192 + 4..GAP + 192 + LABEL_HEIGHT;
+        assert!(label_y.clone().any(|y| (GAP..GAP + 100).any(|x| sheet.get_pixel(x, y) == &Rgb([255, 255, 255]))));
+
+        // 1 フレームの GIF は普通の画像として扱う
+        assert!(build(&clip(1)).unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_the_length_of_mp4() {
+        let mut mvhd = vec![0; 4 + 8];
+        mvhd.extend_from_slice(&1000u32.to_be_bytes());
+        mvhd.extend_from_slice(&4200u32.to_be_bytes());
+        let mut mp4 = mp4_box(b"ftyp", b"isom\0\0\x02\0");
+        mp4.extend(mp4_box(b"moov", &mp4_box(b"mvhd", &mvhd)));
+        assert!(is_mp4(&mp4));
+        assert_eq!(mp4_duration(&mp4), Some(4.2));
+        assert_eq!(mp4_duration(&mp4[..mp4.len() - 4]), None);
+
+        let mut long = mvhd.clone();
+        long[16..20].copy_from_slice(&600_000u32.to_be_bytes());
+        let mut long_mp4 = mp4_box(b"ftyp", b"isom");
+        long_mp4.extend(mp4_box(b"moov", &mp4_box(b"mvhd", &long)));
+        assert!(build(&long_mp4).unwrap_err().contains("longer than"));
+        // 映像のない MP4 は ffmpeg の有無にかかわらず読めない
+        assert!(build(&mp4).is_err());
+        assert_eq!(build_with(&mp4, None).unwrap_err(), "ffmpeg is not installed");
+    }
+
+    /// 4.2 秒の (中身のない) MP4
+    fn mp4() -> Vec<u8> {
+        let mut mvhd = vec![0; 4 + 8];
+        mvhd.extend_from_slice(&1000u32.to_be_bytes());
+        mvhd.extend_from_slice(&4200u32.to_be_bytes());
+        let mut mp4 = mp4_box(b"ftyp", b"isom\0\0\x02\0");
+        mp4.extend(mp4_box(b"moov", &mp4_box(b"mvhd", &mvhd)));
+        mp4
+    }
+
+    /// ffmpeg の代わりに `body` を実行するスクリプト
+    #[cfg(unix)]
+    fn fake_ffmpeg(dir: &tempfile::TempDir, body: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let path = dir.path().join("ffmpeg");
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn videos_are_sampled_with_ffmpeg() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let frame = dir.path().join("frame.png");
+        fs::write(&frame, png(64, 36, [0, 200, 0])).unwrap();
+        let ffmpeg = Ffmpeg::new(fake_ffmpeg(&dir, &format!("cat '{}'", frame.display())), FFMPEG_TIMEOUT);
+
+        let (sheet, info) = build_with(&mp4(), Some(&ffmpeg)).unwrap().unwrap();
+        assert_eq!(info, ClipInfo { sampled: FRAMES, total_frames: None, duration_ms: 4200 });
+        assert!(info.describe().starts_with("video, 4.2s"));
+        assert_eq!(sheet.get_pixel(GAP + 10, GAP + 10), &Rgb([0, 200, 0]));
+
+        let broken = Ffmpeg::new(fake_ffmpeg(&dir, "echo 'moov atom not found' >&2; exit 1"), FFMPEG_TIMEOUT);
+        assert!(build_with(&mp4(), Some(&broken)).unwrap_err().contains("moov atom not found"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stuck_ffmpeg_is_killed() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let ffmpeg = Ffmpeg::new(fake_ffmpeg(&dir, "exec sleep 30"), Duration::from_millis(200));
+        let started = Instant::now();
+        assert!(build_with(&mp4(), Some(&ffmpeg)).unwrap_err().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
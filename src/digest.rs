@@ -0,0 +1,212 @@
+//! チャンネルのダイジェスト (日次・週次のまとめ記事)
+//!
+//! 前回のダイジェスト以降の発言を集めて書き起こしにし、モデルにまとめさせて記事にする。
+//! 発言はプラットフォームの API から取得し、取れない場合 (IRC など) は会話履歴から取り出す。
+
+use call_agent::chat::prompt::{Message, MessageContext};
+use serde::{Deserialize, Serialize};
+
+use crate::{history::UserMeta, image_cache};
+
+/// ダイジェストを作るときの人格プロンプトの代わり
+pub const DIGEST_PROMPT: &str = "You write a recap of a chat channel for people who missed the conversation.
+The user message is a transcript of the messages since the last recap, oldest first, one message per line as '[name] text'.
+Write the recap in the main language of the transcript, in Markdown, with these sections (omit empty ones):
+## Topics - the main threads of conversation, a short paragraph each
+## Decisions - things that were agreed or decided
+## Links - URLs that were shared, with who shared them and what they are about
+## Images - notable images that were shared (the transcript shows them as [image: url])
+Do not invent anything that is not in the transcript. Do not add meta commentary.";
+
+/// 書き起こしに入れる最大文字数 (超えたら古い発言から落とす)
+pub const MAX_TRANSCRIPT_CHARS: usize = 30_000;
+
+/// ダイジェストの頻度
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DigestFrequency {
+    #[default]
+    Off,
+    Daily,
+    Weekly,
+}
+
+impl DigestFrequency {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "daily" => Ok(Self::Daily),
+            "weekly" => Ok(Self::Weekly),
+            _ => Err(format!("unknown digest frequency: {} (off, daily, weekly)", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Daily => "daily",
+            Self::Weekly => "weekly",
+        }
+    }
+
+    /// ダイジェストの間隔 (秒)
+    pub fn period_secs(&self) -> Option<u64> {
+        match self {
+            Self::Off => None,
+            Self::Daily => Some(24 * 60 * 60),
+            Self::Weekly => Some(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// ダイジェストに入れる発言
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DigestEntry {
+    pub message_id: String,
+    pub user_name: String,
+    pub text: String,
+    /// 画像の URL (data URL のものは書き起こしでは `[image]` になる)
+    pub images: Vec<String>,
+}
+
+/// 会話履歴からユーザーとアシスタントの発言を取り出す (ツールのやり取りは含めない)
+pub fn entries_from_history<'a>(messages: impl IntoIterator<Item = &'a Message>) -> Vec<DigestEntry> {
+    let mut entries = Vec::new();
+    for message in messages {
+        match message {
+            Message::User { content, .. } => {
+                let Some(meta) = UserMeta::from_message(message) else {
+                    continue;
+                };
+                let images = content.iter().filter_map(|c| match c {
+                    MessageContext::Image(image) => Some(image.url.clone()),
+                    MessageContext::Text(_) => None,
+                }).collect();
+                entries.push(DigestEntry { message_id: meta.message_id, user_name: meta.user_name, text: meta.body, images });
+            }
+            Message::Assistant { name, content, tool_calls } if tool_calls.as_ref().is_none_or(|c| c.is_empty()) => {
+                let text: Vec<&str> = content.iter().filter_map(|c| match c {
+                    MessageContext::Text(text) => Some(text.as_str()),
+                    MessageContext::Image(_) => None,
+                }).collect();
+                if !text.is_empty() {
+                    entries.push(DigestEntry {
+                        message_id: String::new(),
+                        user_name: name.clone().unwrap_or_else(|| "assistant".to_string()),
+                        text: text.join("\n"),
+                        images: Vec::new(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    entries
+}
+
+/// `last_message_id` より後の発言だけを残す (見つからなければすべて)
+pub fn after_message(entries: Vec<DigestEntry>, last_message_id: Option<&str>) -> Vec<DigestEntry> {
+    let Some(last) = last_message_id.filter(|id| !id.is_empty()) else {
+        return entries;
+    };
+    match entries.iter().rposition(|entry| entry.message_id == last) {
+        Some(index) => entries.into_iter().skip(index + 1).collect(),
+        None => entries,
+    }
+}
+
+/// 最後の発言の ID (次回はこれより後を集める)
+pub fn last_message_id(entries: &[DigestEntry]) -> Option<String> {
+    entries.iter().rev().find(|entry| !entry.message_id.is_empty()).map(|entry| entry.message_id.clone())
+}
+
+/// モデルに渡す書き起こし (新しい発言を優先して `max_chars` に収める)
+pub fn transcript(entries: &[DigestEntry], max_chars: usize) -> String {
+    let mut lines = Vec::new();
+    let mut total = 0;
+    for entry in entries.iter().rev() {
+        let mut line = format!("[{}] {}", entry.user_name, entry.text.replace('\n', " "));
+        for image in &entry.images {
+            if image.starts_with("data:") || image_cache::hash_of(image).is_some() {
+                line.push_str(" [image]");
+            } else {
+                line.push_str(&format!(" [image: {}]", image));
+            }
+        }
+        total += line.chars().count() + 1;
+        if total > max_chars && !lines.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    lines.reverse();
+    lines.join("\n")
+}
+
+/// 記事のキー (`digest-<channel>-<date>`)
+pub fn article_key(channel: &str, date: &str) -> String {
+    let channel: String = channel
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    format!("digest-{}-{}", channel.trim_matches('-'), date)
+}
+
+/// 記事の本文
+pub fn render_article(frequency: DigestFrequency, date: &str, summary: &str, message_count: usize) -> String {
+    let title = match frequency {
+        DigestFrequency::Weekly => "Weekly digest",
+        _ => "Daily digest",
+    };
+    format!("# {} {}\n\n{}\n\n---\n*{} messages*\n", title, date, summary.trim(), message_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{user, with_images};
+
+    fn entry(id: &str, text: &str) -> DigestEntry {
+        DigestEntry { message_id: id.into(), user_name: "alice".into(), text: text.into(), images: Vec::new() }
+    }
+
+    #[test]
+    fn history_entries_skip_tool_traffic() {
+        let history = vec![
+            with_images(user("1", "alice", "1", "look at this"), &["https://cdn.example/cat.png"]),
+            Message::Assistant { name: Some("observer".into()), content: vec![], tool_calls: Some(vec![]) },
+            Message::Tool { tool_call_id: "c1".into(), content: vec![MessageContext::Text("tool output".into())] },
+            Message::Assistant { name: Some("observer".into()), content: vec![MessageContext::Text("cute".into())], tool_calls: None },
+        ];
+        let entries = entries_from_history(&history);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].images, vec!["https://cdn.example/cat.png"]);
+        assert_eq!((entries[1].user_name.as_str(), entries[1].text.as_str()), ("observer", "cute"));
+        assert_eq!(last_message_id(&entries).as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn keeps_only_messages_after_the_last_digest() {
+        let entries = vec![entry("1", "a"), entry("2", "b"), entry("3", "c")];
+        let ids = |e: Vec<DigestEntry>| e.into_iter().map(|e| e.message_id).collect::<Vec<_>>();
+        assert_eq!(ids(after_message(entries.clone(), Some("2"))), vec!["3"]);
+        assert_eq!(ids(after_message(entries.clone(), Some("9"))), vec!["1", "2", "3"]);
+        assert_eq!(ids(after_message(entries, None)).len(), 3);
+    }
+
+    #[test]
+    fn transcript_prefers_recent_messages() {
+        let mut entries = vec![entry("1", &"old ".repeat(20)), entry("2", "new\nline")];
+        entries[1].images = vec!["data:image/png;base64,xx".into(), "https://x.dev/a.png".into()];
+        assert_eq!(transcript(&entries, 60), "[alice] new line [image] [image: https://x.dev/a.png]");
+        assert!(transcript(&entries, 1000).starts_with("[alice] old"));
+    }
+
+    #[test]
+    fn article_keys_are_predictable() {
+        assert_eq!(article_key("123456", "2025-01-06"), "digest-123456-2025-01-06");
+        assert_eq!(article_key("irc:#Rust", "2025-01-06"), "digest-irc--rust-2025-01-06");
+        assert_eq!(DigestFrequency::from_name("Weekly").unwrap().period_secs(), Some(604800));
+        assert!(DigestFrequency::from_name("hourly").is_err());
+    }
+}
@@ -1,227 +1,36 @@
-use std::{str::FromStr, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
 
-use actix_web::cookie::time::serde::timestamp;
-use call_agent::chat::client::OpenAIClient;
-use tokio::time;
-use std::time::Duration;
-use dashmap::DashMap;
 use log::{error, info, warn};
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-use serenity::{all::{ChannelId, Command, CommandOptionType, Context, CreateCommand, CreateCommandOption, CreateInteractionResponse, CreateInteractionResponseFollowup, CreateInteractionResponseMessage, CreateMessage, EditInteractionResponse, EventHandler, Interaction, MessageFlags, Ready, User, UserId}, async_trait, futures::StreamExt};
+use observer::{digest::DigestFrequency, reactions::ReactionAction};
+use serenity::{all::{ChannelId, Command, CommandInteraction, CommandOptionType, Context, CreateCommand, CreateAttachment, CreateCommandOption, CreateInteractionResponse, CreateInteractionResponseMessage, EditInteractionResponse, EventHandler, GuildId, Interaction, Member, MessageId, MessageUpdateEvent, Reaction, ReactionType, Ready, User}, async_trait};
 
+use crate::{agent::AIModel, bot::{Bot, ReactionEvent}, platform::discord::{to_input_message, DiscordPlatform, RETRY_BUTTON_ID}};
 
-use observer::prefix::{ADMIN_USERS, RATE_CP, SEC_PER_RATE};
-use crate::agent::{AIModel, ChannelState, InputMessage};
-
-const TIMEOUT: Duration = Duration::from_secs(180);
-
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct ChConf {
-    pub enable: bool,
-}
-
+/// serenity のイベントを `Bot` に渡す
 pub struct Handler {
-    /// Handlerに1つのOpenAIClientを保持
-    pub base_client: Arc<OpenAIClient>,
-    /// 有効なチャンネルのset
-    pub channels_conf: DashMap<u64, ChConf>,
-    /// 各チャンネルごとの状態（会話履歴）を保持（DashMapは並列処理可能）
-    pub channels: DashMap<ChannelId, Arc<ChannelState>>,
-    /// ユーザーごとにレートリミット
-    pub user_configs: DashMap<String, PerUserConfig>,
+    pub bot: Arc<Bot>,
+    /// ready は再接続のたびに呼ばれるので、予約の実行ループは 1 度だけ起動する
+    pub scheduler_started: AtomicBool,
 }
 
-pub struct PerUserConfig {
-    pub rate_limit: u64, // レートリミットの秒数
-    pub model: AIModel,
+/// Discord の予約実行ループが扱うチャンネル (IRC などはキーに `:` を含む)
+fn is_discord_channel(channel: &str) -> bool {
+    !channel.contains(':')
 }
 
 impl Handler {
-    /// チャンネルの状態を取得または作成する
-    async fn get_or_create_channel_state(&self, channel_id: ChannelId) -> Arc<ChannelState> {
-        if let Some(existing) = self.channels.get(&channel_id) {
-            Arc::clone(&existing)
-        } else {
-            let new_state = Arc::new(ChannelState::new(&self.base_client).await);
-            self.channels.insert(channel_id, new_state.clone());
-            new_state
-        }
-    }
-
-    /// メッセージを推論する
-    async fn handle_mentioned_message(
-        &self,
-        ctx: &Context,
-        msg: &serenity::all::Message,
-        state: Arc<ChannelState>,
-        message: InputMessage,
-    ) -> String {
-        // 有効なチャンネルかどうかを確認
-        if let Some(conf) = self.channels_conf.get(&msg.channel_id.get()) {
-            if !conf.enable {
-                return "Err: AI is disabled in this channel".to_string();
-            }
-        } else {
-            return "Err: AI is disabled in this channel".to_string();
-        }
-
-        // 使用モデルの取り出し
-        let user_id = message.user_id.clone();
-        let mut user_conf = self.user_configs.entry(user_id.clone()).or_insert(
-            PerUserConfig {
-                rate_limit: 1, // デフォルトは1
-                model: AIModel::default(), // デフォルトモデルを使用
-            }
-        );
-        let model = user_conf.model.clone();
-        let model_cost = model.to_sec_per_rate() as u64; // モデルのレート使用量
-        let sec_per_rate = *SEC_PER_RATE as u64; // レートの回復時間
-        let cp = *RATE_CP as u64; // レートの許容量
-        
-        // レートリミットの計算
-        let limit_line = sec_per_rate * cp;
-        let add_line = model_cost * sec_per_rate;
-        let time_stamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
-        let mut user_line = user_conf.rate_limit;
-        if user_line > time_stamp + limit_line {
-            return format!("Err: rate limit - try again after <t:{}:R>", (user_line - limit_line));
-        }
-        if user_line == 0 {
-            // リミットレスアカウント
-        } else if user_line < time_stamp {
-            user_line = time_stamp + add_line;
-            user_conf.rate_limit = user_line;
-        } else {
-            user_line += add_line;
-            user_conf.rate_limit = user_line;
-        }
-
-        // タイピング表示のタスクを開始する
-        let typing_task = tokio::spawn({
-            let ctx = ctx.clone();
-            let channel_id = msg.channel_id;
-            async move {
-                loop {
-                    if let Err(e) = channel_id.broadcast_typing(&ctx.http).await {
-                        error!("setting typing indicator - {:?}", e);
-                    }
-                    time::sleep(Duration::from_secs(4)).await;
-                }
-            }
-        });
-
-        // AIに質問、タイムアウトを設定
-        let answer_text = match time::timeout(TIMEOUT, state.reasoning(ctx, msg, message, model)).await {
-            Ok(answer) => answer,
-            Err(_) => "Err: timeout".to_string(),
-        };
-        typing_task.abort();
-        answer_text
-    }
-
-    /// メッセージを分割して送信する
-    async fn send_split_message(&self, ctx: &Context, channel_id: ChannelId, text: String) {
-        let chunks = Self::split_into_chunks(&text, 2000);
-
-        // 最初のチャンクを送信
-        if let Some(first_chunk) = chunks.get(0) {
-            let response = CreateMessage::new()
-                .content(first_chunk)
-                .flags(MessageFlags::SUPPRESS_EMBEDS);
-            if let Err(why) = channel_id.send_message(&ctx.http, response).await {
-                error!("{:?}", why);
-            }
-        }
-
-        // 残りのチャンクを送信
-        for chunk in chunks.iter().skip(1) {
-            let response = CreateMessage::new()
-                .content(chunk)
-                .flags(MessageFlags::SUPPRESS_EMBEDS);
-            if let Err(why) = channel_id.send_message(&ctx.http, response).await {
-                error!("{:?}", why);
-            }
-        }
-    }
-
-    /// テキストを指定された長さで分割する
-    fn split_into_chunks(text: &str, max_len: usize) -> Vec<String> {
-        // kaomoji の中のバッククォートだけをエスケープする
-        let kaomoji_re = Regex::new(r"\([^)]+`[^)]+\)").unwrap();
-        let mut chunks = Vec::new();
-        let mut current_chunk = String::new();
-
-        for line in text.lines() {
-            let escaped = if kaomoji_re.is_match(line) {
-                kaomoji_re
-                    .replace_all(line, |caps: &regex::Captures| {
-                        // マッチした kaomoji 部分だけバッククォートを \` に置換
-                        caps[0].replace("`", r"\`")
-                    })
-                    .into_owned()
-            } else {
-                line.to_string()
-            };
-
-            if current_chunk.len() + escaped.len() + 1 > max_len {
-                chunks.push(current_chunk);
-                current_chunk = String::new();
-            }
-            if !current_chunk.is_empty() {
-                current_chunk.push('\n');
-            }
-            current_chunk.push_str(&escaped);
-        }
-
-        if !current_chunk.is_empty() {
-            chunks.push(current_chunk);
-        }
-        chunks
-    }
-
-    /// チャンネル設定の保存
-    fn save_ch_conf(&self) {
-        let json_path = "./data/ch_conf.json";
-        let mut conf_map = std::collections::HashMap::new();
-        for entry in self.channels_conf.iter() {
-            conf_map.insert(*entry.key(), entry.value().clone());
-        }
-        match serde_json::to_string_pretty(&conf_map) {
-            Ok(json_str) => {
-                if let Err(e) = std::fs::write(json_path, json_str) {
-                    error!("Failed to write channel configuration to {}: {:?}", json_path, e);
-                } else {
-                    info!("Channel configuration saved to {}", json_path);
-                }
-            }
-            Err(e) => {
-                error!("Failed to serialize channel configuration: {:?}", e);
-            }
-        }
+    /// 管理者か、チャンネル管理権限を持つメンバーか (DM では権限がないので管理者だけ)
+    fn can_manage(&self, user: &User, member: Option<&Member>) -> bool {
+        self.bot.is_admin(&user.id.to_string())
+            || member.and_then(|m| m.permissions).is_some_and(|p| p.manage_channels())
     }
+}
 
-    /// チャンネル設定の読み込み
-    pub fn load(&self) {
-        let json_path = "./data/ch_conf.json";
-        if let Ok(json_str) = std::fs::read_to_string(json_path) {
-            match serde_json::from_str::<std::collections::HashMap<u64, ChConf>>(&json_str) {
-                Ok(conf_map) => {
-                    for (key, value) in conf_map {
-                        self.channels_conf.insert(key, value);
-                    }
-                    info!("Channel configuration loaded from {}", json_path);
-                }
-                Err(e) => {
-                    error!("Failed to deserialize channel configuration: {:?}", e);
-                }
-            }
-        } else {
-            info!("No channel configuration found at {}", json_path);
-        }
+/// コマンドに返信する
+async fn respond(ctx: &Context, command: &CommandInteraction, response_data: CreateInteractionResponseMessage) {
+    let response = CreateInteractionResponse::Message(response_data);
+    if let Err(why) = command.create_response(&ctx.http, response).await {
+        error!("Failed to respond to {} - {:?}", command.data.name, why);
     }
 }
 
@@ -235,40 +44,95 @@ impl EventHandler for Handler {
             return;
         }
 
-        // 画像ファイル URL をフィルタして取得
-        let attachment_urls: Vec<String> = msg
-            .attachments
-            .iter()
-            .map(|att| att.url.clone())
-            .collect();
-
-
-        let state = self.get_or_create_channel_state(msg.channel_id).await;
+        let is_mentioned = msg.mentions.iter().any(|user| user.id == bot_id);
+        let platform = DiscordPlatform::new(&ctx);
+        self.bot.handle_message(&platform, &msg.channel_id.to_string(), to_input_message(&msg), is_mentioned).await;
+    }
 
-        let message = InputMessage {
-            content: msg.content.clone(),
-            name: msg.author.name.clone(),
-            message_id: msg.id.to_string(),
-            reply_msg: msg.referenced_message.as_ref().map(|m| m.content.clone() + &m.attachments.iter().map(|att| att.url.clone()).collect::<Vec<String>>().join(", ")),
-            user_id: msg.author.id.to_string(),
-            attached_files: attachment_urls,
+    /// メッセージが編集されたときは履歴の本文を書き換える
+    async fn message_update(&self, ctx: Context, _old_if_available: Option<serenity::all::Message>, _new: Option<serenity::all::Message>, event: MessageUpdateEvent) {
+        // 埋め込みの展開などでも呼ばれるので本文が変わったときだけ
+        let Some(content) = event.content else {
+            return;
         };
+        if event.author.is_some_and(|author| author.id == ctx.cache.current_user().id) {
+            return;
+        }
+        self.bot.message_edited(&event.channel_id.to_string(), &event.id.to_string(), &content).await;
+    }
 
-        info!("Message: {:?}", message);
+    /// メッセージが削除されたときは履歴から取り除く
+    async fn message_delete(&self, _ctx: Context, channel_id: ChannelId, deleted_message_id: MessageId, _guild_id: Option<GuildId>) {
+        self.bot.messages_deleted(&channel_id.to_string(), &[deleted_message_id.to_string()]).await;
+    }
 
-        let is_mentioned = msg.mentions.iter().any(|user| user.id == bot_id);
+    async fn message_delete_bulk(&self, _ctx: Context, channel_id: ChannelId, multiple_deleted_messages_ids: Vec<MessageId>, _guild_id: Option<GuildId>) {
+        let ids: Vec<String> = multiple_deleted_messages_ids.iter().map(|id| id.to_string()).collect();
+        self.bot.messages_deleted(&channel_id.to_string(), &ids).await;
+    }
 
-        if is_mentioned {
-            let answer_text = self.handle_mentioned_message(&ctx, &msg, state, message).await;
-            self.send_split_message(&ctx, msg.channel_id, answer_text).await;
-        } else {
-            state.add_message(message).await;
+    /// リアクションが付けられたときの処理
+    async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        let bot_id = ctx.cache.current_user().id;
+        let Some(user_id) = reaction.user_id.filter(|id| *id != bot_id) else {
+            return;
+        };
+        let ReactionType::Unicode(emoji) = &reaction.emoji else {
+            return;
+        };
+        let channel = reaction.channel_id.to_string();
+        // 対応のない絵文字ならメッセージを取得しない
+        if self.bot.reaction_action(&channel, emoji).is_none() || !self.bot.is_enabled(&channel) {
+            return;
         }
+
+        let message = match reaction.message(&ctx.http).await {
+            Ok(message) => message,
+            Err(why) => {
+                error!("Failed to fetch the reacted message - {:?}", why);
+                return;
+            }
+        };
+        let user_name = match &reaction.member {
+            Some(member) => member.user.name.clone(),
+            None => match user_id.to_user(&ctx.http).await {
+                Ok(user) => user.name,
+                Err(_) => user_id.to_string(),
+            },
+        };
+        let platform = DiscordPlatform::new(&ctx);
+        let thread = if self.bot.reaction_action(&channel, emoji) == Some(ReactionAction::Article) {
+            platform.thread_context(&message).await
+        } else {
+            Vec::new()
+        };
+        let event = ReactionEvent {
+            emoji: emoji.clone(),
+            user_id: user_id.to_string(),
+            user_name,
+            message: to_input_message(&message),
+            from_bot: message.author.id == bot_id,
+            thread,
+        };
+        self.bot.handle_reaction(&platform, &channel, event).await;
     }
 
-    
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        // 応答に付けた作り直しボタン
+        if let Interaction::Component(component) = &interaction
+            && component.data.custom_id == RETRY_BUTTON_ID {
+            if let Err(why) = component.create_response(&ctx.http, CreateInteractionResponse::Acknowledge).await {
+                error!("Failed to acknowledge the retry button - {:?}", why);
+            }
+            let platform = DiscordPlatform::new(&ctx);
+            let user = &component.user;
+            let can_manage = self.can_manage(user, component.member.as_ref());
+            self.bot.retry(&platform, &component.channel_id.to_string(), &user.id.to_string(), &user.name, None, can_manage).await;
+            return;
+        }
         if let Interaction::Command(command) = interaction {
+            let channel = command.channel_id.to_string();
+            let command_user_id = command.user.id.to_string();
             match command.data.name.as_str() {
                 "ping" => {
                     let start = std::time::Instant::now();
@@ -289,253 +153,175 @@ impl EventHandler for Handler {
                 }
 
                 "reset" => {
-                    let state = if let Some(existing) = self.channels.get(&command.channel_id) {
-                        existing.clone()
-                    } else {
-                        let new_state = Arc::new(ChannelState::new(&self.base_client).await);
-                        self.channels.insert(command.channel_id, new_state.clone());
-                        new_state
-                    };
-
-                    state.clear_prompt().await;
-
-                    let response_data = CreateInteractionResponseMessage::new()
-                    .content("reset brain");
-
-                    let response = CreateInteractionResponse::Message(response_data);
-
-                    if let Err(why) = command.create_response(&ctx.http, response).await {
-                        error!("Failed to respond to reset: {:?}", why);
-                    }
+                    let message = self.bot.reset(&channel).await;
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content(message)).await;
                 }
 
                 "enable" => {
-                    let channel_id = command.channel_id.get();
-                    if let Some(mut ch_conf) = self.channels_conf.get_mut(&channel_id) {
-                        if ch_conf.enable {
-                            let response_data = CreateInteractionResponseMessage::new()
-                            .content("Info: AI is already enabled");
-
-                            let response = CreateInteractionResponse::Message(response_data);
-
-                            if let Err(why) = command.create_response(&ctx.http, response).await {
-                                error!("Failed to respond to enable - {:?}", why);
-                            }
-                            return;
-                        } else {
-                            ch_conf.enable = true;
-
-                            let response_data = CreateInteractionResponseMessage::new()
-                            .content("Info: AI is enabled");
-
-                            let response = CreateInteractionResponse::Message(response_data);
-
-                            if let Err(why) = command.create_response(&ctx.http, response).await {
-                                error!("Failed to respond to enable - {:?}", why);
-                            }
-                            self.save_ch_conf();
-                        }
-                    } else {
-                        self.channels_conf.insert(channel_id, ChConf { enable: true });
-                        let response_data = CreateInteractionResponseMessage::new()
-                        .content("Info: AI is enabled");
-                        let response = CreateInteractionResponse::Message(response_data);
-                        if let Err(why) = command.create_response(&ctx.http, response).await {
-                            error!("Failed to respond to enable - {:?}", why);
-                        }
-                        self.save_ch_conf();
-                    }
+                    let message = self.bot.set_enabled(&channel, true);
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content(message)).await;
                 }
 
                 "disable" => {
-                    let channel_id = command.channel_id.get();
-                    if let Some(mut ch_conf) = self.channels_conf.get_mut(&channel_id) {
-                        if !ch_conf.enable {
-                            let response_data = CreateInteractionResponseMessage::new()
-                            .content("Info: AI is already disabled");
-
-                            let response = CreateInteractionResponse::Message(response_data);
-
-                            if let Err(why) = command.create_response(&ctx.http, response).await {
-                                error!("Failed to respond to disable - {:?}", why);
-                            }
-                            return;
-                        } else {
-                            ch_conf.enable = false;
-
-                            let response_data = CreateInteractionResponseMessage::new()
-                            .content("Info: AI is disabled");
-
-                            let response = CreateInteractionResponse::Message(response_data);
-
-                            if let Err(why) = command.create_response(&ctx.http, response).await {
-                                error!("Failed to respond to disable - {:?}", why);
-                            }
-                            self.save_ch_conf();
-                        }
-                    } else {
-                        self.channels_conf.insert(channel_id, ChConf { enable: false });
+                    let message = self.bot.set_enabled(&channel, false);
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content(message)).await;
+                }
+
+                "attach_conf" => {
+                    if !self.can_manage(&command.user, command.member.as_deref()) {
                         let response_data = CreateInteractionResponseMessage::new()
-                        .content("Info: AI is disabled");
-                        let response = CreateInteractionResponse::Message(response_data);
-                        if let Err(why) = command.create_response(&ctx.http, response).await {
-                            error!("Failed to respond to disable - {:?}", why);
-                        }
-                        self.save_ch_conf();
+                            .content("Error: You do not have permission to configure attachments.")
+                            .ephemeral(true);
+                        respond(&ctx, &command, response_data).await;
+                        return;
                     }
+                    let threshold = command.data.options[0].value.as_i64().unwrap_or(0).max(0) as usize;
+                    let code_blocks = command.data.options.get(1).and_then(|o| o.value.as_bool());
+                    let message = self.bot.attach_conf(&channel, threshold, code_blocks);
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content(message)).await;
                 }
 
                 "collect_history" => {
-                    let entry_num = command.data.options[0].value.as_i64().unwrap_or(32) as usize;
-                    let state = if let Some(existing) = self.channels.get(&command.channel_id) {
-                        existing.clone()
-                    } else {
-                        let new_state = Arc::new(ChannelState::new(&self.base_client).await);
-                        self.channels.insert(command.channel_id, new_state.clone());
-                        new_state
-                    };
-                    let mut messages_stream = Box::pin(command.channel_id.messages_iter(&ctx.http).take(entry_num));
-                    let mut messages_vec = Vec::new();
-                    while let Some(message_result) = messages_stream.next().await {
-                        if let Ok(message) = message_result {
-                            messages_vec.push(message);
-                        }
-                    }
-                    for message in messages_vec.into_iter().rev() {
-                        state.add_message(InputMessage {
-                            content: message.content.clone(),
-                            name: message.author.name.clone(),
-                            message_id: message.id.to_string(),
-                            reply_msg: message.referenced_message.as_ref().map(|m| m.content.clone()),
-                            user_id: message.author.id.to_string(),
-                            attached_files: Vec::new(),
-                        }).await;
-                    }
-                    
-                    let response_data = CreateInteractionResponseMessage::new()
-                        .content(format!("Info: Complete collecting history ({} entries)", entry_num));
-
-                    let response = CreateInteractionResponse::Message(response_data);
+                    let entry_num = command.data.options.first()
+                        .and_then(|o| o.value.as_i64())
+                        .unwrap_or(32) as usize;
+                    let platform = DiscordPlatform::new(&ctx);
+                    let message = self.bot.collect_history(&platform, &channel, entry_num).await;
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content(message)).await;
+                }
 
-                    if let Err(why) = command.create_response(&ctx.http, response).await {
-                        error!("Failed to respond to collect_history - {:?}", why);
+                "export" => {
+                    // 会話履歴には他のユーザーの発言も含まれるので、管理者かチャンネル管理権限を持つユーザーに限る
+                    if !self.can_manage(&command.user, command.member.as_deref()) {
+                        let response_data = CreateInteractionResponseMessage::new()
+                            .content("Error: You do not have permission to export the history.")
+                            .ephemeral(true);
+                        respond(&ctx, &command, response_data).await;
+                        return;
                     }
+
+                    let format_name = command.data.options.first()
+                        .and_then(|o| o.value.as_str())
+                        .unwrap_or("markdown");
+                    let response_data = match self.bot.export(&channel, format_name).await {
+                        Err(e_str) => CreateInteractionResponseMessage::new()
+                            .content(format!("Error: {}", e_str)),
+                        Ok((file_name, exported)) => CreateInteractionResponseMessage::new()
+                            .content(format!("Info: Exported history ({})", format_name))
+                            .add_file(CreateAttachment::bytes(exported.into_bytes(), file_name)),
+                    };
+                    respond(&ctx, &command, response_data.ephemeral(true)).await;
                 }
 
                 "rate_conf" => {
-                    let command_user_id = command.user.id.to_string();
-                    if !ADMIN_USERS.contains(&command_user_id) {
+                    if !self.bot.is_admin(&command_user_id) {
                         let response_data = CreateInteractionResponseMessage::new()
                             .content("Error: You do not have permission to modify rate limits.");
-                        let response = CreateInteractionResponse::Message(response_data);
-                        if let Err(why) = command.create_response(&ctx.http, response).await {
-                            error!("Failed to respond to rate_conf - {:?}", why);
-                        }
+                        respond(&ctx, &command, response_data).await;
                         return;
                     }
                     let user_line = if command.data.options.len() > 1 {
-                        command.data.options[1].value.as_i64().unwrap_or(1) as i64
+                        command.data.options[1].value.as_i64().unwrap_or(1)
                     } else {
                         1
                     };
-                    let target_user_id = match command.data.options[0].value.as_user_id() {
-                        Some(user_id) => user_id.to_string(),
-                        None => {
-                            let response_data = CreateInteractionResponseMessage::new()
-                                .content("Error: Invalid user ID.");
-                            let response = CreateInteractionResponse::Message(response_data);
-                            if let Err(why) = command.create_response(&ctx.http, response).await {
-                                error!("Failed to respond to rate_conf - {:?}", why);
-                            }
-                            return;
-                        }
+                    let Some(target_user_id) = command.data.options[0].value.as_user_id() else {
+                        let response_data = CreateInteractionResponseMessage::new()
+                            .content("Error: Invalid user ID.");
+                        respond(&ctx, &command, response_data).await;
+                        return;
                     };
-                    // ユーザーidから名前を取得
-                    let user_data = UserId::from_str(&target_user_id).unwrap().to_user(&ctx.http).await.unwrap_or(User::default());
-                    let target_user_name = user_data.name.clone();
-
-                    let mut user_conf = self.user_configs.entry(target_user_id.clone()).or_insert(
-                        PerUserConfig {
-                            rate_limit: 0, // デフォルトは無制限
-                            model: AIModel::default(), // デフォルトモデルを使用
-                        }
-                    );
-
-                    // レートリミットを設定
-                    let timestamp = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .expect("Time went backwards")
-                        .as_secs();
-                    if user_line == 0 {
-                        user_conf.rate_limit = 0; // 無制限
-                    } else if user_line < 0 {
-                        user_conf.rate_limit = timestamp; // リセット
-                    } else {
-                        if user_conf.rate_limit < timestamp {
-                            user_conf.rate_limit = timestamp;
-                        }
-                        let sec_per_rate = *SEC_PER_RATE as u64; 
-                        user_conf.rate_limit += user_line as u64 * sec_per_rate;
+                    let platform = DiscordPlatform::new(&ctx);
+                    let message = self.bot.rate_conf(&platform, &target_user_id.to_string(), user_line).await;
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content(message)).await;
+                }
+
+                "digest" => {
+                    if !self.can_manage(&command.user, command.member.as_deref()) {
+                        let response_data = CreateInteractionResponseMessage::new()
+                            .content("Error: You do not have permission to configure digests.")
+                            .ephemeral(true);
+                        respond(&ctx, &command, response_data).await;
+                        return;
                     }
-                    let message = if user_conf.rate_limit == 0 {
-                        format!("Info: {} rate limit line set to unlimited", target_user_name).to_string()
-                    } else {
-                        let sec_per_rate = *SEC_PER_RATE as u64; // レートの回復時間
-                        let cp = *RATE_CP as u64; // レートの許容量
-                        
-                        // レートリミットの計算
-                        let limit_line = sec_per_rate * cp;
-                        let now_rate = ((timestamp + limit_line) as i64 - user_conf.rate_limit as i64) / sec_per_rate as i64;
-                        let next_time =  user_conf.rate_limit - limit_line;
-                        format!("Info: rate limit forcibly consumed. Now {}'s rate is {} (relative: <t:{}:R>)", target_user_name, now_rate, next_time)
+                    let frequency = command.data.options.first()
+                        .and_then(|o| o.value.as_str())
+                        .unwrap_or("now");
+                    if frequency == "now" {
+                        // 推論に時間がかかるので先に返信し、結果はチャンネルに流す
+                        respond(&ctx, &command, CreateInteractionResponseMessage::new().content("Info: creating digest...")).await;
+                        let platform = DiscordPlatform::new(&ctx);
+                        let text = self.bot.digest_now(&platform, &channel).await;
+                        self.bot.send_split_message(&platform, &channel, text).await;
+                        return;
+                    }
+                    let message = match DigestFrequency::from_name(frequency) {
+                        Ok(frequency) => self.bot.set_digest(&channel, frequency),
+                        Err(e_str) => format!("Error: {}", e_str),
                     };
-                    let response_data = CreateInteractionResponseMessage::new()
-                        .content(message);
-
-                    let response = CreateInteractionResponse::Message(response_data);
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content(message)).await;
+                }
 
-                    if let Err(why) = command.create_response(&ctx.http, response).await {
-                        error!("Failed to respond to rate_conf - {:?}", why);
+                "reactions" => {
+                    let emoji = command.data.options.iter().find(|o| o.name == "emoji").and_then(|o| o.value.as_str());
+                    let action = command.data.options.iter().find(|o| o.name == "action").and_then(|o| o.value.as_str());
+                    let can_manage = self.can_manage(&command.user, command.member.as_deref());
+                    if action.is_some() && !can_manage {
+                        let response_data = CreateInteractionResponseMessage::new()
+                            .content("Error: You do not have permission to configure reactions.")
+                            .ephemeral(true);
+                        respond(&ctx, &command, response_data).await;
+                        return;
                     }
+                    let message = self.bot.reaction_conf(&channel, emoji, action);
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content(message)).await;
                 }
 
-                "model" => {
-                    let command_user_id = command.user.id.to_string();
-                    let default_model_name = AIModel::default().to_model_name();
-                    let model_name = command.data.options[0].value.as_str().unwrap_or(&default_model_name);
-                    let model = AIModel::from_model_name(model_name);
-                    match model {
-                        Err(e_str) => {
-                            let response_data = CreateInteractionResponseMessage::new()
-                                .content(format!("Error: {}", e_str))
-                                .ephemeral(true);
-                            let response = CreateInteractionResponse::Message(response_data);
-                            if let Err(why) = command.create_response(&ctx.http, response).await {
-                                error!("Failed to respond to model - {:?}", why);
-                            }
-                            return;
-                        },
-                        Ok(model) => {
-                            let mut user_conf = self.user_configs.entry(command_user_id.clone()).or_insert(
-                                PerUserConfig {
-                                    rate_limit: 0, // デフォルトは無制限
-                                    model: AIModel::default(), // デフォルトモデルを使用
-                                }
-                            );
-                            user_conf.model = model.clone();
-                            let response_data = CreateInteractionResponseMessage::new()
-                                .content(format!("Info: Model set to {}", model.to_model_name()))
-                                .ephemeral(true);
-    
-                            let response = CreateInteractionResponse::Message(response_data);
-        
-                            if let Err(why) = command.create_response(&ctx.http, response).await {
-                                error!("Failed to respond to model - {:?}", why);
-                            }
-                            return ;
-                        }
+                "moderation" => {
+                    let policy = command.data.options.first().and_then(|o| o.value.as_str());
+                    let can_manage = self.can_manage(&command.user, command.member.as_deref());
+                    if policy.is_some() && !can_manage {
+                        let response_data = CreateInteractionResponseMessage::new()
+                            .content("Error: You do not have permission to configure moderation.")
+                            .ephemeral(true);
+                        respond(&ctx, &command, response_data).await;
+                        return;
                     }
+                    let message = self.bot.moderation_conf(&channel, policy);
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content(message)).await;
+                }
 
+                "schedule" => {
+                    let can_manage = self.can_manage(&command.user, command.member.as_deref());
+                    let cancel = command.data.options.first()
+                        .and_then(|o| o.value.as_i64())
+                        .map(|id| id.max(0) as u64);
+                    let message = self.bot.schedule(&channel, &command_user_id, can_manage, cancel);
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content(message)).await;
+                }
+
+                "retry" => {
+                    // 推論に時間がかかるので先に返信し、結果はチャンネルに流す
+                    let model_name = command.data.options.first().and_then(|o| o.value.as_str());
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content("Info: regenerating the last answer...").ephemeral(true)).await;
+                    let can_manage = self.can_manage(&command.user, command.member.as_deref());
+                    let platform = DiscordPlatform::new(&ctx);
+                    self.bot.retry(&platform, &channel, &command_user_id, &command.user.name, model_name, can_manage).await;
+                }
+
+                "forget_me" => {
+                    let confirm = command.data.options.first().and_then(|o| o.value.as_bool()).unwrap_or(false);
+                    let message = self.bot.forget_me(&command_user_id, &command.user.name, confirm).await;
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content(message).ephemeral(true)).await;
+                }
+
+                "model" => {
+                    let model_name = command.data.options.first().and_then(|o| o.value.as_str());
+                    let message = match self.bot.set_model(&command_user_id, model_name) {
+                        Ok(message) => message,
+                        Err(e_str) => format!("Error: {}", e_str),
+                    };
+                    respond(&ctx, &command, CreateInteractionResponseMessage::new().content(message).ephemeral(true)).await;
                 }
 
 
@@ -548,6 +334,10 @@ impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
         info!("{} is connected!", ready.user.name);
 
+        if !self.scheduler_started.swap(true, Ordering::SeqCst) {
+            tokio::spawn(self.bot.clone().run_scheduler(DiscordPlatform::new(&ctx), is_discord_channel));
+        }
+
         // グローバルコマンドを登録
         Command::set_global_commands(&ctx.http, vec![
             CreateCommand::new("ping")
@@ -561,6 +351,18 @@ impl EventHandler for Handler {
             CreateCommand::new("disable")
                 .description("disable AI"),
 
+            CreateCommand::new("attach_conf")
+                .description("attach long answers as a file (admins / channel managers)")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::Integer, "threshold", "attach when the answer exceeds this many messages (0 to disable)")
+                        .required(true)
+                        .min_int_value(0)
+                        .max_int_value(20)
+                )
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::Boolean, "code_blocks", "also attach code blocks as separate files")
+                ),
+
             CreateCommand::new("collect_history")
                 .description("collect message history")
                 .add_option(
@@ -568,6 +370,54 @@ impl EventHandler for Handler {
                         .max_int_value(128)
                         .min_int_value(1)
                 ),
+            CreateCommand::new("export")
+                .description("export conversation history (admins / channel managers)")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::String, "format", "output format")
+                        .add_string_choice("Markdown", "markdown")
+                        .add_string_choice("JSON", "json")
+                        .add_string_choice("OpenAI chat JSONL", "jsonl")
+                ),
+            CreateCommand::new("digest")
+                .description("post a daily or weekly digest article of this channel (admins / channel managers)")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::String, "frequency", "how often to post the digest")
+                        .required(true)
+                        .add_string_choice("Daily", "daily")
+                        .add_string_choice("Weekly", "weekly")
+                        .add_string_choice("Off", "off")
+                        .add_string_choice("Now (once)", "now")
+                ),
+            CreateCommand::new("reactions")
+                .description("show or change which reactions trigger actions (changes: admins / channel managers)")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::String, "emoji", "emoji to assign")
+                )
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::String, "action", "action to trigger")
+                        .add_string_choice("Article (message and thread)", "article")
+                        .add_string_choice("Regenerate answer", "regenerate")
+                        .add_string_choice("Translate", "translate")
+                        .add_string_choice("Explain", "explain")
+                        .add_string_choice("None (remove / disable all)", "none")
+                        .add_string_choice("Reset to defaults", "reset")
+                ),
+            CreateCommand::new("moderation")
+                .description("show or change how moderation handles this channel (changes: admins / channel managers)")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::String, "policy", "what to do with flagged messages and answers")
+                        .add_string_choice("Off", "off")
+                        .add_string_choice("Flag (notify admins)", "flag")
+                        .add_string_choice("Redact", "redact")
+                        .add_string_choice("Block", "block")
+                        .add_string_choice("Reset to the default", "reset")
+                ),
+            CreateCommand::new("schedule")
+                .description("list scheduled jobs in this channel, or cancel one")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::Integer, "cancel", "id of the job to cancel")
+                        .min_int_value(1)
+                ),
             CreateCommand::new("rate_conf")
                 .description("modify user rate")
                 .add_option(
@@ -598,6 +448,21 @@ impl EventHandler for Handler {
                         .add_int_choice("sub 65536", 65536)
 
                 ),
+            CreateCommand::new("forget_me")
+                .description("remove your messages from Observer's history and memory (asks for confirmation)")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::Boolean, "confirm", "set to true to proceed after reviewing the warning")
+                ),
+            CreateCommand::new("retry")
+                .description("regenerate the last answer, optionally with another model")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::String, "model", "model to use for this answer only")
+                        .add_string_choice(AIModel::MO4Mini.to_model_discription(), AIModel::MO4Mini.to_model_name())
+                        .add_string_choice(AIModel::MO3.to_model_discription(), AIModel::MO3.to_model_name())
+                        .add_string_choice(AIModel::M5Nano.to_model_discription(), AIModel::M5Nano.to_model_name())
+                        .add_string_choice(AIModel::M5Mini.to_model_discription(), AIModel::M5Mini.to_model_name())
+                        .add_string_choice(AIModel::M5.to_model_discription(), AIModel::M5.to_model_name())
+                ),
                 CreateCommand::new("model")
                 .description("set using model")
                 .add_option(
@@ -0,0 +1,611 @@
+//! 会話履歴 (プロンプト) の解析と書き出し
+//!
+//! ユーザーメッセージは `agent::ChannelState::prepare_user_prompt` で
+//! `[META]msg_id:..,user_name:..,replay_msg:..;\n本文` の形にされているので、ここで分解する。
+
+use std::ops::{Range, RangeInclusive};
+
+use call_agent::chat::prompt::{Message, MessageContext, MessageImage};
+use serde_json::{json, Value};
+
+/// 書き出し時にツールの結果を切り詰める文字数
+pub const TOOL_RESULT_PREVIEW_CHARS: usize = 1000;
+
+/// ユーザーメッセージに埋め込まれたメタデータ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserMeta {
+    pub message_id: String,
+    pub user_name: String,
+    pub reply_msg: Option<String>,
+    pub body: String,
+}
+
+impl UserMeta {
+    /// `[META]...;\n本文` 形式のテキストを分解する
+    pub fn parse(text: &str) -> Option<Self> {
+        let rest = text.strip_prefix("[META]msg_id:")?;
+        let (meta, body) = rest.split_once(";\n").unwrap_or((rest, ""));
+        let (message_id, meta) = meta.split_once(",user_name:")?;
+        let (user_name, reply_msg) = meta.split_once(",replay_msg:")?;
+        Some(Self {
+            message_id: message_id.to_string(),
+            user_name: user_name.to_string(),
+            reply_msg: (reply_msg != "none").then(|| reply_msg.to_string()),
+            body: body.to_string(),
+        })
+    }
+
+    /// `[META]...;\n本文` 形式に戻す
+    pub fn render(&self) -> String {
+        format!(
+            "[META]msg_id:{},user_name:{},replay_msg:{};\n{}",
+            self.message_id,
+            self.user_name,
+            self.reply_msg.as_deref().unwrap_or("none"),
+            self.body,
+        )
+    }
+
+    /// ユーザーメッセージからメタデータを取り出す
+    pub fn from_message(message: &Message) -> Option<Self> {
+        match message {
+            Message::User { content, .. } => content.iter().find_map(|c| match c {
+                MessageContext::Text(text) => Self::parse(text),
+                MessageContext::Image(_) => None,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// メッセージへの応答でツールが作ったもの (メッセージが消されたときに見直す)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Derived {
+    Article(String),
+    Memory(String),
+}
+
+impl Derived {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Article(_) => "article",
+            Self::Memory(_) => "memory",
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Article(key) | Self::Memory(key) => key,
+        }
+    }
+}
+
+/// `message_id` のユーザーメッセージの位置
+pub fn find_user_message<'a>(messages: impl IntoIterator<Item = &'a Message>, message_id: &str) -> Option<usize> {
+    messages
+        .into_iter()
+        .position(|m| UserMeta::from_message(m).is_some_and(|meta| meta.message_id == message_id))
+}
+
+/// 発言が履歴のどこにあり、その応答 (ツールのやり取りから最後の応答まで) がどこにあるか
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TurnSpan {
+    pub user: usize,
+    pub answer: RangeInclusive<usize>,
+}
+
+/// 発言 `message_id` のターンを探す (応答がなければ None)
+pub fn find_turn<'a>(messages: impl IntoIterator<Item = &'a Message>, message_id: &str) -> Option<TurnSpan> {
+    let messages: Vec<&Message> = messages.into_iter().collect();
+    let user = find_user_message(messages.iter().copied(), message_id)?;
+    let answer = answer_span(messages.iter().copied(), user)?;
+    Some(TurnSpan { user, answer })
+}
+
+/// `user` 番目の発言への応答 (ツールのやり取りから最後の応答まで) の範囲
+///
+/// 応答は発言の直後に入れられる (`agent::ChannelState::reason`) ので、あいだに別の発言があれば応答はないものとする。
+pub fn answer_span<'a>(messages: impl IntoIterator<Item = &'a Message>, user: usize) -> Option<RangeInclusive<usize>> {
+    for (index, message) in messages.into_iter().enumerate().skip(user + 1) {
+        match message {
+            Message::User { .. } => return None,
+            Message::Assistant { tool_calls, .. } if tool_calls.as_ref().is_none_or(|c| c.is_empty()) => {
+                return Some(user + 1..=index);
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// `user` 番目の発言のターン全体 (発言から次のユーザーメッセージの手前まで) の範囲
+/// 応答のない (失敗した) ターンでもツールのやり取りごと含む
+pub fn turn_range<'a>(messages: impl IntoIterator<Item = &'a Message>, user: usize) -> Range<usize> {
+    let mut end = user + 1;
+    for message in messages.into_iter().skip(user + 1) {
+        if matches!(message, Message::User { .. }) {
+            break;
+        }
+        end += 1;
+    }
+    user..end
+}
+
+/// ユーザーメッセージの本文を書き換える (メタデータと画像はそのまま)
+pub fn rewrite_body(message: &mut Message, body: &str) -> bool {
+    let Message::User { content, .. } = message else {
+        return false;
+    };
+    for part in content.iter_mut() {
+        if let MessageContext::Text(text) = part
+            && let Some(mut meta) = UserMeta::parse(text) {
+            meta.body = body.to_string();
+            *text = meta.render();
+            return true;
+        }
+    }
+    false
+}
+
+/// ユーザーメッセージに続くターン (次のユーザーメッセージまで) でツールが作った記事とメモリ
+pub fn derived_from<'a>(turn: impl IntoIterator<Item = &'a Message>) -> Vec<Derived> {
+    let mut derived = Vec::new();
+    for message in turn {
+        let calls = match message {
+            Message::User { .. } => break,
+            Message::Assistant { tool_calls: Some(calls), .. } => calls,
+            _ => continue,
+        };
+        for call in calls {
+            let args = &call.function.arguments;
+            let (Some(action), Some(key)) = (args["action"].as_str(), args["key"].as_str()) else {
+                continue;
+            };
+            let item = match (call.function.name.as_str(), action) {
+                // web_deploy_tool と同じくファイル名に使えない文字を除く
+                ("web_deploy_tool", "create") => Derived::Article(
+                    key.chars().filter(|&c| !matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*')).collect(),
+                ),
+                ("memory_tool", "add" | "push") => Derived::Memory(key.to_string()),
+                _ => continue,
+            };
+            if !derived.contains(&item) {
+                derived.push(item);
+            }
+        }
+    }
+    derived
+}
+
+/// 書き出し形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+    /// OpenAI の chat completions 形式のメッセージを 1 行ずつ
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "markdown" | "md" => Ok(Self::Markdown),
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::Jsonl),
+            _ => Err(format!("Unknown export format: {}", name)),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Markdown => "md",
+            Self::Json => "json",
+            Self::Jsonl => "jsonl",
+        }
+    }
+}
+
+/// 履歴を指定の形式で書き出す
+pub fn export<'a>(messages: impl IntoIterator<Item = &'a Message>, format: ExportFormat) -> String {
+    let messages: Vec<Message> = messages.into_iter().map(redact).collect();
+    match format {
+        ExportFormat::Markdown => export_markdown(&messages),
+        ExportFormat::Json => export_json(&messages),
+        ExportFormat::Jsonl => messages
+            .iter()
+            .filter_map(|m| serde_json::to_string(m).ok())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// 履歴を省略せずに JSON にする (`restore` で元に戻せる)
+///
+/// call_agent の `Message` は API に送る形でしか書き出せず読み戻せないので、保存用に独自の形にする。
+pub fn snapshot<'a>(messages: impl IntoIterator<Item = &'a Message>) -> Value {
+    fn parts(content: &[MessageContext]) -> Value {
+        content.iter().map(|c| match c {
+            MessageContext::Text(text) => json!({ "text": text }),
+            MessageContext::Image(image) => json!({ "image": image.url, "detail": image.detail }),
+        }).collect()
+    }
+    messages.into_iter().map(|message| match message {
+        Message::User { name, content } => json!({ "role": "user", "name": name, "content": parts(content) }),
+        Message::Tool { tool_call_id, content } => json!({ "role": "tool", "tool_call_id": tool_call_id, "content": parts(content) }),
+        Message::Assistant { name, content, tool_calls } => json!({
+            "role": "assistant",
+            "name": name,
+            "content": parts(content),
+            "tool_calls": tool_calls,
+        }),
+        Message::System { name, content } => json!({ "role": "system", "name": name, "content": content }),
+        Message::Developer { name, content } => json!({ "role": "developer", "name": name, "content": content }),
+    }).collect()
+}
+
+/// `snapshot` で書き出した履歴を読み込む
+pub fn restore(value: &Value) -> Result<Vec<Message>, String> {
+    fn parts(value: &Value) -> Result<Vec<MessageContext>, String> {
+        value.as_array().ok_or("content must be an array")?.iter().map(|part| {
+            if let Some(text) = part.get("text").and_then(Value::as_str) {
+                Ok(MessageContext::Text(text.to_string()))
+            } else if let Some(url) = part.get("image").and_then(Value::as_str) {
+                let detail = part.get("detail").and_then(Value::as_str).map(|d| d.to_string());
+                Ok(MessageContext::Image(MessageImage { url: url.to_string(), detail }))
+            } else {
+                Err(format!("unknown content part: {}", part))
+            }
+        }).collect()
+    }
+    let name = |m: &Value| m.get("name").and_then(Value::as_str).map(|n| n.to_string());
+    let text = |m: &Value| m.get("content").and_then(Value::as_str).map(|c| c.to_string()).ok_or("content must be a string".to_string());
+
+    value.as_array().ok_or("snapshot must be an array")?.iter().map(|m| {
+        match m.get("role").and_then(Value::as_str) {
+            Some("user") => Ok(Message::User { name: name(m), content: parts(&m["content"])? }),
+            Some("tool") => Ok(Message::Tool {
+                tool_call_id: m.get("tool_call_id").and_then(Value::as_str).ok_or("tool_call_id is required")?.to_string(),
+                content: parts(&m["content"])?,
+            }),
+            Some("assistant") => Ok(Message::Assistant {
+                name: name(m),
+                content: parts(&m["content"])?,
+                tool_calls: serde_json::from_value(m.get("tool_calls").cloned().unwrap_or_default()).map_err(|e| e.to_string())?,
+            }),
+            Some("system") => Ok(Message::System { name: name(m), content: text(m)? }),
+            Some("developer") => Ok(Message::Developer { name: name(m), content: text(m)? }),
+            role => Err(format!("unknown role: {:?}", role)),
+        }
+    }).collect()
+}
+
+/// 画像を説明文に置き換え、ツールの結果を切り詰めた複製を作る
+fn redact(message: &Message) -> Message {
+    let redact_content = |content: &Vec<MessageContext>, truncate: bool| -> Vec<MessageContext> {
+        content
+            .iter()
+            .map(|c| match c {
+                MessageContext::Text(text) if truncate => MessageContext::Text(truncate_chars(text, TOOL_RESULT_PREVIEW_CHARS)),
+                MessageContext::Text(text) => MessageContext::Text(text.clone()),
+                MessageContext::Image(image) => MessageContext::Text(image_placeholder(image)),
+            })
+            .collect()
+    };
+    match message {
+        Message::User { name, content } => Message::User {
+            name: name.clone(),
+            content: redact_content(content, false),
+        },
+        Message::Tool { tool_call_id, content } => Message::Tool {
+            tool_call_id: tool_call_id.clone(),
+            content: redact_content(content, true),
+        },
+        Message::Assistant { name, content, tool_calls } => Message::Assistant {
+            name: name.clone(),
+            content: redact_content(content, false),
+            tool_calls: tool_calls.clone(),
+        },
+        other => other.clone(),
+    }
+}
+
+fn image_placeholder(image: &MessageImage) -> String {
+    let detail = image.detail.as_deref().unwrap_or("auto");
+    if let Some(data) = image.url.strip_prefix("data:") {
+        let (mime, payload) = data.split_once(";base64,").unwrap_or((data, ""));
+        // base64 4 文字で 3 バイト
+        format!("[image: {}, ~{} KB, detail={}]", mime, payload.len() * 3 / 4 / 1024, detail)
+    } else {
+        format!("[image: {}, detail={}]", image.url, detail)
+    }
+}
+
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    let total = text.chars().count();
+    if total <= max_chars {
+        return text.to_string();
+    }
+    let head: String = text.chars().take(max_chars).collect();
+    format!("{}...<{} characters truncated>", head, total - max_chars)
+}
+
+fn texts(content: &[MessageContext]) -> String {
+    content
+        .iter()
+        .filter_map(|c| match c {
+            MessageContext::Text(text) => Some(text.as_str()),
+            MessageContext::Image(_) => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// ユーザーメッセージを本文 (先頭のテキスト) と添付 (画像のプレースホルダなど) に分ける
+fn split_user_content(content: &[MessageContext]) -> (String, Vec<String>) {
+    let mut parts = content.iter().map(|c| match c {
+        MessageContext::Text(text) => text.clone(),
+        MessageContext::Image(image) => image_placeholder(image),
+    });
+    let text = parts.next().unwrap_or_default();
+    (text, parts.collect())
+}
+
+fn export_markdown(messages: &[Message]) -> String {
+    let mut out = String::from("# Conversation export\n");
+    for message in messages {
+        out.push('\n');
+        match message {
+            Message::User { name, content } => {
+                let (text, attachments) = split_user_content(content);
+                match UserMeta::parse(&text) {
+                    Some(meta) => {
+                        out.push_str(&format!(
+                            "## 👤 {} (user_id: {}, msg_id: {})\n",
+                            meta.user_name,
+                            name.as_deref().unwrap_or("-"),
+                            meta.message_id
+                        ));
+                        if let Some(reply) = &meta.reply_msg {
+                            out.push_str(&format!("> reply to: {}\n\n", reply.replace('\n', " ")));
+                        }
+                        out.push_str(&meta.body);
+                    }
+                    None => {
+                        out.push_str(&format!("## 👤 {}\n", name.as_deref().unwrap_or("user")));
+                        out.push_str(&text);
+                    }
+                }
+                // 画像などのプレースホルダは本文の後に並べる
+                for attachment in &attachments {
+                    out.push('\n');
+                    out.push_str(attachment);
+                }
+            }
+            Message::Assistant { name, content, tool_calls } => {
+                out.push_str(&format!("## 🤖 {}\n", name.as_deref().unwrap_or("assistant")));
+                let text = texts(content);
+                if !text.is_empty() {
+                    out.push_str(&text);
+                    out.push('\n');
+                }
+                for call in tool_calls.iter().flatten() {
+                    out.push_str(&format!(
+                        "\n**tool call** `{}` (id: {})\n```json\n{}\n```\n",
+                        call.function.name,
+                        call.id,
+                        serde_json::to_string_pretty(&call.function.arguments).unwrap_or_default()
+                    ));
+                }
+            }
+            Message::Tool { tool_call_id, content } => {
+                out.push_str(&format!("### 🔧 tool result (id: {})\n```\n{}\n```", tool_call_id, texts(content)));
+            }
+            Message::System { content, .. } => {
+                out.push_str(&format!("## ⚙️ system\n{}", content));
+            }
+            Message::Developer { content, .. } => {
+                out.push_str(&format!("## ⚙️ developer\n{}", content));
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn export_json(messages: &[Message]) -> String {
+    // ツール結果にツール名を付けるため、呼び出し ID から名前を引けるようにしておく
+    let mut tool_names = std::collections::HashMap::new();
+    let entries: Vec<Value> = messages
+        .iter()
+        .map(|message| match message {
+            Message::User { name, content } => {
+                let (text, attachments) = split_user_content(content);
+                match UserMeta::parse(&text) {
+                    Some(meta) => json!({
+                        "role": "user",
+                        "user_id": name,
+                        "user_name": meta.user_name,
+                        "message_id": meta.message_id,
+                        "reply_to": meta.reply_msg,
+                        "content": meta.body,
+                        "attachments": attachments,
+                    }),
+                    None => json!({ "role": "user", "user_id": name, "content": text, "attachments": attachments }),
+                }
+            }
+            Message::Assistant { name, content, tool_calls } => {
+                let calls: Vec<Value> = tool_calls
+                    .iter()
+                    .flatten()
+                    .map(|call| {
+                        tool_names.insert(call.id.clone(), call.function.name.clone());
+                        json!({
+                            "id": call.id,
+                            "name": call.function.name,
+                            "arguments": call.function.arguments,
+                        })
+                    })
+                    .collect();
+                json!({ "role": "assistant", "name": name, "content": texts(content), "tool_calls": calls })
+            }
+            Message::Tool { tool_call_id, content } => json!({
+                "role": "tool",
+                "tool_call_id": tool_call_id,
+                "tool_name": tool_names.get(tool_call_id),
+                "result": texts(content),
+            }),
+            Message::System { content, .. } => json!({ "role": "system", "content": content }),
+            Message::Developer { content, .. } => json!({ "role": "developer", "content": content }),
+        })
+        .collect();
+    serde_json::to_string_pretty(&json!({ "messages": entries })).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use call_agent::chat::function::{FunctionCall, FunctionCallInner};
+
+    fn sample() -> Vec<Message> {
+        vec![
+            Message::User {
+                name: Some("42".into()),
+                content: vec![
+                    MessageContext::Text("[META]msg_id:100,user_name:alice,replay_msg:none;\nhello".into()),
+                    MessageContext::Image(MessageImage { url: "data:image/png;base64,AAAA".into(), detail: Some("low".into()) }),
+                ],
+            },
+            Message::Assistant {
+                name: Some("observer".into()),
+                content: vec![],
+                tool_calls: Some(vec![FunctionCall {
+                    id: "call_1".into(),
+                    tool_type: "function".into(),
+                    function: FunctionCallInner { name: "browser".into(), arguments: json!({ "url": "https://example.com" }) },
+                }]),
+            },
+            Message::Tool {
+                tool_call_id: "call_1".into(),
+                content: vec![MessageContext::Text("x".repeat(TOOL_RESULT_PREVIEW_CHARS + 5))],
+            },
+            Message::Assistant {
+                name: Some("observer".into()),
+                content: vec![MessageContext::Text("hi".into())],
+                tool_calls: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn parses_user_meta() {
+        let meta = UserMeta::parse("[META]msg_id:1,user_name:bob,replay_msg:a, b;c;\nbody\nmore").unwrap();
+        assert_eq!(meta.message_id, "1");
+        assert_eq!(meta.user_name, "bob");
+        assert_eq!(meta.reply_msg.as_deref(), Some("a, b;c"));
+        assert_eq!(meta.body, "body\nmore");
+        assert_eq!(UserMeta::parse("[META]msg_id:1,user_name:bob,replay_msg:none;\n").unwrap().reply_msg, None);
+        assert!(UserMeta::parse("plain text").is_none());
+    }
+
+    #[test]
+    fn rewrites_and_finds_user_messages() {
+        let mut messages = sample();
+        assert_eq!(find_user_message(&messages, "100"), Some(0));
+        assert_eq!(find_user_message(&messages, "101"), None);
+        assert!(rewrite_body(&mut messages[0], "hello, fixed"));
+        let meta = UserMeta::from_message(&messages[0]).unwrap();
+        assert_eq!((meta.message_id.as_str(), meta.body.as_str()), ("100", "hello, fixed"));
+        // 画像は残る
+        assert!(matches!(&messages[0], Message::User { content, .. } if content.len() == 2));
+        assert!(!rewrite_body(&mut messages[3], "x"));
+    }
+
+    #[test]
+    fn finds_the_turn_right_after_the_message() {
+        let mut messages = sample();
+        assert_eq!(find_turn(&messages, "100"), Some(TurnSpan { user: 0, answer: 1..=3 }));
+        assert_eq!(find_turn(&messages, "101"), None);
+        // あいだに別の発言がある (応答のない) 発言
+        let chatter = Message::User {
+            name: None,
+            content: vec![MessageContext::Text("[META]msg_id:101,user_name:bob,replay_msg:none;\nlol".into())],
+        };
+        messages.insert(0, chatter);
+        assert_eq!(find_turn(&messages, "101"), None);
+        assert_eq!(find_turn(&messages, "100"), Some(TurnSpan { user: 1, answer: 2..=4 }));
+        assert_eq!(turn_range(&messages, 0), 0..1);
+        assert_eq!(turn_range(&messages, 1), 1..5);
+    }
+
+    #[test]
+    fn finds_articles_and_memories_made_in_the_turn() {
+        let call = |name: &str, arguments: Value| FunctionCall {
+            id: "c".into(),
+            tool_type: "function".into(),
+            function: FunctionCallInner { name: name.into(), arguments },
+        };
+        let mut messages = sample();
+        messages.insert(1, Message::Assistant {
+            name: None,
+            content: vec![],
+            tool_calls: Some(vec![
+                call("web_deploy_tool", json!({ "action": "create", "key": "rust/news", "content": "..." })),
+                call("web_deploy_tool", json!({ "action": "get", "key": "old" })),
+                call("memory_tool", json!({ "action": "push", "key": "diary", "value": "..." })),
+            ]),
+        });
+        messages.push(Message::User { name: None, content: vec![] });
+        messages.push(Message::Assistant {
+            name: None,
+            content: vec![],
+            tool_calls: Some(vec![call("memory_tool", json!({ "action": "add", "key": "later", "value": "..." }))]),
+        });
+        let derived = derived_from(messages.iter().skip(1));
+        assert_eq!(derived, vec![Derived::Article("rustnews".into()), Derived::Memory("diary".into())]);
+        assert_eq!((derived[1].kind(), derived[1].key()), ("memory", "diary"));
+    }
+
+    #[test]
+    fn jsonl_is_openai_chat_format_without_images() {
+        let out = export(&sample(), ExportFormat::Jsonl);
+        let lines: Vec<Value> = out.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["role"], "user");
+        assert_eq!(lines[0]["content"][1]["text"], "[image: image/png, ~0 KB, detail=low]");
+        assert_eq!(lines[1]["tool_calls"][0]["function"]["name"], "browser");
+        assert_eq!(lines[2]["role"], "tool");
+        assert!(lines[2]["content"].as_str().unwrap().ends_with("...<5 characters truncated>"));
+        assert!(!out.contains("base64"));
+    }
+
+    #[test]
+    fn json_has_metadata_and_tool_names() {
+        let out: Value = serde_json::from_str(&export(&sample(), ExportFormat::Json)).unwrap();
+        let messages = out["messages"].as_array().unwrap();
+        assert_eq!(messages[0]["user_name"], "alice");
+        assert_eq!(messages[0]["message_id"], "100");
+        assert_eq!(messages[0]["content"], "hello");
+        assert_eq!(messages[0]["attachments"][0], "[image: image/png, ~0 KB, detail=low]");
+        assert_eq!(messages[1]["tool_calls"][0]["arguments"]["url"], "https://example.com");
+        assert_eq!(messages[2]["tool_name"], "browser");
+    }
+
+    #[test]
+    fn markdown_is_readable() {
+        let out = export(&sample(), ExportFormat::Markdown);
+        assert!(out.contains("## 👤 alice (user_id: 42, msg_id: 100)\nhello\n[image: image/png"));
+        assert!(out.contains("**tool call** `browser` (id: call_1)"));
+        assert!(out.contains("## 🤖 observer\nhi"));
+    }
+
+    #[test]
+    fn snapshot_round_trips_without_loss() {
+        let mut messages = sample();
+        messages.push(Message::Developer { name: None, content: "be nice".into() });
+        let json = serde_json::to_string(&snapshot(&messages)).unwrap();
+        let restored = restore(&serde_json::from_str(&json).unwrap()).unwrap();
+        assert_eq!(snapshot(&restored), snapshot(&messages));
+        assert!(matches!(&restored[0], Message::User { content, .. } if matches!(&content[1], MessageContext::Image(i) if i.url == "data:image/png;base64,AAAA")));
+        assert!(restore(&json!([{ "role": "nope" }])).is_err());
+    }
+}
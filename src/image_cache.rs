@@ -0,0 +1,443 @@
+//! 取り込んだ画像のディスクキャッシュ
+//!
+//! 取得した画像を内容のハッシュ (SHA-256) をキーに正規化済みの形で一度だけ保存し、
+//! 会話履歴には data URL の代わりに `image-cache:<hash>` という参照を置く。
+//! 参照は API に送る直前に `prepare` で data URL に戻し、そのときに古い画像は
+//! low detail や説明文に落としてトークンを節約する。
+//! Discord の CDN の URL は署名付きで期限が切れるので、署名のクエリを除いた URL からもハッシュを引けるようにしておく。
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+
+use base64::prelude::*;
+use call_agent::chat::prompt::{Message, MessageContext, MessageImage};
+use log::warn;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    attachments,
+    contact_sheet::ClipInfo,
+    images::{self, EncodedImage, LoadedImage, OutputFormat, MAX_IMAGE_BYTES, MAX_TOTAL_BYTES},
+};
+
+/// 履歴に置く参照の接頭辞
+pub const SCHEME: &str = "image-cache:";
+/// 新しいほうからこの数のユーザーの発言の画像はそのまま送る
+pub const FULL_DETAIL_TURNS: usize = 2;
+/// それより古く、この数までの発言の画像は low detail で送り、さらに古いものは説明文にする
+pub const LOW_DETAIL_TURNS: usize = 6;
+
+const INDEX_FILE: &str = "urls.json";
+/// URL の索引で無視するクエリ (Discord の CDN の署名)
+const SIGNING_PARAMS: [&str; 3] = ["ex", "is", "hm"];
+
+static INSTALLED: OnceLock<ImageCache> = OnceLock::new();
+
+/// プロセス全体で使うキャッシュを設定する (起動時に一度だけ)
+pub fn install(cache: ImageCache) {
+    if INSTALLED.set(cache).is_err() {
+        warn!("image cache is already installed");
+    }
+}
+
+/// 設定されたキャッシュ (なければ画像は履歴に data URL のまま入る)
+pub fn installed() -> Option<&'static ImageCache> {
+    INSTALLED.get()
+}
+
+pub fn reference(hash: &str) -> String {
+    format!("{}{}", SCHEME, hash)
+}
+
+/// 参照ならハッシュを返す
+pub fn hash_of(url: &str) -> Option<&str> {
+    url.strip_prefix(SCHEME)
+}
+
+/// URL の索引のキー
+/// 期限で変わる Discord の CDN の署名 (`SIGNING_PARAMS`) とフラグメントだけを除き、ほかのクエリは画像を区別するので残す
+fn url_key(url: &str) -> String {
+    let Ok(mut parsed) = reqwest::Url::parse(url) else {
+        return url.split('#').next().unwrap_or(url).to_string();
+    };
+    parsed.set_fragment(None);
+    let kept: Vec<(String, String)> = parsed
+        .query_pairs()
+        .filter(|(name, _)| !SIGNING_PARAMS.contains(&name.as_ref()))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    if kept.is_empty() {
+        parsed.set_query(None);
+    } else {
+        parsed.query_pairs_mut().clear().extend_pairs(kept);
+    }
+    parsed.into()
+}
+
+pub struct ImageCache {
+    dir: PathBuf,
+    /// URL (クエリを除く) → ハッシュ
+    urls: Mutex<HashMap<String, String>>,
+}
+
+impl ImageCache {
+    /// `dir` に保存するキャッシュを開く (索引があれば読み込む)
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, String> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        let urls = fs::read_to_string(dir.join(INDEX_FILE))
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        Ok(Self { dir, urls: Mutex::new(urls) })
+    }
+
+    fn path(&self, hash: &str, format: OutputFormat) -> PathBuf {
+        let extension = match format {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::WebP => "webp",
+        };
+        self.dir.join(format!("{}.{}", hash, extension))
+    }
+
+    fn caption_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.txt", hash))
+    }
+
+    fn clip_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.clip.json", hash))
+    }
+
+    /// 保存されている画像の形式とバイト列
+    fn read(&self, hash: &str) -> Option<(OutputFormat, Vec<u8>)> {
+        // ハッシュ以外の文字でキャッシュの外を読ませない
+        if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        [OutputFormat::Jpeg, OutputFormat::WebP]
+            .into_iter()
+            .find_map(|format| fs::read(self.path(hash, format)).ok().map(|data| (format, data)))
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.read(hash).is_some()
+    }
+
+    /// 保存されている (正規化済みの) 画像のバイト列
+    pub fn bytes(&self, hash: &str) -> Option<Vec<u8>> {
+        self.read(hash).map(|(_, data)| data)
+    }
+
+    /// 参照を data URL に戻す
+    pub fn data_url(&self, hash: &str) -> Option<String> {
+        let (format, data) = self.read(hash)?;
+        Some(format!("data:{};base64,{}", format.mime(), BASE64_STANDARD.encode(data)))
+    }
+
+    /// 取得した画像を正規化して保存し、ハッシュを返す (同じ内容なら正規化も保存もしない)
+    pub fn insert(&self, bytes: &[u8]) -> Result<String, String> {
+        let hash = format!("{:x}", Sha256::digest(bytes));
+        if !self.contains(&hash) {
+            self.store(&hash, &images::normalize(bytes)?)?;
+        }
+        Ok(hash)
+    }
+
+    /// 正規化した画像を保存する
+    fn store(&self, hash: &str, image: &EncodedImage) -> Result<(), String> {
+        if let Some(clip) = &image.clip {
+            fs::write(self.clip_path(hash), serde_json::to_string(clip).unwrap_or_default()).map_err(|e| e.to_string())?;
+        }
+        fs::write(self.path(hash, image.format), &image.data).map_err(|e| e.to_string())
+    }
+
+    fn remember_url(&self, url: &str, hash: &str) {
+        let mut urls = self.urls.lock().unwrap();
+        if urls.insert(url_key(url), hash.to_string()).as_deref() == Some(hash) {
+            return;
+        }
+        let json = serde_json::to_string(&*urls).unwrap_or_default();
+        if let Err(e) = fs::write(self.dir.join(INDEX_FILE), json) {
+            warn!("Failed to save the image index - {:?}", e);
+        }
+    }
+
+    /// 以前に取得した URL ならそのハッシュ
+    pub fn lookup_url(&self, url: &str) -> Option<String> {
+        let hash = self.urls.lock().unwrap().get(&url_key(url)).cloned()?;
+        self.contains(&hash).then_some(hash)
+    }
+
+    /// URL の画像をキャッシュに入れてハッシュと取得したバイト数を返す
+    /// 以前に取得した URL なら取得しない (期限切れの CDN の URL でも読める)
+    pub async fn load(&self, client: &reqwest::Client, url: &str, max_bytes: usize) -> Result<(String, usize), String> {
+        if let Some(hash) = self.lookup_url(url) {
+            return Ok((hash, 0));
+        }
+        let (_, bytes) = attachments::fetch(client, url, max_bytes).await?;
+        let fetched = bytes.len();
+        let hash = format!("{:x}", Sha256::digest(&bytes));
+        if !self.contains(&hash) {
+            self.store(&hash, &images::normalize_blocking(bytes).await?)?;
+        }
+        self.remember_url(url, &hash);
+        Ok((hash, fetched))
+    }
+
+    /// コンタクトシートなら元のアニメーションや動画の情報
+    pub fn clip(&self, hash: &str) -> Option<ClipInfo> {
+        if !self.contains(hash) {
+            return None;
+        }
+        serde_json::from_str(&fs::read_to_string(self.clip_path(hash)).ok()?).ok()
+    }
+
+    /// 添付ファイルのうち画像をキャッシュに入れて参照にする (読めない画像と重複は飛ばす)
+    pub async fn load_all(&self, urls: &[String]) -> Vec<LoadedImage> {
+        let client = reqwest::Client::new();
+        let mut total = 0;
+        let mut out = Vec::new();
+        for url in urls.iter().filter(|url| images::is_visual(url)) {
+            let remaining = MAX_TOTAL_BYTES.saturating_sub(total);
+            if remaining == 0 {
+                break;
+            }
+            match self.load(&client, url, MAX_IMAGE_BYTES.min(remaining)).await {
+                Ok((hash, fetched)) => {
+                    total += fetched;
+                    let reference = reference(&hash);
+                    if !out.iter().any(|loaded: &LoadedImage| loaded.url == reference) {
+                        out.push(LoadedImage { url: reference, name: attachments::file_name(url), clip: self.clip(&hash) });
+                    }
+                }
+                Err(e) => warn!("Skipping image {}: {}", url, e),
+            }
+        }
+        out
+    }
+
+    /// 画像の説明文を保存する (古い画像を説明文に置き換えるときに使う)
+    pub fn set_caption(&self, hash: &str, caption: &str) {
+        if self.contains(hash)
+            && let Err(e) = fs::write(self.caption_path(hash), caption)
+        {
+            warn!("Failed to save the caption of {} - {:?}", hash, e);
+        }
+    }
+
+    pub fn caption(&self, hash: &str) -> Option<String> {
+        if !self.contains(hash) {
+            return None;
+        }
+        fs::read_to_string(self.caption_path(hash)).ok()
+    }
+
+    /// 画像と説明文を消す (`/forget_me` など)
+    pub fn remove(&self, hash: &str) {
+        if !self.contains(hash) {
+            return;
+        }
+        for path in [self.path(hash, OutputFormat::Jpeg), self.path(hash, OutputFormat::WebP), self.caption_path(hash), self.clip_path(hash)] {
+            let _ = fs::remove_file(path);
+        }
+        let mut urls = self.urls.lock().unwrap();
+        urls.retain(|_, h| h != hash);
+        let json = serde_json::to_string(&*urls).unwrap_or_default();
+        let _ = fs::write(self.dir.join(INDEX_FILE), json);
+    }
+}
+
+/// 添付ファイルの画像を取り込む (キャッシュがあれば参照、なければ data URL)
+pub async fn load_all(urls: &[String]) -> Vec<LoadedImage> {
+    match installed() {
+        Some(cache) => cache.load_all(urls).await,
+        None => images::load_all(urls).await,
+    }
+}
+
+/// メッセージに含まれる参照のハッシュ
+pub fn references(message: &Message) -> Vec<String> {
+    let content = match message {
+        Message::User { content, .. } | Message::Tool { content, .. } | Message::Assistant { content, .. } => content,
+        _ => return Vec::new(),
+    };
+    content
+        .iter()
+        .filter_map(|c| match c {
+            MessageContext::Image(image) => hash_of(&image.url).map(str::to_string),
+            MessageContext::Text(_) => None,
+        })
+        .collect()
+}
+
+/// API に送る直前の履歴を整える
+/// 参照を data URL に戻し、古い発言の画像は low detail に、さらに古いものは説明文にする
+pub fn prepare(cache: Option<&ImageCache>, messages: &mut VecDeque<Message>) {
+    let mut user_turns = 0;
+    for message in messages.iter_mut().rev() {
+        let content = match message {
+            Message::User { content, .. } => {
+                user_turns += 1;
+                content
+            }
+            Message::Tool { content, .. } | Message::Assistant { content, .. } => content,
+            _ => continue,
+        };
+        for part in content.iter_mut() {
+            let MessageContext::Image(image) = part else {
+                continue;
+            };
+            if let Some(replacement) = prepare_image(cache, image, user_turns) {
+                *part = replacement;
+            }
+        }
+    }
+}
+
+/// 置き換えが必要なら置き換え後の内容を返す
+fn prepare_image(cache: Option<&ImageCache>, image: &mut MessageImage, age: usize) -> Option<MessageContext> {
+    let hash = hash_of(&image.url).map(str::to_string);
+    if age > LOW_DETAIL_TURNS {
+        let caption = hash.as_deref().and_then(|h| cache?.caption(h));
+        return Some(MessageContext::Text(match caption {
+            Some(caption) => format!("[earlier image: {}]", caption),
+            None => "[earlier image omitted]".to_string(),
+        }));
+    }
+    if let Some(hash) = hash {
+        match cache.and_then(|cache| cache.data_url(&hash)) {
+            Some(url) => image.url = url,
+            None => return Some(MessageContext::Text("[image no longer available]".to_string())),
+        }
+    }
+    if age > FULL_DETAIL_TURNS {
+        image.detail = Some("low".to_string());
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_http::{self, Response};
+    use crate::test_support::{gif, image, png};
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+    use tempfile::TempDir;
+
+    /// 色だけが違う 8x8 の PNG
+    fn red(level: u8) -> Vec<u8> {
+        png(8, 8, [level, 0, 0])
+    }
+
+    /// `[META]` のない、部品だけのユーザーメッセージ
+    fn user(content: Vec<MessageContext>) -> Message {
+        Message::User { name: Some("42".into()), content }
+    }
+
+    #[tokio::test]
+    async fn stores_each_image_once_and_survives_expired_urls() {
+        let dir = TempDir::new().unwrap();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let (addr, _handle) = local_http::serve("127.0.0.1:0", move |request| {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                match request.path.split('?').next().unwrap() {
+                    "/a.png" | "/copy-of-a.png" => Response { status: 200, headers: vec![], body: red(10) },
+                    "/b.png" => Response { status: 200, headers: vec![], body: red(200) },
+                    // 期限切れの CDN の URL
+                    _ => Response { status: 404, headers: vec![], body: Vec::new() },
+                }
+            }
+        }).await.unwrap();
+        let url = |path: &str| format!("http://{}/{}", addr, path);
+        let cache = ImageCache::open(dir.path()).unwrap();
+
+        let refs = cache.load_all(&[url("a.png?ex=1"), url("copy-of-a.png"), url("b.png")]).await;
+        assert_eq!(refs.len(), 2, "the same image is referenced once");
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        let stored = fs::read_dir(dir.path()).unwrap().filter(|e| e.as_ref().unwrap().path().extension().unwrap() == "webp").count();
+        assert_eq!(stored, 2);
+
+        // 署名が変わった URL でも取得し直さない (索引は開き直しても残る)
+        let reopened = ImageCache::open(dir.path()).unwrap();
+        assert_eq!(reopened.load_all(&[url("a.png?ex=2&hm=new")]).await[0].url, refs[0].url);
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        assert!(reopened.data_url(hash_of(&refs[0].url).unwrap()).unwrap().starts_with("data:image/webp;base64,"));
+        assert_eq!(reopened.data_url("../urls"), None);
+
+        reopened.remove(hash_of(&refs[0].url).unwrap());
+        assert_eq!(reopened.lookup_url(&url("a.png")), None);
+
+        // コンタクトシートの情報も一緒に残す
+        let hash = reopened.insert(&gif(4, 4, 500, &[[0, 0, 0, 255], [80, 0, 0, 255], [160, 0, 0, 255]])).unwrap();
+        assert_eq!(reopened.clip(&hash).unwrap().total_frames, Some(3));
+        assert_eq!(reopened.clip(hash_of(&refs[1].url).unwrap()), None);
+    }
+
+    #[test]
+    fn url_keys_drop_only_signatures() {
+        assert_eq!(url_key("https://cdn.discordapp.com/a.png?ex=1&is=2&hm=3#x"), "https://cdn.discordapp.com/a.png");
+        assert_eq!(url_key("https://example.com/img.php?id=1&ex=9"), "https://example.com/img.php?id=1");
+        assert_ne!(url_key("https://example.com/img.php?id=1"), url_key("https://example.com/img.php?id=2"));
+    }
+
+    #[tokio::test]
+    async fn query_parameters_distinguish_images() {
+        let dir = TempDir::new().unwrap();
+        let (addr, _handle) = local_http::serve("127.0.0.1:0", |request| async move {
+            match request.path.as_str() {
+                "/img.php?id=1" => Response { status: 200, headers: vec![], body: red(10) },
+                _ => Response { status: 200, headers: vec![], body: red(200) },
+            }
+        }).await.unwrap();
+        let cache = ImageCache::open(dir.path()).unwrap();
+        let client = reqwest::Client::new();
+        let (first, _) = cache.load(&client, &format!("http://{}/img.php?id=1", addr), MAX_IMAGE_BYTES).await.unwrap();
+        let (second, fetched) = cache.load(&client, &format!("http://{}/img.php?id=2", addr), MAX_IMAGE_BYTES).await.unwrap();
+        assert_ne!(first, second);
+        assert!(fetched > 0, "the second image is fetched, not taken from the index");
+    }
+
+    #[test]
+    fn prepare_resolves_references_and_downgrades_old_images() {
+        let dir = TempDir::new().unwrap();
+        let cache = ImageCache::open(dir.path()).unwrap();
+        let old = cache.insert(&red(1)).unwrap();
+        let middle = cache.insert(&red(2)).unwrap();
+        let recent = cache.insert(&red(3)).unwrap();
+        cache.set_caption(&old, "a cat on a sofa");
+
+        let mut messages: VecDeque<Message> = VecDeque::new();
+        messages.push_back(user(vec![MessageContext::Text("oldest".into()), image("data:image/png;base64,AAAA")]));
+        messages.push_back(user(vec![image(&reference(&old))]));
+        for _ in 0..LOW_DETAIL_TURNS - 3 {
+            messages.push_back(user(vec![MessageContext::Text("chat".into())]));
+        }
+        // 新しいほうから 3 番目 (low detail)、2 番目、1 番目
+        messages.push_back(user(vec![image(&reference(&middle)), image(&reference("deadbeef"))]));
+        messages.push_back(user(vec![MessageContext::Text("chat".into())]));
+        messages.push_back(user(vec![image(&reference(&recent))]));
+        let before = messages.clone();
+
+        prepare(Some(&cache), &mut messages);
+        let parts = |i: usize| match &messages[i] {
+            Message::User { content, .. } => content.clone(),
+            _ => unreachable!(),
+        };
+        assert!(matches!(&parts(0)[1], MessageContext::Text(t) if t == "[earlier image omitted]"));
+        assert!(matches!(&parts(1)[0], MessageContext::Text(t) if t == "[earlier image: a cat on a sofa]"));
+        let n = messages.len();
+        assert!(matches!(&parts(n - 3)[0], MessageContext::Image(i) if i.url.starts_with("data:image/webp") && i.detail.as_deref() == Some("low")));
+        assert!(matches!(&parts(n - 3)[1], MessageContext::Text(t) if t == "[image no longer available]"));
+        assert!(matches!(&parts(n - 1)[0], MessageContext::Image(i) if i.url.starts_with("data:image/webp") && i.detail.is_none()));
+
+        // 履歴に残すほうは参照のまま
+        assert_eq!(references(&before[n - 1]), vec![recent]);
+    }
+}
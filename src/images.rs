@@ -0,0 +1,375 @@
+//! 画像の取り込み
+//!
+//! 添付された画像 (agent) と `image_captioner` に渡された URL を同じ手順でモデルに渡せる形にする。
+//! 上限付きで取得し、拡張子ではなく中身から形式を判定してデコードし、EXIF の向きを反映・
+//! 透過を白で合成・大きすぎれば縮小してから、写真は JPEG、図やスクリーンショットは可逆の WebP にする。
+//! 小さい画像は拡大しない。
+//! アニメーションと短い動画は `contact_sheet` でフレームを並べた 1 枚にする。
+
+use std::io::Cursor;
+
+use base64::prelude::*;
+use image::{
+    codecs::{gif::GifDecoder, jpeg::JpegEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    metadata::Orientation,
+    AnimationDecoder, DynamicImage, ExtendedColorType, ImageDecoder, ImageEncoder, ImageFormat, ImageReader, RgbImage,
+};
+use log::warn;
+
+use crate::{attachments, contact_sheet::{self, ClipInfo}};
+
+/// 1 枚の画像の上限
+pub const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+/// 1 メッセージで取得する画像の合計の上限
+pub const MAX_TOTAL_BYTES: usize = 50 * 1024 * 1024;
+/// これより長い辺は縮小する
+pub const MAX_LONG_SIDE: u32 = 2000;
+const JPEG_QUALITY: u8 = 85;
+/// 色数を数えるときの標本 (縦横それぞれ)
+const COLOR_SAMPLES: u32 = 128;
+/// 標本の中の色がこれより多ければ写真とみなす
+const PHOTO_COLORS: usize = 1024;
+
+/// 受け付ける画像の形式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceFormat {
+    Png,
+    Jpeg,
+    Gif,
+    WebP,
+}
+
+impl SourceFormat {
+    /// 先頭のバイト列から形式を判定する (CDN の Content-Type や拡張子は当てにしない)
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(Self::Jpeg)
+        } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            Some(Self::Gif)
+        } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+            Some(Self::WebP)
+        } else {
+            None
+        }
+    }
+
+    fn image_format(self) -> ImageFormat {
+        match self {
+            Self::Png => ImageFormat::Png,
+            Self::Jpeg => ImageFormat::Jpeg,
+            Self::Gif => ImageFormat::Gif,
+            Self::WebP => ImageFormat::WebP,
+        }
+    }
+}
+
+/// モデルに渡す形式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// 写真 (非可逆で小さくする)
+    Jpeg,
+    /// 図やスクリーンショット (文字がにじまないように可逆)
+    WebP,
+}
+
+impl OutputFormat {
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::WebP => "image/webp",
+        }
+    }
+}
+
+/// モデルに渡せる形にした画像
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncodedImage {
+    pub format: OutputFormat,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+    /// アニメーションや動画をコンタクトシートにしたときの元の情報
+    pub clip: Option<ClipInfo>,
+}
+
+impl EncodedImage {
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.format.mime(), BASE64_STANDARD.encode(&self.data))
+    }
+}
+
+/// 画像をデコードする (GIF は最初のフレーム、JPEG と WebP は EXIF の向きを反映する)
+pub fn decode(bytes: &[u8]) -> Result<DynamicImage, String> {
+    let format = SourceFormat::sniff(bytes).ok_or("unsupported image format")?;
+    if format == SourceFormat::Gif {
+        let decoder = GifDecoder::new(Cursor::new(bytes)).map_err(|e| format!("broken gif: {}", e))?;
+        let frame = decoder
+            .into_frames()
+            .next()
+            .ok_or("gif has no frames")?
+            .map_err(|e| format!("broken gif: {}", e))?;
+        return Ok(DynamicImage::ImageRgba8(frame.into_buffer()));
+    }
+    let mut decoder = ImageReader::with_format(Cursor::new(bytes), format.image_format())
+        .into_decoder()
+        .map_err(|e| format!("broken image: {}", e))?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|e| format!("broken image: {}", e))?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// 透過を白い背景に合成する
+pub fn flatten(image: &DynamicImage) -> RgbImage {
+    if !image.color().has_alpha() {
+        return image.to_rgb8();
+    }
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let p = rgba.get_pixel(x, y);
+        let alpha = p[3] as f32 / 255.0;
+        let blend = |c: u8| (c as f32 * alpha + 255.0 * (1.0 - alpha)).round() as u8;
+        image::Rgb([blend(p[0]), blend(p[1]), blend(p[2])])
+    })
+}
+
+/// 長い辺が `max_long_side` を超えるときだけ縮小する
+pub fn fit(image: RgbImage, max_long_side: u32) -> RgbImage {
+    let (w, h) = image.dimensions();
+    if w.max(h) <= max_long_side {
+        return image;
+    }
+    let scale = max_long_side as f32 / w.max(h) as f32;
+    let (nw, nh) = (((w as f32 * scale).round() as u32).max(1), ((h as f32 * scale).round() as u32).max(1));
+    image::imageops::resize(&image, nw, nh, FilterType::Lanczos3)
+}
+
+/// 色数が多ければ写真とみなす (図やスクリーンショットは色が少ない)
+pub fn is_photo(image: &RgbImage) -> bool {
+    let (w, h) = image.dimensions();
+    let mut colors = std::collections::HashSet::new();
+    for sy in 0..COLOR_SAMPLES.min(h) {
+        for sx in 0..COLOR_SAMPLES.min(w) {
+            let x = sx * w / COLOR_SAMPLES.min(w);
+            let y = sy * h / COLOR_SAMPLES.min(h);
+            colors.insert(image.get_pixel(x, y).0);
+            if colors.len() > PHOTO_COLORS {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// 写真なら JPEG、それ以外は可逆の WebP にする
+pub fn encode(image: &RgbImage) -> Result<EncodedImage, String> {
+    let format = if is_photo(image) { OutputFormat::Jpeg } else { OutputFormat::WebP };
+    let (width, height) = image.dimensions();
+    let mut data = Vec::new();
+    let result = match format {
+        OutputFormat::Jpeg => JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY)
+            .write_image(image.as_raw(), width, height, ExtendedColorType::Rgb8),
+        OutputFormat::WebP => WebPEncoder::new_lossless(&mut data)
+            .write_image(image.as_raw(), width, height, ExtendedColorType::Rgb8),
+    };
+    result.map_err(|e| format!("failed to encode image: {}", e))?;
+    Ok(EncodedImage { format, width, height, data, clip: None })
+}
+
+/// 取得した画像をモデルに渡せる形にする (動くものはコンタクトシートにする)
+pub fn normalize(bytes: &[u8]) -> Result<EncodedImage, String> {
+    if let Some((sheet, clip)) = contact_sheet::build(bytes)? {
+        let mut image = encode(&fit(sheet, MAX_LONG_SIDE))?;
+        image.clip = Some(clip);
+        return Ok(image);
+    }
+    let image = decode(bytes)?;
+    encode(&fit(flatten(&image), MAX_LONG_SIDE))
+}
+
+/// `normalize` をブロッキング用のスレッドで行う (動画は ffmpeg を待つので非同期のタスクを止めない)
+pub async fn normalize_blocking(bytes: Vec<u8>) -> Result<EncodedImage, String> {
+    tokio::task::spawn_blocking(move || normalize(&bytes))
+        .await
+        .map_err(|e| format!("image decoding failed - {}", e))?
+}
+
+/// URL の画像を `max_bytes` まで取得して正規化する
+pub async fn load(client: &reqwest::Client, url: &str, max_bytes: usize) -> Result<(EncodedImage, usize), String> {
+    let (_, bytes) = attachments::fetch(client, url, max_bytes).await?;
+    let fetched = bytes.len();
+    Ok((normalize_blocking(bytes).await?, fetched))
+}
+
+/// 取り込んだ添付ファイル
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoadedImage {
+    /// モデルに渡す URL (data URL かキャッシュの参照)
+    pub url: String,
+    /// 添付ファイル名
+    pub name: String,
+    pub clip: Option<ClipInfo>,
+}
+
+impl LoadedImage {
+    /// コンタクトシートなら画像の前に添える説明
+    pub fn note(&self) -> Option<String> {
+        self.clip.as_ref().map(|clip| format!("[{}: {}]", self.name, clip.describe()))
+    }
+}
+
+/// 画像として取り込む添付ファイルか (動画はコンタクトシートにする)
+pub fn is_visual(url: &str) -> bool {
+    let name = attachments::file_name(url);
+    attachments::is_image(&name) || attachments::is_video(&name)
+}
+
+/// 添付ファイルのうち画像を取得して data URL にする (読めない画像は飛ばす)
+pub async fn load_all(urls: &[String]) -> Vec<LoadedImage> {
+    let client = reqwest::Client::new();
+    let mut total = 0;
+    let mut out = Vec::new();
+    for url in urls.iter().filter(|url| is_visual(url)) {
+        let remaining = MAX_TOTAL_BYTES.saturating_sub(total);
+        if remaining == 0 {
+            break;
+        }
+        match load(&client, url, MAX_IMAGE_BYTES.min(remaining)).await {
+            Ok((image, fetched)) => {
+                total += fetched;
+                out.push(LoadedImage { url: image.data_url(), name: attachments::file_name(url), clip: image.clip });
+            }
+            Err(e) => warn!("Skipping image {}: {}", url, e),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_http::{self, Response};
+    use crate::test_support::gif;
+    use image::{Rgb, Rgba, RgbaImage};
+
+    fn encode_as(image: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bytes), format).unwrap();
+        bytes
+    }
+
+    /// 色数の多い写真のような画像
+    fn photo(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| Rgb([(x * 7 % 256) as u8, (y * 5 % 256) as u8, ((x * y) % 256) as u8]))
+    }
+
+    /// 2 色の図
+    fn diagram(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, _| if x < width / 2 { Rgb([0, 0, 0]) } else { Rgb([255, 255, 255]) })
+    }
+
+    /// JPEG の先頭に向き (EXIF の Orientation) を入れる
+    fn with_orientation(jpeg: &[u8], orientation: u8) -> Vec<u8> {
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0".to_vec();
+        exif.extend_from_slice(&[orientation, 0, 0, 0, 0, 0, 0]);
+        let mut out = jpeg[..2].to_vec();
+        out.extend_from_slice(&[0xFF, 0xE1]);
+        out.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+        out.extend_from_slice(&exif);
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
+
+    #[test]
+    fn sniffs_formats_from_content() {
+        let image = DynamicImage::ImageRgb8(diagram(4, 4));
+        assert_eq!(SourceFormat::sniff(&encode_as(&image, ImageFormat::Png)), Some(SourceFormat::Png));
+        assert_eq!(SourceFormat::sniff(&encode_as(&image, ImageFormat::Jpeg)), Some(SourceFormat::Jpeg));
+        assert_eq!(SourceFormat::sniff(&encode_as(&image, ImageFormat::WebP)), Some(SourceFormat::WebP));
+        assert_eq!(SourceFormat::sniff(&gif(6, 4, 100, &[[1, 2, 3, 255]])), Some(SourceFormat::Gif));
+        assert_eq!(SourceFormat::sniff(b"<html>not an image</html>"), None);
+        assert_eq!(normalize(b"<html>").unwrap_err(), "unsupported image format");
+    }
+
+    #[test]
+    fn photos_become_jpeg_and_diagrams_lossless_webp() {
+        let photo = normalize(&encode_as(&DynamicImage::ImageRgb8(photo(300, 200)), ImageFormat::Png)).unwrap();
+        assert_eq!((photo.format, photo.width, photo.height), (OutputFormat::Jpeg, 300, 200));
+        assert!(photo.data_url().starts_with("data:image/jpeg;base64,"));
+
+        let png = encode_as(&DynamicImage::ImageRgb8(diagram(300, 200)), ImageFormat::Png);
+        let diagram = normalize(&png).unwrap();
+        assert_eq!(diagram.format, OutputFormat::WebP);
+        // 可逆なので色はそのまま
+        let decoded = image::load_from_memory(&diagram.data).unwrap().to_rgb8();
+        assert_eq!(decoded.get_pixel(10, 10), &Rgb([0, 0, 0]));
+        assert_eq!(decoded.get_pixel(290, 10), &Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn large_images_shrink_and_small_ones_are_not_upscaled() {
+        let large = normalize(&encode_as(&DynamicImage::ImageRgb8(diagram(3000, 1000)), ImageFormat::Png)).unwrap();
+        assert_eq!((large.width, large.height), (2000, 667));
+        let small = normalize(&encode_as(&DynamicImage::ImageRgb8(diagram(40, 30)), ImageFormat::Png)).unwrap();
+        assert_eq!((small.width, small.height), (40, 30));
+    }
+
+    #[test]
+    fn transparency_is_flattened_onto_white() {
+        let mut rgba = RgbaImage::from_pixel(8, 8, Rgba([0, 0, 0, 0]));
+        rgba.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        let flat = flatten(&DynamicImage::ImageRgba8(rgba));
+        assert_eq!(flat.get_pixel(0, 0), &Rgb([255, 0, 0]));
+        assert_eq!(flat.get_pixel(5, 5), &Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn exif_orientation_is_applied() {
+        let jpeg = encode_as(&DynamicImage::ImageRgb8(diagram(40, 20)), ImageFormat::Jpeg);
+        let rotated = decode(&with_orientation(&jpeg, 6)).unwrap();
+        assert_eq!((rotated.width(), rotated.height()), (20, 40));
+        let upright = decode(&with_orientation(&jpeg, 1)).unwrap();
+        assert_eq!((upright.width(), upright.height()), (40, 20));
+    }
+
+    #[test]
+    fn gifs_use_the_first_frame_and_broken_ones_are_errors() {
+        let first = decode(&gif(6, 4, 100, &[[0, 0, 255, 255], [255, 0, 0, 255]])).unwrap().to_rgb8();
+        assert_eq!(first.get_pixel(1, 1), &Rgb([0, 0, 255]));
+        // 以前は unwrap で落ちていた
+        assert!(decode(b"GIF89a\x01\x00").unwrap_err().starts_with("broken gif"));
+    }
+
+    #[tokio::test]
+    async fn loads_only_readable_images_within_the_cap() {
+        let png = encode_as(&DynamicImage::ImageRgb8(diagram(30, 20)), ImageFormat::Png);
+        let (addr, _handle) = local_http::serve("127.0.0.1:0", move |request| {
+            let png = png.clone();
+            async move {
+                let body = match request.path.split('?').next().unwrap() {
+                    // Discord の CDN は画像でも octet-stream を返すことがある
+                    "/a.png" => png,
+                    "/huge.jpg" => vec![0xFF; MAX_IMAGE_BYTES + 1],
+                    "/fake.webp" => b"<html>expired</html>".to_vec(),
+                    _ => return Response { status: 404, headers: vec![], body: Vec::new() },
+                };
+                Response { status: 200, headers: vec![("Content-Type".into(), "application/octet-stream".into())], body }
+            }
+        }).await.unwrap();
+        let url = |path: &str| format!("http://{}/{}", addr, path);
+
+        let loaded = load_all(&[
+            url("a.png?ex=1&is=2"),
+            url("huge.jpg"),
+            url("fake.webp"),
+            url("missing.gif"),
+            url("notes.txt"),
+        ]).await;
+        assert_eq!(loaded.len(), 1);
+        assert!(loaded[0].url.starts_with("data:image/webp;base64,"));
+        assert_eq!((loaded[0].name.as_str(), loaded[0].note()), ("a.png", None));
+    }
+}
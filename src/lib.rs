@@ -1,2 +1,23 @@
+pub mod attachments;
+pub mod cassette;
+pub mod contact_sheet;
+pub mod digest;
+pub mod history;
+pub mod image_cache;
+pub mod images;
+mod local_http;
+pub mod markdown;
+pub mod mock_llm;
+pub mod moderation;
+pub mod openai_api;
 pub mod prefix;
-pub mod tools;
\ No newline at end of file
+pub mod privacy;
+pub mod reactions;
+pub mod sources;
+pub mod splitter;
+#[cfg(test)]
+mod test_support;
+pub mod tools;
+pub mod transcription;
+pub mod turn;
+pub mod untrusted;
@@ -0,0 +1,121 @@
+//! テスト用のローカル HTTP サーバー
+//!
+//! カセットやモックの LLM サーバーのように、`127.0.0.1` で JSON を返すだけの
+//! 小さなサーバー向け。actix-web の上で、どのパスのリクエストも 1 つのハンドラに渡す。
+
+use std::{future::Future, io, net::SocketAddr};
+
+use actix_web::{dev::ServerHandle, http::StatusCode, web, App, HttpRequest, HttpResponse, HttpServer};
+use serde_json::Value;
+use tokio::task::JoinHandle;
+
+/// 受け付けるリクエストボディの上限
+const MAX_REQUEST_BYTES: usize = 32 * 1024 * 1024;
+
+pub(crate) struct Request {
+    pub method: String,
+    /// クエリ文字列を含むパス
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn from_actix(request: &HttpRequest, body: web::Bytes) -> Self {
+        Self {
+            method: request.method().to_string(),
+            path: request.uri().path_and_query().map_or("/", |p| p.as_str()).to_string(),
+            headers: request
+                .headers()
+                .iter()
+                .map(|(k, v)| (k.to_string(), String::from_utf8_lossy(v.as_bytes()).into_owned()))
+                .collect(),
+            body: body.to_vec(),
+        }
+    }
+}
+
+pub(crate) struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn json(status: u16, body: &Value) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: impl ToString) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    fn into_actix(self) -> HttpResponse {
+        let mut response = HttpResponse::build(StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR));
+        for (name, value) in self.headers {
+            response.append_header((name, value));
+        }
+        response.body(self.body)
+    }
+}
+
+/// 起動中のサーバー。drop すると止まる
+pub(crate) struct LocalServer {
+    handle: ServerHandle,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl LocalServer {
+    /// サーバーが止まるまで待つ
+    pub async fn wait(&mut self) {
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        // 停止の指示は呼んだ時点で送られるので、返り値の Future は待たなくてよい
+        drop(self.handle.stop(false));
+    }
+}
+
+/// `addr` で待ち受けて、リクエストごとに `handler` を呼ぶ
+pub(crate) async fn serve<F, Fut>(addr: &str, handler: F) -> io::Result<(SocketAddr, LocalServer)>
+where
+    F: Fn(Request) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Response> + 'static,
+{
+    let server = HttpServer::new(move || {
+        let handler = handler.clone();
+        App::new()
+            .app_data(web::PayloadConfig::new(MAX_REQUEST_BYTES))
+            .default_service(web::to(move |request: HttpRequest, body: web::Bytes| {
+                let response = handler(Request::from_actix(&request, body));
+                async move { response.await.into_actix() }
+            }))
+    })
+    .workers(1)
+    .disable_signals()
+    .shutdown_timeout(0)
+    .bind(addr)?;
+    let local_addr = server
+        .addrs()
+        .first()
+        .copied()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "no address bound"))?;
+    let server = server.run();
+    let handle = server.handle();
+    Ok((local_addr, LocalServer { handle, task: tokio::spawn(server) }))
+}
@@ -1,181 +1,54 @@
-use std::sync::Arc;
-use dashmap::DashMap;
+use std::sync::{atomic::AtomicBool, Arc};
 mod agent;
+mod api;
+mod bot;
 mod handler;
+mod platform;
+mod repl;
 
+use bot::{Bot, BotSettings};
 use handler::Handler;
 
 use call_agent::chat::{api::{UserLocation, WebSearchOptions}, client::{ModelConfig, OpenAIClient}};
-use observer::{prefix::{ASSISTANT_NAME, DISCORD_TOKEN, ENABLE_BROWSER_TOOL, ENABLE_GET_TIME_TOOL, ENABLE_IMAGE_CAPTIONER_TOOL, ENABLE_MEMORY_TOOL, ENABLE_WEB_DEPLOY_TOOL, MAIN_MODEL_API_KEY, MAIN_MODEL_ENDPOINT, MODEL_GENERATE_MAX_TOKENS, MODEL_NAME}, tools::{self, browsing_worker::BrowsingWorker, get_time::GetTime, image_captioner::ImageCaptionerTool, web_deploy::WebDeploy, web_scraper::Browser}};
+use observer::{attachments::{self, AttachmentStore}, image_cache::{self, ImageCache}, moderation::{Moderator, ModeratorConfig}, openai_api::ApiGateway, prefix::{API_KEYS, ASSISTANT_NAME, DISCORD_TOKEN, ENABLE_BROWSER_TOOL, ENABLE_GET_TIME_TOOL, ENABLE_IMAGE_CAPTIONER_TOOL, DEFAULT_TIMEZONE, ENABLE_MEMORY_TOOL, ENABLE_SCHEDULER, ENABLE_WEB_DEPLOY_TOOL, IMAGE_GENERATION, IRC, MAIN_MODEL_API_KEY, MAIN_MODEL_ENDPOINT, MAX_JOBS_PER_USER, MODEL_GENERATE_MAX_TOKENS, MODEL_NAME, MODERATION, TRANSCRIPTION}, tools::{self, attachment_reader::AttachmentReader, browsing_worker::BrowsingWorker, get_time::GetTime, image_captioner::ImageCaptionerTool, image_generator::{ImageGenerator, ImageGeneratorConfig}, scheduler::{Scheduler, SchedulerTool}, web_deploy::WebDeploy, web_scraper::Browser}, transcription::{Transcriber, TranscriberConfig}};
 use tools::memory::MemoryTool;
 
 use serenity::model::prelude::*;
 use serenity::prelude::*;
 use log::error;
-use regex::Regex;
 
-use reqwest::Client as ReqwestClient;
-use std::io::Cursor;
-use image::{codecs::gif::GifDecoder, io::Reader as ImageReader, AnimationDecoder, DynamicImage, GenericImageView, RgbaImage};
-use base64;
-
-async fn fetch_and_encode_images(urls: &[String]) -> Vec<String> {
-    println!("fetch_and_encode_images: {:?}", urls);
-    // 拡張子チェック＆クエリ対応
-    let ext_re = Regex::new(r"(?i)\.(png|jpe?g|gif|webp)(?:[?#].*)?$").unwrap();
-    // パラメータなし画像URLを即取得する正規表現
-    let strict_ext_re = Regex::new(r"(?i)\.(png|jpe?g|gif|webp)$").unwrap();
-    let client = ReqwestClient::new();
-    let mut total_bytes = 0u64;
-    let mut out = Vec::new();
-
-    for url in urls.iter().filter(|u| ext_re.is_match(u)) {
-        // パラメータなし URL は問答無用でオリジナルを取得
-        if strict_ext_re.is_match(url) {
-            if let Ok(resp) = client.get(url).send().await {
-                if let Ok(bytes) = resp.bytes().await {
-                    // 拡張子から MIME を決定
-                    let ext = strict_ext_re
-                        .captures(url)
-                        .and_then(|c| c.get(1))
-                        .unwrap()
-                        .as_str()
-                        .to_lowercase();
-                    let mime = match ext.as_str() {
-                        "png"  => "image/png",
-                        "jpg" | "jpeg" => "image/jpeg",
-                        "gif"  => "image/gif",
-                        "webp" => "image/webp",
-                        _      => "application/octet-stream",
-                    };
-                    out.push(format!("data:{};base64,{}", mime, base64::encode(&bytes)));
-                }
-            }
-            continue;
-        }
-        let ext = ext_re.captures(url).and_then(|c| c.get(1)).unwrap().as_str().to_lowercase();
-        // HEAD でサイズチェック
-        let len = client.head(url).send().await
-            .ok()
-            .and_then(|r| r.headers()
-                .get(reqwest::header::CONTENT_LENGTH)
-                .and_then(|v| v.to_str().ok()?.parse().ok()))
-            .unwrap_or(0);
-        if len == 0 || len > 20 * 1024 * 1024 || total_bytes + len > 50 * 1024 * 1024 {
-            continue;
-        }
-        // GET してバイト列取得
-        let bytes = match client.get(url).send().await {
-            Ok(resp) => match resp.bytes().await {
-                Ok(b) => b,
-                Err(_) => continue,
-            },
-            Err(_) => continue,
-        };
-        // 解像度チェック
-        let reader = match ext.as_str() {
-            "gif" => {
-                let decoder = GifDecoder::new(Cursor::new(&bytes)).unwrap();
-                let mut frames = decoder.into_frames();
-        
-                // Frame を取り出し
-                let frame = match frames.next() {
-                    Some(Ok(frame)) => frame,
-                    _ => continue,
-                };
-        
-                // Frame をバッファ（RgbaImage）に変換
-                let buf: RgbaImage = frame.into_buffer();
-                DynamicImage::ImageRgba8(buf)
-            }
-            _ => {
-                // 通常の画像
-                let img = match ImageReader::new(Cursor::new(&bytes)).with_guessed_format() {
-                    Ok(reader) => match reader.decode() {
-                        Ok(i) => i,
-                        Err(_) => continue,
-                    },
-                    Err(_) => continue,
-                };
-                // 透過があれば白背景でフラット化
-                if img.color().has_alpha() {
-                    let (w, h) = img.dimensions();
-                    let mut bg = RgbaImage::new(w, h);
-                    for (x, y, p) in img.to_rgba8().enumerate_pixels() {
-                        let alpha = p.0[3] as f32 / 255.0;
-                        let inv = 1.0 - alpha;
-                        let r = (p[0] as f32 * alpha + 255.0 * inv) as u8;
-                        let g = (p[1] as f32 * alpha + 255.0 * inv) as u8;
-                        let b = (p[2] as f32 * alpha + 255.0 * inv) as u8;
-                        bg.put_pixel(x, y, image::Rgba([r, g, b, 255]));
-                    }
-                    DynamicImage::ImageRgba8(bg)
-                } else {
-                    img
-                }
-            }
-        };
-        // 解像度を調整（長辺>2000なら縮小、短辺<512なら拡大）
-        let (w, h) = reader.dimensions();
-        let mut img = reader;
-        // 長辺が2000pxを超える場合は縮小
-        if img.dimensions().0.max(img.dimensions().1) > 2000 {
-            let long = img.dimensions().0.max(img.dimensions().1) as f32;
-            let scale = 2000.0 / long;
-            img = img.resize(
-                (w as f32 * scale) as u32,
-                (h as f32 * scale) as u32,
-                image::imageops::FilterType::Lanczos3,
-            );
-        }
-        // 短辺が512px未満の場合は拡大
-        if img.dimensions().0.min(img.dimensions().1) < 512 {
-            let (w2, h2) = img.dimensions();
-            let short = w2.min(h2) as f32;
-            let scale = 512.0 / short;
-            img = img.resize(
-                (w2 as f32 * scale) as u32,
-                (h2 as f32 * scale) as u32,
-                image::imageops::FilterType::Lanczos3,
-            );
-        }
-        // PNGで再エンコード → data URL
-        let mut buf = Vec::new();
-        if img
-            .write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png)
-            .is_err()
-        {
-            continue;
-        }
-        total_bytes += len;
-        out.push(format!("data:image/png;base64,{}", base64::encode(&buf)));
+/// 設定からモデレーションを作る (無効なら None、ルールが不正なら起動を止める)
+fn build_moderator() -> Option<Arc<Moderator>> {
+    if !MODERATION.enable {
+        return None;
     }
-
-    out
+    let config = ModeratorConfig {
+        words: MODERATION.words.clone(),
+        patterns: MODERATION.patterns.clone(),
+        endpoint: Some(MODERATION.endpoint.clone()),
+        api_key: Some(MODERATION.api_key.clone()),
+        model: MODERATION.model.clone(),
+    };
+    Some(Arc::new(Moderator::new(config).expect("Invalid moderation settings")))
 }
 
+/// 設定から音声の書き起こしを作る (無効なら None)
+fn build_transcriber() -> Option<Arc<Transcriber>> {
+    if !TRANSCRIPTION.enable {
+        return None;
+    }
+    let config = TranscriberConfig {
+        endpoint: TRANSCRIPTION.endpoint.clone(),
+        api_key: Some(TRANSCRIPTION.api_key.clone()),
+        model: TRANSCRIPTION.model.clone(),
+        language: Some(TRANSCRIPTION.language.clone()),
+    };
+    Some(Arc::new(Transcriber::new(config)))
+}
 
-
-#[tokio::main]
-async fn main() {
-    // ロガーの初期化
-    env_logger::Builder::new()
-        .filter_level(log::LevelFilter::Debug)
-        .filter_module("serenity", log::LevelFilter::Off) // serenityクレートのログを除外
-        .filter_module("reqwest", log::LevelFilter::Off) // reqwestクレートのログを除外
-        .filter_module("hyper", log::LevelFilter::Off) // hyperクレートのログを除外
-        .filter_module("rustls", log::LevelFilter::Off) // rustlsクレートのログを除外
-        .filter_module("h2", log::LevelFilter::Off) // h2クレートのログを除外
-        .filter_module("tungstenite", log::LevelFilter::Off) // tungsteniteクレートのログを除外
-        .filter_module("tracing", log::LevelFilter::Off) // tracingクレートのログを除外
-        .filter_module("html5ever", log::LevelFilter::Off) // html5everクレートのログを除外
-        .filter_module("selectors", log::LevelFilter::Off) // selectorsクレートのログを除外
-        .filter_module("playwright", log::LevelFilter::Off) // markup5everクレートのログを除外
-        .init();
-
-    // Discord Bot のトークンを取得
-    let token = *DISCORD_TOKEN;
-
+/// ツールを定義した OpenAIClient を作る
+/// web_deploy_tool・scheduler・memory_tool が有効ならそれぞれの本体も返す
+async fn build_base_client(moderator: Option<Arc<Moderator>>) -> (OpenAIClient, Option<Arc<WebDeploy>>, Option<Arc<Scheduler>>, Option<Arc<MemoryTool>>) {
     // モデル設定
     let conf = ModelConfig {
         model: MODEL_NAME.to_string(),
@@ -200,17 +73,24 @@ async fn main() {
     if *ENABLE_BROWSER_TOOL {
         base_client.def_tool(Arc::new(Browser::new()));
     }
-    if *ENABLE_MEMORY_TOOL {
-        base_client.def_tool(Arc::new(MemoryTool::new()));
-    }
+    let memory = if *ENABLE_MEMORY_TOOL {
+        let memory = Arc::new(MemoryTool::new());
+        base_client.def_tool(memory.clone());
+        Some(memory)
+    } else {
+        None
+    };
     if *ENABLE_GET_TIME_TOOL {
         base_client.def_tool(Arc::new(GetTime::new()));
     }
-    if *ENABLE_WEB_DEPLOY_TOOL {
-        let web_deploy = Arc::new(WebDeploy::new().await);
-        web_deploy.start_server("0.0.0.0:80".to_string());
-        base_client.def_tool(web_deploy);
-    }
+    let web_deploy = if *ENABLE_WEB_DEPLOY_TOOL {
+        // モデルが自分で書く記事も公開前にモデレーションにかける
+        let web_deploy = Arc::new(WebDeploy::new().await.with_moderator(moderator));
+        base_client.def_tool(web_deploy.clone());
+        Some(web_deploy)
+    } else {
+        None
+    };
     if *ENABLE_IMAGE_CAPTIONER_TOOL {
         base_client.def_tool(Arc::new(
             ImageCaptionerTool::new({
@@ -235,6 +115,17 @@ async fn main() {
             })
         ));
     }
+    if IMAGE_GENERATION.enable {
+        base_client.def_tool(Arc::new(ImageGenerator::new(ImageGeneratorConfig {
+            endpoint: IMAGE_GENERATION.endpoint.clone(),
+            api_key: Some(IMAGE_GENERATION.api_key.clone()),
+            model: IMAGE_GENERATION.model.clone(),
+            default_size: IMAGE_GENERATION.default_size.clone(),
+            default_quality: IMAGE_GENERATION.default_quality.clone(),
+            rate_cost: IMAGE_GENERATION.rate_cost,
+        })));
+    }
+    base_client.def_tool(Arc::new(AttachmentReader::new()));
     base_client.def_tool(Arc::new(
         BrowsingWorker::new({
             let mut c = OpenAIClient::new(
@@ -265,22 +156,98 @@ async fn main() {
         })
         )
     );
+    let scheduler = if *ENABLE_SCHEDULER {
+        let default_timezone = DEFAULT_TIMEZONE.parse().unwrap_or_else(|_| {
+            error!("Unknown timezone {}, using UTC", *DEFAULT_TIMEZONE);
+            chrono_tz::UTC
+        });
+        let scheduler = Arc::new(Scheduler::load("./data/schedule.json", *MAX_JOBS_PER_USER, default_timezone));
+        base_client.def_tool(Arc::new(SchedulerTool::new(scheduler.clone())));
+        Some(scheduler)
+    } else {
+        None
+    };
     base_client.set_model_config(&conf);
-    let base_client = Arc::new(base_client);
+    (base_client, web_deploy, scheduler, memory)
+}
 
-    let channels = DashMap::new();
+#[tokio::main]
+async fn main() {
+    // `observer repl [session]` ならターミナルで、`observer irc` なら IRC だけで起動する
+    let args: Vec<String> = std::env::args().collect();
+    let repl_session = (args.get(1).map(String::as_str) == Some("repl"))
+        .then(|| args.get(2).cloned().unwrap_or_else(|| repl::DEFAULT_SESSION.to_string()));
+    let irc_only = args.get(1).map(String::as_str) == Some("irc");
 
+    // ロガーの初期化
+    env_logger::Builder::new()
+        .filter_level(if repl_session.is_some() { log::LevelFilter::Warn } else { log::LevelFilter::Debug })
+        .filter_module("serenity", log::LevelFilter::Off) // serenityクレートのログを除外
+        .filter_module("reqwest", log::LevelFilter::Off) // reqwestクレートのログを除外
+        .filter_module("hyper", log::LevelFilter::Off) // hyperクレートのログを除外
+        .filter_module("rustls", log::LevelFilter::Off) // rustlsクレートのログを除外
+        .filter_module("h2", log::LevelFilter::Off) // h2クレートのログを除外
+        .filter_module("tungstenite", log::LevelFilter::Off) // tungsteniteクレートのログを除外
+        .filter_module("tracing", log::LevelFilter::Off) // tracingクレートのログを除外
+        .filter_module("html5ever", log::LevelFilter::Off) // html5everクレートのログを除外
+        .filter_module("selectors", log::LevelFilter::Off) // selectorsクレートのログを除外
+        .filter_module("playwright", log::LevelFilter::Off) // markup5everクレートのログを除外
+        .init();
+
+    // 取り込んだ画像は内容のハッシュで保存し、履歴には参照だけを残す
+    image_cache::install(ImageCache::open("./data/images").expect("Failed to open the image cache"));
+    // 長い添付ファイルは再起動後も続きを読めるように保存しておく
+    attachments::install(AttachmentStore::open("./data/attachments").expect("Failed to open the attachment store"));
+
+    let moderator = build_moderator();
+    let (base_client, web_deploy, scheduler, memory) = build_base_client(moderator.clone()).await;
+    let bot = Arc::new(
+        Bot::new(Arc::new(base_client), web_deploy.clone(), BotSettings::from_config())
+            .with_scheduler(scheduler)
+            .with_memory(memory)
+            .with_moderator(moderator)
+            .with_transcriber(build_transcriber()),
+    );
+
+    if let Some(session) = repl_session {
+        let store = repl::SessionStore::new(bot.settings.session_dir.clone());
+        repl::Repl::new(bot, store, &session).run().await;
+        return;
+    }
+
+    // 記事のサーバーを起動 (API キーが設定されていれば OpenAI 互換 API も)
+    // REPL では 80 番を使わないように、bot として動くときだけ
+    if let Some(web_deploy) = web_deploy {
+        let api = (!API_KEYS.is_empty()).then(|| Arc::new(ApiGateway::new(
+            API_KEYS.clone(),
+            Arc::new(api::ApiBackend::new(bot.clone())),
+            "./data/api_usage.json",
+        )));
+        web_deploy.start_server("0.0.0.0:80".to_string(), api);
+    }
+
+    bot.load();
+
+    // IRC が設定されていれば Discord と並行して接続する
+    if irc_only {
+        let Some(settings) = IRC.clone() else {
+            error!("IRC is not configured. please edit 'irc' in 'config.json'");
+            return;
+        };
+        platform::irc::run(bot, settings).await;
+        return;
+    }
+    if let Some(settings) = IRC.clone() {
+        tokio::spawn(platform::irc::run(bot.clone(), settings));
+    }
+
+    // Discord Bot のトークンを取得
+    let token = *DISCORD_TOKEN;
 
     // Bot のインテント設定（MESSAGE_CONTENT を含む）
-    let intents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
-    let handler = Handler {
-        base_client: base_client.clone(),
-        channels: channels.clone(),
-        channels_conf: DashMap::new(),
-        user_configs: DashMap::new(),
-    };
-    handler.load();
-    let mut client = Client::builder(&token, intents)
+    let intents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT | GatewayIntents::GUILD_MESSAGE_REACTIONS;
+    let handler = Handler { bot, scheduler_started: AtomicBool::new(false) };
+    let mut client = Client::builder(token, intents)
         .event_handler(handler)
         .await
         .expect("Error creating client");
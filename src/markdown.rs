@@ -0,0 +1,571 @@
+//! Discord 向けの Markdown 変換
+//!
+//! モデルは Discord で表示できない Markdown (表・4 段以上の見出し・HTML) をよく出力するので、
+//! 送信前に Discord で読める形に落とし込む。コードブロックの中身には手を付けない。
+//! IRC には Markdown がないので、`to_irc` で制御コード (太字・斜体など) に置き換える。
+
+use regex::{Captures, Regex};
+use unicode_width::UnicodeWidthStr;
+
+/// 表を等幅ブロックにする場合の最大表示幅 (これを超えると箇条書きにする)
+const MAX_TABLE_WIDTH: usize = 64;
+
+lazy_static::lazy_static! {
+    static ref TABLE_SEPARATOR_RE: Regex = Regex::new(r"^\s*\|?\s*:?-{2,}:?\s*(\|\s*:?-{2,}:?\s*)*\|?\s*$").unwrap();
+    static ref HEADING_RE: Regex = Regex::new(r"^\s{0,3}(#{4,6})\s+(.+?)\s*#*\s*$").unwrap();
+    static ref DISCORD_HEADING_RE: Regex = Regex::new(r"^\s{0,3}#{1,3}\s+\S").unwrap();
+    static ref HORIZONTAL_RULE_RE: Regex = Regex::new(r"^\s{0,3}([-*_])(\s*[-*_]){2,}\s*$").unwrap();
+    static ref HTML_BR_RE: Regex = Regex::new(r"(?i)<br\s*/?>").unwrap();
+    static ref HTML_BOLD_RE: Regex = Regex::new(r"(?is)<(b|strong)>(.*?)</(b|strong)>").unwrap();
+    static ref HTML_ITALIC_RE: Regex = Regex::new(r"(?is)<(i|em)>(.*?)</(i|em)>").unwrap();
+    static ref HTML_STRIKE_RE: Regex = Regex::new(r"(?is)<(s|del|strike)>(.*?)</(s|del|strike)>").unwrap();
+    static ref HTML_UNDERLINE_RE: Regex = Regex::new(r"(?is)<u>(.*?)</u>").unwrap();
+    static ref HTML_CODE_RE: Regex = Regex::new(r"(?is)<code>(.*?)</code>").unwrap();
+    static ref HTML_LINK_RE: Regex = Regex::new(r#"(?is)<a\s+[^>]*href\s*=\s*["']([^"']+)["'][^>]*>(.*?)</a>"#).unwrap();
+    static ref HTML_IMG_RE: Regex = Regex::new(r#"(?is)<img\s+[^>]*src\s*=\s*["']([^"']+)["'][^>]*/?>"#).unwrap();
+    static ref HTML_LIST_ITEM_RE: Regex = Regex::new(r"(?i)<li[^>]*>").unwrap();
+    /// 実在する HTML のタグだけ (`Vec<String>` のような型引数は文中に残す)
+    static ref HTML_TAG_RE: Regex = Regex::new(concat!(
+        r"(?i)</?(?:a|abbr|article|aside|b|big|blockquote|body|br|caption|center|cite|code|col|dd|del|details|div|dl|dt|em|",
+        r"figcaption|figure|font|footer|h[1-6]|head|header|hr|html|i|img|ins|kbd|li|main|mark|nav|ol|p|pre|q|s|samp|section|",
+        r"small|span|strike|strong|sub|summary|sup|table|tbody|td|tfoot|th|thead|tr|tt|u|ul|var)(?:\s[^<>]*)?/?>",
+    )).unwrap();
+    static ref INLINE_CODE_RE: Regex = Regex::new(r"`[^`\n]+`").unwrap();
+    static ref ANY_HEADING_RE: Regex = Regex::new(r"^\s{0,3}#{1,6}\s+(.+?)\s*#*\s*$").unwrap();
+    static ref SUBTEXT_RE: Regex = Regex::new(r"^-#\s+(.*)$").unwrap();
+    static ref BOLD_RE: Regex = Regex::new(r"\*\*([^*\n]+?)\*\*|__([^_\n]+?)__").unwrap();
+    static ref ITALIC_RE: Regex = Regex::new(r"\*([^*\s](?:[^*\n]*[^*\s])?)\*").unwrap();
+    static ref STRIKE_RE: Regex = Regex::new(r"~~([^~\n]+?)~~").unwrap();
+    static ref MD_LINK_RE: Regex = Regex::new(r"\[([^\]\n]+)\]\((\S+?)\)").unwrap();
+    static ref ANGLE_URL_RE: Regex = Regex::new(r"<(https?://[^>\s]+)>").unwrap();
+}
+
+/// IRC の制御コード
+const IRC_BOLD: &str = "\x02";
+const IRC_ITALIC: &str = "\x1d";
+const IRC_STRIKE: &str = "\x1e";
+const IRC_MONOSPACE: &str = "\x11";
+/// 灰色 (Discord の `-#` の代わり)
+const IRC_GREY: &str = "\x0314";
+const IRC_COLOR_END: &str = "\x03";
+
+/// 変換結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscordMarkdown {
+    /// Discord にそのまま送れるテキスト
+    pub text: String,
+    /// 変換した表の数
+    pub tables: usize,
+    /// 変換した見出しの数
+    pub headings: usize,
+    /// 変換した HTML タグの数
+    pub html_tags: usize,
+}
+
+impl DiscordMarkdown {
+    /// 表や見出しを含む構造化された出力かどうか
+    pub fn is_structured(&self) -> bool {
+        self.tables > 0 || self.headings > 1
+    }
+}
+
+/// Markdown を Discord で表示できる形に変換する
+pub fn to_discord(markdown: &str) -> DiscordMarkdown {
+    let mut out = DiscordMarkdown {
+        text: String::new(),
+        tables: 0,
+        headings: 0,
+        html_tags: 0,
+    };
+    let mut lines: Vec<String> = Vec::new();
+    let src: Vec<&str> = markdown.lines().collect();
+    let mut in_fence = false;
+    let mut i = 0;
+
+    while i < src.len() {
+        let line = src[i];
+        if line.trim_start().starts_with("```") {
+            in_fence = !in_fence;
+            lines.push(line.to_string());
+            i += 1;
+            continue;
+        }
+        if in_fence {
+            lines.push(line.to_string());
+            i += 1;
+            continue;
+        }
+
+        // 表: ヘッダ行 + 区切り行 + 本文
+        if is_table_row(line) && src.get(i + 1).is_some_and(|l| TABLE_SEPARATOR_RE.is_match(l)) {
+            let header = parse_row(line);
+            let mut rows = Vec::new();
+            i += 2;
+            while i < src.len() && is_table_row(src[i]) {
+                rows.push(parse_row(src[i]));
+                i += 1;
+            }
+            lines.extend(render_table(&header, &rows));
+            out.tables += 1;
+            continue;
+        }
+
+        if let Some(caps) = HEADING_RE.captures(line) {
+            lines.push(format!("**{}**", caps[2].trim()));
+            out.headings += 1;
+            i += 1;
+            continue;
+        }
+        if DISCORD_HEADING_RE.is_match(line) {
+            out.headings += 1;
+        }
+
+        if HORIZONTAL_RULE_RE.is_match(line) {
+            lines.push("──────────".to_string());
+            i += 1;
+            continue;
+        }
+
+        let (converted, tags) = convert_html(line);
+        out.html_tags += tags;
+        lines.extend(converted.split('\n').map(|s| s.to_string()));
+        i += 1;
+    }
+
+    out.text = lines.join("\n");
+    out
+}
+
+fn is_table_row(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.starts_with('|') && trimmed.len() > 1 && trimmed[1..].contains('|')
+}
+
+fn parse_row(line: &str) -> Vec<String> {
+    let trimmed = line.trim();
+    let inner = trimmed.strip_prefix('|').unwrap_or(trimmed);
+    let inner = inner.strip_suffix('|').unwrap_or(inner);
+    inner
+        .split('|')
+        .map(|cell| {
+            let (cell, _) = convert_html(cell.trim());
+            cell.replace('\n', " ")
+        })
+        .collect()
+}
+
+/// 表を等幅のコードブロック、または幅が広すぎる場合は箇条書きにする
+fn render_table(header: &[String], rows: &[Vec<String>]) -> Vec<String> {
+    let cols = header.len().max(rows.iter().map(|r| r.len()).max().unwrap_or(0));
+    let cell = |row: &[String], c: usize| -> String {
+        row.get(c).map(|s| strip_inline_markup(s)).unwrap_or_default()
+    };
+    let widths: Vec<usize> = (0..cols)
+        .map(|c| {
+            std::iter::once(cell(header, c))
+                .chain(rows.iter().map(|r| cell(r, c)))
+                .map(|s| UnicodeWidthStr::width(s.as_str()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let total = widths.iter().sum::<usize>() + 3 * cols.saturating_sub(1);
+
+    if total <= MAX_TABLE_WIDTH {
+        let pad = |s: String, w: usize| {
+            let fill = w.saturating_sub(UnicodeWidthStr::width(s.as_str()));
+            s + &" ".repeat(fill)
+        };
+        let format_row = |row: &[String]| -> String {
+            (0..cols)
+                .map(|c| pad(cell(row, c), widths[c]))
+                .collect::<Vec<_>>()
+                .join(" | ")
+                .trim_end()
+                .to_string()
+        };
+        let mut out = vec!["```".to_string(), format_row(header)];
+        out.push(widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<_>>().join("-+-"));
+        out.extend(rows.iter().map(|r| format_row(r)));
+        out.push("```".to_string());
+        out
+    } else {
+        // 1 行目のセルを見出しにして残りを「列名: 値」で並べる
+        rows.iter()
+            .map(|row| {
+                let title = row.first().cloned().unwrap_or_default();
+                let rest: Vec<String> = (1..cols)
+                    .filter_map(|c| {
+                        let value = row.get(c)?.trim();
+                        if value.is_empty() {
+                            return None;
+                        }
+                        let name = header.get(c).map(|h| h.trim()).unwrap_or("");
+                        Some(if name.is_empty() { value.to_string() } else { format!("{}: {}", name, value) })
+                    })
+                    .collect();
+                if rest.is_empty() {
+                    format!("- **{}**", title)
+                } else {
+                    format!("- **{}** — {}", title, rest.join(" / "))
+                }
+            })
+            .collect()
+    }
+}
+
+/// 等幅ブロック内では Markdown が効かないので装飾記号を外す
+fn strip_inline_markup(s: &str) -> String {
+    s.replace("**", "").replace("__", "").replace('`', "")
+}
+
+/// HTML タグを Discord の Markdown に置き換える。インラインコードの中は変換しない。
+/// 戻り値は (変換後の文字列, 変換したタグの数)
+fn convert_html(line: &str) -> (String, usize) {
+    if !line.contains('<') && !line.contains('&') {
+        return (line.to_string(), 0);
+    }
+    let mut out = String::new();
+    let mut tags = 0;
+    let mut last = 0;
+    for m in INLINE_CODE_RE.find_iter(line) {
+        let (s, n) = convert_html_segment(&line[last..m.start()]);
+        out.push_str(&s);
+        out.push_str(m.as_str());
+        tags += n;
+        last = m.end();
+    }
+    let (s, n) = convert_html_segment(&line[last..]);
+    out.push_str(&s);
+    (out, tags + n)
+}
+
+fn convert_html_segment(segment: &str) -> (String, usize) {
+    let mut tags = 0;
+    let mut count = |re: &Regex, s: &str| {
+        tags += re.find_iter(s).count();
+    };
+    let mut s = segment.to_string();
+
+    count(&HTML_BR_RE, &s);
+    s = HTML_BR_RE.replace_all(&s, "\n").into_owned();
+    count(&HTML_LINK_RE, &s);
+    s = HTML_LINK_RE.replace_all(&s, |c: &Captures| format!("[{}]({})", &c[2], &c[1])).into_owned();
+    count(&HTML_IMG_RE, &s);
+    s = HTML_IMG_RE.replace_all(&s, "$1").into_owned();
+    count(&HTML_BOLD_RE, &s);
+    s = HTML_BOLD_RE.replace_all(&s, "**$2**").into_owned();
+    count(&HTML_ITALIC_RE, &s);
+    s = HTML_ITALIC_RE.replace_all(&s, "*$2*").into_owned();
+    count(&HTML_STRIKE_RE, &s);
+    s = HTML_STRIKE_RE.replace_all(&s, "~~$2~~").into_owned();
+    count(&HTML_UNDERLINE_RE, &s);
+    s = HTML_UNDERLINE_RE.replace_all(&s, "__${1}__").into_owned();
+    count(&HTML_CODE_RE, &s);
+    s = HTML_CODE_RE.replace_all(&s, "`$1`").into_owned();
+    count(&HTML_LIST_ITEM_RE, &s);
+    s = HTML_LIST_ITEM_RE.replace_all(&s, "\n- ").into_owned();
+    // 残りのタグは捨てる
+    count(&HTML_TAG_RE, &s);
+    s = HTML_TAG_RE.replace_all(&s, "").into_owned();
+
+    let s = s
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    (s, tags)
+}
+
+/// コードブロック
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeBlock {
+    /// フェンスに付いていた言語タグ (無ければ空)
+    pub lang: String,
+    pub code: String,
+}
+
+impl CodeBlock {
+    /// 言語タグから推測したファイル拡張子
+    pub fn extension(&self) -> &'static str {
+        extension_for_lang(&self.lang)
+    }
+}
+
+/// テキストに含まれるコードブロックを順番に取り出す
+pub fn extract_code_blocks(markdown: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut current: Option<CodeBlock> = None;
+    for line in markdown.lines() {
+        let trimmed = line.trim();
+        if let Some(info) = trimmed.strip_prefix("```") {
+            match current.take() {
+                Some(block) => blocks.push(block),
+                None => {
+                    current = Some(CodeBlock {
+                        lang: info.split_whitespace().next().unwrap_or("").to_string(),
+                        code: String::new(),
+                    })
+                }
+            }
+            continue;
+        }
+        if let Some(block) = current.as_mut() {
+            block.code.push_str(line);
+            block.code.push('\n');
+        }
+    }
+    // 閉じられていないブロックもそのまま返す
+    blocks.extend(current);
+    blocks
+}
+
+/// フェンスの言語タグからファイル拡張子を決める
+pub fn extension_for_lang(lang: &str) -> &'static str {
+    match lang.to_ascii_lowercase().as_str() {
+        "rust" | "rs" => "rs",
+        "python" | "py" | "python3" => "py",
+        "javascript" | "js" | "node" => "js",
+        "typescript" | "ts" => "ts",
+        "jsx" => "jsx",
+        "tsx" => "tsx",
+        "json" | "json5" => "json",
+        "yaml" | "yml" => "yml",
+        "toml" => "toml",
+        "ini" | "conf" | "cfg" => "ini",
+        "html" => "html",
+        "css" => "css",
+        "scss" => "scss",
+        "xml" => "xml",
+        "c" | "h" => "c",
+        "cpp" | "c++" | "cc" | "cxx" | "hpp" => "cpp",
+        "cs" | "csharp" | "c#" => "cs",
+        "go" | "golang" => "go",
+        "java" => "java",
+        "kotlin" | "kt" => "kt",
+        "swift" => "swift",
+        "ruby" | "rb" => "rb",
+        "php" => "php",
+        "lua" => "lua",
+        "sh" | "bash" | "shell" | "zsh" | "console" => "sh",
+        "powershell" | "ps1" | "pwsh" => "ps1",
+        "bat" | "batch" | "cmd" => "bat",
+        "sql" => "sql",
+        "md" | "markdown" => "md",
+        "diff" | "patch" => "diff",
+        "dockerfile" | "docker" => "dockerfile",
+        "makefile" | "make" => "mk",
+        "haskell" | "hs" => "hs",
+        "scala" => "scala",
+        "zig" => "zig",
+        "nim" => "nim",
+        "dart" => "dart",
+        "r" => "r",
+        "tex" | "latex" => "tex",
+        "csv" => "csv",
+        _ => "txt",
+    }
+}
+
+/// Markdown (Discord 向けに変換したものを含む) を IRC の制御コードに置き換える
+/// コードブロックは囲いを外して中身をそのまま残す
+pub fn to_irc(markdown: &str) -> String {
+    let mut lines = Vec::new();
+    let mut in_fence = false;
+    for line in markdown.lines() {
+        if line.trim_start().starts_with("```") {
+            in_fence = !in_fence;
+            continue;
+        }
+        if in_fence {
+            lines.push(line.to_string());
+        } else if HORIZONTAL_RULE_RE.is_match(line) {
+            lines.push("----".to_string());
+        } else if let Some(caps) = SUBTEXT_RE.captures(line) {
+            lines.push(format!("{}{}{}", IRC_GREY, strip_inline_markup(&caps[1]), IRC_COLOR_END));
+        } else if let Some(caps) = ANY_HEADING_RE.captures(line) {
+            lines.push(format!("{}{}{}", IRC_BOLD, strip_inline_markup(&caps[1]), IRC_BOLD));
+        } else {
+            lines.push(irc_inline(line));
+        }
+    }
+    lines.join("\n")
+}
+
+/// 行内の装飾を置き換える (インラインコードの中はそのまま)
+fn irc_inline(line: &str) -> String {
+    let mut out = String::new();
+    let mut last = 0;
+    for code in INLINE_CODE_RE.find_iter(line) {
+        out.push_str(&irc_inline_segment(&line[last..code.start()]));
+        let inner = &code.as_str()[1..code.as_str().len() - 1];
+        out.push_str(&format!("{}{}{}", IRC_MONOSPACE, inner, IRC_MONOSPACE));
+        last = code.end();
+    }
+    out.push_str(&irc_inline_segment(&line[last..]));
+    out
+}
+
+fn irc_inline_segment(segment: &str) -> String {
+    let s = MD_LINK_RE.replace_all(segment, "$1 ($2)");
+    let s = ANGLE_URL_RE.replace_all(&s, "$1");
+    let s = BOLD_RE.replace_all(&s, |caps: &Captures| {
+        let inner = caps.get(1).or(caps.get(2)).map(|m| m.as_str()).unwrap_or_default();
+        format!("{}{}{}", IRC_BOLD, inner, IRC_BOLD)
+    });
+    let s = ITALIC_RE.replace_all(&s, format!("{}$1{}", IRC_ITALIC, IRC_ITALIC).as_str());
+    let s = STRIKE_RE.replace_all(&s, format!("{}$1{}", IRC_STRIKE, IRC_STRIKE).as_str());
+    s.into_owned()
+}
+
+/// 記事化した場合にチャンネルへ流す短い要約 (先頭の段落) を作る
+pub fn summarize(markdown: &str, max_chars: usize) -> String {
+    let mut summary = String::new();
+    let mut in_fence = false;
+    for line in markdown.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") {
+            in_fence = !in_fence;
+            continue;
+        }
+        if in_fence || trimmed.starts_with('|') || trimmed.starts_with('#') {
+            if !summary.is_empty() {
+                break;
+            }
+            continue;
+        }
+        if trimmed.is_empty() {
+            if !summary.is_empty() {
+                break;
+            }
+            continue;
+        }
+        if !summary.is_empty() {
+            summary.push('\n');
+        }
+        summary.push_str(trimmed);
+    }
+    let (summary, _) = convert_html(&summary);
+    if summary.chars().count() > max_chars {
+        let cut: String = summary.chars().take(max_chars.saturating_sub(1)).collect();
+        format!("{}…", cut.trim_end())
+    } else {
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_is_unchanged() {
+        let text = "こんにちは\n**bold** and `code`\n- item";
+        let out = to_discord(text);
+        assert_eq!(out.text, text);
+        assert!(!out.is_structured());
+    }
+
+    #[test]
+    fn narrow_table_becomes_monospace_block() {
+        let text = "| name | value |\n|------|:-----:|\n| a | 1 |\n| ビール | 22 |";
+        let out = to_discord(text);
+        assert_eq!(out.tables, 1);
+        assert_eq!(
+            out.text,
+            "```\nname   | value\n-------+------\na      | 1\nビール | 22\n```"
+        );
+    }
+
+    #[test]
+    fn wide_table_becomes_bullet_list() {
+        let long = "x".repeat(80);
+        let text = format!("| item | note |\n|---|---|\n| foo | {} |\n| bar | |", long);
+        let out = to_discord(&text);
+        assert_eq!(out.tables, 1);
+        assert_eq!(out.text, format!("- **foo** — note: {}\n- **bar**", long));
+    }
+
+    #[test]
+    fn deep_headings_become_bold() {
+        let out = to_discord("### keep\n#### Detail ####\n###### tiny");
+        assert_eq!(out.text, "### keep\n**Detail**\n**tiny**");
+        assert_eq!(out.headings, 3);
+    }
+
+    #[test]
+    fn subtext_is_not_a_heading() {
+        let out = to_discord("-# model: gpt-5");
+        assert_eq!(out.text, "-# model: gpt-5");
+        assert_eq!(out.headings, 0);
+    }
+
+    #[test]
+    fn html_is_downgraded() {
+        let out = to_discord("a<br>b <b>bold</b> <a href=\"https://x.y\">link</a> <span>t</span> &amp;");
+        assert_eq!(out.text, "a\nb **bold** [link](https://x.y) t &");
+        assert_eq!(out.html_tags, 5);
+    }
+
+    #[test]
+    fn generics_in_prose_are_not_tags() {
+        let text = "returns `Vec<u8>`, takes Vec<String> and Option<Box<dyn Error>>, or HashMap<K, V>";
+        let out = to_discord(text);
+        assert_eq!(out.text, text);
+        assert_eq!(out.html_tags, 0);
+        assert_eq!(to_discord("a Vec<T> in <div class=\"x\">a div</div>").text, "a Vec<T> in a div");
+    }
+
+    #[test]
+    fn code_blocks_are_left_alone() {
+        let text = "```html\n<b>x</b>\n| a | b |\n|---|---|\n#### h\n```";
+        assert_eq!(to_discord(text).text, text);
+    }
+
+    #[test]
+    fn inline_code_is_left_alone() {
+        assert_eq!(to_discord("use `<br>` here").text, "use `<br>` here");
+    }
+
+    #[test]
+    fn horizontal_rule_is_replaced() {
+        assert_eq!(to_discord("a\n---\nb").text, "a\n──────────\nb");
+    }
+
+    #[test]
+    fn code_blocks_are_extracted_with_language() {
+        let text = "intro\n```rust\nfn main() {}\n```\ntext\n```\nplain\n```\n```Python title\nprint(1)";
+        let blocks = extract_code_blocks(text);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0], CodeBlock { lang: "rust".into(), code: "fn main() {}\n".into() });
+        assert_eq!(blocks[0].extension(), "rs");
+        assert_eq!(blocks[1].extension(), "txt");
+        assert_eq!(blocks[2].lang, "Python");
+        assert_eq!(blocks[2].extension(), "py");
+        assert_eq!(blocks[2].code, "print(1)\n");
+    }
+
+    #[test]
+    fn summary_takes_first_paragraph() {
+        let text = "## Title\n\nfirst line\nsecond line\n\nnext paragraph";
+        assert_eq!(summarize(text, 200), "first line\nsecond line");
+        assert_eq!(summarize(text, 6), "first…");
+    }
+
+    #[test]
+    fn irc_uses_control_codes() {
+        let text = "## Title\n**bold** *it* ~~del~~ `a*b*`\n[docs](https://example.com) <https://x.dev>\n-# note";
+        assert_eq!(
+            to_irc(text),
+            "\x02Title\x02\n\x02bold\x02 \x1dit\x1d \x1edel\x1e \x11a*b*\x11\ndocs (https://example.com) https://x.dev\n\x0314note\x03"
+        );
+    }
+
+    #[test]
+    fn irc_keeps_code_block_contents() {
+        let text = "```rust\nlet x = **y**;\n```\ndone";
+        assert_eq!(to_irc(text), "let x = **y**;\ndone");
+    }
+}
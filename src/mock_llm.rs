@@ -0,0 +1,554 @@
+//! OpenAI 互換のモック LLM サーバー
+//!
+//! JSON のシナリオに書いた応答を `/v1/chat/completions` へのリクエストごとに順番に返す。
+//! `MAIN_MODEL_ENDPOINT` をこのサーバーに向ければ、API を使わずにツールの動作まで確認できる。
+//!
+//! ```json
+//! {
+//!   "name": "browse-and-answer",
+//!   "steps": [
+//!     {
+//!       "expect": { "user_contains": "天気" },
+//!       "respond": { "tool_calls": [{ "name": "browser", "arguments": { "url": "https://example.com" } }] }
+//!     },
+//!     {
+//!       "expect": { "tool_result_contains": "Example" },
+//!       "respond": {
+//!         "content": "晴れです ({{tool_result}})",
+//!         "annotations": [{ "url": "https://example.com" }]
+//!       }
+//!     },
+//!     { "respond": { "status": 429, "retry_after": 2 } }
+//!   ]
+//! }
+//! ```
+//!
+//! 応答の `content` では `{{user}}` (最後のユーザーメッセージ) と
+//! `{{tool_result}}` (最後のツールの結果) が置き換えられる。
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::local_http::{self, LocalServer, Request, Response};
+
+/// シナリオ
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Scenario {
+    #[serde(default)]
+    pub name: String,
+    /// シナリオの説明 (JSON にはコメントが書けないので)
+    #[serde(default)]
+    pub description: String,
+    /// 設定されていれば `Authorization: Bearer <api_key>` を要求する
+    #[serde(default)]
+    pub api_key: Option<String>,
+    pub steps: Vec<Step>,
+}
+
+impl Scenario {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| format!("invalid scenario: {}", e))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path.as_ref())
+            .map_err(|e| format!("failed to read {}: {}", path.as_ref().display(), e))?;
+        Self::from_json(&json)
+    }
+}
+
+/// 1 リクエスト分の期待値と応答
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Step {
+    #[serde(default)]
+    pub expect: Expect,
+    #[serde(default)]
+    pub respond: Reply,
+}
+
+/// リクエストに対する期待値 (設定したものだけ確認する)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Expect {
+    pub model: Option<String>,
+    /// 最後のユーザーメッセージに含まれる文字列
+    pub user_contains: Option<String>,
+    /// 最後のユーザーメッセージに画像が含まれるか
+    pub has_image: Option<bool>,
+    /// 最後のツールの結果に含まれる文字列
+    pub tool_result_contains: Option<String>,
+    /// 定義されているべきツール
+    pub tools_include: Vec<String>,
+    /// `web_search_options` が指定されているか
+    pub web_search: Option<bool>,
+}
+
+/// 返す応答
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Reply {
+    pub content: Option<String>,
+    pub tool_calls: Vec<ScriptedToolCall>,
+    /// `url_citation` として返す引用
+    pub annotations: Vec<Citation>,
+    /// 200 以外ならエラー応答にする
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    /// `Retry-After` ヘッダー (秒)
+    pub retry_after: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ScriptedToolCall {
+    #[serde(default)]
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub arguments: Value,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Citation {
+    pub url: String,
+    #[serde(default)]
+    pub title: String,
+}
+
+struct MockState {
+    scenario: Scenario,
+    next_step: usize,
+    requests: Vec<Value>,
+    failures: Vec<String>,
+}
+
+/// モック LLM サーバー
+pub struct MockLlmServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    server: LocalServer,
+}
+
+impl MockLlmServer {
+    /// `addr` (例: `127.0.0.1:0`) でサーバーを起動する
+    pub async fn start(scenario: Scenario, addr: &str) -> io::Result<Self> {
+        let state = Arc::new(Mutex::new(MockState {
+            scenario,
+            next_step: 0,
+            requests: Vec::new(),
+            failures: Vec::new(),
+        }));
+        let handler_state = state.clone();
+        let (addr, server) = local_http::serve(addr, move |request| {
+            let state = handler_state.clone();
+            async move { handle_request(request, &state) }
+        })
+        .await?;
+        Ok(Self { addr, state, server })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// `OpenAIClient::new` や `main_model_endpoint` に渡すエンドポイント
+    pub fn endpoint(&self) -> String {
+        format!("http://{}/v1", self.addr)
+    }
+
+    /// 受け取ったリクエストボディ
+    pub fn requests(&self) -> Vec<Value> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// 期待値を満たさなかったリクエストの説明
+    pub fn failures(&self) -> Vec<String> {
+        self.state.lock().unwrap().failures.clone()
+    }
+
+    /// まだ使われていないステップ数
+    pub fn remaining_steps(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.scenario.steps.len() - state.next_step
+    }
+
+    /// サーバーが止まるまで待つ
+    pub async fn wait(mut self) {
+        self.server.wait().await;
+    }
+}
+
+fn handle_request(request: Request, state: &Arc<Mutex<MockState>>) -> Response {
+    if request.method != "POST" || !request.path.ends_with("/chat/completions") {
+        return error_response(404, &format!("unknown endpoint: {} {}", request.method, request.path), "invalid_request_error");
+    }
+    let mut state = state.lock().unwrap();
+
+    if let Some(api_key) = &state.scenario.api_key {
+        let expected = format!("Bearer {}", api_key);
+        if request.header("authorization") != Some(expected.as_str()) {
+            return error_response(401, "Incorrect API key provided", "invalid_request_error");
+        }
+    }
+
+    let body: Value = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(e) => return error_response(400, &format!("invalid request body: {}", e), "invalid_request_error"),
+    };
+    state.requests.push(body.clone());
+
+    let Some(step) = state.scenario.steps.get(state.next_step).cloned() else {
+        let message = format!("scenario '{}' has no more steps", state.scenario.name);
+        warn!("{}", message);
+        state.failures.push(message.clone());
+        return error_response(500, &message, "mock_exhausted");
+    };
+    state.next_step += 1;
+    let step_no = state.next_step;
+
+    if let Err(mismatch) = check_expect(&step.expect, &body) {
+        let message = format!("step {}: {}", step_no, mismatch);
+        warn!("mock-llm expectation failed - {}", message);
+        state.failures.push(message.clone());
+        return error_response(400, &message, "mock_expectation_failed");
+    }
+
+    let reply = &step.respond;
+    let status = reply.status.unwrap_or(200);
+    if status != 200 || reply.error.is_some() {
+        let err_type = if status == 429 { "rate_limit_exceeded" } else { "server_error" };
+        let message = reply.error.clone().unwrap_or_else(|| format!("mock error {}", status));
+        let response = error_response(status, &message, err_type);
+        return match reply.retry_after {
+            Some(secs) => response.with_header("Retry-After", secs),
+            None => response,
+        };
+    }
+
+    Response::json(200, &completion(&body, reply, step_no))
+}
+
+fn error_response(status: u16, message: &str, err_type: &str) -> Response {
+    Response::json(status, &json!({ "error": { "message": message, "type": err_type, "code": status } }))
+}
+
+/// `chat.completion` 形式の応答を作る
+fn completion(request: &Value, reply: &Reply, step_no: usize) -> Value {
+    let content = reply.content.as_ref().map(|c| {
+        c.replace("{{user}}", &last_text(request, "user").unwrap_or_default())
+            .replace("{{tool_result}}", &last_text(request, "tool").unwrap_or_default())
+    });
+    let tool_calls: Vec<Value> = reply
+        .tool_calls
+        .iter()
+        .enumerate()
+        .map(|(i, call)| {
+            json!({
+                "id": call.id.clone().unwrap_or_else(|| format!("call_mock_{}_{}", step_no, i)),
+                "type": "function",
+                "function": {
+                    "name": call.name,
+                    // 本物の API と同じく JSON 文字列で返す
+                    "arguments": call.arguments.to_string(),
+                }
+            })
+        })
+        .collect();
+    let content_len = content.as_ref().map(|c| c.chars().count()).unwrap_or(0);
+    let annotations: Vec<Value> = reply
+        .annotations
+        .iter()
+        .map(|c| {
+            json!({
+                "type": "url_citation",
+                "url_citation": { "url": c.url, "title": c.title, "start_index": 0, "end_index": content_len }
+            })
+        })
+        .collect();
+
+    let mut message = json!({
+        "role": "assistant",
+        "content": content,
+        "refusal": null,
+        "annotations": annotations,
+    });
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = Value::Array(tool_calls);
+    }
+    let finish_reason = if reply.tool_calls.is_empty() { "stop" } else { "tool_calls" };
+
+    // トークン数はおおよそ (4 文字 = 1 トークン)
+    let prompt_tokens = request.get("messages").map(|m| m.to_string().len() / 4).unwrap_or(0);
+    let completion_tokens = content_len / 4 + reply.tool_calls.len() * 10;
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    json!({
+        "id": format!("chatcmpl-mock-{}", step_no),
+        "object": "chat.completion",
+        "created": created,
+        "model": request.get("model").cloned().unwrap_or(Value::Null),
+        "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        }
+    })
+}
+
+fn last_message<'a>(request: &'a Value, role: &str) -> Option<&'a Value> {
+    request
+        .get("messages")?
+        .as_array()?
+        .iter()
+        .rev()
+        .find(|m| m.get("role").and_then(|r| r.as_str()) == Some(role))
+}
+
+fn last_text(request: &Value, role: &str) -> Option<String> {
+    match last_message(request, role)?.get("content")? {
+        Value::String(text) => Some(text.clone()),
+        Value::Array(parts) => Some(
+            parts
+                .iter()
+                .filter_map(|p| p.get("text").and_then(|t| t.as_str()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ),
+        _ => None,
+    }
+}
+
+fn check_expect(expect: &Expect, request: &Value) -> Result<(), String> {
+    if let Some(model) = &expect.model
+        && request.get("model").and_then(|m| m.as_str()) != Some(model.as_str())
+    {
+        return Err(format!("expected model {}, got {}", model, request.get("model").unwrap_or(&Value::Null)));
+    }
+    if let Some(needle) = &expect.user_contains {
+        let text = last_text(request, "user").unwrap_or_default();
+        if !text.contains(needle.as_str()) {
+            return Err(format!("last user message does not contain {:?}: {:?}", needle, text));
+        }
+    }
+    if let Some(has_image) = expect.has_image {
+        let found = last_message(request, "user")
+            .and_then(|m| m.get("content")?.as_array())
+            .is_some_and(|parts| parts.iter().any(|p| p.get("type").and_then(|t| t.as_str()) == Some("image_url")));
+        if found != has_image {
+            return Err(format!("expected image in last user message: {}, found: {}", has_image, found));
+        }
+    }
+    if let Some(needle) = &expect.tool_result_contains {
+        let text = last_text(request, "tool").unwrap_or_default();
+        if !text.contains(needle.as_str()) {
+            return Err(format!("last tool result does not contain {:?}: {:?}", needle, text));
+        }
+    }
+    for name in &expect.tools_include {
+        let defined = request
+            .get("tools")
+            .and_then(|t| t.as_array())
+            .is_some_and(|tools| tools.iter().any(|t| t["function"]["name"].as_str() == Some(name.as_str())));
+        if !defined {
+            return Err(format!("tool {} is not defined in the request", name));
+        }
+    }
+    if let Some(web_search) = expect.web_search
+        && request.get("web_search_options").is_some() != web_search
+    {
+        return Err(format!("expected web_search_options: {}", web_search));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::browsing_worker::BrowsingWorker;
+    use call_agent::chat::{
+        client::{ModelConfig, OpenAIClient, ToolMode},
+        function::Tool,
+        prompt::{Message, MessageContext, MessageImage},
+    };
+
+    struct UpperTool;
+
+    impl Tool for UpperTool {
+        fn def_name(&self) -> &str {
+            "upper"
+        }
+
+        fn def_description(&self) -> &str {
+            "Uppercase the text"
+        }
+
+        fn def_parameters(&self) -> Value {
+            json!({ "type": "object", "properties": { "text": { "type": "string" } } })
+        }
+
+        fn run(&self, args: Value) -> Result<String, String> {
+            Ok(args["text"].as_str().unwrap_or_default().to_uppercase())
+        }
+    }
+
+    fn model_config() -> ModelConfig {
+        ModelConfig {
+            model: "mock-model".to_string(),
+            model_name: None,
+            top_p: None,
+            parallel_tool_calls: None,
+            temperature: None,
+            max_completion_tokens: None,
+            reasoning_effort: None,
+            presence_penalty: None,
+            strict: None,
+            web_search_options: None,
+        }
+    }
+
+    fn client(server: &MockLlmServer) -> OpenAIClient {
+        let mut client = OpenAIClient::new(&server.endpoint(), Some("sk-mock"));
+        client.set_model_config(&model_config());
+        client
+    }
+
+    fn user(text: &str) -> Vec<Message> {
+        vec![Message::User { name: None, content: vec![MessageContext::Text(text.to_string())] }]
+    }
+
+    #[tokio::test]
+    async fn drives_a_tool_loop() {
+        let scenario = Scenario::from_json(
+            r#"
+            {
+              "name": "upper",
+              "api_key": "sk-mock",
+              "steps": [
+                {
+                  "expect": { "user_contains": "shout", "tools_include": ["upper"] },
+                  "respond": { "tool_calls": [{ "name": "upper", "arguments": { "text": "hello" } }] }
+                },
+                {
+                  "expect": { "tool_result_contains": "HELLO" },
+                  "respond": { "content": "done: {{tool_result}}" }
+                }
+              ]
+            }
+            "#,
+        )
+        .unwrap();
+        let server = MockLlmServer::start(scenario, "127.0.0.1:0").await.unwrap();
+        let mut client = client(&server);
+        client.def_tool(Arc::new(UpperTool));
+        let mut prompt = client.create_prompt();
+        prompt.add(user("please shout hello")).await;
+
+        let mut reasoning = prompt.reasoning(None, &ToolMode::Auto).await.unwrap();
+        assert_eq!(reasoning.show_tool_calls()[0].0, "upper");
+        reasoning.proceed(&ToolMode::Auto).await.unwrap();
+        assert!(reasoning.can_finish());
+        assert_eq!(reasoning.content.as_deref(), Some("done: HELLO"));
+        assert!(server.failures().is_empty(), "{:?}", server.failures());
+        assert_eq!(server.remaining_steps(), 0);
+    }
+
+    #[tokio::test]
+    async fn checks_vision_input() {
+        let scenario = Scenario::from_json(r#"{ "steps": [{ "expect": { "has_image": true }, "respond": { "content": "a cat" } }] }"#).unwrap();
+        let server = MockLlmServer::start(scenario, "127.0.0.1:0").await.unwrap();
+        let mut prompt = client(&server).create_prompt();
+        prompt.add(vec![Message::User {
+            name: None,
+            content: vec![
+                MessageContext::Text("what is this?".to_string()),
+                MessageContext::Image(MessageImage { url: "data:image/png;base64,AAAA".to_string(), detail: None }),
+            ],
+        }]).await;
+        let res = prompt.generate(None).await.unwrap();
+        assert_eq!(res.content.as_deref(), Some("a cat"));
+        assert!(server.failures().is_empty(), "{:?}", server.failures());
+    }
+
+    #[tokio::test]
+    async fn browsing_worker_reads_annotations() {
+        let scenario = Scenario::from_json(
+            r#"
+            {
+              "steps": [
+                {
+                  "respond": {
+                    "content": "summary of the page",
+                    "annotations": [
+                      { "url": "https://example.com/a", "title": "A" },
+                      { "url": "https://example.org/b" }
+                    ]
+                  }
+                }
+              ]
+            }
+            "#,
+        )
+        .unwrap();
+        let server = MockLlmServer::start(scenario, "127.0.0.1:0").await.unwrap();
+        let worker = BrowsingWorker::new(client(&server));
+        let result = tokio::task::spawn_blocking(move || worker.run(json!({ "query": "summarize https://example.com/a" })))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(result.starts_with("<untrusted_content source=\"browsing_worker\">"));
+        let result: Value = serde_json::from_str(&crate::untrusted::unwrap(&result)).unwrap();
+        assert_eq!(result["Summary"], "summary of the page");
+        assert_eq!(result["links"], json!(["https://example.com/a", "https://example.org/b"]));
+    }
+
+    #[tokio::test]
+    async fn scripted_errors_and_failures() {
+        let scenario = Scenario::from_json(
+            r#"
+            {
+              "name": "errors",
+              "steps": [
+                { "respond": { "status": 429, "retry_after": 3, "error": "Rate limit reached" } },
+                { "expect": { "model": "other-model" }, "respond": { "content": "never" } }
+              ]
+            }
+            "#,
+        )
+        .unwrap();
+        let server = MockLlmServer::start(scenario, "127.0.0.1:0").await.unwrap();
+        let http = reqwest::Client::new();
+        let url = format!("{}/chat/completions", server.endpoint());
+        let body = json!({ "model": "mock-model", "messages": [] });
+
+        let res = http.post(&url).json(&body).send().await.unwrap();
+        assert_eq!(res.status().as_u16(), 429);
+        assert_eq!(res.headers()["retry-after"], "3");
+        assert_eq!(res.json::<Value>().await.unwrap()["error"]["type"], "rate_limit_exceeded");
+
+        let res = http.post(&url).json(&body).send().await.unwrap();
+        assert_eq!(res.status().as_u16(), 400);
+        let res = http.post(&url).json(&body).send().await.unwrap();
+        assert_eq!(res.status().as_u16(), 500);
+        assert_eq!(server.failures().len(), 2);
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn example_scenarios_parse() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/mock-llm");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let scenario = Scenario::load(&path).unwrap_or_else(|e| panic!("{}", e));
+            assert!(!scenario.steps.is_empty(), "{} has no steps", path.display());
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! 入力と出力のモデレーション
+//!
+//! 単語リストと正規表現のルールで照合し、設定されていれば OpenAI 互換の
+//! `/moderations` エンドポイントにも問い合わせる。どう扱うか (止める・伏せる・記録だけ) は
+//! チャンネルごとの `ModerationPolicy` で決める。
+
+use std::{ops::Range, time::Duration};
+
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// 伏せた部分に入れる文字列
+pub const REDACTED: &str = "[redacted]";
+
+const ENDPOINT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 引っかかったときの扱い
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationPolicy {
+    /// 照合しない
+    Off,
+    /// そのまま通して管理者に知らせる
+    #[default]
+    Flag,
+    /// 該当部分を伏せて通す
+    Redact,
+    /// 止める
+    Block,
+}
+
+impl ModerationPolicy {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "flag" => Ok(Self::Flag),
+            "redact" => Ok(Self::Redact),
+            "block" => Ok(Self::Block),
+            _ => Err(format!("unknown moderation policy: {} (off, flag, redact, block)", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Flag => "flag",
+            Self::Redact => "redact",
+            Self::Block => "block",
+        }
+    }
+}
+
+/// 照合の結果
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Verdict {
+    /// ルールに一致した範囲と、一致したルール
+    pub matches: Vec<(Range<usize>, String)>,
+    /// エンドポイントが判定したカテゴリ
+    pub categories: Vec<String>,
+}
+
+impl Verdict {
+    pub fn is_clean(&self) -> bool {
+        self.matches.is_empty() && self.categories.is_empty()
+    }
+
+    /// 引っかかった理由 (通知用)
+    pub fn reasons(&self) -> Vec<String> {
+        let mut reasons: Vec<String> = Vec::new();
+        for (_, rule) in &self.matches {
+            if !reasons.contains(rule) {
+                reasons.push(rule.clone());
+            }
+        }
+        reasons.extend(self.categories.iter().map(|c| format!("endpoint:{}", c)));
+        reasons
+    }
+
+    /// 一致した部分を伏せる (エンドポイントだけが引っかけた場合は位置がわからないので全体を伏せる)
+    pub fn redact(&self, text: &str) -> String {
+        if self.matches.is_empty() {
+            return if self.categories.is_empty() { text.to_string() } else { REDACTED.to_string() };
+        }
+        let mut ranges: Vec<Range<usize>> = self.matches.iter().map(|(r, _)| r.clone()).collect();
+        ranges.sort_by_key(|r| r.start);
+        let mut out = String::new();
+        let mut pos = 0;
+        for range in ranges {
+            if range.end <= pos {
+                continue;
+            }
+            let start = range.start.max(pos);
+            out.push_str(&text[pos..start]);
+            out.push_str(REDACTED);
+            pos = range.end;
+        }
+        out.push_str(&text[pos..]);
+        out
+    }
+}
+
+/// ポリシーを当てはめた結果
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// 問題なし
+    Clean,
+    /// 通すが管理者に知らせる
+    Flagged(Vec<String>),
+    /// 伏せた本文で通す
+    Redacted(String, Vec<String>),
+    /// 止める
+    Blocked(Vec<String>),
+}
+
+impl Outcome {
+    /// 通知や監査ログに書く扱いの名前
+    pub fn action(&self) -> &'static str {
+        match self {
+            Self::Clean => "clean",
+            Self::Flagged(_) => "flagged",
+            Self::Redacted(..) => "redacted",
+            Self::Blocked(_) => "blocked",
+        }
+    }
+
+    /// 管理者に知らせる理由 (問題がなければ None)
+    pub fn reasons(&self) -> Option<&[String]> {
+        match self {
+            Self::Clean => None,
+            Self::Flagged(reasons) | Self::Redacted(_, reasons) | Self::Blocked(reasons) => Some(reasons),
+        }
+    }
+}
+
+/// 照合の設定
+#[derive(Clone, Debug, Default)]
+pub struct ModeratorConfig {
+    /// 大文字小文字を区別せずに照合する語
+    pub words: Vec<String>,
+    /// 正規表現のルール
+    pub patterns: Vec<String>,
+    /// `/moderations` を持つ API のベース URL (例: `https://api.openai.com/v1/`)
+    pub endpoint: Option<String>,
+    pub api_key: Option<String>,
+    pub model: String,
+}
+
+pub struct Moderator {
+    rules: Vec<(Regex, String)>,
+    endpoint: Option<String>,
+    api_key: Option<String>,
+    model: String,
+    http: reqwest::Client,
+}
+
+impl Moderator {
+    pub fn new(config: ModeratorConfig) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for word in config.words.iter().filter(|w| !w.trim().is_empty()) {
+            let regex = RegexBuilder::new(&regex::escape(word.trim()))
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| e.to_string())?;
+            rules.push((regex, format!("word:{}", word.trim())));
+        }
+        for pattern in &config.patterns {
+            let regex = Regex::new(pattern).map_err(|e| format!("invalid moderation pattern {}: {}", pattern, e))?;
+            rules.push((regex, format!("pattern:{}", pattern)));
+        }
+        let http = reqwest::Client::builder()
+            .timeout(ENDPOINT_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            rules,
+            endpoint: config.endpoint.filter(|e| !e.is_empty()),
+            api_key: config.api_key.filter(|k| !k.is_empty()),
+            model: config.model,
+            http,
+        })
+    }
+
+    /// ルールだけで照合する
+    pub fn check_rules(&self, text: &str) -> Verdict {
+        let mut verdict = Verdict::default();
+        for (regex, name) in &self.rules {
+            for m in regex.find_iter(text).filter(|m| !m.is_empty()) {
+                verdict.matches.push((m.range(), name.clone()));
+            }
+        }
+        verdict
+    }
+
+    /// ルールとエンドポイントで照合する
+    /// エンドポイントに繋がらないときはルールの結果だけを返す
+    pub async fn check(&self, text: &str) -> Verdict {
+        let mut verdict = self.check_rules(text);
+        if self.endpoint.is_some() && !text.trim().is_empty() {
+            match self.check_endpoint(text).await {
+                Ok(categories) => verdict.categories = categories,
+                Err(e) => log::error!("moderation endpoint failed - {}", e),
+            }
+        }
+        verdict
+    }
+
+    async fn check_endpoint(&self, text: &str) -> Result<Vec<String>, String> {
+        let Some(endpoint) = &self.endpoint else {
+            return Ok(Vec::new());
+        };
+        let url = format!("{}/moderations", endpoint.trim_end_matches('/'));
+        let mut request = self.http.post(&url).json(&json!({ "model": self.model, "input": text }));
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("{} returned {}", url, response.status()));
+        }
+        let body: Value = response.json().await.map_err(|e| e.to_string())?;
+        Ok(flagged_categories(&body))
+    }
+
+    /// 照合してポリシーを当てはめる
+    pub async fn moderate(&self, text: &str, policy: ModerationPolicy) -> Outcome {
+        if policy == ModerationPolicy::Off {
+            return Outcome::Clean;
+        }
+        let verdict = self.check(text).await;
+        apply(policy, text, &verdict)
+    }
+
+    /// ルールだけで照合してポリシーを当てはめる (エンドポイントには問い合わせない)
+    pub fn moderate_rules(&self, text: &str, policy: ModerationPolicy) -> Outcome {
+        if policy == ModerationPolicy::Off {
+            return Outcome::Clean;
+        }
+        apply(policy, text, &self.check_rules(text))
+    }
+}
+
+/// `/moderations` の応答から引っかかったカテゴリを取り出す
+pub fn flagged_categories(body: &Value) -> Vec<String> {
+    let mut categories = Vec::new();
+    for result in body["results"].as_array().into_iter().flatten() {
+        if !result["flagged"].as_bool().unwrap_or(false) {
+            continue;
+        }
+        let mut names: Vec<String> = result["categories"]
+            .as_object()
+            .map(|c| c.iter().filter(|(_, v)| v.as_bool() == Some(true)).map(|(k, _)| k.clone()).collect())
+            .unwrap_or_default();
+        if names.is_empty() {
+            names.push("flagged".to_string());
+        }
+        for name in names {
+            if !categories.contains(&name) {
+                categories.push(name);
+            }
+        }
+    }
+    categories
+}
+
+/// 照合結果にポリシーを当てはめる
+pub fn apply(policy: ModerationPolicy, text: &str, verdict: &Verdict) -> Outcome {
+    if verdict.is_clean() || policy == ModerationPolicy::Off {
+        return Outcome::Clean;
+    }
+    let reasons = verdict.reasons();
+    match policy {
+        ModerationPolicy::Off => Outcome::Clean,
+        ModerationPolicy::Flag => Outcome::Flagged(reasons),
+        ModerationPolicy::Redact => Outcome::Redacted(verdict.redact(text), reasons),
+        ModerationPolicy::Block => Outcome::Blocked(reasons),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_http::{self, Response};
+
+    fn moderator(endpoint: Option<String>) -> Moderator {
+        Moderator::new(ModeratorConfig {
+            words: vec!["BadWord".into()],
+            patterns: vec![r"\b\d{3}-\d{4}-\d{4}\b".into()],
+            endpoint,
+            api_key: Some("test".into()),
+            model: "omni-moderation-latest".into(),
+        }).unwrap()
+    }
+
+    #[test]
+    fn rules_match_words_and_patterns() {
+        let verdict = moderator(None).check_rules("a badword and 090-1234-5678 here");
+        assert_eq!(verdict.reasons(), vec!["word:BadWord", r"pattern:\b\d{3}-\d{4}-\d{4}\b"]);
+        assert_eq!(verdict.redact("a badword and 090-1234-5678 here"), "a [redacted] and [redacted] here");
+        assert!(moderator(None).check_rules("all fine").is_clean());
+        assert!(Moderator::new(ModeratorConfig { patterns: vec!["(".into()], ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn policies_decide_the_outcome() {
+        let verdict = moderator(None).check_rules("BADWORD!");
+        assert_eq!(apply(ModerationPolicy::Flag, "BADWORD!", &verdict), Outcome::Flagged(vec!["word:BadWord".into()]));
+        assert_eq!(apply(ModerationPolicy::Redact, "BADWORD!", &verdict), Outcome::Redacted("[redacted]!".into(), vec!["word:BadWord".into()]));
+        assert_eq!(apply(ModerationPolicy::Block, "BADWORD!", &verdict), Outcome::Blocked(vec!["word:BadWord".into()]));
+        assert_eq!(apply(ModerationPolicy::Off, "BADWORD!", &verdict), Outcome::Clean);
+        assert_eq!(ModerationPolicy::from_name("Redact"), Ok(ModerationPolicy::Redact));
+    }
+
+    #[tokio::test]
+    async fn endpoint_categories_are_included() {
+        let (addr, _handle) = local_http::serve("127.0.0.1:0", |request| async move {
+            let body: Value = serde_json::from_slice(&request.body).unwrap();
+            let flagged = body["input"].as_str().unwrap().contains("threat");
+            assert_eq!(request.header("authorization"), Some("Bearer test"));
+            Response::json(200, &json!({
+                "results": [{ "flagged": flagged, "categories": { "violence": flagged, "sexual": false } }]
+            }))
+        }).await.unwrap();
+        let moderator = moderator(Some(format!("http://{}/v1/", addr)));
+
+        let verdict = moderator.check("this is a threat").await;
+        assert_eq!(verdict.categories, vec!["violence"]);
+        // 位置がわからないので全体を伏せる
+        assert_eq!(verdict.redact("this is a threat"), REDACTED);
+        assert!(moderator.check("hello").await.is_clean());
+        assert_eq!(moderator.moderate("this is a threat", ModerationPolicy::Off).await, Outcome::Clean);
+        // ルールだけの照合はエンドポイントに問い合わせない
+        assert_eq!(moderator.moderate_rules("this is a threat", ModerationPolicy::Block), Outcome::Clean);
+        assert_eq!(moderator.moderate_rules("a badword threat", ModerationPolicy::Block), Outcome::Blocked(vec!["word:BadWord".into()]));
+    }
+}
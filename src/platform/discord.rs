@@ -0,0 +1,179 @@
+//! Discord (serenity) の実装
+
+use std::str::FromStr;
+
+use log::{debug, error};
+use observer::splitter::DISCORD_MESSAGE_LIMIT;
+use serenity::{all::{ButtonStyle, ChannelId, Context, CreateActionRow, CreateAttachment, CreateButton, CreateMessage, GetMessages, Message, MessageFlags, MessageId, UserId}, futures::StreamExt};
+
+use crate::agent::InputMessage;
+
+use super::{ChatPlatform, Reply};
+
+/// 応答の作り直しボタンの ID
+pub const RETRY_BUTTON_ID: &str = "retry";
+
+/// リアクションで記事にするときに遡る返信元の数と、取り込むスレッドの発言数
+const MAX_REPLY_CHAIN: usize = 10;
+const MAX_THREAD_MESSAGES: u8 = 100;
+
+/// イベントごとに `Context` から作る
+#[derive(Clone)]
+pub struct DiscordPlatform {
+    pub ctx: Context,
+}
+
+impl DiscordPlatform {
+    pub fn new(ctx: &Context) -> Self {
+        Self { ctx: ctx.clone() }
+    }
+
+    fn channel_id(channel: &str) -> Result<ChannelId, String> {
+        ChannelId::from_str(channel).map_err(|e| format!("invalid channel id {}: {:?}", channel, e))
+    }
+
+    /// メッセージを含むスレッドを古い順に集める
+    /// 返信元を `MAX_REPLY_CHAIN` 件まで遡り、メッセージから始まったスレッドがあればその発言も加える
+    pub async fn thread_context(&self, message: &Message) -> Vec<InputMessage> {
+        let mut chain = vec![to_input_message(message)];
+        let mut reference = message.message_reference.as_ref().and_then(|r| Some((r.channel_id, r.message_id?)));
+        while let Some((channel_id, message_id)) = reference.take() {
+            if chain.len() > MAX_REPLY_CHAIN {
+                break;
+            }
+            match channel_id.message(&self.ctx.http, message_id).await {
+                Ok(parent) => {
+                    chain.push(to_input_message(&parent));
+                    reference = parent.message_reference.as_ref().and_then(|r| Some((r.channel_id, r.message_id?)));
+                }
+                Err(e) => debug!("Failed to fetch the replied message {} - {:?}", message_id, e),
+            }
+        }
+        chain.reverse();
+
+        if let Some(thread) = &message.thread {
+            match thread.id.messages(&self.ctx.http, GetMessages::new().limit(MAX_THREAD_MESSAGES)).await {
+                Ok(mut messages) => {
+                    messages.sort_by_key(|m| m.id);
+                    chain.extend(messages.iter().map(to_input_message));
+                }
+                Err(e) => debug!("Failed to fetch the thread {} - {:?}", thread.id, e),
+            }
+        }
+        chain
+    }
+}
+
+/// serenity のメッセージを推論用のメッセージに変換する
+pub fn to_input_message(msg: &serenity::all::Message) -> InputMessage {
+    InputMessage {
+        content: msg.content.clone(),
+        name: msg.author.name.clone(),
+        message_id: msg.id.to_string(),
+        reply_msg: msg.referenced_message.as_ref().map(|m| m.content.clone() + &m.attachments.iter().map(|att| att.url.clone()).collect::<Vec<String>>().join(", ")),
+        user_id: msg.author.id.to_string(),
+        attached_files: msg.attachments.iter().map(|att| att.url.clone()).collect(),
+    }
+}
+
+impl ChatPlatform for DiscordPlatform {
+    fn message_limit(&self) -> usize {
+        DISCORD_MESSAGE_LIMIT
+    }
+
+    /// Discord のチャンネルは数値の ID (ほかのプラットフォームはキーに `:` を含む)
+    fn owns_channel(&self, channel: &str) -> bool {
+        !channel.contains(':')
+    }
+
+    async fn post_status(&self, channel: &str, text: &str) {
+        let Ok(channel_id) = Self::channel_id(channel) else {
+            return;
+        };
+        let status_res = CreateMessage::new()
+            .content(text)
+            .flags(MessageFlags::SUPPRESS_EMBEDS);
+
+        if let Err(e) = channel_id.send_message(&self.ctx.http, status_res).await {
+            debug!("Error sending message: {:?}", e);
+        }
+    }
+
+    async fn send_reply(&self, channel: &str, reply: Reply) -> Result<Option<String>, String> {
+        let channel_id = Self::channel_id(channel)?;
+        let files: Vec<CreateAttachment> = reply.files
+            .into_iter()
+            .map(|f| CreateAttachment::bytes(f.data, f.name))
+            .collect();
+        let mut response = CreateMessage::new()
+            .content(reply.content)
+            .add_files(files)
+            .flags(MessageFlags::SUPPRESS_EMBEDS);
+        if reply.retry_button {
+            let button = CreateButton::new(RETRY_BUTTON_ID)
+                .label("Retry")
+                .emoji('🔁')
+                .style(ButtonStyle::Secondary);
+            response = response.components(vec![CreateActionRow::Buttons(vec![button])]);
+        }
+        channel_id.send_message(&self.ctx.http, response)
+            .await
+            .map(|message| Some(message.id.to_string()))
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    async fn delete_message(&self, channel: &str, message_id: &str) -> Result<(), String> {
+        let channel_id = Self::channel_id(channel)?;
+        let message_id = MessageId::from_str(message_id).map_err(|e| format!("invalid message id {}: {:?}", message_id, e))?;
+        channel_id.delete_message(&self.ctx.http, message_id)
+            .await
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    async fn broadcast_typing(&self, channel: &str) {
+        let Ok(channel_id) = Self::channel_id(channel) else {
+            return;
+        };
+        if let Err(e) = channel_id.broadcast_typing(&self.ctx.http).await {
+            error!("setting typing indicator - {:?}", e);
+        }
+    }
+
+    async fn fetch_history(&self, channel: &str, limit: usize) -> Result<Vec<InputMessage>, String> {
+        let channel_id = Self::channel_id(channel)?;
+        let mut messages_stream = Box::pin(channel_id.messages_iter(&self.ctx.http).take(limit));
+        let mut messages_vec = Vec::new();
+        while let Some(message_result) = messages_stream.next().await {
+            if let Ok(message) = message_result {
+                messages_vec.push(InputMessage {
+                    // 履歴の画像は取り込まない
+                    attached_files: Vec::new(),
+                    ..to_input_message(&message)
+                });
+            }
+        }
+        // 新しい順に返ってくるので古い順にする
+        messages_vec.reverse();
+        Ok(messages_vec)
+    }
+
+    async fn fetch_since(&self, channel: &str, after_message_id: Option<&str>, limit: usize) -> Result<Vec<InputMessage>, String> {
+        let channel_id = Self::channel_id(channel)?;
+        // 1 回の取得は 100 件まで
+        let mut builder = GetMessages::new().limit(limit.min(100) as u8);
+        if let Some(after) = after_message_id {
+            let after = MessageId::from_str(after).map_err(|e| format!("invalid message id {}: {:?}", after, e))?;
+            builder = builder.after(after);
+        }
+        let mut messages = channel_id.messages(&self.ctx.http, builder)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        messages.sort_by_key(|m| m.id);
+        Ok(messages.iter().map(to_input_message).collect())
+    }
+
+    async fn resolve_user(&self, user_id: &str) -> Option<String> {
+        let user_id = UserId::from_str(user_id).ok()?;
+        user_id.to_user(&self.ctx.http).await.ok().map(|u| u.name)
+    }
+}
@@ -0,0 +1,78 @@
+//! テスト用のメモリ上のプラットフォーム
+
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::agent::InputMessage;
+
+use super::{ChatPlatform, Reply};
+
+/// 送られたものをすべて記録する
+#[derive(Default)]
+pub struct FakePlatform {
+    pub limit: usize,
+    pub history: HashMap<String, Vec<InputMessage>>,
+    pub users: HashMap<String, String>,
+    pub statuses: Mutex<Vec<(String, String)>>,
+    pub replies: Mutex<Vec<(String, Reply)>>,
+    pub typing: Mutex<usize>,
+    pub deleted: Mutex<Vec<String>>,
+}
+
+impl FakePlatform {
+    pub fn new() -> Self {
+        Self { limit: 2000, ..Default::default() }
+    }
+
+    /// 送られたメッセージの本文 (送信順)
+    pub fn sent(&self) -> Vec<String> {
+        self.replies.lock().unwrap().iter().map(|(_, r)| r.content.clone()).collect()
+    }
+}
+
+impl ChatPlatform for FakePlatform {
+    fn message_limit(&self) -> usize {
+        self.limit
+    }
+
+    fn owns_channel(&self, _channel: &str) -> bool {
+        true
+    }
+
+    async fn post_status(&self, channel: &str, text: &str) {
+        self.statuses.lock().unwrap().push((channel.to_string(), text.to_string()));
+    }
+
+    /// メッセージ ID は送信順の番号
+    async fn send_reply(&self, channel: &str, reply: Reply) -> Result<Option<String>, String> {
+        let mut replies = self.replies.lock().unwrap();
+        replies.push((channel.to_string(), reply));
+        Ok(Some(replies.len().to_string()))
+    }
+
+    async fn delete_message(&self, _channel: &str, message_id: &str) -> Result<(), String> {
+        self.deleted.lock().unwrap().push(message_id.to_string());
+        Ok(())
+    }
+
+    async fn broadcast_typing(&self, _channel: &str) {
+        *self.typing.lock().unwrap() += 1;
+    }
+
+    async fn fetch_history(&self, channel: &str, limit: usize) -> Result<Vec<InputMessage>, String> {
+        let history = self.history.get(channel).cloned().unwrap_or_default();
+        let skip = history.len().saturating_sub(limit);
+        Ok(history.into_iter().skip(skip).collect())
+    }
+
+    async fn fetch_since(&self, channel: &str, after_message_id: Option<&str>, limit: usize) -> Result<Vec<InputMessage>, String> {
+        let history = self.history.get(channel).ok_or("no history")?;
+        let start = after_message_id
+            .and_then(|id| history.iter().position(|m| m.message_id == id))
+            .map_or(0, |i| i + 1);
+        Ok(history[start..].iter().take(limit).cloned().collect())
+    }
+
+    async fn resolve_user(&self, user_id: &str) -> Option<String> {
+        self.users.get(user_id).cloned()
+    }
+}
@@ -0,0 +1,889 @@
+//! IRC の実装
+//!
+//! クライアントライブラリは使わず、必要な部分 (登録・SASL PLAIN・PING・PRIVMSG) だけを実装する。
+//! - チャンネルは `irc:#channel` (クエリは `irc:nick`) を `ChannelState` のキーにする
+//! - 自分の nick が含まれる発言をメンションとして扱う
+//! - 有効化・レート・モデルの切り替えは `!enable` などのコマンドで行う
+//! - 接続が切れたら指数バックオフで再接続する
+
+use std::{
+    sync::{atomic::{AtomicU64, Ordering}, Arc, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use base64::prelude::*;
+use log::{error, info, warn};
+use observer::{digest::DigestFrequency, markdown, prefix::IrcSettings, splitter::split_irc_lines};
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc,
+    task::JoinHandle,
+    time,
+};
+use tokio_rustls::{rustls::{pki_types::ServerName, ClientConfig, RootCertStore}, TlsConnector};
+
+use crate::{agent::InputMessage, bot::Bot};
+
+use super::{ChatPlatform, Reply};
+
+/// IRC の 1 行の最大バイト数 (CRLF を含む)
+const IRC_LINE_LIMIT: usize = 512;
+/// サーバーが中継時に付ける `:nick!user@host ` のうち user@host の最大長の見込み
+const MAX_USERHOST_LEN: usize = 74;
+/// 1 回の応答で送る最大行数 (超えた分は省略する)
+const MAX_REPLY_LINES: usize = 20;
+/// `split_message` に渡す上限 (IRC 側でさらに行に分割する)
+const IRC_MESSAGE_LIMIT: usize = 2000;
+/// 送信の間隔と、間隔なしで送れる行数
+const SEND_INTERVAL: Duration = Duration::from_millis(500);
+const SEND_BURST: u32 = 4;
+/// 無通信がこれだけ続いたら PING を送り、さらに続いたら切断とみなす
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// 再接続の待ち時間
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// これより長く繋がっていたらバックオフを戻す
+const STABLE_CONNECTION: Duration = Duration::from_secs(600);
+/// SASL の AUTHENTICATE 1 行あたりの最大長
+const SASL_CHUNK: usize = 400;
+
+/// IRC のメッセージ 1 行
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IrcMessage {
+    /// `account` タグ (account-tag で送信者がログインしているサービスのアカウント)
+    pub account: Option<String>,
+    pub prefix: Option<String>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl IrcMessage {
+    pub fn new(command: &str, params: &[&str]) -> Self {
+        Self {
+            account: None,
+            prefix: None,
+            command: command.to_string(),
+            params: params.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    /// `@tags :prefix COMMAND params :trailing` を読む (タグは `account` だけを残す)
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        let mut account = None;
+        if let Some(tagged) = rest.strip_prefix('@') {
+            let (tags, tail) = tagged.split_once(' ')?;
+            account = tags
+                .split(';')
+                .find_map(|tag| tag.strip_prefix("account="))
+                .filter(|a| !a.is_empty() && *a != "*")
+                .map(str::to_string);
+            rest = tail.trim_start();
+        }
+        let prefix = if let Some(stripped) = rest.strip_prefix(':') {
+            let (prefix, tail) = stripped.split_once(' ')?;
+            rest = tail.trim_start();
+            Some(prefix.to_string())
+        } else {
+            None
+        };
+
+        let mut params = Vec::new();
+        let (head, trailing) = match rest.split_once(" :") {
+            Some((head, trailing)) => (head, Some(trailing)),
+            None => (rest, None),
+        };
+        let mut words = head.split(' ').filter(|w| !w.is_empty());
+        let command = words.next()?.to_ascii_uppercase();
+        params.extend(words.map(str::to_string));
+        if let Some(trailing) = trailing {
+            params.push(trailing.to_string());
+        }
+        Some(Self { account, prefix, command, params })
+    }
+
+    /// 送信用の 1 行 (CRLF は含まない)
+    pub fn to_line(&self) -> String {
+        let mut line = String::new();
+        if let Some(prefix) = &self.prefix {
+            line.push(':');
+            line.push_str(prefix);
+            line.push(' ');
+        }
+        line.push_str(&self.command);
+        for (i, param) in self.params.iter().enumerate() {
+            // 改行が混ざると別のコマンドとして解釈されてしまう
+            let param = param.replace(['\r', '\n'], " ");
+            // 発言の本文は単語 1 つでも trailing として送る
+            let last = i + 1 == self.params.len();
+            let text = matches!(self.command.as_str(), "PRIVMSG" | "NOTICE");
+            if last && (text || param.is_empty() || param.contains(' ') || param.starts_with(':')) {
+                line.push_str(" :");
+            } else {
+                line.push(' ');
+            }
+            line.push_str(&param);
+        }
+        line
+    }
+
+    /// 送信者の nick
+    pub fn nick(&self) -> Option<&str> {
+        self.prefix.as_deref().map(|p| p.split('!').next().unwrap_or(p))
+    }
+
+    fn param(&self, index: usize) -> &str {
+        self.params.get(index).map(String::as_str).unwrap_or_default()
+    }
+}
+
+/// ユーザー ID (レート制限やモデル設定のキー)
+pub fn user_id(nick: &str) -> String {
+    format!("irc:{}", nick.to_lowercase())
+}
+
+/// `ChannelState` や ChConf のキー
+pub fn channel_key(target: &str) -> String {
+    format!("irc:{}", target.to_lowercase())
+}
+
+fn is_channel(target: &str) -> bool {
+    target.starts_with(['#', '&', '+', '!'])
+}
+
+/// `nick!user@host` が `*` と `?` を含むマスクに一致するか (大文字小文字は区別しない)
+pub fn mask_matches(mask: &str, hostmask: &str) -> bool {
+    let mask: Vec<char> = mask.to_lowercase().chars().collect();
+    let text: Vec<char> = hostmask.to_lowercase().chars().collect();
+    let (mut m, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        if m < mask.len() && (mask[m] == '?' || mask[m] == text[t]) {
+            m += 1;
+            t += 1;
+        } else if m < mask.len() && mask[m] == '*' {
+            backtrack = Some((m, t));
+            m += 1;
+        } else if let Some((star, matched)) = backtrack {
+            m = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    mask[m..].iter().all(|&c| c == '*')
+}
+
+/// 自分宛ての発言であれば、先頭の `nick:` を取り除いた本文を返す
+pub fn strip_mention(text: &str, nick: &str) -> Option<String> {
+    let lower = text.to_ascii_lowercase();
+    let nick = nick.to_ascii_lowercase();
+    if let Some(rest) = lower.strip_prefix(&nick) {
+        let rest_start = text.len() - rest.len();
+        let rest = &text[rest_start..];
+        match rest.chars().next() {
+            None => return Some(String::new()),
+            Some(':' | ',') => return Some(rest[1..].trim().to_string()),
+            Some(c) if c.is_whitespace() => return Some(rest.trim().to_string()),
+            _ => {}
+        }
+    }
+    let is_word = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '[' | ']' | '\\' | '^' | '{' | '}' | '|' | '`');
+    let mentioned = lower.match_indices(&nick).any(|(pos, _)| {
+        let before = lower[..pos].chars().next_back();
+        let after = lower[pos + nick.len()..].chars().next();
+        !before.is_some_and(is_word) && !after.is_some_and(is_word)
+    });
+    mentioned.then(|| text.trim().to_string())
+}
+
+/// 色・太字などの制御コードを取り除く
+pub fn strip_formatting(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x03' => {
+                // \x03fg,bg (それぞれ最大 2 桁)
+                for _ in 0..2 {
+                    chars.next_if(|c| c.is_ascii_digit());
+                }
+                if chars.peek() == Some(&',') {
+                    let mut lookahead = chars.clone();
+                    lookahead.next();
+                    if lookahead.peek().is_some_and(|c| c.is_ascii_digit()) {
+                        chars.next();
+                        for _ in 0..2 {
+                            chars.next_if(|c| c.is_ascii_digit());
+                        }
+                    }
+                }
+            }
+            '\x02' | '\x0f' | '\x11' | '\x16' | '\x1d' | '\x1e' | '\x1f' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `!command args` 形式のコマンド
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IrcCommand {
+    Help,
+    Reset,
+    Enable,
+    Disable,
+    Rate { nick: String, line: i64 },
+    Model(Option<String>),
+    /// 直前の応答を作り直す (モデルを指定できる)
+    Retry(Option<String>),
+    /// 自分の発言を消す (true なら確認済み)
+    ForgetMe(bool),
+    /// 予約の一覧 (ID があれば取り消し)
+    Schedule(Option<u64>),
+    /// ダイジェストの頻度 (`now` ならすぐに作る)
+    Digest(String),
+    /// モデレーションの扱いの表示 (指定があれば変更)
+    Moderation(Option<String>),
+    Unknown(String),
+}
+
+impl IrcCommand {
+    /// `prefix` で始まらなければ None
+    pub fn parse(text: &str, prefix: &str) -> Option<Self> {
+        let body = text.trim().strip_prefix(prefix)?;
+        let mut words = body.split_whitespace();
+        let name = words.next()?.to_lowercase();
+        let command = match name.as_str() {
+            "help" => Self::Help,
+            "reset" => Self::Reset,
+            "enable" => Self::Enable,
+            "disable" => Self::Disable,
+            "rate" => match (words.next(), words.next().and_then(|n| n.parse().ok())) {
+                (Some(nick), Some(line)) => Self::Rate { nick: nick.to_string(), line },
+                _ => Self::Unknown("usage: rate <nick> <line>".to_string()),
+            },
+            "model" => Self::Model(words.next().map(str::to_string)),
+            "retry" => Self::Retry(words.next().map(str::to_string)),
+            "forget_me" => Self::ForgetMe(words.next() == Some("confirm")),
+            "digest" => match words.next() {
+                Some(frequency) => Self::Digest(frequency.to_lowercase()),
+                None => Self::Unknown("usage: digest <daily|weekly|off|now>".to_string()),
+            },
+            "moderation" => Self::Moderation(words.next().map(str::to_lowercase)),
+            "schedule" => match (words.next(), words.next().map(str::parse)) {
+                (None, _) => Self::Schedule(None),
+                (Some("cancel"), Some(Ok(id))) => Self::Schedule(Some(id)),
+                _ => Self::Unknown("usage: schedule [cancel <id>]".to_string()),
+            },
+            _ => Self::Unknown(format!("unknown command: {}", name)),
+        };
+        Some(command)
+    }
+
+    /// 管理者だけが使えるか
+    fn requires_admin(&self) -> bool {
+        // nick は誰でも名乗れるので、チャンネル全体に効く操作は admin_masks で確かめる
+        matches!(self, Self::Reset | Self::Enable | Self::Disable | Self::Rate { .. } | Self::Digest(_) | Self::Moderation(Some(_)))
+    }
+
+    /// nick の持ち主であることを確かめてから使うか
+    fn requires_identity(&self) -> bool {
+        // 他人の nick を名乗って、その人の履歴を消したり応答や予約に手を出したりできないようにする
+        matches!(self, Self::ForgetMe(_) | Self::Retry(_) | Self::Schedule(Some(_)))
+    }
+}
+
+/// 1 接続ぶんの送信口
+#[derive(Clone)]
+pub struct IrcPlatform {
+    nick: Arc<RwLock<String>>,
+    sender: mpsc::UnboundedSender<String>,
+}
+
+impl IrcPlatform {
+    fn new(nick: &str, sender: mpsc::UnboundedSender<String>) -> Self {
+        Self { nick: Arc::new(RwLock::new(nick.to_string())), sender }
+    }
+
+    pub fn nick(&self) -> String {
+        self.nick.read().unwrap().clone()
+    }
+
+    fn set_nick(&self, nick: &str) {
+        *self.nick.write().unwrap() = nick.to_string();
+    }
+
+    fn send(&self, message: IrcMessage) {
+        if self.sender.send(message.to_line()).is_err() {
+            warn!("IRC connection is closed, dropping {}", message.command);
+        }
+    }
+
+    /// `irc:#channel` から送信先を取り出す
+    fn target(channel: &str) -> &str {
+        channel.strip_prefix("irc:").unwrap_or(channel)
+    }
+
+    /// 中継時にサーバーが付けるプレフィックスを見込んだ 1 行あたりの本文のバイト数
+    fn line_budget(&self, command: &str, target: &str) -> usize {
+        let overhead = format!(":{}! {} {} :\r\n", self.nick(), command, target).len() + MAX_USERHOST_LEN;
+        IRC_LINE_LIMIT.saturating_sub(overhead)
+    }
+
+    fn send_text(&self, command: &str, channel: &str, text: &str, extra: Option<String>) {
+        let target = Self::target(channel);
+        let mut lines = split_irc_lines(&markdown::to_irc(text), self.line_budget(command, target));
+        if lines.len() > MAX_REPLY_LINES {
+            let omitted = lines.len() - MAX_REPLY_LINES + 1;
+            lines.truncate(MAX_REPLY_LINES - 1);
+            lines.push(format!("… ({} more lines)", omitted));
+        }
+        lines.extend(extra);
+        for line in lines {
+            self.send(IrcMessage::new(command, &[target, &line]));
+        }
+    }
+}
+
+impl ChatPlatform for IrcPlatform {
+    fn message_limit(&self) -> usize {
+        IRC_MESSAGE_LIMIT
+    }
+
+    fn owns_channel(&self, channel: &str) -> bool {
+        channel.starts_with("irc:")
+    }
+
+    async fn post_status(&self, channel: &str, text: &str) {
+        self.send_text("NOTICE", channel, text, None);
+    }
+
+    async fn send_reply(&self, channel: &str, reply: Reply) -> Result<Option<String>, String> {
+        let note = (!reply.files.is_empty()).then(|| {
+            let names: Vec<&str> = reply.files.iter().map(|f| f.name.as_str()).collect();
+            format!("(attachments are not supported on IRC: {})", names.join(", "))
+        });
+        self.send_text("PRIVMSG", channel, &reply.content, note);
+        Ok(None)
+    }
+
+    async fn delete_message(&self, _channel: &str, _message_id: &str) -> Result<(), String> {
+        Err("IRC messages cannot be deleted".to_string())
+    }
+
+    async fn broadcast_typing(&self, _channel: &str) {}
+
+    async fn fetch_history(&self, _channel: &str, _limit: usize) -> Result<Vec<InputMessage>, String> {
+        Err("IRC has no message history".to_string())
+    }
+
+    async fn fetch_since(&self, _channel: &str, _after_message_id: Option<&str>, _limit: usize) -> Result<Vec<InputMessage>, String> {
+        Err("IRC has no message history".to_string())
+    }
+
+    async fn resolve_user(&self, user_id: &str) -> Option<String> {
+        Some(user_id.strip_prefix("irc:").unwrap_or(user_id).to_string())
+    }
+}
+
+/// 行の送信タスク (サーバーに切断されないように間隔を空ける)
+async fn write_loop<W: AsyncWrite + Unpin>(mut writer: W, mut receiver: mpsc::UnboundedReceiver<String>) -> io::Result<()> {
+    // ircd と同じ方式で、送信ごとにタイマーを進め、先行しすぎたら待つ
+    let mut timer = Instant::now();
+    while let Some(line) = receiver.recv().await {
+        let now = Instant::now();
+        timer = timer.max(now);
+        if let Some(ahead) = (timer - now).checked_sub(SEND_INTERVAL * SEND_BURST) {
+            time::sleep(ahead).await;
+        }
+        timer += SEND_INTERVAL;
+        writer.write_all(line.as_bytes()).await?;
+        writer.write_all(b"\r\n").await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// 1 接続のあいだの状態
+struct Session<'a> {
+    bot: &'a Arc<Bot>,
+    settings: &'a IrcSettings,
+    platform: IrcPlatform,
+    registered: bool,
+    /// この接続で予約を実行するループ (切断したら止める)
+    scheduler_task: Option<JoinHandle<()>>,
+}
+
+/// メッセージ ID の連番
+static MESSAGE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+fn next_message_id() -> String {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or_default();
+    format!("irc-{}-{}", millis, MESSAGE_SEQ.fetch_add(1, Ordering::Relaxed))
+}
+
+impl Session<'_> {
+    fn sasl_credentials(&self) -> Option<(&str, &str)> {
+        Some((self.settings.sasl_user.as_deref()?, self.settings.sasl_password.as_deref()?))
+    }
+
+    /// 接続直後の登録
+    fn register(&self) {
+        let settings = self.settings;
+        if let Some(password) = &settings.password {
+            self.platform.send(IrcMessage::new("PASS", &[password]));
+        }
+        // 送信者のアカウントを知るために account-tag を頼む (SASL とは別に頼み、片方が断られても他方は使えるようにする)
+        self.platform.send(IrcMessage::new("CAP", &["REQ", "account-tag"]));
+        if self.sasl_credentials().is_some() {
+            self.platform.send(IrcMessage::new("CAP", &["REQ", "sasl"]));
+        }
+        let username = settings.username.as_deref().unwrap_or(&settings.nick);
+        let realname = settings.realname.as_deref().unwrap_or(&settings.nick);
+        self.platform.send(IrcMessage::new("NICK", &[&settings.nick]));
+        self.platform.send(IrcMessage::new("USER", &[username, "0", "*", realname]));
+    }
+
+    /// SASL PLAIN の応答 (400 バイトごとに分けて送る)
+    fn authenticate(&self) {
+        let Some((user, password)) = self.sasl_credentials() else {
+            return;
+        };
+        let encoded = BASE64_STANDARD.encode(format!("{}\0{}\0{}", user, user, password));
+        let chunks: Vec<&str> = encoded.as_bytes().chunks(SASL_CHUNK).map(|c| std::str::from_utf8(c).unwrap_or_default()).collect();
+        for chunk in &chunks {
+            self.platform.send(IrcMessage::new("AUTHENTICATE", &[chunk]));
+        }
+        if chunks.last().is_none_or(|c| c.len() == SASL_CHUNK) {
+            self.platform.send(IrcMessage::new("AUTHENTICATE", &["+"]));
+        }
+    }
+
+    /// サーバーからの 1 行を処理する
+    /// 続行できないときは Err
+    async fn handle(&mut self, message: IrcMessage) -> Result<(), String> {
+        match message.command.as_str() {
+            "PING" => self.platform.send(IrcMessage::new("PONG", &[message.param(0)])),
+            "CAP" if message.param(1) == "ACK" && message.param(2).split_whitespace().any(|c| c == "sasl") => {
+                self.platform.send(IrcMessage::new("AUTHENTICATE", &["PLAIN"]));
+            }
+            "CAP" if message.param(1) == "NAK" && message.param(2).split_whitespace().any(|c| c == "sasl") => {
+                warn!("IRC server does not support SASL");
+                self.platform.send(IrcMessage::new("CAP", &["END"]));
+            }
+            // account-tag への返事。SASL を使うなら認証が終わってから CAP END を送る
+            "CAP" if matches!(message.param(1), "ACK" | "NAK") => {
+                if message.param(1) == "NAK" {
+                    warn!("IRC server does not support account-tag; only admins can use forget_me, retry and schedule cancel");
+                }
+                if self.sasl_credentials().is_none() {
+                    self.platform.send(IrcMessage::new("CAP", &["END"]));
+                }
+            }
+            "AUTHENTICATE" if message.param(0) == "+" => self.authenticate(),
+            "903" => self.platform.send(IrcMessage::new("CAP", &["END"])),
+            "902" | "904" | "905" | "906" => return Err(format!("SASL authentication failed - {}", message.params.join(" "))),
+            "001" => {
+                self.registered = true;
+                self.platform.set_nick(message.param(0));
+                info!("Registered on IRC as {}", message.param(0));
+                if !self.settings.channels.is_empty() {
+                    self.platform.send(IrcMessage::new("JOIN", &[&self.settings.channels.join(",")]));
+                }
+                if self.scheduler_task.is_none() {
+                    let owns = |channel: &str| channel.starts_with("irc:");
+                    self.scheduler_task = Some(tokio::spawn(self.bot.clone().run_scheduler(self.platform.clone(), owns)));
+                }
+            }
+            "433" if !self.registered => {
+                let nick = format!("{}_", message.param(1));
+                self.platform.set_nick(&nick);
+                self.platform.send(IrcMessage::new("NICK", &[&nick]));
+            }
+            "NICK" if message.nick().is_some_and(|n| n.eq_ignore_ascii_case(&self.platform.nick())) => {
+                self.platform.set_nick(message.param(0));
+            }
+            "KICK" if message.param(1).eq_ignore_ascii_case(&self.platform.nick()) => {
+                warn!("Kicked from {} - {}", message.param(0), message.param(2));
+                self.platform.send(IrcMessage::new("JOIN", &[message.param(0)]));
+            }
+            "PRIVMSG" => self.privmsg(&message).await,
+            "ERROR" => return Err(format!("server closed the link - {}", message.param(0))),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn privmsg(&self, message: &IrcMessage) {
+        let (Some(prefix), Some(sender)) = (message.prefix.as_deref(), message.nick()) else {
+            return;
+        };
+        let my_nick = self.platform.nick();
+        let mut text = message.param(1).to_string();
+        // CTCP は ACTION だけを発言として扱う
+        if let Some(ctcp) = text.strip_prefix('\x01') {
+            let Some(action) = ctcp.trim_end_matches('\x01').strip_prefix("ACTION ") else {
+                return;
+            };
+            text = format!("* {} {}", sender, action);
+        }
+        let text = strip_formatting(&text);
+
+        // チャンネルならチャンネルへ、クエリなら相手へ返す
+        let target = message.param(0);
+        let query = !is_channel(target);
+        let channel = channel_key(if query { sender } else { target });
+
+        if let Some(command) = IrcCommand::parse(&text, &self.settings.command_prefix) {
+            let is_admin = self.settings.admin_masks.iter().any(|mask| mask_matches(mask, prefix));
+            // サービスにその nick と同じアカウントでログインしていれば本人とみなす
+            let identified = is_admin || message.account.as_deref().is_some_and(|a| a.eq_ignore_ascii_case(sender));
+            let response = self.command(&channel, sender, is_admin, identified, command).await;
+            self.platform.send_text("PRIVMSG", &channel, &response, None);
+            return;
+        }
+
+        let mention = if query { Some(text.trim().to_string()) } else { strip_mention(&text, &my_nick) };
+        let input = InputMessage {
+            content: mention.clone().unwrap_or(text),
+            name: sender.to_string(),
+            message_id: next_message_id(),
+            reply_msg: None,
+            user_id: user_id(sender),
+            attached_files: Vec::new(),
+        };
+        // 推論は時間がかかるので、PING に応答できるように別タスクで行う
+        let bot = self.bot.clone();
+        let platform = self.platform.clone();
+        tokio::spawn(async move {
+            bot.handle_message(&platform, &channel, input, mention.is_some()).await;
+        });
+    }
+
+    async fn command(&self, channel: &str, sender: &str, is_admin: bool, identified: bool, command: IrcCommand) -> String {
+        if command.requires_admin() && !is_admin {
+            return "Err: permission denied".to_string();
+        }
+        if command.requires_identity() && !identified {
+            return "Err: identify with services (log in to the account named after your nick) to use this command".to_string();
+        }
+        let prefix = &self.settings.command_prefix;
+        match command {
+            IrcCommand::Help => format!(
+                "commands: {p}reset, {p}model [name], {p}retry [model], {p}forget_me [confirm], {p}schedule [cancel <id>], {p}enable, {p}disable, {p}rate <nick> <line>, {p}digest <daily|weekly|off|now>, {p}moderation [off|flag|redact|block|reset] (admin only: reset, enable, disable, rate, digest, changing moderation; retry: the asker or an admin; forget_me, retry and schedule cancel need a nick identified with services)",
+                p = prefix,
+            ),
+            IrcCommand::Reset => self.bot.reset(channel).await,
+            IrcCommand::Enable => self.bot.set_enabled(channel, true),
+            IrcCommand::Disable => self.bot.set_enabled(channel, false),
+            IrcCommand::Rate { nick, line } => self.bot.rate_conf(&self.platform, &user_id(&nick), line).await,
+            IrcCommand::Model(name) => self.bot.set_model(&user_id(sender), name.as_deref()).unwrap_or_else(|e| e),
+            IrcCommand::Schedule(cancel) => self.bot.schedule(channel, &user_id(sender), is_admin, cancel),
+            IrcCommand::ForgetMe(confirm) => self.bot.forget_me(&user_id(sender), sender, confirm).await,
+            IrcCommand::Retry(model) => {
+                let bot = self.bot.clone();
+                let platform = self.platform.clone();
+                let channel = channel.to_string();
+                let sender = sender.to_string();
+                tokio::spawn(async move {
+                    bot.retry(&platform, &channel, &user_id(&sender), &sender, model.as_deref(), is_admin).await;
+                });
+                "Info: regenerating the last answer...".to_string()
+            }
+            IrcCommand::Digest(frequency) if frequency == "now" => {
+                // 推論に時間がかかるので別タスクで作って流す
+                let bot = self.bot.clone();
+                let platform = self.platform.clone();
+                let channel = channel.to_string();
+                tokio::spawn(async move {
+                    let text = bot.digest_now(&platform, &channel).await;
+                    bot.send_split_message(&platform, &channel, text).await;
+                });
+                "Info: creating digest...".to_string()
+            }
+            IrcCommand::Digest(frequency) => match DigestFrequency::from_name(&frequency) {
+                Ok(frequency) => self.bot.set_digest(channel, frequency),
+                Err(e) => format!("Err: {}", e),
+            },
+            IrcCommand::Moderation(policy) => self.bot.moderation_conf(channel, policy.as_deref()),
+            IrcCommand::Unknown(error) => format!("Err: {}", error),
+        }
+    }
+}
+
+/// 接続済みのストリームで登録から切断までを処理する
+pub async fn serve<S>(bot: &Arc<Bot>, settings: &IrcSettings, stream: S) -> Result<(), String>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = io::split(stream);
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let writer_task = tokio::spawn(write_loop(writer, receiver));
+    let mut session = Session {
+        bot,
+        settings,
+        platform: IrcPlatform::new(&settings.nick, sender),
+        registered: false,
+        scheduler_task: None,
+    };
+    session.register();
+
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::new();
+    let mut pinged = false;
+    let result = loop {
+        buf.clear();
+        match time::timeout(IDLE_TIMEOUT, reader.read_until(b'\n', &mut buf)).await {
+            Err(_) if pinged => break Err("ping timeout".to_string()),
+            Err(_) => {
+                pinged = true;
+                session.platform.send(IrcMessage::new("PING", &["observer"]));
+            }
+            Ok(Ok(0)) => break Err("connection closed".to_string()),
+            Ok(Err(e)) => break Err(format!("read error - {}", e)),
+            Ok(Ok(_)) => {
+                pinged = false;
+                // IRC では UTF-8 以外が流れてくることもある
+                let line = String::from_utf8_lossy(&buf);
+                let Some(message) = IrcMessage::parse(&line) else {
+                    continue;
+                };
+                if let Err(e) = session.handle(message).await {
+                    break Err(e);
+                }
+            }
+        }
+    };
+    writer_task.abort();
+    if let Some(task) = session.scheduler_task {
+        task.abort();
+    }
+    result
+}
+
+/// サーバーに接続する (設定に応じて TLS)
+async fn connect_and_serve(bot: &Arc<Bot>, settings: &IrcSettings) -> Result<(), String> {
+    let tcp = TcpStream::connect((settings.server.as_str(), settings.port))
+        .await
+        .map_err(|e| format!("failed to connect to {}:{} - {}", settings.server, settings.port, e))?;
+    if !settings.tls {
+        return serve(bot, settings, tcp).await;
+    }
+
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_name = ServerName::try_from(settings.server.clone())
+        .map_err(|e| format!("invalid server name {} - {}", settings.server, e))?;
+    let tls = TlsConnector::from(Arc::new(config))
+        .connect(server_name, tcp)
+        .await
+        .map_err(|e| format!("TLS handshake failed - {}", e))?;
+    serve(bot, settings, tls).await
+}
+
+/// IRC に接続し続ける (切断されたら指数バックオフで再接続する)
+pub async fn run(bot: Arc<Bot>, settings: IrcSettings) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        info!("Connecting to IRC {}:{}", settings.server, settings.port);
+        let started = Instant::now();
+        if let Err(e) = connect_and_serve(&bot, &settings).await {
+            error!("IRC connection lost - {}", e);
+        }
+        if started.elapsed() > STABLE_CONNECTION {
+            backoff = MIN_BACKOFF;
+        }
+        info!("Reconnecting to IRC in {:?}", backoff);
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::tests::offline_bot;
+    use tempfile::TempDir;
+    use tokio::{io::{AsyncBufReadExt, BufReader}, net::TcpListener};
+
+    #[test]
+    fn parses_tags_prefix_and_trailing() {
+        let message = IrcMessage::parse("@time=2024 :alice!a@host PRIVMSG #rust :hello there: world\r\n").unwrap();
+        assert_eq!(message.prefix.as_deref(), Some("alice!a@host"));
+        assert_eq!(message.nick(), Some("alice"));
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.params, vec!["#rust", "hello there: world"]);
+        assert_eq!(message.account, None);
+        let tagged = IrcMessage::parse("@time=2024;account=Alice :alice!a@host PRIVMSG #rust :hi").unwrap();
+        assert_eq!(tagged.account.as_deref(), Some("Alice"));
+
+        let ping = IrcMessage::parse("PING irc.example.net").unwrap();
+        assert_eq!(ping.params, vec!["irc.example.net"]);
+        assert_eq!(IrcMessage::parse(""), None);
+    }
+
+    #[test]
+    fn formats_lines_safely() {
+        assert_eq!(IrcMessage::new("PRIVMSG", &["#a", "hi there"]).to_line(), "PRIVMSG #a :hi there");
+        assert_eq!(IrcMessage::new("NICK", &["observer"]).to_line(), "NICK observer");
+        assert_eq!(IrcMessage::new("PRIVMSG", &["#a", "x\r\nQUIT"]).to_line(), "PRIVMSG #a :x  QUIT");
+        assert_eq!(IrcMessage::new("PRIVMSG", &["#a", ":)"]).to_line(), "PRIVMSG #a ::)");
+    }
+
+    #[test]
+    fn detects_mentions() {
+        assert_eq!(strip_mention("observer: what time is it", "observer").as_deref(), Some("what time is it"));
+        assert_eq!(strip_mention("Observer, hi", "observer").as_deref(), Some("hi"));
+        assert_eq!(strip_mention("ask observer about it", "observer").as_deref(), Some("ask observer about it"));
+        assert_eq!(strip_mention("observers are here", "observer"), None);
+        assert_eq!(strip_mention("nothing", "observer"), None);
+    }
+
+    #[test]
+    fn matches_hostmasks() {
+        assert!(mask_matches("admin!*@*", "Admin!user@host.example"));
+        assert!(mask_matches("*!*@*.example.net", "bob!b@gw.example.net"));
+        assert!(mask_matches("b?b!*", "bob!x@y"));
+        assert!(!mask_matches("admin!*@*", "mallory!admin@host"));
+    }
+
+    #[test]
+    fn strips_formatting_codes() {
+        assert_eq!(strip_formatting("\x02bold\x02 \x0304,01red\x03 \x1ditalic\x0f"), "bold red italic");
+        assert_eq!(strip_formatting("\x0312,x"), ",x");
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(IrcCommand::parse("!enable", "!"), Some(IrcCommand::Enable));
+        assert_eq!(IrcCommand::parse("!rate bob 5", "!"), Some(IrcCommand::Rate { nick: "bob".into(), line: 5 }));
+        assert_eq!(IrcCommand::parse("!model gpt-5", "!"), Some(IrcCommand::Model(Some("gpt-5".into()))));
+        assert!(matches!(IrcCommand::parse("!rate bob", "!"), Some(IrcCommand::Unknown(_))));
+        assert_eq!(IrcCommand::parse("!schedule cancel 3", "!"), Some(IrcCommand::Schedule(Some(3))));
+        assert_eq!(IrcCommand::parse("!schedule", "!"), Some(IrcCommand::Schedule(None)));
+        assert_eq!(IrcCommand::parse("!retry gpt-5", "!"), Some(IrcCommand::Retry(Some("gpt-5".into()))));
+        assert_eq!(IrcCommand::parse("!forget_me confirm", "!"), Some(IrcCommand::ForgetMe(true)));
+        assert_eq!(IrcCommand::parse("!digest Weekly", "!"), Some(IrcCommand::Digest("weekly".into())));
+        assert_eq!(IrcCommand::parse("!moderation Block", "!"), Some(IrcCommand::Moderation(Some("block".into()))));
+        assert!(!IrcCommand::Moderation(None).requires_admin());
+        assert!(IrcCommand::Reset.requires_admin());
+        assert!(IrcCommand::ForgetMe(false).requires_identity() && IrcCommand::Schedule(Some(1)).requires_identity());
+        assert!(!IrcCommand::Schedule(None).requires_identity());
+        assert_eq!(IrcCommand::parse("hello", "!"), None);
+    }
+
+    #[tokio::test]
+    async fn splits_replies_into_irc_lines() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let platform = IrcPlatform::new("observer", sender);
+        let long = "word ".repeat(200);
+        let reply = Reply {
+            content: format!("**title**\n\n{}", long),
+            files: vec![super::super::OutgoingFile { name: "answer.md".into(), data: Vec::new() }],
+            ..Default::default()
+        };
+        platform.send_reply("irc:#rust", reply).await.unwrap();
+
+        let mut lines = Vec::new();
+        while let Ok(line) = receiver.try_recv() {
+            lines.push(line);
+        }
+        assert_eq!(lines[0], "PRIVMSG #rust :\x02title\x02");
+        assert!(lines.len() > 3);
+        assert!(lines.iter().all(|l| l.len() + MAX_USERHOST_LEN + 12 <= IRC_LINE_LIMIT));
+        assert!(lines.last().unwrap().contains("answer.md"));
+    }
+
+    /// サーバーから 1 行読む (`prefix` で始まる行まで読み飛ばす)
+    async fn expect_line<R: AsyncBufReadExt + Unpin>(reader: &mut R, prefix: &str) -> String {
+        let read = async {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                assert_ne!(reader.read_line(&mut line).await.unwrap(), 0, "connection closed while waiting for {}", prefix);
+                if line.starts_with(prefix) {
+                    return line.trim_end().to_string();
+                }
+            }
+        };
+        time::timeout(Duration::from_secs(10), read).await.unwrap_or_else(|_| panic!("timed out waiting for {}", prefix))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn registers_and_answers_commands_against_a_local_ircd() {
+        let dir = TempDir::new().unwrap();
+        let bot = Arc::new(offline_bot(&dir));
+        let settings = IrcSettings {
+            enable: true,
+            server: "127.0.0.1".into(),
+            port: 0,
+            tls: false,
+            nick: "observer".into(),
+            username: None,
+            realname: None,
+            password: None,
+            sasl_user: Some("observer".into()),
+            sasl_password: Some("secret".into()),
+            channels: vec!["#test".into()],
+            admin_masks: vec!["admin!*@*".into()],
+            command_prefix: "!".into(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(address).await.unwrap();
+            serve(&bot, &settings, stream).await
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut send = async |line: &str| writer.write_all(format!("{}\r\n", line).as_bytes()).await.unwrap();
+
+        // SASL PLAIN で登録する
+        expect_line(&mut reader, "CAP REQ").await;
+        expect_line(&mut reader, "NICK observer").await;
+        expect_line(&mut reader, "USER observer 0 *").await;
+        send(":irc.test CAP * ACK :account-tag").await;
+        send(":irc.test CAP * ACK :sasl").await;
+        expect_line(&mut reader, "AUTHENTICATE PLAIN").await;
+        send("AUTHENTICATE +").await;
+        let auth = expect_line(&mut reader, "AUTHENTICATE ").await;
+        assert_eq!(auth, format!("AUTHENTICATE {}", BASE64_STANDARD.encode("observer\0observer\0secret")));
+        send(":irc.test 903 observer :SASL authentication successful").await;
+        expect_line(&mut reader, "CAP END").await;
+        send(":irc.test 001 observer :Welcome").await;
+        expect_line(&mut reader, "JOIN #test").await;
+
+        send("PING :irc.test").await;
+        expect_line(&mut reader, "PONG irc.test").await;
+
+        // 無効なチャンネルでのメンションはエラーを返す
+        send(":alice!a@example PRIVMSG #test :observer: hello").await;
+        assert_eq!(expect_line(&mut reader, "PRIVMSG").await, "PRIVMSG #test :Err: AI is disabled in this channel");
+
+        // 管理者以外は有効化できない
+        send(":alice!a@example PRIVMSG #test :!enable").await;
+        assert_eq!(expect_line(&mut reader, "PRIVMSG").await, "PRIVMSG #test :Err: permission denied");
+        send(":admin!a@example PRIVMSG #test :!enable").await;
+        assert_eq!(expect_line(&mut reader, "PRIVMSG").await, "PRIVMSG #test :Info: AI is enabled");
+
+        // nick を名乗るだけでは他人の履歴を消せない
+        send(":alice!mallory@example PRIVMSG #test :!forget_me confirm").await;
+        assert!(expect_line(&mut reader, "PRIVMSG").await.starts_with("PRIVMSG #test :Err: identify with services"));
+        send("@account=mallory :alice!m@example PRIVMSG #test :!retry").await;
+        assert!(expect_line(&mut reader, "PRIVMSG").await.starts_with("PRIVMSG #test :Err: identify with services"));
+        send("@time=2026-01-01T00:00:00Z;account=alice :alice!a@example PRIVMSG #test :!forget_me confirm").await;
+        assert_eq!(expect_line(&mut reader, "PRIVMSG").await, "PRIVMSG #test :Err: run forget_me without confirm first");
+
+        send("ERROR :Closing link").await;
+        let result = time::timeout(Duration::from_secs(10), client).await.unwrap().unwrap();
+        assert!(result.unwrap_err().contains("Closing link"));
+    }
+}
@@ -0,0 +1,80 @@
+//! チャットプラットフォームの抽象化
+//!
+//! エージェントやコマンドの処理は `ChatPlatform` を通してだけ外部とやり取りする。
+//! Discord と IRC がその実装で、テストではメモリ上の `fake::FakePlatform` を使う。
+
+use std::future::Future;
+
+use crate::agent::{InputMessage, StatusSink};
+
+pub mod discord;
+pub mod irc;
+#[cfg(test)]
+pub mod fake;
+
+/// 送信するファイル
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutgoingFile {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// 送信するメッセージ
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Reply {
+    pub content: String,
+    pub files: Vec<OutgoingFile>,
+    /// 応答を作り直すボタンを付ける (ボタンのないプラットフォームでは無視する)
+    pub retry_button: bool,
+}
+
+impl Reply {
+    pub fn text(content: impl Into<String>) -> Self {
+        Self { content: content.into(), ..Default::default() }
+    }
+}
+
+/// チャットプラットフォーム
+///
+/// チャンネルはプラットフォームごとの文字列で表す (Discord ならチャンネル ID、IRC ならチャンネル名)。
+pub trait ChatPlatform: Send + Sync {
+    /// 1 メッセージあたりの最大文字数
+    fn message_limit(&self) -> usize;
+
+    /// このプラットフォームのチャンネルかどうか (チャンネルのキーで判断する)
+    fn owns_channel(&self, channel: &str) -> bool;
+
+    /// `-# using browser...` のような進捗を流す
+    fn post_status(&self, channel: &str, text: &str) -> impl Future<Output = ()> + Send;
+
+    /// メッセージを送り、送ったメッセージの ID を返す (ID のないプラットフォームでは None)
+    fn send_reply(&self, channel: &str, reply: Reply) -> impl Future<Output = Result<Option<String>, String>> + Send;
+
+    /// 送ったメッセージを消す
+    fn delete_message(&self, channel: &str, message_id: &str) -> impl Future<Output = Result<(), String>> + Send;
+
+    /// 入力中の表示
+    fn broadcast_typing(&self, channel: &str) -> impl Future<Output = ()> + Send;
+
+    /// 直近のメッセージを古い順に `limit` 件まで取得する
+    fn fetch_history(&self, channel: &str, limit: usize) -> impl Future<Output = Result<Vec<InputMessage>, String>> + Send;
+
+    /// `after_message_id` より後のメッセージを古い順に `limit` 件まで取得する (添付の URL も含む)
+    /// ID が None なら直近のメッセージ
+    fn fetch_since(&self, channel: &str, after_message_id: Option<&str>, limit: usize) -> impl Future<Output = Result<Vec<InputMessage>, String>> + Send;
+
+    /// ユーザー ID から表示名を引く
+    fn resolve_user(&self, user_id: &str) -> impl Future<Output = Option<String>> + Send;
+}
+
+/// 推論中の進捗をプラットフォームのチャンネルに流す
+pub struct ChannelStatus<'a, P: ChatPlatform> {
+    pub platform: &'a P,
+    pub channel: &'a str,
+}
+
+impl<P: ChatPlatform> StatusSink for ChannelStatus<'_, P> {
+    async fn post_status(&self, text: String) {
+        self.platform.post_status(self.channel, &text).await;
+    }
+}
@@ -16,6 +16,212 @@ pub struct PromptSettings {
     pub ask_developer_prompt: String,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct FormattingSettings {
+    /// この文字数を超える応答は web_deploy で記事化する (0 で無効)
+    pub auto_deploy_min_chars: usize,
+    /// 表や見出しを含む応答も記事化する
+    pub auto_deploy_structured: bool,
+    /// 記事化したときにチャンネルに流す要約の最大文字数
+    pub summary_max_chars: usize,
+}
+
+impl Default for FormattingSettings {
+    fn default() -> Self {
+        Self {
+            auto_deploy_min_chars: 4000,
+            auto_deploy_structured: false,
+            summary_max_chars: 300,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiKeySettings {
+    /// 使用量の記録やログに使う名前
+    pub name: String,
+    /// `Authorization: Bearer <key>` で送られるキー
+    pub key: String,
+    /// 1 分あたりのリクエスト数の上限
+    #[serde(default = "default_requests_per_minute")]
+    pub requests_per_minute: u32,
+    /// 1 日あたりのトークン数の上限 (0 で無制限)
+    #[serde(default)]
+    pub daily_token_limit: u64,
+}
+
+fn default_requests_per_minute() -> u32 {
+    10
+}
+
+/// OpenAI 互換 API (`/v1/chat/completions`) の設定
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ApiSettings {
+    /// 空なら API は無効
+    pub keys: Vec<ApiKeySettings>,
+}
+
+/// 予約実行 (scheduler ツール) の設定
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct SchedulerSettings {
+    pub enable: bool,
+    /// 1 ユーザーが持てるジョブ数 (0 で無制限)
+    pub max_jobs_per_user: usize,
+    /// 時刻にタイムゾーンがないときに使う IANA タイムゾーン
+    pub default_timezone: String,
+}
+
+impl Default for SchedulerSettings {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            max_jobs_per_user: 5,
+            default_timezone: "Asia/Tokyo".to_string(),
+        }
+    }
+}
+
+/// モデレーションの設定
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ModerationSettings {
+    pub enable: bool,
+    /// 大文字小文字を区別せずに照合する語
+    pub words: Vec<String>,
+    /// 正規表現のルール
+    pub patterns: Vec<String>,
+    /// `/moderations` を持つ API のベース URL (空なら問い合わせない)
+    pub endpoint: String,
+    pub api_key: String,
+    pub model: String,
+    /// チャンネルに設定がないときの扱い (off, flag, redact, block)
+    pub default_policy: String,
+    /// 引っかかったときに知らせるチャンネル (空なら監査ログだけ)
+    pub log_channel: String,
+}
+
+impl Default for ModerationSettings {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            words: Vec::new(),
+            patterns: Vec::new(),
+            endpoint: String::new(),
+            api_key: String::new(),
+            model: "omni-moderation-latest".to_string(),
+            default_policy: "flag".to_string(),
+            log_channel: String::new(),
+        }
+    }
+}
+
+/// 音声メッセージの書き起こしの設定
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct TranscriptionSettings {
+    pub enable: bool,
+    /// `/audio/transcriptions` を持つ API のベース URL (whisper.cpp の server など)
+    pub endpoint: String,
+    pub api_key: String,
+    pub model: String,
+    /// 言語のヒント (空なら自動判定)
+    pub language: String,
+    /// 1 ユーザーが 1 日に書き起こせる分数 (0 で無制限)
+    pub minutes_per_day: u64,
+}
+
+impl Default for TranscriptionSettings {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            endpoint: "http://127.0.0.1:8080/v1/".to_string(),
+            api_key: String::new(),
+            model: "whisper-1".to_string(),
+            language: String::new(),
+            minutes_per_day: 10,
+        }
+    }
+}
+
+/// 画像生成の設定
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ImageGenerationSettings {
+    pub enable: bool,
+    /// `/images/generations` と `/images/edits` を持つ API のベース URL
+    pub endpoint: String,
+    pub api_key: String,
+    pub model: String,
+    /// 指定がないときの大きさ (1024x1024, 1536x1024, 1024x1536, auto)
+    pub default_size: String,
+    /// 指定がないときの品質 (low, medium, high, auto)
+    pub default_quality: String,
+    /// 1 枚ごとに消費するレート (モデルのコストと同じ単位)
+    pub rate_cost: u64,
+}
+
+impl Default for ImageGenerationSettings {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            endpoint: "https://api.openai.com/v1/".to_string(),
+            api_key: String::new(),
+            model: "gpt-image-1".to_string(),
+            default_size: "1024x1024".to_string(),
+            default_quality: "medium".to_string(),
+            rate_cost: 20,
+        }
+    }
+}
+
+/// IRC アダプタの設定
+#[derive(Deserialize, Debug, Clone)]
+pub struct IrcSettings {
+    #[serde(default)]
+    pub enable: bool,
+    pub server: String,
+    #[serde(default = "default_irc_port")]
+    pub port: u16,
+    #[serde(default = "default_true")]
+    pub tls: bool,
+    pub nick: String,
+    /// 省略時は nick
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub realname: Option<String>,
+    /// サーバーパスワード (PASS)
+    #[serde(default)]
+    pub password: Option<String>,
+    /// SASL PLAIN の認証情報 (両方あるときだけ使う)
+    #[serde(default)]
+    pub sasl_user: Option<String>,
+    #[serde(default)]
+    pub sasl_password: Option<String>,
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// 管理者として扱う hostmask (`nick!user@host`、`*` と `?` が使える)
+    #[serde(default)]
+    pub admin_masks: Vec<String>,
+    #[serde(default = "default_command_prefix")]
+    pub command_prefix: String,
+}
+
+fn default_irc_port() -> u16 {
+    6697
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_command_prefix() -> String {
+    "!".to_string()
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Settings {
     pub assistant_name: String,
@@ -29,6 +235,20 @@ pub struct Settings {
     pub rate_cp: usize,
     pub model: ModelSettings,
     pub prompt: PromptSettings,
+    #[serde(default)]
+    pub formatting: FormattingSettings,
+    #[serde(default)]
+    pub api: ApiSettings,
+    #[serde(default)]
+    pub irc: Option<IrcSettings>,
+    #[serde(default)]
+    pub scheduler: SchedulerSettings,
+    #[serde(default)]
+    pub moderation: ModerationSettings,
+    #[serde(default)]
+    pub transcription: TranscriptionSettings,
+    #[serde(default)]
+    pub image_generation: ImageGenerationSettings,
     pub discord_token: String,
     pub server_domain: String,
     pub admin_users: Vec<String>,
@@ -54,6 +274,17 @@ lazy_static::lazy_static! {
     pub static ref SEC_PER_RATE: usize = CONFIG.sec_per_rate;
     pub static ref RATE_CP: usize = CONFIG.rate_cp;
     pub static ref ADMIN_USERS: Vec<String> = CONFIG.admin_users.clone();
+    pub static ref AUTO_DEPLOY_MIN_CHARS: usize = CONFIG.formatting.auto_deploy_min_chars;
+    pub static ref AUTO_DEPLOY_STRUCTURED: bool = CONFIG.formatting.auto_deploy_structured;
+    pub static ref SUMMARY_MAX_CHARS: usize = CONFIG.formatting.summary_max_chars;
+    pub static ref API_KEYS: Vec<ApiKeySettings> = CONFIG.api.keys.clone();
+    pub static ref ENABLE_SCHEDULER: bool = CONFIG.scheduler.enable;
+    pub static ref MAX_JOBS_PER_USER: usize = CONFIG.scheduler.max_jobs_per_user;
+    pub static ref DEFAULT_TIMEZONE: &'static str = &CONFIG.scheduler.default_timezone;
+    pub static ref IRC: Option<IrcSettings> = CONFIG.irc.clone().filter(|irc| irc.enable);
+    pub static ref MODERATION: &'static ModerationSettings = &CONFIG.moderation;
+    pub static ref TRANSCRIPTION: &'static TranscriptionSettings = &CONFIG.transcription;
+    pub static ref IMAGE_GENERATION: &'static ImageGenerationSettings = &CONFIG.image_generation;
 }
 
 impl Settings {
@@ -80,6 +311,46 @@ impl Settings {
     "prompt": {
         "ask_developer_prompt": "重要: あなたはDiscord上で活動しているObserverという名前の人格で自然に会話します\ntool callを活用してください\n依頼は応答で返し、どうしても詳細な説明が必要な場合のみ質問者に尋ねてください\n適切な場合には、(´-ω-`) のような絵文字を使って感情を表現してください\nあまり'!'を使わないようにしてください\n重要: 一語だけの返答が可能な場合は、一語で応答してください（例: 'うん', 'そう', 'は？', 'えぇ...', '??????'）\n重要: バランスのとれた、知的に引き込む、かつ技術的に情報に精通した態度を保ってください\nカジュアルな会話を行います。会話の流れを意識するように\n顔文字やスラングを適度に使い、返答は短めでテンポよくします\n静かでかわいい性格\n最も重要: 周りの人のしゃべり方などを真似するのがもっとも効果的\n応答にメタデータを含めないでください\nネットを使った場合は情報源を示すようにしなさい\n応答が長くなったり、説明がとても長くなる もしくは説明がまとめれたときはweb_deploy_toolを使うと良いでしょう\n記事を書いたらどんな記事を書いたかかるくmemoryしておくとよいでしょう"
     },
+    "formatting": {
+        "auto_deploy_min_chars": 4000,
+        "auto_deploy_structured": false,
+        "summary_max_chars": 300
+    },
+    "api": {
+        "keys": []
+    },
+    "scheduler": {
+        "enable": true,
+        "max_jobs_per_user": 5,
+        "default_timezone": "Asia/Tokyo"
+    },
+    "moderation": {
+        "enable": false,
+        "words": [],
+        "patterns": [],
+        "endpoint": "",
+        "api_key": "",
+        "model": "omni-moderation-latest",
+        "default_policy": "flag",
+        "log_channel": ""
+    },
+    "transcription": {
+        "enable": false,
+        "endpoint": "http://127.0.0.1:8080/v1/",
+        "api_key": "",
+        "model": "whisper-1",
+        "language": "",
+        "minutes_per_day": 10
+    },
+    "image_generation": {
+        "enable": false,
+        "endpoint": "https://api.openai.com/v1/",
+        "api_key": "",
+        "model": "gpt-image-1",
+        "default_size": "1024x1024",
+        "default_quality": "medium",
+        "rate_cost": 20
+    },
     "discord_token": "YOUR_API_KEY",
     "server_domain": "dev.371tti.net"
 }
@@ -0,0 +1,334 @@
+//! ユーザーの発言の消去 (`/forget_me`)
+//!
+//! 会話履歴からユーザーの発言を取り除き、その発言から作られたメモリと、
+//! 発言を引用している記事を洗い出す。実際の削除や記録は `Bot` が行う。
+
+use std::{collections::{HashSet, VecDeque}, path::Path};
+
+use call_agent::chat::prompt::Message;
+use serde_json::{json, Value};
+
+use crate::{history::{self, Derived, UserMeta}, image_cache};
+
+/// 匿名化したメモリでユーザー名の代わりに入れる文字列
+pub const FORGOTTEN_USER: &str = "[forgotten user]";
+
+/// 確認してから実行するまでの猶予 (秒)
+pub const CONFIRM_WINDOW_SECS: u64 = 5 * 60;
+
+/// 記事が発言を引用しているとみなす最短の行の長さ (短い相づちなどで誤検出しないため)
+const MIN_QUOTE_CHARS: usize = 20;
+
+/// 取り除いた発言
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Removed {
+    pub message_ids: Vec<String>,
+    pub bodies: Vec<String>,
+    /// 発言に添付された画像 (`image_cache` のハッシュ)
+    pub images: Vec<String>,
+    /// 発言への応答でツールが作った記事とメモリ
+    pub derived: Vec<Derived>,
+}
+
+impl Removed {
+    pub fn count(&self) -> usize {
+        self.message_ids.len()
+    }
+
+    pub fn extend(&mut self, other: Removed) {
+        self.message_ids.extend(other.message_ids);
+        self.bodies.extend(other.bodies);
+        for hash in other.images {
+            if !self.images.contains(&hash) {
+                self.images.push(hash);
+            }
+        }
+        for item in other.derived {
+            if !self.derived.contains(&item) {
+                self.derived.push(item);
+            }
+        }
+    }
+}
+
+fn is_from(message: &Message, user_id: &str) -> bool {
+    matches!(message, Message::User { name: Some(name), .. } if name == user_id)
+}
+
+/// 履歴の中のユーザーの発言の数
+pub fn count_user_messages<'a>(messages: impl IntoIterator<Item = &'a Message>, user_id: &str) -> usize {
+    messages.into_iter().filter(|m| is_from(m, user_id)).count()
+}
+
+/// 履歴からユーザーの発言 (`Message::User.name` がユーザー ID のもの) を、その応答とツールのやり取りごと取り除く
+/// 応答は発言を言い換えたり引用したりしているので、発言だけを消しても内容が残ってしまう
+pub fn remove_user_messages(prompt: &mut VecDeque<Message>, user_id: &str) -> Removed {
+    let mut removed = Removed::default();
+    let mut index = 0;
+    while index < prompt.len() {
+        if !is_from(&prompt[index], user_id) {
+            index += 1;
+            continue;
+        }
+        let turn: Vec<Message> = prompt.drain(history::turn_range(&*prompt, index)).collect();
+        let meta = UserMeta::from_message(&turn[0]);
+        removed.extend(Removed {
+            message_ids: meta.iter().map(|m| m.message_id.clone()).collect(),
+            bodies: meta.into_iter().map(|m| m.body).collect(),
+            images: turn.iter().flat_map(image_cache::references).collect(),
+            derived: history::derived_from(&turn[1..]),
+        });
+    }
+    removed
+}
+
+/// `history::snapshot` で保存された履歴のファイルからユーザーの発言を取り除く
+pub fn purge_snapshot_file(path: &Path, user_id: &str) -> Result<Removed, String> {
+    let json = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let value: Value = serde_json::from_str(&json).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut prompt: VecDeque<Message> = history::restore(&value)?.into();
+    let removed = remove_user_messages(&mut prompt, user_id);
+    if removed.count() > 0 {
+        let json = serde_json::to_string_pretty(&history::snapshot(&prompt)).map_err(|e| e.to_string())?;
+        std::fs::write(path, json).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(removed)
+}
+
+/// ディレクトリ内の保存された履歴 (*.json) をすべて処理する
+pub fn purge_snapshot_dir(dir: &Path, user_id: &str) -> Removed {
+    let mut removed = Removed::default();
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return removed;
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.extension().is_some_and(|ext| ext == "json") {
+            match purge_snapshot_file(&path, user_id) {
+                Ok(r) => removed.extend(r),
+                Err(e) => log::error!("Failed to purge {} - {}", path.display(), e),
+            }
+        }
+    }
+    removed
+}
+
+/// 取り除いた発言の画像のうち、残った履歴 (`remaining`) とディレクトリ内の保存された履歴のどこからも参照されていないもの
+/// キャッシュは内容のハッシュで共有されるので、ほかの人が同じ画像を貼っていれば消さない
+pub fn unreferenced_images<'a>(images: &[String], remaining: impl IntoIterator<Item = &'a Message>, snapshot_dir: &Path) -> Vec<String> {
+    let mut in_use: HashSet<String> = remaining.into_iter().flat_map(image_cache::references).collect();
+    if let Ok(entries) = std::fs::read_dir(snapshot_dir) {
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let saved = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|json| serde_json::from_str::<Value>(&json).ok())
+                .and_then(|value| history::restore(&value).ok())
+                .unwrap_or_default();
+            in_use.extend(saved.iter().flat_map(image_cache::references));
+        }
+    }
+    images.iter().filter(|hash| !in_use.contains(*hash)).cloned().collect()
+}
+
+/// `text` の中で `name` が単語として現れる位置 (`al` は `always` に一致しない)
+/// 名前の端と隣の文字がともに ASCII の英数字か `_` のときだけ続いているとみなすので、日本語の名前は `太郎さん` にも一致する
+fn name_matches<'a>(text: &'a str, name: &'a str) -> impl Iterator<Item = usize> + 'a {
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+    let (first, last) = (name.chars().next(), name.chars().next_back());
+    text.match_indices(name).map(|(i, _)| i).filter(move |&i| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + name.len()..].chars().next();
+        !((is_word(first) && is_word(before)) || (is_word(last) && is_word(after)))
+    })
+}
+
+/// `text` が `name` を単語として含むか
+pub fn mentions_name(text: &str, name: &str) -> bool {
+    !name.is_empty() && name_matches(text, name).next().is_some()
+}
+
+/// `text` の中の単語としての `name` をすべて `replacement` に置き換える
+pub fn replace_name(text: &str, name: &str, replacement: &str) -> String {
+    if name.is_empty() {
+        return text.to_string();
+    }
+    let mut out = String::new();
+    let mut last = 0;
+    for i in name_matches(text, name).collect::<Vec<_>>() {
+        out.push_str(&text[last..i]);
+        out.push_str(replacement);
+        last = i + name.len();
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+/// 記事がユーザーを引用しているか (名前・メンション・発言の一節を含む)
+pub fn quotes(article: &str, user_name: &str, user_id: &str, bodies: &[String]) -> bool {
+    if mentions_name(article, user_name) {
+        return true;
+    }
+    if article.contains(&format!("<@{}>", user_id)) {
+        return true;
+    }
+    bodies
+        .iter()
+        .flat_map(|body| body.lines())
+        .map(str::trim)
+        .filter(|line| line.chars().count() >= MIN_QUOTE_CHARS)
+        .any(|line| article.contains(line))
+}
+
+/// 実行結果 (監査ログとユーザーへの返答に使う)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForgetReport {
+    pub removed_messages: usize,
+    pub deleted_memories: Vec<String>,
+    pub anonymized_memories: Vec<String>,
+    /// キャッシュから消した画像の数
+    pub deleted_images: usize,
+    /// 置き場から消した添付ファイルの数
+    pub deleted_attachments: usize,
+    /// 管理者が見直す記事
+    pub articles: Vec<String>,
+}
+
+impl ForgetReport {
+    pub fn to_audit(&self, user_id: &str) -> Value {
+        json!({
+            "user_id": user_id,
+            "removed_messages": self.removed_messages,
+            "deleted_memories": self.deleted_memories,
+            "anonymized_memories": self.anonymized_memories,
+            "deleted_images": self.deleted_images,
+            "deleted_attachments": self.deleted_attachments,
+            "articles_for_review": self.articles,
+        })
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "Info: removed {} of your messages from the history, deleted {} and anonymized {} memory entries and deleted {} cached images and {} attachments. {} articles quoting you were flagged for admin review.",
+            self.removed_messages,
+            self.deleted_memories.len(),
+            self.anonymized_memories.len(),
+            self.deleted_images,
+            self.deleted_attachments,
+            self.articles.len(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{user, with_images};
+    use call_agent::chat::{function::{FunctionCall, FunctionCallInner}, prompt::MessageContext};
+
+    fn remember(key: &str) -> Message {
+        Message::Assistant {
+            name: None,
+            content: vec![],
+            tool_calls: Some(vec![FunctionCall {
+                id: "c".into(),
+                tool_type: "function".into(),
+                function: FunctionCallInner { name: "memory_tool".into(), arguments: json!({ "action": "add", "key": key, "value": "v" }) },
+            }]),
+        }
+    }
+
+    fn answer(text: &str) -> Message {
+        Message::Assistant { name: None, content: vec![MessageContext::Text(text.into())], tool_calls: None }
+    }
+
+    fn sample() -> VecDeque<Message> {
+        VecDeque::from(vec![
+            user("42", "x", "1", "my address is 1-2-3 somewhere street"),
+            remember("address"),
+            answer("noted, you live at 1-2-3 somewhere street"),
+            user("7", "x", "2", "hello"),
+            remember("greeting"),
+            answer("hi"),
+            user("42", "x", "3", "bye"),
+        ])
+    }
+
+    #[test]
+    fn removes_only_the_users_messages() {
+        let mut prompt = sample();
+        assert_eq!(count_user_messages(&prompt, "42"), 2);
+        let removed = remove_user_messages(&mut prompt, "42");
+        assert_eq!(removed.message_ids, vec!["1", "3"]);
+        assert_eq!(removed.derived, vec![Derived::Memory("address".into())]);
+        assert!(removed.images.is_empty());
+        // 応答も発言を言い換えているのでターンごと消える
+        assert_eq!(prompt.len(), 3);
+        assert!(!history::snapshot(&prompt).to_string().contains("somewhere street"));
+
+        // 添付した画像のキャッシュの参照も集める
+        let hash = "ab".repeat(32);
+        prompt.push_back(with_images(user("42", "x", "4", "look"), &[&image_cache::reference(&hash)]));
+        assert_eq!(remove_user_messages(&mut prompt, "42").images, vec![hash]);
+        assert_eq!(count_user_messages(&prompt, "42"), 0);
+        assert_eq!(count_user_messages(&prompt, "7"), 1);
+    }
+
+    #[test]
+    fn purges_saved_sessions() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("default.json");
+        std::fs::write(&path, serde_json::to_string(&history::snapshot(&sample())).unwrap()).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a session").unwrap();
+
+        assert_eq!(purge_snapshot_dir(dir.path(), "42").count(), 2);
+        let saved = history::restore(&serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap()).unwrap();
+        assert_eq!(saved.len(), 3);
+        assert_eq!(purge_snapshot_dir(dir.path(), "42").count(), 0);
+    }
+
+    #[test]
+    fn keeps_images_that_are_still_referenced() {
+        let image = |user_id: &str, id: &str, hash: &str| with_images(user(user_id, "x", id, "look"), &[&image_cache::reference(hash)]);
+        let (shared, saved, own) = ("ab".repeat(32), "cd".repeat(32), "ef".repeat(32));
+        let mut prompt: VecDeque<Message> = vec![
+            image("42", "1", &shared),
+            image("42", "2", &saved),
+            image("42", "3", &own),
+            // ほかのユーザーも同じ画像を貼っている
+            image("7", "4", &shared),
+        ].into();
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            dir.path().join("default.json"),
+            serde_json::to_string(&history::snapshot(&[image("7", "5", &saved)])).unwrap(),
+        ).unwrap();
+
+        let removed = remove_user_messages(&mut prompt, "42");
+        assert_eq!(removed.images.len(), 3);
+        assert_eq!(unreferenced_images(&removed.images, &prompt, dir.path()), vec![own]);
+    }
+
+    #[test]
+    fn names_are_matched_as_words() {
+        assert_eq!(replace_name("al said: always ask Al, al.", "al", "X"), "X said: always ask Al, X.");
+        assert_eq!(replace_name("太郎さんと花子", "太郎", "X"), "Xさんと花子");
+        assert_eq!(replace_name("<@42> and <@421>", "<@42>", "X"), "X and <@421>");
+        assert_eq!(replace_name("abc", "", "X"), "abc");
+        assert!(mentions_name("ping bob!", "bob") && !mentions_name("bobby", "bob"));
+    }
+
+    #[test]
+    fn detects_quotes_in_articles() {
+        let bodies = vec!["ok".to_string(), "the release moves to friday because of the bug".to_string()];
+        assert!(quotes("As alice said, ...", "alice", "42", &[]));
+        // 名前が別の単語の一部なら引用とみなさない
+        assert!(!quotes("always use malice-free code", "al", "42", &[]));
+        assert!(!quotes("see alice_2's notes", "alice", "42", &[]));
+        assert!(quotes("thanks <@42>", "", "42", &[]));
+        assert!(quotes("> the release moves to friday because of the bug\n", "", "42", &bodies));
+        // 短い発言は一致しても引用とみなさない
+        assert!(!quotes("ok, the article", "", "42", &bodies));
+    }
+}
@@ -0,0 +1,111 @@
+//! 絵文字リアクションによる操作
+//!
+//! メッセージに付けられたリアクションを操作に対応させる。対応表はチャンネルごとに変えられ、
+//! 設定がなければ `default_map` を使う。
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// リアクションで起こす操作
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReactionAction {
+    /// メッセージとそのスレッドを記事にする
+    Article,
+    /// Observer の直前の応答を作り直す
+    Regenerate,
+    /// メッセージを翻訳する
+    Translate,
+    /// メッセージを解説する
+    Explain,
+}
+
+impl ReactionAction {
+    pub fn all() -> [ReactionAction; 4] {
+        [Self::Article, Self::Regenerate, Self::Translate, Self::Explain]
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        Self::all()
+            .into_iter()
+            .find(|action| action.name() == name.to_lowercase())
+            .ok_or_else(|| format!("unknown reaction action: {} (article, regenerate, translate, explain)", name))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Article => "article",
+            Self::Regenerate => "regenerate",
+            Self::Translate => "translate",
+            Self::Explain => "explain",
+        }
+    }
+
+    /// モデルへの指示 (`target` は対象のメッセージ、記事ならスレッド全体の書き起こし)
+    /// 作り直しはもとの発言をそのまま使うので指示はない
+    pub fn instruction(&self, emoji: &str, author: &str, target: &str) -> Option<String> {
+        let task = match self {
+            Self::Article => "Turn the following conversation into a well-structured Markdown article with a title (# heading). \
+                Keep the facts, links and conclusions; do not invent anything. Reply with the article only.",
+            Self::Translate => "Translate the following message. If it is in Japanese, translate it into English; otherwise translate it into Japanese. \
+                Reply with the translation only.",
+            Self::Explain => "Explain the following message so that anyone in the channel can understand it: \
+                background, jargon, and what the links point to. Keep it short.",
+            Self::Regenerate => return None,
+        };
+        Some(format!("[reaction {} on a message by {}] {}\n---\n{}", emoji, author, task, target))
+    }
+}
+
+/// 設定がないチャンネルで使う対応表
+pub fn default_map() -> BTreeMap<String, ReactionAction> {
+    BTreeMap::from([
+        ("📝".to_string(), ReactionAction::Article),
+        ("🔁".to_string(), ReactionAction::Regenerate),
+        ("🌐".to_string(), ReactionAction::Translate),
+        ("❓".to_string(), ReactionAction::Explain),
+    ])
+}
+
+/// 異体字セレクタ (U+FE0F) の有無で同じ絵文字が別物にならないようにする
+pub fn normalize_emoji(emoji: &str) -> String {
+    emoji.trim().replace('\u{fe0f}', "")
+}
+
+/// 対応表から操作を引く
+pub fn lookup(map: &BTreeMap<String, ReactionAction>, emoji: &str) -> Option<ReactionAction> {
+    let emoji = normalize_emoji(emoji);
+    map.iter().find(|(key, _)| normalize_emoji(key) == emoji).map(|(_, action)| *action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_map_covers_all_actions() {
+        let map = default_map();
+        for action in ReactionAction::all() {
+            assert!(map.values().any(|a| *a == action));
+        }
+        assert_eq!(lookup(&map, "❓\u{fe0f}"), Some(ReactionAction::Explain));
+        assert_eq!(lookup(&map, "👍"), None);
+    }
+
+    #[test]
+    fn actions_round_trip_by_name() {
+        for action in ReactionAction::all() {
+            assert_eq!(ReactionAction::from_name(action.name()), Ok(action));
+        }
+        assert!(ReactionAction::from_name("delete").is_err());
+    }
+
+    #[test]
+    fn instructions_quote_the_target() {
+        let text = ReactionAction::Translate.instruction("🌐", "alice", "こんにちは").unwrap();
+        assert!(text.starts_with("[reaction 🌐 on a message by alice]"));
+        assert!(text.ends_with("---\nこんにちは"));
+        assert_eq!(ReactionAction::Regenerate.instruction("🔁", "alice", "x"), None);
+    }
+}
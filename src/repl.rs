@@ -0,0 +1,424 @@
+//! ターミナルから Observer と話す (`observer repl [session]`)
+//!
+//! Discord と同じ `ChannelState` とツールを使い、ツールコールとその生の結果をその場に表示する。
+//! セッションは名前ごとに会話履歴を持ち、`./data/repl/<session>.json` に保存される。
+
+use std::{collections::BTreeSet, io::Write, path::{Path, PathBuf}, sync::Arc};
+
+use call_agent::chat::prompt::{Message, MessageContext};
+use log::error;
+use observer::{history::{self, UserMeta}, sources, turn::{self, TurnContext}};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::{agent::{AIModel, InputMessage, StatusSink}, bot::{Bot, ChConf}};
+
+/// REPL の利用者として使うユーザー ID
+const USER_ID: &str = "repl";
+pub const DEFAULT_SESSION: &str = "default";
+const HELP: &str = "\
+/session [name]  list sessions or switch to <name>
+/model [name]    show models or set the model
+/reset           clear the current session
+/help            show this help
+/exit            quit";
+
+/// REPL の入力
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Say(String),
+    Session(Option<String>),
+    Model(Option<String>),
+    Reset,
+    Help,
+    Exit,
+    Unknown(String),
+    Empty,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Self {
+        let line = line.trim();
+        if line.is_empty() {
+            return Command::Empty;
+        }
+        let Some(rest) = line.strip_prefix('/') else {
+            return Command::Say(line.to_string());
+        };
+        let (name, arg) = match rest.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, Some(arg.trim().to_string()).filter(|a| !a.is_empty())),
+            None => (rest, None),
+        };
+        match name {
+            "session" | "sessions" => Command::Session(arg),
+            "model" => Command::Model(arg),
+            "reset" => Command::Reset,
+            "help" => Command::Help,
+            "exit" | "quit" => Command::Exit,
+            _ => Command::Unknown(name.to_string()),
+        }
+    }
+}
+
+/// セッション名として使えるか (ファイル名になるので英数字と `-` `_` に限る)
+pub fn is_valid_session_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn text_of(content: &[MessageContext]) -> String {
+    content.iter().map(|c| match c {
+        MessageContext::Text(text) => text.clone(),
+        MessageContext::Image(image) => format!("[image: {}]", image.url.chars().take(64).collect::<String>()),
+    }).collect::<Vec<_>>().join("\n")
+}
+
+/// 次の発言に付けるメッセージ ID (復元した履歴の ID と重ならないように、その最大値の次)
+pub fn next_message_id<'a>(messages: impl IntoIterator<Item = &'a Message>) -> u64 {
+    messages
+        .into_iter()
+        .filter_map(UserMeta::from_message)
+        .filter_map(|meta| meta.message_id.parse::<u64>().ok())
+        .max()
+        .map_or(1, |id| id + 1)
+}
+
+/// ターンで追加されたツールコールと結果を表示用の行にする
+pub fn format_tool_traffic(turn: &[Message]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for message in turn {
+        match message {
+            Message::Assistant { tool_calls: Some(calls), .. } => {
+                for call in calls {
+                    lines.push(format!("→ {} {} [{}]", call.function.name, call.function.arguments, call.id));
+                }
+            }
+            Message::Tool { tool_call_id, content } => {
+                lines.push(format!("← [{}]\n{}", tool_call_id, text_of(content)));
+            }
+            _ => {}
+        }
+    }
+    lines
+}
+
+/// ターミナルに進捗とツールのやり取りを流す
+struct TerminalStatus;
+
+impl StatusSink for TerminalStatus {
+    async fn post_status(&self, text: String) {
+        println!("\x1b[2m{}\x1b[0m", text);
+    }
+
+    fn show_turn(&self, turn: &[Message]) {
+        for line in format_tool_traffic(turn) {
+            println!("\x1b[36m{}\x1b[0m", line);
+        }
+    }
+}
+
+/// セッションの保存・読み込み
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    pub fn save(&self, name: &str, messages: &[Message]) -> Result<(), String> {
+        std::fs::create_dir_all(&self.dir).map_err(|e| e.to_string())?;
+        let json = serde_json::to_string_pretty(&history::snapshot(messages)).map_err(|e| e.to_string())?;
+        std::fs::write(self.path(name), json).map_err(|e| e.to_string())
+    }
+
+    pub fn load(&self, name: &str) -> Result<Vec<Message>, String> {
+        let path = self.path(name);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let json = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let value = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+        history::restore(&value)
+    }
+
+    /// ツールが作ったファイルを `files/` に保存してパスを返す
+    pub fn save_file(&self, name: &str, data: &[u8]) -> Result<PathBuf, String> {
+        let name = Path::new(name).file_name().ok_or_else(|| format!("invalid file name: {}", name))?;
+        let dir = self.dir.join("files");
+        std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let path = dir.join(name);
+        std::fs::write(&path, data).map_err(|e| e.to_string())?;
+        Ok(path)
+    }
+
+    /// 保存されているセッション名
+    pub fn list(&self) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let path = e.path();
+                (path.extension()? == "json").then(|| path.file_stem()?.to_str().map(|s| s.to_string()))?
+            })
+            .collect()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+pub struct Repl {
+    bot: Arc<Bot>,
+    store: SessionStore,
+    session: String,
+    user_name: String,
+}
+
+impl Repl {
+    pub fn new(bot: Arc<Bot>, store: SessionStore, session: &str) -> Self {
+        let user_name = std::env::var("USER").ok()
+            .filter(|u| is_valid_session_name(u))
+            .unwrap_or_else(|| "user".to_string());
+        // ローカルの利用なのでレートリミットはかけない
+        bot.set_model(USER_ID, None).ok();
+        if let Some(mut conf) = bot.user_configs.get_mut(USER_ID) {
+            conf.rate_limit = 0;
+        }
+        Self { bot, store, session: session.to_string(), user_name }
+    }
+
+    fn channel(session: &str) -> String {
+        format!("repl:{}", session)
+    }
+
+    fn current_model(&self) -> AIModel {
+        self.bot.user_configs.get(USER_ID)
+            .map(|conf| conf.model.clone())
+            .unwrap_or_else(|| self.bot.settings.default_model.clone())
+    }
+
+    /// セッションを開く (保存されていれば履歴を読み込む)
+    async fn open_session(&mut self, name: &str) -> Result<(), String> {
+        if !is_valid_session_name(name) {
+            return Err(format!("invalid session name: {}", name));
+        }
+        let channel = Self::channel(name);
+        if !self.bot.channels.contains_key(&channel) {
+            let messages = self.store.load(name)?;
+            let state = self.bot.channel_state(&channel).await;
+            state.prompt_stream.lock().await.add(messages).await;
+            self.bot.channels_conf.insert(channel, ChConf { enable: true, ..Default::default() });
+        }
+        self.session = name.to_string();
+        Ok(())
+    }
+
+    async fn save_session(&self) {
+        let state = self.bot.channel_state(&Self::channel(&self.session)).await;
+        let messages: Vec<Message> = state.prompt_stream.lock().await.prompt.iter().cloned().collect();
+        if let Err(e) = self.store.save(&self.session, &messages) {
+            error!("Failed to save session {} - {}", self.session, e);
+        }
+    }
+
+    async fn say(&self, content: String) {
+        let state = self.bot.channel_state(&Self::channel(&self.session)).await;
+        let message_id = next_message_id(state.prompt_stream.lock().await.prompt.iter());
+        let message = InputMessage {
+            content,
+            name: self.user_name.clone(),
+            message_id: message_id.to_string(),
+            reply_msg: None,
+            user_id: USER_ID.to_string(),
+            attached_files: Vec::new(),
+        };
+        let context = TurnContext {
+            channel: Self::channel(&self.session),
+            user_id: USER_ID.to_string(),
+            user_name: self.user_name.clone(),
+            ..Default::default()
+        };
+        let config = self.bot.reasoning_config(&self.current_model());
+        let answer = turn::scope(context, state.run_reasoning(message, &config, &TerminalStatus)).await;
+        if answer.is_error {
+            println!("\x1b[31m{}\x1b[0m", answer.content);
+        } else {
+            println!("{}{}", answer.content, sources::render_full(&answer.sources));
+            // ツールが作ったファイルはセッションの隣に保存する
+            for file in &answer.files {
+                match self.store.save_file(&file.name, &file.data) {
+                    Ok(path) => println!("📎 {}", path.display()),
+                    Err(e) => println!("\x1b[31mfailed to save {} - {}\x1b[0m", file.name, e),
+                }
+            }
+            println!("\x1b[2m{}\x1b[0m", answer.footer.trim_start());
+        }
+        self.save_session().await;
+    }
+
+    /// コマンドを実行する (終了するなら false)
+    async fn execute(&mut self, command: Command) -> bool {
+        match command {
+            Command::Empty => {}
+            Command::Say(content) => self.say(content).await,
+            Command::Session(None) => {
+                let mut sessions: BTreeSet<String> = self.store.list().into_iter().collect();
+                sessions.extend(self.bot.channels.iter().filter_map(|e| e.key().strip_prefix("repl:").map(|s| s.to_string())));
+                for session in sessions {
+                    let mark = if session == self.session { "*" } else { " " };
+                    println!("{} {}", mark, session);
+                }
+            }
+            Command::Session(Some(name)) => match self.open_session(&name).await {
+                Ok(()) => println!("Info: switched to session {}", name),
+                Err(e) => println!("Error: {}", e),
+            },
+            Command::Model(None) => {
+                let current = self.current_model().to_model_name();
+                for model in AIModel::all() {
+                    let mark = if model.to_model_name() == current { "*" } else { " " };
+                    println!("{} {:<12} {}", mark, model.to_model_name(), model.to_model_discription());
+                }
+            }
+            Command::Model(Some(name)) => match self.bot.set_model(USER_ID, Some(&name)) {
+                Ok(message) => println!("{}", message),
+                Err(e) => println!("Error: {}", e),
+            },
+            Command::Reset => {
+                println!("{}", self.bot.reset(&Self::channel(&self.session)).await);
+                self.save_session().await;
+            }
+            Command::Help => println!("{}", HELP),
+            Command::Exit => return false,
+            Command::Unknown(name) => println!("Error: unknown command /{} (try /help)", name),
+        }
+        true
+    }
+
+    /// 標準入力を 1 行ずつ処理する
+    pub async fn run(mut self) {
+        let session = self.session.clone();
+        if let Err(e) = self.open_session(&session).await {
+            eprintln!("Error: {}", e);
+            return;
+        }
+        println!("observer repl - session {} (history in {}, /help for commands)", self.session, self.store.dir().display());
+
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        loop {
+            print!("{}> ", self.session);
+            std::io::stdout().flush().ok();
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    break;
+                }
+            };
+            if !self.execute(Command::parse(&line)).await {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::tests::mock_bot;
+    use call_agent::chat::function::{FunctionCall, FunctionCallInner};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("  hello  "), Command::Say("hello".to_string()));
+        assert_eq!(Command::parse(""), Command::Empty);
+        assert_eq!(Command::parse("/session"), Command::Session(None));
+        assert_eq!(Command::parse("/session  work "), Command::Session(Some("work".to_string())));
+        assert_eq!(Command::parse("/model gpt-5"), Command::Model(Some("gpt-5".to_string())));
+        assert_eq!(Command::parse("/reset"), Command::Reset);
+        assert_eq!(Command::parse("/quit"), Command::Exit);
+        assert_eq!(Command::parse("/nope x"), Command::Unknown("nope".to_string()));
+        assert!(is_valid_session_name("work-1_a"));
+        assert!(!is_valid_session_name("../etc"));
+        assert!(!is_valid_session_name(""));
+    }
+
+    fn tool_turn() -> Vec<Message> {
+        vec![
+            Message::Assistant {
+                name: Some("observer".to_string()),
+                content: Vec::new(),
+                tool_calls: Some(vec![FunctionCall {
+                    id: "call_1".to_string(),
+                    tool_type: "function".to_string(),
+                    function: FunctionCallInner { name: "echo".to_string(), arguments: json!({ "text": "hi" }) },
+                }]),
+            },
+            Message::Tool { tool_call_id: "call_1".to_string(), content: vec![MessageContext::Text("hi".to_string())] },
+            Message::Assistant { name: Some("observer".to_string()), content: vec![MessageContext::Text("done".to_string())], tool_calls: None },
+        ]
+    }
+
+    #[test]
+    fn formats_tool_calls_and_raw_results() {
+        let lines = format_tool_traffic(&tool_turn());
+        assert_eq!(lines, vec![
+            r#"→ echo {"text":"hi"} [call_1]"#.to_string(),
+            "← [call_1]\nhi".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn sessions_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let store = SessionStore::new(dir.path().join("repl"));
+        assert!(store.list().is_empty());
+        assert!(store.load("work").unwrap().is_empty());
+
+        store.save("work", &tool_turn()).unwrap();
+        assert_eq!(store.list(), vec!["work".to_string()]);
+        let loaded = store.load("work").unwrap();
+        assert_eq!(format_tool_traffic(&loaded), format_tool_traffic(&tool_turn()));
+
+        // 生成されたファイルはセッションの一覧に混ざらない
+        let path = store.save_file("../generated-1.png", &[1, 2, 3]).unwrap();
+        assert_eq!(path, dir.path().join("repl/files/generated-1.png"));
+        assert_eq!(store.list(), vec!["work".to_string()]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reopened_sessions_continue_the_message_ids() {
+        let dir = TempDir::new().unwrap();
+        let scenario = |answer: &str| json!({ "steps": [{ "respond": { "content": answer } }] }).to_string();
+        let (_server, bot) = mock_bot(&scenario("first answer"), &dir).await;
+        let mut repl = Repl::new(Arc::new(bot), SessionStore::new(dir.path().join("repl")), "work");
+        repl.open_session("work").await.unwrap();
+        repl.say("first question".to_string()).await;
+
+        // 再起動して同じセッションを開き直す
+        let (_server, bot) = mock_bot(&scenario("second answer"), &dir).await;
+        let mut repl = Repl::new(Arc::new(bot), SessionStore::new(dir.path().join("repl")), "work");
+        repl.open_session("work").await.unwrap();
+        repl.say("second question".to_string()).await;
+
+        let messages = SessionStore::new(dir.path().join("repl")).load("work").unwrap();
+        let ids: Vec<String> = messages.iter().filter_map(UserMeta::from_message).map(|meta| meta.message_id).collect();
+        assert_eq!(ids, vec!["1".to_string(), "2".to_string()]);
+        let answers: Vec<String> = messages.iter().filter_map(|m| match m {
+            Message::Assistant { content, .. } => Some(text_of(content)),
+            _ => None,
+        }).collect();
+        assert_eq!(answers, vec!["first answer".to_string(), "second answer".to_string()]);
+        assert_eq!(next_message_id(&messages), 3);
+    }
+}
@@ -0,0 +1,225 @@
+//! ツールの実行結果から情報源 (URL) を集める
+//!
+//! 1 回の推論で追加されたメッセージ (アシスタントのツールコールとツールの結果) を走査し、
+//! `browser` が取得した URL、`browsing_worker` が返した引用、`image_captioner` が読んだ画像を
+//! 重複なしで出現順に並べる。
+
+use std::collections::HashMap;
+
+use call_agent::chat::prompt::{Message, MessageContext};
+use regex::Regex;
+
+use crate::untrusted;
+
+/// Discord 向けの短い出典表記に載せる最大件数
+const MAX_COMPACT_SOURCES: usize = 8;
+
+lazy_static::lazy_static! {
+    // 検索結果ページ自体は情報源として扱わない
+    static ref SEARCH_PAGE_RE: Regex = Regex::new(
+        r"(?i)^https?://(www\.)?(bing\.com/search|google\.[a-z.]+/search|duckduckgo\.com/\?|search\.yahoo\.co(m|\.jp)/search)"
+    ).unwrap();
+}
+
+/// 推論中に追加されたメッセージから情報源の URL を集める
+pub fn collect_sources<'a>(messages: impl IntoIterator<Item = &'a Message>) -> Vec<String> {
+    let mut calls: HashMap<&str, (&str, &serde_json::Value)> = HashMap::new();
+    let mut sources = Vec::new();
+
+    for message in messages {
+        match message {
+            Message::Assistant { tool_calls: Some(tool_calls), .. } => {
+                for call in tool_calls {
+                    calls.insert(call.id.as_str(), (call.function.name.as_str(), &call.function.arguments));
+                }
+            }
+            Message::Tool { tool_call_id, content } => {
+                let Some((name, args)) = calls.get(tool_call_id.as_str()) else {
+                    continue;
+                };
+                let result: String = content
+                    .iter()
+                    .filter_map(|c| match c {
+                        MessageContext::Text(text) => Some(text.as_str()),
+                        MessageContext::Image(_) => None,
+                    })
+                    .collect();
+                // 失敗したツールコールは情報源にしない
+                if result.starts_with("Error:") {
+                    continue;
+                }
+                match *name {
+                    "browser" | "image_captioner" => {
+                        if let Some(url) = args.get("url").and_then(|v| v.as_str()) {
+                            push_source(&mut sources, url);
+                        }
+                    }
+                    "browsing_worker" => {
+                        let links = serde_json::from_str::<serde_json::Value>(&untrusted::unwrap(&result))
+                            .ok()
+                            .and_then(|v| v.get("links").cloned());
+                        if let Some(serde_json::Value::Array(links)) = links {
+                            for link in links.iter().filter_map(|l| l.as_str()) {
+                                push_source(&mut sources, link);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    sources
+}
+
+fn push_source(sources: &mut Vec<String>, url: &str) {
+    let url = normalize_url(url);
+    if !(url.starts_with("http://") || url.starts_with("https://")) || SEARCH_PAGE_RE.is_match(&url) {
+        return;
+    }
+    if !sources.contains(&url) {
+        sources.push(url);
+    }
+}
+
+/// 重複判定用に URL を正規化する (フラグメント・末尾の / ・トラッキング用パラメータを落とす)
+fn normalize_url(url: &str) -> String {
+    let url = url.trim();
+    let url = url.split('#').next().unwrap_or(url);
+    let (base, query) = match url.split_once('?') {
+        Some((base, query)) => (base, Some(query)),
+        None => (url, None),
+    };
+    let base = base.trim_end_matches('/');
+    let params: Vec<&str> = query
+        .map(|q| {
+            q.split('&')
+                .filter(|p| !p.is_empty() && !p.starts_with("utm_"))
+                .collect()
+        })
+        .unwrap_or_default();
+    if params.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}?{}", base, params.join("&"))
+    }
+}
+
+fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map(|(_, r)| r).unwrap_or(url);
+    let host = rest.split(['/', '?']).next().unwrap_or(rest);
+    host.strip_prefix("www.").unwrap_or(host)
+}
+
+/// Discord 向けの短い出典表記
+pub fn render_compact(sources: &[String]) -> String {
+    if sources.is_empty() {
+        return String::new();
+    }
+    let mut links: Vec<String> = sources
+        .iter()
+        .take(MAX_COMPACT_SOURCES)
+        .enumerate()
+        .map(|(i, url)| format!("[{} {}](<{}>)", i + 1, host(url), url))
+        .collect();
+    if sources.len() > MAX_COMPACT_SOURCES {
+        links.push(format!("+{}", sources.len() - MAX_COMPACT_SOURCES));
+    }
+    format!("\n-# sources: {}", links.join(" · "))
+}
+
+/// 記事向けの出典一覧
+pub fn render_full(sources: &[String]) -> String {
+    if sources.is_empty() {
+        return String::new();
+    }
+    let list: Vec<String> = sources
+        .iter()
+        .enumerate()
+        .map(|(i, url)| format!("{}. [{}]({})", i + 1, host(url), url))
+        .collect();
+    format!("\n\n## Sources\n{}", list.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use call_agent::chat::function::{FunctionCall, FunctionCallInner};
+    use serde_json::json;
+
+    fn call(id: &str, name: &str, args: serde_json::Value) -> FunctionCall {
+        FunctionCall {
+            id: id.to_string(),
+            tool_type: "function".to_string(),
+            function: FunctionCallInner { name: name.to_string(), arguments: args },
+        }
+    }
+
+    fn tool_result(id: &str, text: &str) -> Message {
+        Message::Tool {
+            tool_call_id: id.to_string(),
+            content: vec![MessageContext::Text(text.to_string())],
+        }
+    }
+
+    #[test]
+    fn collects_sources_from_all_tools_in_order() {
+        let messages = vec![
+            Message::Assistant {
+                name: None,
+                content: vec![],
+                tool_calls: Some(vec![
+                    call("1", "browser", json!({ "url": "https://www.bing.com/search?q=rust" })),
+                    call("2", "browser", json!({ "url": "https://example.com/a/" })),
+                    call("3", "browsing_worker", json!({ "query": "..." })),
+                    call("4", "image_captioner", json!({ "url": "https://img.example.com/x.png", "query": "?" })),
+                    call("5", "memory_tool", json!({ "action": "get" })),
+                    call("6", "browser", json!({ "url": "https://broken.example.com" })),
+                ]),
+            },
+            tool_result("1", "\"...\""),
+            tool_result("2", "\"...\""),
+            // browsing_worker の結果は封筒に入っている
+            tool_result("3", &untrusted::wrap("browsing_worker", &json!({
+                "Summary": "s",
+                "links": ["https://example.com/a#top", "https://news.example.org/b?utm_source=x&id=3"]
+            }).to_string())),
+            tool_result("4", "{\"caption\":\"c\"}"),
+            tool_result("5", "{}"),
+            tool_result("6", "Error: Scrape error: Network error occurred."),
+        ];
+        assert_eq!(
+            collect_sources(&messages),
+            vec![
+                "https://example.com/a".to_string(),
+                "https://news.example.org/b?id=3".to_string(),
+                "https://img.example.com/x.png".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn tool_results_without_calls_are_ignored() {
+        let messages = vec![tool_result("x", "{\"links\":[\"https://a.example\"]}")];
+        assert!(collect_sources(&messages).is_empty());
+    }
+
+    #[test]
+    fn compact_footer_is_numbered_and_capped() {
+        let sources: Vec<String> = (0..10).map(|i| format!("https://www.site{}.example/p", i)).collect();
+        let footer = render_compact(&sources);
+        assert!(footer.starts_with("\n-# sources: [1 site0.example](<https://www.site0.example/p>) · "));
+        assert!(footer.ends_with(" · +2"));
+        assert_eq!(render_compact(&[]), "");
+    }
+
+    #[test]
+    fn full_footer_lists_everything() {
+        let sources = vec!["https://a.example/x".to_string(), "https://b.example".to_string()];
+        assert_eq!(
+            render_full(&sources),
+            "\n\n## Sources\n1. [a.example](https://a.example/x)\n2. [b.example](https://b.example)"
+        );
+    }
+}
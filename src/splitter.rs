@@ -0,0 +1,401 @@
+//! Discord 向けのメッセージ分割
+//!
+//! Discord の文字数制限はバイト数ではなく文字数 (Unicode scalar value) で数えられるので、
+//! ここでもすべて `chars()` 単位で数える。
+//! - コードブロック (```) の途中で分割するときは閉じてから次のチャンクで言語タグ付きで開き直す
+//! - 1 行が上限を超える場合は空白などの区切りで行内分割する
+//! - リンク・URL・インラインコード・顔文字の途中では可能な限り分割しない
+//!
+//! IRC は 1 行 512 バイトの制限なので、`split_irc_lines` だけはバイト数で数える。
+
+use regex::Regex;
+
+/// Discord の 1 メッセージあたりの最大文字数
+pub const DISCORD_MESSAGE_LIMIT: usize = 2000;
+
+const FENCE: &str = "```";
+/// チャンク末尾でコードブロックを閉じるのに必要な文字数 ("\n```")
+const FENCE_CLOSE_LEN: usize = 4;
+
+lazy_static::lazy_static! {
+    // kaomoji の中のバッククォートだけをエスケープする ((´-ω-`) のように末尾に来るものも含む)
+    static ref KAOMOJI_BACKQUOTE_RE: Regex = Regex::new(r"\([^()`\n]{1,12}`[^()`\n]{0,12}\)").unwrap();
+    // 分割してはいけない範囲
+    static ref PROTECTED_RE: Regex = Regex::new(concat!(
+        r"\[[^\]\n]*\]\([^)\s]*\)",     // [text](url)
+        r"|<https?://[^>\s]+>",         // <url>
+        r"|https?://[^\s<>]+",          // 生 URL
+        r"|`[^`\n]+`",                  // インラインコード
+        r"|\([^()\n]{1,24}\)",          // 顔文字 (半角括弧)
+        r"|（[^（）\n]{1,24}）",         // 顔文字 (全角括弧)
+    )).unwrap();
+}
+
+/// テキストを `max_len` 文字以下のチャンクに分割する
+pub fn split_message(text: &str, max_len: usize) -> Vec<String> {
+    // コードブロックの開き直しができる最低限の長さは確保する
+    let max_len = max_len.max(FENCE_CLOSE_LEN * 4);
+    let mut splitter = Splitter::new(max_len);
+
+    for line in text.lines() {
+        let escaped = if splitter.fence.is_none() && KAOMOJI_BACKQUOTE_RE.is_match(line) {
+            KAOMOJI_BACKQUOTE_RE
+                .replace_all(line, |caps: &regex::Captures| {
+                    // マッチした kaomoji 部分だけバッククォートを \` に置換
+                    caps[0].replace('`', r"\`")
+                })
+                .into_owned()
+        } else {
+            line.to_string()
+        };
+        splitter.push_line(&escaped);
+    }
+
+    splitter.finish()
+}
+
+struct Splitter {
+    max_len: usize,
+    chunks: Vec<String>,
+    current: String,
+    current_len: usize,
+    /// 現在開いているコードブロックの開始行 (例: "```rust")
+    fence: Option<String>,
+    /// current がコードブロックの開き直し行だけで構成されているか
+    only_reopened: bool,
+}
+
+impl Splitter {
+    fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            chunks: Vec::new(),
+            current: String::new(),
+            current_len: 0,
+            fence: None,
+            only_reopened: false,
+        }
+    }
+
+    fn push_line(&mut self, line: &str) {
+        let line_len = line.chars().count();
+        let fence_after = match fence_marker(line) {
+            Some(_) if self.fence.is_some() => None,
+            Some(open) => Some(open.to_string()),
+            None => self.fence.clone(),
+        };
+        let reserve = if fence_after.is_some() { FENCE_CLOSE_LEN } else { 0 };
+
+        if self.fits(line_len, reserve) {
+            self.append(line, line_len);
+            self.fence = fence_after;
+            return;
+        }
+
+        // 入りきらないので現在のチャンクを確定させる
+        if self.has_content() {
+            self.flush();
+        }
+        if self.fits(line_len, reserve) {
+            self.append(line, line_len);
+            self.fence = fence_after;
+            return;
+        }
+
+        // 1 行が長すぎるので行内で分割する
+        let mut rest = line;
+        while !rest.is_empty() {
+            let rest_len = rest.chars().count();
+            let cap = self.capacity(reserve);
+            if rest_len <= cap {
+                self.append(rest, rest_len);
+                break;
+            }
+            let at = find_break(rest, cap);
+            let (piece, tail) = rest.split_at(at);
+            self.append(piece, piece.chars().count());
+            self.flush();
+            rest = tail;
+        }
+        self.fence = fence_after;
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        if self.has_content() {
+            if self.fence.is_some() {
+                self.current.push('\n');
+                self.current.push_str(FENCE);
+            }
+            self.chunks.push(self.current);
+        }
+        self.chunks
+    }
+
+    fn has_content(&self) -> bool {
+        !self.current.is_empty() && !self.only_reopened
+    }
+
+    fn separator_len(&self) -> usize {
+        if self.current.is_empty() { 0 } else { 1 }
+    }
+
+    fn fits(&self, len: usize, reserve: usize) -> bool {
+        self.current_len + self.separator_len() + len + reserve <= self.max_len
+    }
+
+    fn capacity(&self, reserve: usize) -> usize {
+        self.max_len
+            .saturating_sub(self.current_len + self.separator_len() + reserve)
+            .max(1)
+    }
+
+    fn append(&mut self, s: &str, len: usize) {
+        if !self.current.is_empty() {
+            self.current.push('\n');
+            self.current_len += 1;
+        }
+        self.current.push_str(s);
+        self.current_len += len;
+        self.only_reopened = false;
+    }
+
+    /// 現在のチャンクを確定し、コードブロック中なら閉じて次のチャンクで開き直す
+    fn flush(&mut self) {
+        if self.fence.is_some() {
+            self.current.push('\n');
+            self.current.push_str(FENCE);
+        }
+        self.chunks.push(std::mem::take(&mut self.current));
+        self.current_len = 0;
+        if let Some(open) = &self.fence {
+            self.current.push_str(open);
+            self.current_len = open.chars().count();
+            self.only_reopened = true;
+        }
+    }
+}
+
+/// IRC 向けに、各行が `max_bytes` バイト以下になるように分割する
+/// IRC では空行を送れないので空行は落とす
+pub fn split_irc_lines(text: &str, max_bytes: usize) -> Vec<String> {
+    let max_bytes = max_bytes.max(16);
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let mut rest = line.trim_end();
+        while rest.len() > max_bytes {
+            let cap = rest
+                .char_indices()
+                .take_while(|(pos, c)| pos + c.len_utf8() <= max_bytes)
+                .count();
+            let (head, tail) = rest.split_at(find_break(rest, cap));
+            if !head.trim().is_empty() {
+                lines.push(head.trim_end().to_string());
+            }
+            rest = tail.trim_start();
+        }
+        if !rest.trim().is_empty() {
+            lines.push(rest.to_string());
+        }
+    }
+    lines
+}
+
+/// 行がコードブロックの区切りであれば開始行 (言語タグ付き) を返す
+fn fence_marker(line: &str) -> Option<&str> {
+    let trimmed = line.trim();
+    if !trimmed.starts_with(FENCE) {
+        return None;
+    }
+    // ```code``` のような 1 行完結のものは区切りとして扱わない
+    if trimmed.len() > FENCE.len() * 2 && trimmed.ends_with(FENCE) {
+        return None;
+    }
+    Some(trimmed)
+}
+
+/// `text` の先頭 `cap` 文字以内で分割に適したバイト位置を返す
+fn find_break(text: &str, cap: usize) -> usize {
+    let protected: Vec<(usize, usize)> = PROTECTED_RE
+        .find_iter(text)
+        .map(|m| (m.start(), m.end()))
+        .collect();
+    let is_protected = |pos: usize| protected.iter().any(|&(s, e)| s < pos && pos < e);
+
+    let mut hard = 0;
+    let mut safe = None;
+    let mut soft = None;
+    for (i, (pos, c)) in text.char_indices().enumerate() {
+        let end = pos + c.len_utf8();
+        if i >= cap {
+            break;
+        }
+        hard = end;
+        if is_protected(end) {
+            continue;
+        }
+        safe = Some(end);
+        if c.is_whitespace() || matches!(c, '、' | '。' | '，' | '．' | '！' | '？' | ',' | '.' | '!' | '?') {
+            soft = Some(end);
+        }
+    }
+    soft.or(safe).unwrap_or(hard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_within_limit(chunks: &[String], max_len: usize) {
+        for chunk in chunks {
+            assert!(
+                chunk.chars().count() <= max_len,
+                "chunk has {} chars (limit {})",
+                chunk.chars().count(),
+                max_len
+            );
+        }
+    }
+
+    #[test]
+    fn short_text_is_single_chunk() {
+        let chunks = split_message("hello\nworld", 2000);
+        assert_eq!(chunks, vec!["hello\nworld".to_string()]);
+    }
+
+    #[test]
+    fn empty_text_produces_no_chunks() {
+        assert!(split_message("", 2000).is_empty());
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        // 1 文字 3 バイトなので、バイト数で数えると 3 分割されてしまう
+        let line = "あ".repeat(1500);
+        let chunks = split_message(&line, 2000);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].chars().count(), 1500);
+    }
+
+    #[test]
+    fn long_single_line_is_split() {
+        let line = "あ".repeat(4500);
+        let chunks = split_message(&line, 2000);
+        assert_eq!(chunks.len(), 3);
+        assert_within_limit(&chunks, 2000);
+        assert_eq!(chunks.concat(), line);
+    }
+
+    #[test]
+    fn long_line_prefers_whitespace() {
+        let line = format!("{} {}", "a".repeat(30), "b".repeat(30));
+        let chunks = split_message(&line, 40);
+        assert_eq!(chunks[0], format!("{} ", "a".repeat(30)));
+        assert_eq!(chunks[1], "b".repeat(30));
+    }
+
+    #[test]
+    fn lines_are_grouped_up_to_limit() {
+        let text = ["a".repeat(10), "b".repeat(10), "c".repeat(10)].join("\n");
+        let chunks = split_message(&text, 21);
+        assert_eq!(chunks, vec![format!("{}\n{}", "a".repeat(10), "b".repeat(10)), "c".repeat(10)]);
+    }
+
+    #[test]
+    fn code_fence_is_closed_and_reopened() {
+        let mut text = String::from("intro\n```rust\n");
+        for i in 0..20 {
+            text.push_str(&format!("let x{} = {};\n", i, i));
+        }
+        text.push_str("```\noutro");
+        let chunks = split_message(&text, 100);
+        assert!(chunks.len() > 1);
+        assert_within_limit(&chunks, 100);
+        for chunk in &chunks {
+            // 各チャンクでフェンスの数が偶数 (= 閉じている)
+            assert_eq!(chunk.matches("```").count() % 2, 0, "unbalanced fence in {:?}", chunk);
+        }
+        for chunk in chunks.iter().skip(1).filter(|c| c.contains("let x")) {
+            assert!(chunk.starts_with("```rust\n"), "not reopened with language: {:?}", chunk);
+        }
+        assert!(chunks.last().unwrap().ends_with("outro"));
+    }
+
+    #[test]
+    fn unterminated_fence_is_closed() {
+        let chunks = split_message("```py\nprint(1)", 2000);
+        assert_eq!(chunks, vec!["```py\nprint(1)\n```".to_string()]);
+    }
+
+    #[test]
+    fn inline_fence_does_not_toggle_state() {
+        let text = format!("```inline```\n{}", "a ".repeat(30));
+        let chunks = split_message(&text, 40);
+        assert!(chunks.iter().all(|c| c.matches("```").count() % 2 == 0));
+        assert!(!chunks[1].starts_with("```"));
+    }
+
+    #[test]
+    fn does_not_split_inside_link() {
+        let link = "[docs](https://example.com/some/long/path)";
+        let line = format!("{}{}", "a".repeat(20), link);
+        let chunks = split_message(&line, 50);
+        assert!(chunks.iter().any(|c| c.contains(link)), "link was split: {:?}", chunks);
+        assert_within_limit(&chunks, 50);
+    }
+
+    #[test]
+    fn does_not_split_inside_bare_url() {
+        let url = "https://example.com/a/b/c/d";
+        let line = format!("see {} now", url);
+        let chunks = split_message(&line, 30);
+        assert!(chunks.iter().any(|c| c.contains(url)), "url was split: {:?}", chunks);
+        assert_within_limit(&chunks, 30);
+    }
+
+    #[test]
+    fn does_not_split_inside_kaomoji() {
+        let line = format!("{}(´-ω-`)", "あ".repeat(14));
+        let chunks = split_message(&line, 18);
+        assert!(chunks.iter().any(|c| c.contains(r"(´-ω-\`)")), "kaomoji was split: {:?}", chunks);
+    }
+
+    #[test]
+    fn kaomoji_backquote_is_escaped() {
+        let chunks = split_message("ねむい (´-ω-`)", 2000);
+        assert_eq!(chunks, vec![r"ねむい (´-ω-\`)".to_string()]);
+    }
+
+    #[test]
+    fn backquote_inside_code_block_is_not_escaped() {
+        let text = "```\n(a`b)\n```";
+        assert_eq!(split_message(text, 2000), vec![text.to_string()]);
+    }
+
+    #[test]
+    fn protected_span_longer_than_limit_is_hard_split() {
+        let url = format!("https://example.com/{}", "x".repeat(100));
+        let chunks = split_message(&url, 30);
+        assert_within_limit(&chunks, 30);
+        assert_eq!(chunks.concat(), url);
+    }
+
+    #[test]
+    fn irc_lines_fit_byte_budget() {
+        let text = "短い行\n\nこれは長い日本語の行です。区切りで分割されるはずです。 and some english words here";
+        let lines = split_irc_lines(text, 40);
+        assert_eq!(lines[0], "短い行");
+        assert!(lines.len() > 2);
+        for line in &lines {
+            assert!(line.len() <= 40, "{} bytes: {}", line.len(), line);
+            assert!(!line.is_empty());
+        }
+        assert_eq!(lines[1..].concat().replace(' ', ""), text.lines().nth(2).unwrap().replace(' ', ""));
+    }
+
+    #[test]
+    fn irc_lines_do_not_split_urls() {
+        let text = "see https://example.com/a/b/c for details";
+        let lines = split_irc_lines(text, 32);
+        assert!(lines.iter().any(|l| l.contains("https://example.com/a/b/c")), "{:?}", lines);
+    }
+}
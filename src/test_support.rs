@@ -0,0 +1,56 @@
+//! テストで使う画像と会話履歴の組み立て
+
+use std::io::Cursor;
+
+use call_agent::chat::prompt::{Message, MessageContext, MessageImage};
+use image::{codecs::gif::GifEncoder, Delay, Frame, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+
+use crate::history::UserMeta;
+
+/// `color` 一色の PNG
+pub fn png(width: u32, height: u32, color: [u8; 3]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    RgbImage::from_pixel(width, height, Rgb(color))
+        .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .unwrap();
+    bytes
+}
+
+/// `frames` の色を 1 枚ずつ `delay_ms` で切り替えるアニメーション GIF
+pub fn gif(width: u32, height: u32, delay_ms: u32, frames: &[[u8; 4]]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut bytes);
+        encoder.encode_frames(frames.iter().map(|color| {
+            Frame::from_parts(RgbaImage::from_pixel(width, height, Rgba(*color)), 0, 0, Delay::from_numer_denom_ms(delay_ms, 1))
+        })).unwrap();
+    }
+    bytes
+}
+
+/// `prepare_user_prompt` と同じ `[META]` 付きの本文を持つユーザーメッセージ
+pub fn user(user_id: &str, user_name: &str, message_id: &str, body: &str) -> Message {
+    let meta = UserMeta {
+        message_id: message_id.to_string(),
+        user_name: user_name.to_string(),
+        reply_msg: None,
+        body: body.to_string(),
+    };
+    Message::User { name: Some(user_id.to_string()), content: vec![MessageContext::Text(meta.render())] }
+}
+
+/// 画像の URL (data URL や `image-cache:` の参照)
+pub fn image(url: &str) -> MessageContext {
+    MessageContext::Image(MessageImage { url: url.to_string(), detail: None })
+}
+
+/// ユーザーメッセージに画像を添える
+pub fn with_images(message: Message, urls: &[&str]) -> Message {
+    match message {
+        Message::User { name, mut content } => {
+            content.extend(urls.iter().map(|url| image(url)));
+            Message::User { name, content }
+        }
+        other => other,
+    }
+}
@@ -1,9 +1,11 @@
 
-use call_agent::chat::{client::{OpenAIClient, ToolMode}, function::Tool, prompt::{Message, MessageContext}};
+use call_agent::chat::{client::OpenAIClient, function::Tool, prompt::{Message, MessageContext}};
 use log::info;
 use serde_json::Value;
 use tokio::runtime::Runtime;
 
+use crate::untrusted;
+
 
 /// **テキストの長さを計算するツール**
 pub struct BrowsingWorker {
@@ -49,9 +51,9 @@ impl Tool for BrowsingWorker {
 
         let mut model = self.model.clone().create_prompt();
 
-        let result = std::thread::spawn(move || -> Result<String, String> {
+        let (summary, links) = std::thread::spawn(move || -> Result<(String, Vec<String>), String> {
             let rt = Runtime::new().expect("Failed to create runtime");
-            let messages = Vec::from(vec![
+            let messages = vec![
                 Message::System { 
                     name: Some("owner".to_string()), 
                     content: "You are an excellent AI assistant who searches for web pages regarding the request content and faithfully summarizes the entire content of that page. Absolutely use the internet to research and compile information.Also, be sure to indicate the source (URL).".to_string() 
@@ -62,45 +64,32 @@ impl Tool for BrowsingWorker {
                         MessageContext::Text(query.clone()),
                     ],
                 }
-            ]);
+            ];
 
             // モデルに投げる
-            let res: String = rt.block_on(async {
+            rt.block_on(async {
                 model.add(messages).await;
                 let return_value = model.generate(None).await.map_err(|_| "Failed to generate".to_string())?;
-                let mut string = return_value.content.ok_or("Failed to result".to_string())?;
-                let annotations = &return_value.api_result.response.choices
-                    .unwrap()[0].message.annotations;
-                let captions = annotations.as_ref()
-                    .unwrap()
-                    .as_array()
-                    .unwrap()
-                    .iter()
-                    .map(|v| 
-                        v.as_object()
-                        .unwrap()
-                        .get("url_citation")
-                        .unwrap()
-                        .as_object()
-                        .unwrap()
-                        .get("url")
-                        .unwrap()
-                        .as_str()
-                        .unwrap_or("")
-                        )
-                    .collect::<Vec<_>>()
-                    .join(" ")
-                    .to_string();
-                string = format!("{}\n\nLinks: {}", string, captions);
-                Ok(string)
-            }).map_err(|e: String| e.to_string())?;
-
-            Ok(res)
+                let summary = return_value.content.ok_or("Failed to result".to_string())?;
+                // 検索で参照した URL は annotations の url_citation に入っている
+                let links = return_value.api_result.response.choices
+                    .as_ref()
+                    .and_then(|choices| choices.first())
+                    .and_then(|choice| choice.message.annotations.as_ref())
+                    .and_then(|annotations| annotations.as_array())
+                    .map(|annotations| annotations.iter()
+                        .filter_map(|v| v.get("url_citation")?.get("url")?.as_str())
+                        .map(|url| url.to_string())
+                        .collect::<Vec<_>>())
+                    .unwrap_or_default();
+                Ok((summary, links))
+            })
         })
         .join()
         .map_err(|_| "Thread panicked".to_string())??;
 
-        // JSONで結果を返す
-        Ok(serde_json::json!({ "Summary": result }).to_string())
+        // JSONで結果を返す (ページの要約なので外部の内容として渡す)
+        let json = serde_json::json!({ "Summary": summary, "links": links }).to_string();
+        Ok(untrusted::wrap("browsing_worker", &json))
     }
 }
\ No newline at end of file
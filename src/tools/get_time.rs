@@ -7,6 +7,12 @@ use std::collections::HashMap;
 
 pub struct GetTime {}
 
+impl Default for GetTime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl GetTime {
     pub fn new() -> Self {
         Self {}
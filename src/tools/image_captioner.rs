@@ -1,11 +1,12 @@
-use std::{collections::VecDeque, io::Cursor};
+use std::collections::VecDeque;
 
 use call_agent::chat::{client::OpenAIClient, function::Tool, prompt::{Message, MessageContext, MessageImage}};
-use image::{codecs::gif::GifDecoder, AnimationDecoder, DynamicImage, GenericImageView, ImageReader, RgbaImage};
 use reqwest::Client;
 use serde_json::Value;
 use tokio::runtime::Runtime;
 
+use crate::{image_cache, images::{self, MAX_IMAGE_BYTES}, untrusted};
+
 /// **テキストの長さを計算するツール**
 pub struct ImageCaptionerTool {
     pub model: OpenAIClient,
@@ -15,99 +16,6 @@ impl ImageCaptionerTool {
     pub fn new(model: OpenAIClient) -> Self {
         Self { model }
     }
-    
-    pub async fn fetch_and_encode_image(url: &str) -> Option<String> {
-        // Content-Type ヘッダーで MIME タイプとサイズを判定
-        let client = Client::new();
-        let head = client.head(url).send().await.ok()?;
-        // MIME タイプ確認
-        let ct = head
-            .headers()
-            .get(reqwest::header::CONTENT_TYPE)?
-            .to_str()
-            .ok()?;
-        if !ct.starts_with("image/") {
-            return None;
-        }
-        let mime = ct.split(';').next().unwrap();
-        // サイズチェック（20MB上限）
-        let len = head
-            .headers()
-            .get(reqwest::header::CONTENT_LENGTH)
-            .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
-            .unwrap_or(0);
-        if len == 0 || len > 20 * 1024 * 1024 {
-            return None;
-        }
-        // GET してバイト列取得
-        let bytes = client.get(url).send().await.ok()?.bytes().await.ok()?;
-
-        // 画像デコード＋透過処理／GIFは最初のフレームを抽出
-        let img: DynamicImage = if mime == "image/gif" {
-            let decoder = GifDecoder::new(Cursor::new(&bytes)).ok()?;
-            let mut frames = decoder.into_frames();
-            let frame = frames.next()?.ok()?;
-            let buf: RgbaImage = frame.into_buffer();
-            DynamicImage::ImageRgba8(buf)
-        } else {
-            let adyn = ImageReader::new(Cursor::new(&bytes))
-                .with_guessed_format().ok()?
-                .decode().ok()?;
-            // 透過があれば白背景に合成
-            if adyn.color().has_alpha() {
-                let (w, h) = adyn.dimensions();
-                let mut bg = RgbaImage::new(w, h);
-                for (x, y, p) in adyn.to_rgba8().enumerate_pixels() {
-                    let alpha = p.0[3] as f32 / 255.0;
-                    let inv = 1.0 - alpha;
-                    let r = (p[0] as f32 * alpha + 255.0 * inv) as u8;
-                    let g = (p[1] as f32 * alpha + 255.0 * inv) as u8;
-                    let b = (p[2] as f32 * alpha + 255.0 * inv) as u8;
-                    bg.put_pixel(x, y, image::Rgba([r, g, b, 255]));
-                }
-                DynamicImage::ImageRgba8(bg)
-            } else {
-                adyn
-            }
-        };
-    
-        // 解像度を調整（長辺>2000なら縮小、短辺<512なら拡大）
-        let (w, h) = img.dimensions();
-        let mut img = img;
-        // 長辺が2000pxを超える場合は縮小
-        if img.dimensions().0.max(img.dimensions().1) > 2000 {
-            let long = img.dimensions().0.max(img.dimensions().1) as f32;
-            let scale = 2000.0 / long;
-            img = img.resize(
-                (w as f32 * scale) as u32,
-                (h as f32 * scale) as u32,
-                image::imageops::FilterType::Lanczos3,
-            );
-        }
-        // 短辺が512px未満の場合は拡大
-        if img.dimensions().0.min(img.dimensions().1) < 512 {
-            let (w2, h2) = img.dimensions();
-            let short = w2.min(h2) as f32;
-            let scale = 512.0 / short;
-            img = img.resize(
-                (w2 as f32 * scale) as u32,
-                (h2 as f32 * scale) as u32,
-                image::imageops::FilterType::Lanczos3,
-            );
-        }
-        // PNGで再エンコード → data URL
-        let mut buf = Vec::new();
-        if img
-            .write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png)
-            .is_err()
-        {
-            return None;
-        }
-        // PNG に再エンコード→data URL
-        let mut buf = Vec::new();
-        img.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png).ok()?;
-        Some(format!("data:image/png;base64,{}", base64::encode(&buf)))
-    }
 }
 
 impl Tool for ImageCaptionerTool {
@@ -155,10 +63,21 @@ impl Tool for ImageCaptionerTool {
         let result = std::thread::spawn(move || -> Result<String, String> {
             let rt = Runtime::new().expect("Failed to create runtime");
 
-            // 画像を取得してエンコード
-            let data_url = rt.block_on(async {
-                Self::fetch_and_encode_image(&url).await
-            }).ok_or_else(|| "Failed to fetch and encode image".to_string())?;
+            // 画像を取得してエンコード (キャッシュがあれば通して、説明文も残す)
+            let cache = image_cache::installed();
+            let (hash, data_url) = rt.block_on(async {
+                match cache {
+                    Some(cache) => {
+                        let hash = match image_cache::hash_of(&url) {
+                            Some(hash) => hash.to_string(),
+                            None => cache.load(&Client::new(), &url, MAX_IMAGE_BYTES).await?.0,
+                        };
+                        let data_url = cache.data_url(&hash).ok_or("the image is no longer available")?;
+                        Ok((Some(hash), data_url))
+                    }
+                    None => images::load(&Client::new(), &url, MAX_IMAGE_BYTES).await.map(|(image, _)| (None, image.data_url())),
+                }
+            }).map_err(|e: String| format!("Failed to fetch and encode image: {}", e))?;
 
             let messages = VecDeque::from(vec![
                 Message::User {
@@ -180,18 +99,21 @@ impl Tool for ImageCaptionerTool {
                 .response
                 .choices
                 .ok_or_else(|| "Missing choices in response".to_string())?
-                .get(0)
+                .first()
                 .ok_or_else(|| "No choice available".to_string())?
                 .message
                 .content
                 .clone()
                 .ok_or_else(|| "No content in message".to_string())?;
+            if let (Some(cache), Some(hash)) = (cache, hash) {
+                cache.set_caption(&hash, &caption);
+            }
             Ok(caption)
         })
         .join()
         .map_err(|_| "Thread panicked".to_string())??;
 
-        // JSONで結果を返す
-        Ok(serde_json::json!({ "caption": result }).to_string())
+        // 画像の中の文字も外部の内容なので封筒に入れる
+        Ok(untrusted::wrap("image_captioner", &serde_json::json!({ "caption": result }).to_string()))
     }
 }
\ No newline at end of file
@@ -0,0 +1,317 @@
+use std::{io::Cursor, thread, time::Duration};
+
+use base64::prelude::*;
+use call_agent::chat::function::Tool;
+use image::ImageFormat;
+use reqwest::multipart::{Form, Part};
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+
+use crate::{attachments, image_cache::{self, ImageCache}, turn, untrusted};
+
+/// 指定できる大きさと品質
+pub const SIZES: [&str; 4] = ["1024x1024", "1536x1024", "1024x1536", "auto"];
+pub const QUALITIES: [&str; 4] = ["low", "medium", "high", "auto"];
+/// 編集する画像の上限
+const MAX_SOURCE_BYTES: usize = 20 * 1024 * 1024;
+/// 生成には時間がかかるが、推論全体のタイムアウトより短くする
+const ENDPOINT_TIMEOUT: Duration = Duration::from_secs(150);
+
+/// 画像生成の設定
+#[derive(Clone, Debug, Default)]
+pub struct ImageGeneratorConfig {
+    /// `/images/generations` と `/images/edits` を持つ API のベース URL
+    pub endpoint: String,
+    pub api_key: Option<String>,
+    pub model: String,
+    pub default_size: String,
+    pub default_quality: String,
+    /// 1 枚ごとに消費するレート
+    pub rate_cost: u64,
+}
+
+/// 画像を生成・編集して応答に添付するツール
+#[derive(Clone)]
+pub struct ImageGenerator {
+    config: ImageGeneratorConfig,
+}
+
+impl ImageGenerator {
+    pub fn new(config: ImageGeneratorConfig) -> Self {
+        Self { config }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.config.endpoint.trim_end_matches('/'), path)
+    }
+
+    /// 画像を生成して PNG を返す (`source` があればそれを編集する)
+    pub async fn generate(&self, prompt: &str, size: &str, quality: &str, source: Option<Vec<u8>>) -> Result<Vec<u8>, String> {
+        let http = reqwest::Client::builder()
+            .timeout(ENDPOINT_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let request = match source {
+            Some(source) => {
+                let form = Form::new()
+                    .part("image", Part::bytes(to_png(&source)?).file_name("image.png").mime_str("image/png").map_err(|e| e.to_string())?)
+                    .text("prompt", prompt.to_string())
+                    .text("model", self.config.model.clone())
+                    .text("size", size.to_string())
+                    .text("quality", quality.to_string());
+                http.post(self.url("images/edits")).multipart(form)
+            }
+            None => http.post(self.url("images/generations")).json(&json!({
+                "model": self.config.model,
+                "prompt": prompt,
+                "n": 1,
+                "size": size,
+                "quality": quality,
+            })),
+        };
+        let request = match self.config.api_key.as_ref().filter(|k| !k.is_empty()) {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        };
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        let body: Value = response.json().await.map_err(|e| e.to_string())?;
+        if !status.is_success() {
+            let message = body["error"]["message"].as_str().unwrap_or("no message");
+            return Err(format!("the images endpoint returned {}: {}", status, message));
+        }
+
+        // gpt-image-1 は base64、DALL·E などは URL を返す
+        let image = &body["data"][0];
+        let bytes = if let Some(b64) = image["b64_json"].as_str() {
+            BASE64_STANDARD.decode(b64).map_err(|e| e.to_string())?
+        } else if let Some(url) = image["url"].as_str() {
+            attachments::fetch(&http, url, MAX_SOURCE_BYTES).await?.1
+        } else {
+            return Err("no image in the response".to_string());
+        };
+        to_png(&bytes)
+    }
+}
+
+/// 編集する画像を読む
+/// キャッシュがあれば通すので、履歴の参照 (`image-cache:<hash>`) や期限の切れた CDN の URL でも読める
+async fn load_source(cache: Option<&ImageCache>, url: &str) -> Result<Vec<u8>, String> {
+    let client = reqwest::Client::new();
+    let Some(cache) = cache else {
+        return Ok(attachments::fetch(&client, url, MAX_SOURCE_BYTES).await?.1);
+    };
+    let hash = match image_cache::hash_of(url) {
+        Some(hash) => hash.to_string(),
+        None => cache.load(&client, url, MAX_SOURCE_BYTES).await?.0,
+    };
+    cache.bytes(&hash).ok_or_else(|| "the image is no longer available".to_string())
+}
+
+/// PNG にそろえる (もともと PNG ならそのまま)
+fn to_png(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Ok(bytes.to_vec());
+    }
+    let image = image::load_from_memory(bytes).map_err(|e| format!("not an image: {}", e))?;
+    let mut png = Vec::new();
+    image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).map_err(|e| e.to_string())?;
+    Ok(png)
+}
+
+/// 指定がなければ既定値、選べない値ならエラー
+fn choose<'a>(args: &'a Value, key: &str, default: &'a str, allowed: &[&str]) -> Result<&'a str, String> {
+    let value = args[key].as_str().unwrap_or(default);
+    if allowed.contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("Invalid '{}' parameter - use one of {}", key, allowed.join(", ")))
+    }
+}
+
+impl Tool for ImageGenerator {
+    fn def_name(&self) -> &str {
+        "image_generator"
+    }
+
+    fn def_description(&self) -> &str {
+        "Generates an image from a prompt, or edits an image when 'image_url' is given (e.g. one of the attached images). \
+        The PNG is attached to your reply automatically, so do not link or embed it; just say what you made. Each image is expensive, so make one at a time."
+    }
+
+    fn def_parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "prompt": {
+                    "type": "string",
+                    "description": "Detailed description of the image to make, or of the change to apply when editing."
+                },
+                "size": {
+                    "type": "string",
+                    "enum": SIZES,
+                    "description": "Image size (square, landscape, portrait or auto)."
+                },
+                "quality": {
+                    "type": "string",
+                    "enum": QUALITIES,
+                    "description": "Rendering quality. Higher is slower."
+                },
+                "image_url": {
+                    "type": "string",
+                    "description": "URL of an image to edit instead of generating a new one."
+                },
+                "$explain": {
+                    "type": "string",
+                    "description": "A brief explanation of what you are doing with this tool."
+                },
+            },
+            "required": ["prompt"]
+        })
+    }
+
+    fn run(&self, args: Value) -> Result<String, String> {
+        let prompt = args["prompt"].as_str().ok_or("Missing 'prompt' parameter")?.to_string();
+        let size = choose(&args, "size", &self.config.default_size, &SIZES)?.to_string();
+        let quality = choose(&args, "quality", &self.config.default_quality, &QUALITIES)?.to_string();
+        let image_url = args["image_url"].as_str().map(str::to_string);
+        // 添付先のターンは task-local なので、スレッドに移る前に確かめる
+        turn::current().ok_or("image_generator can only be used in a conversation")?;
+        untrusted::check_side_effect("image_generator")?;
+
+        let generator = self.clone();
+        let (thread_size, thread_quality) = (size.clone(), quality.clone());
+        let png = thread::spawn(move || {
+            let rt = Runtime::new().expect("Failed to create runtime");
+            rt.block_on(async {
+                let source = match image_url {
+                    Some(url) => Some(
+                        load_source(image_cache::installed(), &url)
+                            .await
+                            .map_err(|e| format!("could not fetch the image to edit: {}", e))?,
+                    ),
+                    None => None,
+                };
+                let edited = source.is_some();
+                generator.generate(&prompt, &thread_size, &thread_quality, source).await.map(|png| (png, edited))
+            })
+        })
+        .join()
+        .map_err(|_| "Thread panicked".to_string())?;
+        let (png, edited) = png.map_err(|e| format!("Failed to generate the image: {}", e))?;
+
+        let name = format!("{}-{}.png", if edited { "edited" } else { "generated" }, chrono::Utc::now().timestamp_millis());
+        let bytes = png.len();
+        turn::attach_file(&name, png, self.config.rate_cost);
+        Ok(json!({
+            "attached": name,
+            "size": size,
+            "quality": quality,
+            "bytes": bytes,
+        }).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{local_http::{self, LocalServer, Response}, test_support::png, turn::TurnContext};
+    use image::RgbImage;
+
+    const RED: [u8; 3] = [200, 30, 30];
+
+    /// サーバーは返した `LocalServer` を drop するまで動く
+    async fn fake_endpoint() -> (String, LocalServer) {
+        let (addr, server) = local_http::serve("127.0.0.1:0", |request| async move {
+            let data = json!({ "data": [{ "b64_json": BASE64_STANDARD.encode(png(4, 4, RED)) }] });
+            match request.path.as_str() {
+                "/v1/images/generations" => {
+                    let body: Value = serde_json::from_slice(&request.body).unwrap();
+                    assert_eq!(request.header("authorization"), Some("Bearer sk-test"));
+                    if body["prompt"] == "forbidden" {
+                        return Response::json(400, &json!({ "error": { "message": "rejected by the safety system" } }));
+                    }
+                    assert_eq!((body["model"].as_str(), body["size"].as_str(), body["quality"].as_str()), (Some("gpt-image-1"), Some("1536x1024"), Some("low")));
+                    Response::json(200, &data)
+                }
+                "/v1/images/edits" => {
+                    let body = String::from_utf8_lossy(&request.body).to_string();
+                    assert!(request.header("content-type").unwrap().starts_with("multipart/form-data"));
+                    assert!(body.contains("filename=\"image.png\"") && body.contains("add a hat"));
+                    Response::json(200, &data)
+                }
+                _ => Response::json(404, &json!({})),
+            }
+        }).await.unwrap();
+        (format!("http://{}/v1/", addr), server)
+    }
+
+    fn generator(endpoint: String) -> ImageGenerator {
+        ImageGenerator::new(ImageGeneratorConfig {
+            endpoint,
+            api_key: Some("sk-test".into()),
+            model: "gpt-image-1".into(),
+            default_size: "1024x1024".into(),
+            default_quality: "medium".into(),
+            rate_cost: 20,
+        })
+    }
+
+    #[tokio::test]
+    async fn generates_and_edits_images() {
+        let (endpoint, _server) = fake_endpoint().await;
+        let generator = generator(endpoint);
+        let image = generator.generate("a red square", "1536x1024", "low", None).await.unwrap();
+        assert_eq!(image, png(4, 4, RED));
+
+        // 編集元は PNG にそろえて送る
+        let mut jpeg = Vec::new();
+        RgbImage::new(8, 8).write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg).unwrap();
+        assert!(generator.generate("add a hat", "auto", "auto", Some(jpeg)).await.is_ok());
+
+        let err = generator.generate("forbidden", "1024x1024", "low", None).await.unwrap_err();
+        assert!(err.contains("400") && err.contains("rejected by the safety system"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn sources_are_read_through_the_cache() {
+        let (addr, _handle) = local_http::serve("127.0.0.1:0", |request| async move {
+            match request.path.as_str() {
+                "/a.png?ex=1" => Response { status: 200, headers: vec![], body: png(4, 4, RED) },
+                // 期限切れの CDN の URL
+                _ => Response::json(404, &json!({})),
+            }
+        }).await.unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = ImageCache::open(dir.path()).unwrap();
+        let hash = cache.insert(&png(8, 8, RED)).unwrap();
+
+        // 履歴の参照はキャッシュから読む
+        let cached = load_source(Some(&cache), &image_cache::reference(&hash)).await.unwrap();
+        assert_eq!(cached, cache.bytes(&hash).unwrap());
+        // 一度取得した URL は署名の期限が切れていても読める
+        let url = |query: &str| format!("http://{}/a.png?{}", addr, query);
+        let fetched = load_source(Some(&cache), &url("ex=1")).await.unwrap();
+        assert_eq!(load_source(Some(&cache), &url("ex=2")).await.unwrap(), fetched);
+        // キャッシュがなければそのまま取得する
+        assert_eq!(load_source(None, &url("ex=1")).await.unwrap(), png(4, 4, RED));
+        assert!(load_source(None, &url("ex=2")).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn run_attaches_the_image_to_the_turn() {
+        let (endpoint, _server) = fake_endpoint().await;
+        let generator = generator(endpoint);
+        let args = json!({ "prompt": "a red square", "size": "1536x1024", "quality": "low" });
+        assert!(generator.run(args.clone()).unwrap_err().contains("only be used in a conversation"));
+        assert!(generator.run(json!({ "prompt": "x", "size": "4k" })).unwrap_err().contains("Invalid 'size'"));
+
+        let (result, files) = turn::scope(TurnContext::default(), async {
+            (generator.run(args).unwrap(), turn::take_files())
+        }).await;
+        let result: Value = serde_json::from_str(&result).unwrap();
+        assert!(result["attached"].as_str().unwrap().starts_with("generated-"));
+        assert_eq!(files.len(), 1);
+        assert_eq!((&files[0].data, files[0].rate_cost), (&png(4, 4, RED), 20));
+    }
+}
@@ -9,6 +9,8 @@ use std::sync::Mutex;
 use chrono::{DateTime, Local};
 use log::error;
 
+use crate::{privacy, untrusted};
+
 const MEMORY_DIR: &str = "memory";
 const MAX_KEYS: usize = 100;
 
@@ -22,42 +24,52 @@ pub struct MemoryResponse {
 /// MemoryTool 構造体：key-value ペアで記憶を管理（最大 100 件）
 pub struct MemoryTool {
     memory: Mutex<HashMap<String, String>>,
+    /// .md ファイルを保存するディレクトリ
+    dir: PathBuf,
+}
+
+impl Default for MemoryTool {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemoryTool {
     /// 新しいインスタンスを生成し、memory ディレクトリ内の .md ファイルからメモリを読み込む
     pub fn new() -> Self {
+        Self::open(MEMORY_DIR)
+    }
+
+    /// `dir` 内の .md ファイルからメモリを読み込む
+    pub fn open(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
         let mut mem_map = HashMap::new();
 
         // memory ディレクトリがなければ作成
-        if let Err(e) = fs::create_dir_all(MEMORY_DIR) {
+        if let Err(e) = fs::create_dir_all(&dir) {
             error!("Failed to create memory directory: {}", e);
         } else {
             // memory/ 内の .md ファイルをすべて読み込む
-            if let Ok(entries) = fs::read_dir(MEMORY_DIR) {
+            if let Ok(entries) = fs::read_dir(&dir) {
                 for entry in entries.filter_map(|e| e.ok()) {
                     let path = entry.path();
-                    if path.is_file() {
-                        if let Some(ext) = path.extension() {
-                            if ext == "md" {
-                                // ファイル名（拡張子除く）を key とする
-                                if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
-                                    let mut file = match fs::File::open(&path) {
-                                        Ok(f) => f,
-                                        Err(e) => {
-                                            error!("Failed to open file {:?}: {}", path, e);
-                                            continue;
-                                        }
-                                    };
-                                    let mut contents = String::new();
-                                    if let Err(e) = file.read_to_string(&mut contents) {
-                                        error!("Failed to read file {:?}: {}", path, e);
-                                        continue;
-                                    }
-                                    mem_map.insert(stem.to_string(), contents);
-                                }
+                    // ファイル名（拡張子除く）を key とする
+                    if path.is_file()
+                        && path.extension().is_some_and(|ext| ext == "md")
+                        && let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                        let mut file = match fs::File::open(&path) {
+                            Ok(f) => f,
+                            Err(e) => {
+                                error!("Failed to open file {:?}: {}", path, e);
+                                continue;
                             }
+                        };
+                        let mut contents = String::new();
+                        if let Err(e) = file.read_to_string(&mut contents) {
+                            error!("Failed to read file {:?}: {}", path, e);
+                            continue;
                         }
+                        mem_map.insert(stem.to_string(), contents);
                     }
                 }
             }
@@ -65,6 +77,7 @@ impl MemoryTool {
 
         MemoryTool {
             memory: Mutex::new(mem_map),
+            dir,
         }
     }
 
@@ -75,7 +88,7 @@ impl MemoryTool {
             return Err(format!("Cannot add new key. Maximum {} keys reached.", MAX_KEYS));
         }
         mem.insert(key.to_string(), value.to_string());
-        Self::save_to_file(key, value)
+        self.save_to_file(key, value)
     }
 
     /// key の内容に対して新たな値を末尾に追加する (push)。既存の内容があれば改行区切りで追加、
@@ -92,7 +105,7 @@ impl MemoryTool {
             value.to_string()
         };
         mem.insert(key.to_string(), new_value.clone());
-        Self::save_to_file(key, new_value.as_str())
+        self.save_to_file(key, new_value.as_str())
     }
 
     /// 指定された key の値を取得する。key が None の場合は全件返す。
@@ -120,23 +133,21 @@ impl MemoryTool {
             Some(k) => {
                 let mut mem = self.memory.lock().unwrap();
                 mem.remove(k);
-                let file_path = Self::get_file_path(k);
-                if file_path.exists() {
-                    if let Err(e) = fs::remove_file(&file_path) {
-                        error!("Failed to remove file {:?}: {}", file_path, e);
-                    }
+                let file_path = self.get_file_path(k);
+                if file_path.exists()
+                    && let Err(e) = fs::remove_file(&file_path) {
+                    error!("Failed to remove file {:?}: {}", file_path, e);
                 }
             }
             None => {
                 let mut mem = self.memory.lock().unwrap();
                 mem.clear();
-                if let Ok(entries) = fs::read_dir(MEMORY_DIR) {
+                if let Ok(entries) = fs::read_dir(&self.dir) {
                     for entry in entries.filter_map(|e| e.ok()) {
                         let path = entry.path();
-                        if path.is_file() && path.extension().map(|ext| ext == "md").unwrap_or(false) {
-                            if let Err(e) = fs::remove_file(&path) {
-                                error!("Failed to remove file {:?}: {}", path, e);
-                            }
+                        if path.is_file() && path.extension().map(|ext| ext == "md").unwrap_or(false)
+                            && let Err(e) = fs::remove_file(&path) {
+                            error!("Failed to remove file {:?}: {}", path, e);
                         }
                     }
                 }
@@ -144,9 +155,30 @@ impl MemoryTool {
         }
     }
 
+    /// 値に単語として含まれる `needles` をすべて `replacement` に置き換え、書き換えたキーを返す
+    pub fn anonymize(&self, needles: &[&str], replacement: &str) -> Vec<String> {
+        let mut mem = self.memory.lock().unwrap();
+        let mut changed = Vec::new();
+        for (key, value) in mem.iter_mut() {
+            let mut new_value = value.clone();
+            for needle in needles {
+                new_value = privacy::replace_name(&new_value, needle, replacement);
+            }
+            if new_value != *value {
+                *value = new_value;
+                if let Err(e) = self.save_to_file(key, value) {
+                    error!("{}", e);
+                }
+                changed.push(key.clone());
+            }
+        }
+        changed.sort();
+        changed
+    }
+
     /// 指定した key と value を .md ファイルに保存する (上書き)。
-    fn save_to_file(key: &str, value: &str) -> Result<(), String> {
-        let file_path = Self::get_file_path(key);
+    fn save_to_file(&self, key: &str, value: &str) -> Result<(), String> {
+        let file_path = self.get_file_path(key);
         let mut file = fs::File::create(&file_path)
             .map_err(|e| format!("Failed to create file {:?}: {}", file_path, e))?;
         file.write_all(value.as_bytes())
@@ -155,22 +187,21 @@ impl MemoryTool {
     }
 
     /// 指定した key に対応する .md ファイルのパスを取得する
-    fn get_file_path(key: &str) -> PathBuf {
-        let mut path = PathBuf::from(MEMORY_DIR);
+    fn get_file_path(&self, key: &str) -> PathBuf {
+        let mut path = self.dir.clone();
         path.push(format!("{}.md", key));
         path
     }
 
     /// 指定した key に対応する .md ファイルの最終更新日時を取得する
     /// (人間に読みやすい形式: "YYYY-MM-DD HH:MM:SS")
-    fn get_last_modified(key: &str) -> Option<String> {
-        let file_path = Self::get_file_path(key);
-        if let Ok(metadata) = fs::metadata(&file_path) {
-            if let Ok(modified) = metadata.modified() {
-                // SystemTime をローカル日時に変換
-                let datetime: DateTime<Local> = modified.into();
-                return Some(datetime.format("%Y-%m-%d %H:%M:%S").to_string());
-            }
+    fn get_last_modified(&self, key: &str) -> Option<String> {
+        let file_path = self.get_file_path(key);
+        if let Ok(metadata) = fs::metadata(&file_path)
+            && let Ok(modified) = metadata.modified() {
+            // SystemTime をローカル日時に変換
+            let datetime: DateTime<Local> = modified.into();
+            return Some(datetime.format("%Y-%m-%d %H:%M:%S").to_string());
         }
         None
     }
@@ -248,6 +279,7 @@ Use 'add' to create or update an entry, 'push' to append to an existing entry, '
                 let value = args.get("value")
                     .and_then(|v| v.as_str())
                     .ok_or_else(|| "Missing or invalid 'value' parameter for add action".to_string())?;
+                untrusted::check_side_effect("memory_tool add")?;
                 self.add_memory(key, value)?;
                 let response = MemoryResponse {
                     status: "Memory added/updated.".to_string(),
@@ -262,6 +294,7 @@ Use 'add' to create or update an entry, 'push' to append to an existing entry, '
                 let value = args.get("value")
                     .and_then(|v| v.as_str())
                     .ok_or_else(|| "Missing or invalid 'value' parameter for push action".to_string())?;
+                untrusted::check_side_effect("memory_tool push")?;
                 self.push_memory(key, value)?;
                 let response = MemoryResponse {
                     status: format!("Value pushed to key '{}'.", key),
@@ -276,7 +309,7 @@ Use 'add' to create or update an entry, 'push' to append to an existing entry, '
                 for (k, v) in mem.iter() {
                     mem_with_meta.insert(k.clone(), json!({
                         "value": v,
-                        "last_modified": Self::get_last_modified(k)
+                        "last_modified": self.get_last_modified(k)
                     }));
                 }
                 let response = MemoryResponse {
@@ -295,6 +328,7 @@ Use 'add' to create or update an entry, 'push' to append to an existing entry, '
             },
             "clear" => {
                 let key = args.get("key").and_then(|v| v.as_str());
+                untrusted::check_side_effect("memory_tool clear")?;
                 self.clear_memory(key);
                 let status_msg = match key {
                     Some(k) => format!("Memory for key '{}' cleared.", k),
@@ -5,4 +5,7 @@ pub mod memory;
 // pub mod text_len;
 pub mod web_deploy;
 pub mod image_captioner;
-pub mod browsing_worker;
\ No newline at end of file
+pub mod browsing_worker;
+pub mod scheduler;
+pub mod attachment_reader;
+pub mod image_generator;
\ No newline at end of file
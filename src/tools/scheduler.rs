@@ -0,0 +1,494 @@
+//! 予約実行 (一回限りと cron 式のジョブ)
+//!
+//! ジョブは保存したプロンプトを予定の時刻に対象のチャンネルで推論させるだけのもので、
+//! 実行自体は bot 側のループ (`Bot::run_scheduler`) が行う。
+//! ジョブは JSON ファイルに保存するので再起動しても消えない。
+
+use std::{fs, path::PathBuf, str::FromStr, sync::Mutex};
+
+use call_agent::chat::function::Tool;
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+
+use crate::{turn, untrusted};
+
+/// cron ジョブの最短間隔 (これより頻繁なものは登録できない)
+const MIN_CRON_INTERVAL: Duration = Duration::minutes(10);
+/// `delay_minutes` の上限 (およそ 10 年)
+const MAX_DELAY_MINUTES: f64 = 10.0 * 366.0 * 24.0 * 60.0;
+/// 一覧で表示するプロンプトの最大文字数
+const PROMPT_PREVIEW_CHARS: usize = 60;
+
+/// いつ実行するか
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Schedule {
+    /// 一回限り
+    Once { at: DateTime<Utc> },
+    /// cron 式 (5 または 6 フィールド) とタイムゾーン
+    Cron { expression: String, timezone: String },
+}
+
+impl Schedule {
+    /// 5 フィールドの cron 式には秒を補う
+    fn parse_cron(expression: &str) -> Result<cron::Schedule, String> {
+        let fields = expression.split_whitespace().count();
+        let normalized = if fields == 5 { format!("0 {}", expression) } else { expression.to_string() };
+        cron::Schedule::from_str(&normalized).map_err(|e| format!("invalid cron expression '{}': {}", expression, e))
+    }
+
+    fn parse_timezone(timezone: &str) -> Result<Tz, String> {
+        timezone.parse().map_err(|_| format!("unknown timezone '{}'", timezone))
+    }
+
+    /// `after` より後の次の実行時刻
+    pub fn next_after(&self, after: DateTime<Utc>) -> Result<Option<DateTime<Utc>>, String> {
+        match self {
+            Schedule::Once { at } => Ok((*at > after).then_some(*at)),
+            Schedule::Cron { expression, timezone } => {
+                let tz = Self::parse_timezone(timezone)?;
+                let schedule = Self::parse_cron(expression)?;
+                Ok(schedule.after(&after.with_timezone(&tz)).next().map(|t| t.with_timezone(&Utc)))
+            }
+        }
+    }
+
+    /// 登録できるか確かめる (実行時刻が過去・cron が頻繁すぎるものは拒否する)
+    fn validate(&self, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+        let first = self.next_after(now)?.ok_or("the time is in the past")?;
+        if let Schedule::Cron { .. } = self
+            && let Some(second) = self.next_after(first)?
+            && second - first < MIN_CRON_INTERVAL {
+            return Err(format!("cron jobs must be at least {} minutes apart", MIN_CRON_INTERVAL.num_minutes()));
+        }
+        Ok(first)
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Schedule::Once { .. } => "once".to_string(),
+            Schedule::Cron { expression, timezone } => format!("cron `{}` ({})", expression, timezone),
+        }
+    }
+
+    fn timezone(&self) -> Option<Tz> {
+        match self {
+            Schedule::Once { .. } => None,
+            Schedule::Cron { timezone, .. } => Self::parse_timezone(timezone).ok(),
+        }
+    }
+}
+
+/// 予約されたジョブ
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    /// 登録したユーザー (実行時はこのユーザーの発言として扱う)
+    pub owner: String,
+    pub owner_name: String,
+    /// `ChannelState` のキー
+    pub channel: String,
+    pub prompt: String,
+    pub schedule: Schedule,
+    pub created_at: DateTime<Utc>,
+    pub next_run: DateTime<Utc>,
+}
+
+impl Job {
+    /// 一覧の 1 行
+    pub fn summary(&self, default_tz: Tz) -> String {
+        let tz = self.schedule.timezone().unwrap_or(default_tz);
+        let prompt: String = self.prompt.chars().take(PROMPT_PREVIEW_CHARS).collect();
+        let ellipsis = if self.prompt.chars().count() > PROMPT_PREVIEW_CHARS { "…" } else { "" };
+        format!(
+            "#{} next {} - {} by {}: {}{}",
+            self.id,
+            self.next_run.with_timezone(&tz).format("%Y-%m-%d %H:%M %Z"),
+            self.schedule.describe(),
+            self.owner_name,
+            prompt.replace('\n', " "),
+            ellipsis,
+        )
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct JobFile {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+/// ジョブの保存と取り出し
+pub struct Scheduler {
+    path: PathBuf,
+    state: Mutex<JobFile>,
+    /// 1 ユーザーが持てるジョブ数 (0 で無制限)
+    max_jobs_per_user: usize,
+    /// 時刻の指定がないときのタイムゾーン
+    pub default_timezone: Tz,
+    /// ジョブが追加・削除されたら実行ループを起こす
+    changed: Notify,
+}
+
+impl Scheduler {
+    /// `path` から読み込む (なければ空)
+    pub fn load(path: impl Into<PathBuf>, max_jobs_per_user: usize, default_timezone: Tz) -> Self {
+        let path = path.into();
+        let state = fs::read_to_string(&path)
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).map_err(|e| error!("Failed to parse {:?} - {}", path, e)).ok())
+            .unwrap_or_default();
+        Self { path, state: Mutex::new(state), max_jobs_per_user, default_timezone, changed: Notify::new() }
+    }
+
+    fn save(&self, state: &JobFile) {
+        if let Some(dir) = self.path.parent()
+            && let Err(e) = fs::create_dir_all(dir) {
+            error!("Failed to create {:?} - {}", dir, e);
+        }
+        match serde_json::to_string_pretty(state) {
+            Ok(data) => {
+                if let Err(e) = fs::write(&self.path, data) {
+                    error!("Failed to save jobs to {:?} - {}", self.path, e);
+                }
+            }
+            Err(e) => error!("Failed to serialize jobs - {}", e),
+        }
+    }
+
+    /// ジョブを登録する
+    pub fn add(&self, owner: &str, owner_name: &str, channel: &str, prompt: &str, schedule: Schedule, now: DateTime<Utc>) -> Result<Job, String> {
+        if prompt.trim().is_empty() {
+            return Err("prompt is empty".to_string());
+        }
+        let next_run = schedule.validate(now)?;
+        let mut state = self.state.lock().unwrap();
+        let owned = state.jobs.iter().filter(|job| job.owner == owner).count();
+        if self.max_jobs_per_user != 0 && owned >= self.max_jobs_per_user {
+            return Err(format!("you already have {} scheduled jobs (limit {})", owned, self.max_jobs_per_user));
+        }
+        state.next_id += 1;
+        let job = Job {
+            id: state.next_id,
+            owner: owner.to_string(),
+            owner_name: owner_name.to_string(),
+            channel: channel.to_string(),
+            prompt: prompt.to_string(),
+            schedule,
+            created_at: now,
+            next_run,
+        };
+        state.jobs.push(job.clone());
+        self.save(&state);
+        drop(state);
+        self.changed.notify_waiters();
+        Ok(job)
+    }
+
+    /// ジョブの一覧 (`channel` を指定するとそのチャンネルのものだけ)
+    pub fn list(&self, channel: Option<&str>) -> Vec<Job> {
+        let state = self.state.lock().unwrap();
+        let mut jobs: Vec<Job> = state.jobs
+            .iter()
+            .filter(|job| channel.is_none_or(|c| job.channel == c))
+            .cloned()
+            .collect();
+        jobs.sort_by_key(|job| job.next_run);
+        jobs
+    }
+
+    /// ジョブを取り消す (登録者か `is_admin` のときだけ)
+    pub fn cancel(&self, id: u64, requester: &str, is_admin: bool) -> Result<Job, String> {
+        let mut state = self.state.lock().unwrap();
+        let index = state.jobs.iter().position(|job| job.id == id).ok_or(format!("job #{} not found", id))?;
+        if state.jobs[index].owner != requester && !is_admin {
+            return Err(format!("job #{} belongs to {}", id, state.jobs[index].owner_name));
+        }
+        let job = state.jobs.remove(index);
+        self.save(&state);
+        drop(state);
+        self.changed.notify_waiters();
+        Ok(job)
+    }
+
+    /// 実行時刻を過ぎたジョブを取り出す (`owns` が true のチャンネルのものだけ)
+    /// 一回限りのものは削除し、cron のものは次の時刻に進める (止まっていた間の分はまとめて 1 回)
+    pub fn take_due(&self, now: DateTime<Utc>, owns: impl Fn(&str) -> bool) -> Vec<Job> {
+        let mut state = self.state.lock().unwrap();
+        let mut due = Vec::new();
+        state.jobs.retain_mut(|job| {
+            if job.next_run > now || !owns(&job.channel) {
+                return true;
+            }
+            due.push(job.clone());
+            match job.schedule.next_after(now) {
+                Ok(Some(next)) => {
+                    job.next_run = next;
+                    true
+                }
+                Ok(None) => false,
+                Err(e) => {
+                    error!("Dropping job #{} - {}", job.id, e);
+                    false
+                }
+            }
+        });
+        if !due.is_empty() {
+            self.save(&state);
+        }
+        due
+    }
+
+    /// 次に実行するジョブの時刻
+    pub fn next_wakeup(&self, owns: impl Fn(&str) -> bool) -> Option<DateTime<Utc>> {
+        let state = self.state.lock().unwrap();
+        state.jobs.iter().filter(|job| owns(&job.channel)).map(|job| job.next_run).min()
+    }
+
+    /// ジョブが追加・削除されるまで待つ
+    pub async fn changed(&self) {
+        self.changed.notified().await;
+    }
+}
+
+/// モデルから予約を操作するツール
+pub struct SchedulerTool {
+    scheduler: std::sync::Arc<Scheduler>,
+}
+
+impl SchedulerTool {
+    pub fn new(scheduler: std::sync::Arc<Scheduler>) -> Self {
+        Self { scheduler }
+    }
+
+    /// 引数から実行時刻を組み立てる
+    fn schedule_from_args(&self, args: &Value, now: DateTime<Utc>) -> Result<Schedule, String> {
+        let str_arg = |name: &str| args.get(name).and_then(Value::as_str).filter(|s| !s.is_empty());
+        if let Some(expression) = str_arg("cron") {
+            let timezone = str_arg("timezone").map(str::to_string).unwrap_or_else(|| self.scheduler.default_timezone.name().to_string());
+            return Ok(Schedule::Cron { expression: expression.to_string(), timezone });
+        }
+        if let Some(minutes) = args.get("delay_minutes").and_then(Value::as_f64) {
+            // 大きすぎる値は秒に直したときや時刻に足したときに溢れるので先に弾く
+            if !(0.0..=MAX_DELAY_MINUTES).contains(&minutes) {
+                return Err(format!("'delay_minutes' must be between 0 and {}", MAX_DELAY_MINUTES));
+            }
+            let at = Duration::try_seconds((minutes * 60.0) as i64)
+                .and_then(|delay| now.checked_add_signed(delay))
+                .ok_or_else(|| "'delay_minutes' is out of range".to_string())?;
+            return Ok(Schedule::Once { at });
+        }
+        if let Some(at) = str_arg("at") {
+            let at = DateTime::parse_from_rfc3339(at).map_err(|e| format!("invalid 'at' (use RFC 3339 with an offset): {}", e))?;
+            return Ok(Schedule::Once { at: at.with_timezone(&Utc) });
+        }
+        Err("one of 'delay_minutes', 'at' or 'cron' is required".to_string())
+    }
+}
+
+impl Tool for SchedulerTool {
+    fn def_name(&self) -> &str {
+        "scheduler"
+    }
+
+    fn def_description(&self) -> &str {
+        "Schedule a prompt to be run later in the current channel, as if the current user sent it at that time.
+Use it for reminders ('remind me in 2 hours to ...') and recurring tasks ('every Monday 9:00 post the week's tech news').
+Write the prompt as an instruction to yourself, including who to mention and what to do.
+'add' with 'delay_minutes' or 'at' creates a one-shot job; 'add' with 'cron' creates a recurring job
+(5 fields: minute hour day-of-month month day-of-week; use names like MON for days of the week).
+'list' shows the jobs in this channel and 'cancel' removes one of the user's jobs by id."
+    }
+
+    fn def_parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "action": {
+                    "type": "string",
+                    "enum": ["add", "list", "cancel"],
+                    "description": "The scheduler action to perform."
+                },
+                "prompt": {
+                    "type": "string",
+                    "description": "The instruction to run when the job fires (required for 'add')."
+                },
+                "delay_minutes": {
+                    "type": "number",
+                    "description": "Run once after this many minutes."
+                },
+                "at": {
+                    "type": "string",
+                    "description": "Run once at this time (RFC 3339 with an offset, e.g. 2025-01-01T09:00:00+09:00)."
+                },
+                "cron": {
+                    "type": "string",
+                    "description": "Run repeatedly on this cron expression, e.g. '0 9 * * MON'."
+                },
+                "timezone": {
+                    "type": "string",
+                    "description": format!("IANA timezone for 'cron' (default {}).", self.scheduler.default_timezone.name())
+                },
+                "id": {
+                    "type": "integer",
+                    "description": "The job id (required for 'cancel')."
+                },
+                "$explain": {
+                    "type": "string",
+                    "description": "A brief explanation of what you are doing with this tool."
+                },
+            },
+            "required": ["action"]
+        })
+    }
+
+    fn run(&self, args: Value) -> Result<String, String> {
+        let context = turn::current().ok_or("the scheduler can only be used in a conversation")?;
+        let action = args.get("action")
+            .and_then(Value::as_str)
+            .ok_or_else(|| "Missing or invalid 'action' parameter".to_string())?;
+        let now = Utc::now();
+        match action {
+            "add" => {
+                let prompt = args.get("prompt")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| "Missing or invalid 'prompt' parameter for add action".to_string())?;
+                let schedule = self.schedule_from_args(&args, now)?;
+                untrusted::check_side_effect("scheduler add")?;
+                let job = self.scheduler.add(&context.user_id, &context.user_name, &context.channel, prompt, schedule, now)?;
+                Ok(json!({ "status": "scheduled", "job": job.summary(self.scheduler.default_timezone) }).to_string())
+            }
+            "list" => {
+                let jobs: Vec<String> = self.scheduler
+                    .list(Some(&context.channel))
+                    .iter()
+                    .map(|job| job.summary(self.scheduler.default_timezone))
+                    .collect();
+                Ok(json!({ "jobs": jobs }).to_string())
+            }
+            "cancel" => {
+                let id = args.get("id")
+                    .and_then(Value::as_u64)
+                    .ok_or_else(|| "Missing or invalid 'id' parameter for cancel action".to_string())?;
+                untrusted::check_side_effect("scheduler cancel")?;
+                let job = self.scheduler.cancel(id, &context.user_id, false)?;
+                Ok(json!({ "status": "cancelled", "job": job.summary(self.scheduler.default_timezone) }).to_string())
+            }
+            _ => Err(format!("Unknown action: {}", action)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::turn::TurnContext;
+    use chrono::TimeZone;
+    use tempfile::TempDir;
+
+    fn scheduler(dir: &TempDir, limit: usize) -> Scheduler {
+        Scheduler::load(dir.path().join("jobs.json"), limit, chrono_tz::Asia::Tokyo)
+    }
+
+    fn at(h: u32, m: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 1, 6, h, m, 0).unwrap()
+    }
+
+    fn weekly() -> Schedule {
+        Schedule::Cron { expression: "0 9 * * MON".into(), timezone: "Asia/Tokyo".into() }
+    }
+
+    #[test]
+    fn cron_runs_in_the_given_timezone() {
+        // 2025-01-06 は月曜日。JST 9:00 は UTC 0:00
+        let next = weekly().next_after(Utc.with_ymd_and_hms(2025, 1, 5, 0, 0, 0).unwrap()).unwrap();
+        assert_eq!(next, Some(at(0, 0)));
+        let after = weekly().next_after(at(0, 0)).unwrap().unwrap();
+        assert_eq!(after - at(0, 0), Duration::days(7));
+    }
+
+    #[test]
+    fn rejects_bad_schedules() {
+        let dir = TempDir::new().unwrap();
+        let s = scheduler(&dir, 0);
+        let past = Schedule::Once { at: at(0, 0) };
+        assert!(s.add("u", "alice", "c", "hi", past, at(1, 0)).is_err());
+        let frequent = Schedule::Cron { expression: "*/5 * * * *".into(), timezone: "UTC".into() };
+        assert!(s.add("u", "alice", "c", "hi", frequent, at(1, 0)).unwrap_err().contains("10 minutes"));
+        let bad = Schedule::Cron { expression: "nope".into(), timezone: "UTC".into() };
+        assert!(s.add("u", "alice", "c", "hi", bad, at(1, 0)).is_err());
+        let zone = Schedule::Cron { expression: "0 9 * * *".into(), timezone: "Mars/Base".into() };
+        assert!(s.add("u", "alice", "c", "hi", zone, at(1, 0)).is_err());
+    }
+
+    #[test]
+    fn enforces_per_user_limits_and_ownership() {
+        let dir = TempDir::new().unwrap();
+        let s = scheduler(&dir, 2);
+        let once = Schedule::Once { at: at(2, 0) };
+        let first = s.add("u", "alice", "c", "a", once.clone(), at(1, 0)).unwrap();
+        s.add("u", "alice", "c", "b", once.clone(), at(1, 0)).unwrap();
+        assert!(s.add("u", "alice", "c", "c", once.clone(), at(1, 0)).unwrap_err().contains("limit 2"));
+        s.add("v", "bob", "c", "d", once, at(1, 0)).unwrap();
+
+        assert!(s.cancel(first.id, "v", false).is_err());
+        assert_eq!(s.cancel(first.id, "v", true).unwrap().prompt, "a");
+        assert_eq!(s.list(Some("c")).len(), 2);
+    }
+
+    #[test]
+    fn due_jobs_are_taken_and_persisted() {
+        let dir = TempDir::new().unwrap();
+        let s = scheduler(&dir, 0);
+        s.add("u", "alice", "c1", "once", Schedule::Once { at: at(2, 0) }, at(1, 0)).unwrap();
+        s.add("u", "alice", "irc:#x", "other platform", Schedule::Once { at: at(2, 0) }, at(1, 0)).unwrap();
+        s.add("u", "alice", "c1", "weekly", weekly(), Utc.with_ymd_and_hms(2025, 1, 5, 0, 0, 0).unwrap()).unwrap();
+        let owns = |c: &str| !c.starts_with("irc:");
+
+        assert!(s.take_due(at(0, 30), owns).iter().any(|j| j.prompt == "weekly"));
+        assert_eq!(s.next_wakeup(owns), Some(at(2, 0)));
+        let due: Vec<String> = s.take_due(at(3, 0), owns).into_iter().map(|j| j.prompt).collect();
+        assert_eq!(due, vec!["once"]);
+
+        // 再起動しても残る
+        let reloaded = scheduler(&dir, 0);
+        let prompts: Vec<String> = reloaded.list(None).into_iter().map(|j| j.prompt).collect();
+        assert_eq!(prompts, vec!["other platform", "weekly"]);
+        assert_eq!(reloaded.list(Some("c1"))[0].next_run, at(0, 0) + Duration::days(7));
+    }
+
+    #[tokio::test]
+    async fn tool_uses_the_turn_context() {
+        let dir = TempDir::new().unwrap();
+        let s = std::sync::Arc::new(scheduler(&dir, 0));
+        let tool = SchedulerTool::new(s.clone());
+        assert!(tool.run(json!({ "action": "list" })).is_err());
+
+        let context = TurnContext { channel: "c1".into(), user_id: "42".into(), user_name: "alice".into(), untrusted: false };
+        let result = turn::scope(context, async {
+            tool.run(json!({ "action": "add", "prompt": "remind alice to stretch", "delay_minutes": 120 }))
+        }).await.unwrap();
+        assert!(result.contains("remind alice to stretch"));
+        let jobs = s.list(Some("c1"));
+        assert_eq!((jobs[0].owner.as_str(), jobs[0].owner_name.as_str()), ("42", "alice"));
+        assert!(jobs[0].next_run > Utc::now() + Duration::minutes(119));
+    }
+
+    #[test]
+    fn rejects_delays_out_of_range() {
+        let dir = TempDir::new().unwrap();
+        let tool = SchedulerTool::new(std::sync::Arc::new(scheduler(&dir, 0)));
+        for minutes in [-1.0, 1e300, f64::MAX] {
+            let err = tool.schedule_from_args(&json!({ "delay_minutes": minutes }), at(0, 0)).unwrap_err();
+            assert!(err.contains("delay_minutes"), "{}", err);
+        }
+        assert_eq!(
+            tool.schedule_from_args(&json!({ "delay_minutes": 1.5 }), at(0, 0)),
+            Ok(Schedule::Once { at: at(0, 0) + Duration::seconds(90) }),
+        );
+    }
+}
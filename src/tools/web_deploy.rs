@@ -1,7 +1,7 @@
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
 use call_agent::chat::function::Tool;
 use chrono::{Datelike, Local};
-use log::debug;
+use log::{debug, warn};
 use tokio::runtime::Runtime;
 use tokio::sync::RwLock;
 use std::collections::HashMap;
@@ -9,7 +9,10 @@ use std::{fs, thread};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use crate::moderation::Moderator;
+use crate::openai_api::ApiGateway;
 use crate::prefix::DOMAIN;
+use crate::untrusted;
 use std::fs::File;
 use std::io::{Seek, SeekFrom, Read};
 use actix_web::HttpRequest;
@@ -18,7 +21,9 @@ const BASE_DIR: &str = "./data";
 
 #[derive(Clone)]
 pub struct WebDeploy {
-    file_map: Arc<RwLock<HashMap<String, PathBuf>>> // 記事キー → ファイルパス
+    file_map: Arc<RwLock<HashMap<String, PathBuf>>>, // 記事キー → ファイルパス
+    /// モデルが書いた記事を公開前に照合する
+    moderator: Option<Arc<Moderator>>,
 }
 
 async fn list_articles_by_month(
@@ -59,23 +64,19 @@ async fn get_article_raw(
         let metadata = file.metadata().ok();
         let file_size = metadata.map(|m| m.len()).unwrap_or(0);
 
-        if let Some(range_header) = req.headers().get("Range") {
-            if let Ok(range) = range_header.to_str() {
-                if let Some(range) = range.strip_prefix("bytes=") {
-                    let parts: Vec<&str> = range.split('-').collect();
-                    if let (Some(start_str), Some(end_str)) = (parts.get(0), parts.get(1)) {
-                        if let (Ok(start), Ok(end)) = (start_str.parse::<u64>(), end_str.parse::<u64>()) {
-                            if start < file_size && end < file_size {
-                                let mut buffer = vec![0; (end - start + 1) as usize];
-                                file.seek(SeekFrom::Start(start)).ok();
-                                file.read_exact(&mut buffer).ok();
-                                return HttpResponse::PartialContent()
-                                    .insert_header(("Content-Range", format!("bytes {}-{}/{}", start, end, file_size)))
-                                    .body(buffer);
-                            }
-                        }
-                    }
-                }
+        if let Some(range_header) = req.headers().get("Range")
+            && let Ok(range) = range_header.to_str()
+            && let Some(range) = range.strip_prefix("bytes=") {
+            let parts: Vec<&str> = range.split('-').collect();
+            if let (Some(start_str), Some(end_str)) = (parts.first(), parts.get(1))
+                && let (Ok(start), Ok(end)) = (start_str.parse::<u64>(), end_str.parse::<u64>())
+                && start < file_size && end < file_size {
+                let mut buffer = vec![0; (end - start + 1) as usize];
+                file.seek(SeekFrom::Start(start)).ok();
+                file.read_exact(&mut buffer).ok();
+                return HttpResponse::PartialContent()
+                    .insert_header(("Content-Range", format!("bytes {}-{}/{}", start, end, file_size)))
+                    .body(buffer);
             }
         }
 
@@ -138,7 +139,25 @@ impl WebDeploy {
             fs::create_dir_all(base_dir).expect("Failed to create directory");
         }
 
-        Self { file_map }
+        Self { file_map, moderator: None }
+    }
+
+    pub fn with_moderator(mut self, moderator: Option<Arc<Moderator>>) -> Self {
+        self.moderator = moderator;
+        self
+    }
+
+    /// 公開する前に記事の本文を照合する (引っかかれば公開しない)
+    pub async fn moderate_article(&self, key: &str, content: &str) -> Result<(), String> {
+        let Some(moderator) = &self.moderator else {
+            return Ok(());
+        };
+        let verdict = moderator.check(content).await;
+        if verdict.is_clean() {
+            return Ok(());
+        }
+        warn!("Article {} was withheld by moderation - {:?}", key, verdict.reasons());
+        Err(format!("Blocked: the article was withheld by moderation ({})", verdict.reasons().join(", ")))
     }
 
     pub async fn get_article(&self, key: &str) -> Result<String, String> {
@@ -148,6 +167,13 @@ impl WebDeploy {
         Ok(content)
     }
 
+    /// 公開しているすべての記事のキー
+    pub async fn article_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.file_map.read().await.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
     pub async fn found_article(&self, key: &str) -> bool {
         self.file_map.read().await.contains_key(key)
     }
@@ -169,14 +195,22 @@ impl WebDeploy {
 
 
 
-    pub fn start_server(&self, bind: String) {
+    /// 記事のサーバーを起動する
+    /// `api` を渡すと OpenAI 互換の `/v1/...` も提供する
+    pub fn start_server(&self, bind: String, api: Option<Arc<ApiGateway>>) {
         let map = Arc::clone(&self.file_map);
         thread::spawn(move || {
             let rt = Runtime::new().expect("Failed to create runtime");
             rt.block_on(async move {
                 HttpServer::new(move || {
+                    let api = api.clone();
                     App::new()
                         .app_data(web::Data::new(Arc::clone(&map)))
+                        .configure(|cfg| {
+                            if let Some(api) = api {
+                                ApiGateway::configure(api)(cfg);
+                            }
+                        })
                         .route("/", web::get().to(root_page))
                         .route("/articles/{year}/{month}", web::get().to(list_articles_by_month))
                         .route("/article/raw/{year}/{month}/{article}", web::get().to(get_article_raw))
@@ -239,9 +273,11 @@ impl Tool for WebDeploy {
 
     fn run(&self, args: serde_json::Value) -> Result<String, String> {
             let self_clone = self.clone();
+        // ターンの記録は task-local なので、スレッドに移る前に確かめる
+        let side_effect = untrusted::check_side_effect("web_deploy_tool create");
 
         let args_clone = args.clone();
-        let result = thread::spawn(move || {
+        thread::spawn(move || {
             let rt = Runtime::new().expect("Failed to create runtime");
 
             let action = args_clone.get("action")
@@ -262,9 +298,11 @@ impl Tool for WebDeploy {
                     self_clone.get_article(&key).await
                 }),
                 "create" => rt.block_on(async { 
+                    side_effect?;
                     let content = args_clone.get("content")
                         .and_then(|v| v.as_str())
                         .ok_or("Missing or invalid 'content' parameter")?;
+                    self_clone.moderate_article(&key, content).await?;
                     self_clone.create_article(&key, content).await
                 }),
                 "found" => rt.block_on(async { 
@@ -275,8 +313,24 @@ impl Tool for WebDeploy {
             }
         })
         .join()
-        .map_err(|_| "Thread panicked".to_string())?;
-
-        result
+        .map_err(|_| "Thread panicked".to_string())?
     }   
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::moderation::ModeratorConfig;
+
+    #[tokio::test]
+    async fn model_written_articles_are_moderated() {
+        let moderator = Moderator::new(ModeratorConfig { words: vec!["explicit".into()], ..Default::default() }).unwrap();
+        let web_deploy = WebDeploy { file_map: Default::default(), moderator: None };
+        assert!(web_deploy.moderate_article("a", "something explicit").await.is_ok());
+
+        let web_deploy = web_deploy.with_moderator(Some(Arc::new(moderator)));
+        assert!(web_deploy.moderate_article("a", "a harmless recipe").await.is_ok());
+        let error = web_deploy.moderate_article("a", "something EXPLICIT").await.unwrap_err();
+        assert_eq!(error, "Blocked: the article was withheld by moderation (word:explicit)");
+    }
+}
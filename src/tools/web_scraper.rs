@@ -28,6 +28,8 @@ use playwright::Playwright;
 use tokio::{self};
 use std::fmt;
 
+use crate::untrusted;
+
 const MAX_FILE_SIZE: u64 = 5 * 1024 * 1024; // 5MB
 const WHITELIST: [&str; 110] = [
     "application/json",
@@ -142,6 +144,11 @@ const WHITELIST: [&str; 110] = [
     "text/x-awk",
 ];
 
+/// そのまま本文として読める Content-Type か (`text/plain; charset=utf-8` のようなパラメータ付きでもよい)
+pub fn is_text_content_type(content_type: &str) -> bool {
+    WHITELIST.iter().any(|&item| content_type.contains(item))
+}
+
 #[derive(Debug)]
 pub enum ScraperError {
     NetworkError,
@@ -191,6 +198,12 @@ pub struct Browser {
     client: Client,
 }
 
+impl Default for Browser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Browser {
     /// 新しいWebScraperインスタンスを生成する
     pub fn new() -> Self {
@@ -217,10 +230,9 @@ impl Browser {
             .map_err(|_| ScraperError::NetworkError)?;
 
         // ヘッダーにContent-Lengthがある場合、サイズをチェックする
-        if let Some(len) = response.content_length() {
-            if len > MAX_FILE_SIZE {
-                return Err(ScraperError::FileTooLargeError);
-            }
+        if let Some(len) = response.content_length()
+            && len > MAX_FILE_SIZE {
+            return Err(ScraperError::FileTooLargeError);
         }
 
         let content_type = response.headers().get("content-type").and_then(|v| v.to_str().ok()).unwrap_or("").to_string();
@@ -235,7 +247,7 @@ impl Browser {
         let body = String::from_utf8(body_bytes.to_vec()).map_err(|_| ScraperError::ParseError)?;
 
         if !content_type.contains("text/html") {
-            if WHITELIST.iter().any(|&item| content_type.contains(item)) {
+            if is_text_content_type(&content_type) {
                 return Ok(ScrapedData {
                     items: vec![ScrapedItem {
                         text: body,
@@ -274,7 +286,7 @@ impl Browser {
         selector_str: &str,
     ) -> Result<ScrapedData, ScraperError> {
         let playwright = Playwright::initialize().await.map_err(|_| ScraperError::InitializationError)?;
-        let browser = playwright.chromium().launcher().headless(true).args(&vec![
+        let browser = playwright.chromium().launcher().headless(true).args(&[
             // 一応,,
             String::from("--enable-features=BlockInsecurePrivateNetworkRequests"),
             String::from("--disable-file-system"),
@@ -320,7 +332,7 @@ impl Browser {
         // すべてのテキストとリンクをまとめる
         for item in content.items {
             combined_text.push_str(&item.text);
-            combined_text.push_str(" ");
+            combined_text.push(' ');
     
             if let Some(link) = item.link {
                 combined_text.push_str(&format!("({})", link));
@@ -331,7 +343,7 @@ impl Browser {
     
         // seek_posが文字数を超えていたら空文字を返す
         if seek_pos >= total_chars {
-            return format!("...<0 characters remaining>");
+            return "...<0 characters remaining>".to_string();
         }
     
         // seek_posから取得可能な文字数
@@ -388,7 +400,6 @@ If the content is too long, use 'seek_pos' and 'max_length' to paginate the resu
 - The site may require JavaScript rendering ('playwright' mode).
 - The selector may be incorrect.
 - The site may block scraping.
-IMPORTANT: **Always must include the scraped URL at the end of your response.**
 IMPORTANT: Do not use imaginary URLs.
 For searching, use Bing."
     }
@@ -453,8 +464,9 @@ For searching, use Bing."
             .ok_or_else(|| "Missing 'max_length' parameter".to_string())? as usize;
 
         let scraper = self.clone();
+        let source = format!("browser {}", url);
 
-        Browser::is_safe_url(&url).then(|| ()).ok_or_else(|| "Are you try hacking me?".to_string())?;
+        Browser::is_safe_url(&url).then_some(()).ok_or_else(|| "Are you try hacking me?".to_string())?;
 
         let result = std::thread::spawn(move || {
             let rt = tokio::runtime::Runtime::new().unwrap();
@@ -470,6 +482,8 @@ For searching, use Bing."
         .map_err(|e| format!("Scrape error: {}", e))?;
 
         let res = Browser::compress_content(result, seek_pos, max_length);
-        serde_json::to_string(&res).map_err(|e| format!("Serialization error: {}", e))
+        let json = serde_json::to_string(&res).map_err(|e| format!("Serialization error: {}", e))?;
+        // ページの本文は外部の内容としてモデルに渡す
+        Ok(untrusted::wrap(&source, &json))
     }
 }
@@ -0,0 +1,235 @@
+//! 音声メッセージの書き起こし
+//!
+//! Discord のボイスメッセージ (`.ogg`) などの音声の添付ファイルを、Whisper 互換の
+//! `/audio/transcriptions` (OpenAI や whisper.cpp の server) に送って書き起こす。
+//! 長さは利用量の上限に使うので、送る前にコンテナ (Ogg / WAV) から読み、
+//! 読めなければエンドポイントが返した長さを使う。
+
+use std::time::Duration;
+
+use reqwest::multipart::{Form, Part};
+use serde_json::Value;
+
+/// 取得する音声ファイルの上限 (OpenAI の上限に合わせる)
+pub const MAX_AUDIO_BYTES: usize = 25 * 1024 * 1024;
+
+const ENDPOINT_TIMEOUT: Duration = Duration::from_secs(120);
+
+const AUDIO_EXTENSIONS: [&str; 9] = ["ogg", "oga", "opus", "mp3", "m4a", "wav", "webm", "flac", "mpga"];
+
+/// 音声ファイルか (拡張子で判断する)
+pub fn is_audio(name: &str) -> bool {
+    let lower = name.to_lowercase();
+    lower.rsplit_once('.').is_some_and(|(_, ext)| AUDIO_EXTENSIONS.contains(&ext))
+}
+
+/// `m:ss` 形式の長さ
+pub fn format_duration(secs: f64) -> String {
+    let total = secs.round().max(0.0) as u64;
+    format!("{}:{:02}", total / 60, total % 60)
+}
+
+/// 音声の長さ (秒)
+/// Ogg (Opus / Vorbis) と WAV だけを読み、それ以外は None
+pub fn audio_duration(bytes: &[u8]) -> Option<f64> {
+    if bytes.starts_with(b"OggS") {
+        ogg_duration(bytes)
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WAVE") {
+        wav_duration(bytes)
+    } else {
+        None
+    }
+}
+
+/// 最初のストリームの最後のページの granule position から長さを求める
+fn ogg_duration(bytes: &[u8]) -> Option<f64> {
+    let mut pos = 0;
+    let mut serial = None;
+    let mut rate_and_skip = None;
+    let mut last_granule = None;
+    while bytes.get(pos..pos + 4) == Some(b"OggS") {
+        let header = bytes.get(pos..pos + 27)?;
+        let granule = i64::from_le_bytes(header[6..14].try_into().ok()?);
+        let page_serial = u32::from_le_bytes(header[14..18].try_into().ok()?);
+        let segments = header[26] as usize;
+        let table = bytes.get(pos + 27..pos + 27 + segments)?;
+        let data_start = pos + 27 + segments;
+        let data_len: usize = table.iter().map(|&s| s as usize).sum();
+        let data = bytes.get(data_start..data_start + data_len)?;
+
+        if serial.is_none() {
+            serial = Some(page_serial);
+            rate_and_skip = if data.starts_with(b"OpusHead") {
+                // Opus の granule は常に 48kHz、先頭の pre-skip は再生しない
+                Some((48_000.0, u16::from_le_bytes(data.get(10..12)?.try_into().ok()?) as i64))
+            } else if data.starts_with(b"\x01vorbis") {
+                Some((u32::from_le_bytes(data.get(12..16)?.try_into().ok()?) as f64, 0))
+            } else {
+                return None;
+            };
+        }
+        if serial == Some(page_serial) && granule >= 0 {
+            last_granule = Some(granule);
+        }
+        pos = data_start + data_len;
+    }
+    let (rate, pre_skip) = rate_and_skip?;
+    let samples = last_granule? - pre_skip;
+    (rate > 0.0 && samples >= 0).then(|| samples as f64 / rate)
+}
+
+/// `fmt ` の byte rate と `data` の大きさから長さを求める
+fn wav_duration(bytes: &[u8]) -> Option<f64> {
+    let mut pos = 12;
+    let mut byte_rate = None;
+    while let Some(chunk) = bytes.get(pos..pos + 8) {
+        let size = u32::from_le_bytes(chunk[4..8].try_into().ok()?) as usize;
+        match &chunk[0..4] {
+            b"fmt " => byte_rate = Some(u32::from_le_bytes(bytes.get(pos + 16..pos + 20)?.try_into().ok()?)),
+            b"data" => {
+                let byte_rate = byte_rate? as f64;
+                return (byte_rate > 0.0).then(|| size as f64 / byte_rate);
+            }
+            _ => {}
+        }
+        // チャンクは 2 バイト境界に揃えられている
+        pos += 8 + size + size % 2;
+    }
+    None
+}
+
+/// 書き起こしの結果
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transcript {
+    pub text: String,
+    /// 長さ (秒、わからなければ None)
+    pub duration: Option<f64>,
+}
+
+/// 書き起こしの設定
+#[derive(Clone, Debug, Default)]
+pub struct TranscriberConfig {
+    /// `/audio/transcriptions` を持つ API のベース URL (例: `http://127.0.0.1:8080/v1/`)
+    pub endpoint: String,
+    pub api_key: Option<String>,
+    pub model: String,
+    /// 言語のヒント (ISO-639-1、空なら自動判定)
+    pub language: Option<String>,
+}
+
+pub struct Transcriber {
+    config: TranscriberConfig,
+    http: reqwest::Client,
+}
+
+impl Transcriber {
+    pub fn new(config: TranscriberConfig) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(ENDPOINT_TIMEOUT)
+            .build()
+            .expect("Failed to build reqwest client");
+        Self { config, http }
+    }
+
+    /// 音声を書き起こす
+    pub async fn transcribe(&self, file_name: &str, bytes: Vec<u8>) -> Result<Transcript, String> {
+        let duration = audio_duration(&bytes);
+        let url = format!("{}/audio/transcriptions", self.config.endpoint.trim_end_matches('/'));
+        let mut form = Form::new()
+            .part("file", Part::bytes(bytes).file_name(file_name.to_string()))
+            .text("model", self.config.model.clone())
+            .text("response_format", "verbose_json");
+        if let Some(language) = self.config.language.as_ref().filter(|l| !l.is_empty()) {
+            form = form.text("language", language.clone());
+        }
+        let mut request = self.http.post(&url).multipart(form);
+        if let Some(key) = self.config.api_key.as_ref().filter(|k| !k.is_empty()) {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("{} returned {}", url, response.status()));
+        }
+        let body: Value = response.json().await.map_err(|e| e.to_string())?;
+        let text = body["text"].as_str().ok_or("no text in the transcription response")?.trim().to_string();
+        Ok(Transcript { text, duration: duration.or_else(|| body["duration"].as_f64()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_http::{self, Response};
+    use serde_json::json;
+
+    /// 1 パケットの Ogg ページ
+    fn ogg_page(serial: u32, granule: i64, data: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&serial.to_le_bytes());
+        page.extend_from_slice(&[0; 8]); // sequence, crc
+        page.push(1);
+        page.push(data.len() as u8);
+        page.extend_from_slice(data);
+        page
+    }
+
+    /// `secs` 秒の Opus の Ogg (pre-skip 312)
+    fn ogg_opus(secs: i64) -> Vec<u8> {
+        let mut head = b"OpusHead\x01\x01".to_vec();
+        head.extend_from_slice(&312u16.to_le_bytes());
+        head.extend_from_slice(&48_000u32.to_le_bytes());
+        let mut bytes = ogg_page(7, 0, &head);
+        bytes.extend(ogg_page(7, 0, b"OpusTags"));
+        bytes.extend(ogg_page(9, 999_999_999, b"other stream"));
+        bytes.extend(ogg_page(7, 48_000 * secs + 312, b"audio"));
+        bytes
+    }
+
+    fn wav(secs: u32) -> Vec<u8> {
+        let byte_rate: u32 = 16_000 * 2;
+        let mut bytes = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&[1, 0, 1, 0]);
+        bytes.extend_from_slice(&16_000u32.to_le_bytes());
+        bytes.extend_from_slice(&byte_rate.to_le_bytes());
+        bytes.extend_from_slice(&[2, 0, 16, 0]);
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(byte_rate * secs).to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn reads_durations_from_containers() {
+        assert_eq!(audio_duration(&ogg_opus(42)), Some(42.0));
+        assert_eq!(audio_duration(&wav(3)), Some(3.0));
+        assert_eq!(audio_duration(b"ID3 mp3 data"), None);
+        assert_eq!(audio_duration(&ogg_opus(42)[..30]), None);
+        assert_eq!(format_duration(125.4), "2:05");
+        assert!(is_audio("voice-message.ogg") && is_audio("memo.M4A") && !is_audio("notes.txt"));
+    }
+
+    #[tokio::test]
+    async fn sends_the_file_to_the_endpoint() {
+        let (addr, _handle) = local_http::serve("127.0.0.1:0", |request| async move {
+            let body = String::from_utf8_lossy(&request.body).to_string();
+            assert_eq!(request.path, "/v1/audio/transcriptions");
+            assert!(request.header("content-type").unwrap().starts_with("multipart/form-data"));
+            assert!(body.contains("filename=\"voice-message.ogg\""));
+            assert!(body.contains("whisper-1") && body.contains("verbose_json") && body.contains("ja"));
+            Response::json(200, &json!({ "text": " こんにちは ", "duration": 99.0 }))
+        }).await.unwrap();
+        let transcriber = Transcriber::new(TranscriberConfig {
+            endpoint: format!("http://{}/v1/", addr),
+            api_key: None,
+            model: "whisper-1".into(),
+            language: Some("ja".into()),
+        });
+
+        // コンテナから読めた長さを優先する
+        let transcript = transcriber.transcribe("voice-message.ogg", ogg_opus(5)).await.unwrap();
+        assert_eq!(transcript, Transcript { text: "こんにちは".into(), duration: Some(5.0) });
+        let transcript = transcriber.transcribe("voice-message.ogg", b"unknown".to_vec()).await.unwrap();
+        assert_eq!(transcript.duration, Some(99.0));
+    }
+}
@@ -0,0 +1,136 @@
+//! 推論中のターンの情報 (どのチャンネルの誰への応答か)
+//!
+//! call-agent の `Tool::run` は引数しか受け取らないが、推論と同じタスクで同期的に呼ばれるので、
+//! チャンネルや発言者が必要なツールには task-local で渡す。
+//! ターンの中で外部から取り込んだ内容 (`untrusted`) の出所と、ツールが応答に添付するファイルもここに記録する。
+
+use std::{cell::RefCell, future::Future};
+
+/// 推論を起こした発言の情報
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TurnContext {
+    /// `ChannelState` のキー
+    pub channel: String,
+    pub user_id: String,
+    pub user_name: String,
+    /// 発言そのものが外部から来たもの (OpenAI 互換 API など) なら、最初から外部の内容を取り込んだターンとして扱う
+    pub untrusted: bool,
+}
+
+/// ターンに取り込んだ外部の内容
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Untrusted {
+    /// 取り込んだ内容の出所 (取り込んだ順)
+    pub sources: Vec<String>,
+    /// 指示のような文が見つかった出所
+    pub suspicious: Vec<String>,
+}
+
+impl Untrusted {
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+}
+
+/// ツールが応答に添付するファイル
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TurnFile {
+    pub name: String,
+    pub data: Vec<u8>,
+    /// モデルのコストとは別に消費するレート (画像生成など)
+    pub rate_cost: u64,
+}
+
+tokio::task_local! {
+    static TURN: TurnContext;
+    static UNTRUSTED: RefCell<Untrusted>;
+    static FILES: RefCell<Vec<TurnFile>>;
+}
+
+/// `context` を設定して `future` を実行する
+pub async fn scope<F: Future>(context: TurnContext, future: F) -> F::Output {
+    let mut untrusted = Untrusted::default();
+    if context.untrusted {
+        untrusted.sources.push(format!("request from {}", context.channel));
+    }
+    TURN.scope(context, UNTRUSTED.scope(RefCell::new(untrusted), FILES.scope(RefCell::default(), future))).await
+}
+
+/// 実行中のターンの情報 (推論の外では None)
+pub fn current() -> Option<TurnContext> {
+    TURN.try_with(Clone::clone).ok()
+}
+
+/// 外部の内容をターンに取り込んだことを記録する (推論の外では何もしない)
+pub fn mark_untrusted(source: &str, suspicious: bool) {
+    let _ = UNTRUSTED.try_with(|untrusted| {
+        let mut untrusted = untrusted.borrow_mut();
+        untrusted.sources.push(source.to_string());
+        if suspicious {
+            untrusted.suspicious.push(source.to_string());
+        }
+    });
+}
+
+/// ターンに取り込んだ外部の内容 (推論の外では空)
+pub fn untrusted() -> Untrusted {
+    UNTRUSTED.try_with(|untrusted| untrusted.borrow().clone()).unwrap_or_default()
+}
+
+/// 応答にファイルを添付する (推論の外では false)
+pub fn attach_file(name: &str, data: Vec<u8>, rate_cost: u64) -> bool {
+    FILES.try_with(|files| {
+        files.borrow_mut().push(TurnFile { name: name.to_string(), data, rate_cost });
+    }).is_ok()
+}
+
+/// 添付するファイルを取り出す (推論の外では空)
+pub fn take_files() -> Vec<TurnFile> {
+    FILES.try_with(|files| files.take()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn context_is_visible_only_inside_the_scope() {
+        assert_eq!(current(), None);
+        let context = TurnContext { channel: "c1".into(), user_id: "42".into(), user_name: "alice".into(), untrusted: false };
+        let seen = scope(context.clone(), async { current() }).await;
+        assert_eq!(seen, Some(context));
+        assert_eq!(current(), None);
+    }
+
+    #[tokio::test]
+    async fn untrusted_content_is_tracked_per_turn() {
+        mark_untrusted("outside", false);
+        let seen = scope(TurnContext::default(), async {
+            mark_untrusted("browser https://example.com", true);
+            mark_untrusted("browsing_worker", false);
+            untrusted()
+        }).await;
+        assert_eq!(seen.sources, vec!["browser https://example.com", "browsing_worker"]);
+        assert_eq!(seen.suspicious, vec!["browser https://example.com"]);
+        // 次のターンには持ち越さない
+        assert!(scope(TurnContext::default(), async { untrusted() }).await.is_empty());
+        assert!(untrusted().is_empty());
+
+        // 外部から来た発言のターンは最初から記録がある
+        let context = TurnContext { channel: "api:scripts".into(), untrusted: true, ..Default::default() };
+        assert_eq!(scope(context, async { untrusted() }).await.sources, vec!["request from api:scripts"]);
+    }
+
+    #[tokio::test]
+    async fn attached_files_are_taken_once() {
+        assert!(!attach_file("outside.png", vec![1], 5));
+        let (first, second) = scope(TurnContext::default(), async {
+            assert!(attach_file("a.png", vec![1, 2], 5));
+            assert!(attach_file("b.png", vec![3], 5));
+            (take_files(), take_files())
+        }).await;
+        assert_eq!(first.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(), vec!["a.png", "b.png"]);
+        assert_eq!(first[1], TurnFile { name: "b.png".into(), data: vec![3], rate_cost: 5 });
+        assert!(second.is_empty());
+    }
+}